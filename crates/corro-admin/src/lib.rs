@@ -4,6 +4,7 @@ use std::{
 };
 
 use camino::Utf8PathBuf;
+use corro_agent::api::public::pubsub::cancel_sub;
 use corro_types::{
     actor::{ActorId, ClusterId},
     agent::{Agent, BookedVersions, Bookie, LockKind, LockMeta, LockState},
@@ -123,6 +124,10 @@ pub enum SubsCommand {
         id: Option<Uuid>,
     },
     List,
+    Cancel {
+        hash: Option<String>,
+        id: Option<Uuid>,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -530,6 +535,34 @@ async fn handle_conn(
                         }
                     };
                 }
+                Command::Subs(SubsCommand::Cancel { hash, id }) => {
+                    let id = match (hash, id) {
+                        (Some(hash), _) => agent.subs_manager().get_by_hash(&hash).map(|m| m.id()),
+                        (None, Some(id)) => Some(id),
+                        (None, None) => {
+                            send_error(&mut stream, "specify hash or id for subscription").await;
+                            continue;
+                        }
+                    };
+                    let cancelled = match id {
+                        Some(id) => cancel_sub(agent.subs_manager(), id).await,
+                        None => None,
+                    };
+                    match cancelled {
+                        Some(matcher) => {
+                            info_log(
+                                &mut stream,
+                                format!("cancelled subscription {}", matcher.id()),
+                            )
+                            .await;
+                            send_success(&mut stream).await;
+                        }
+                        None => {
+                            send_error(&mut stream, "unknown subscription hash or id").await;
+                            continue;
+                        }
+                    }
+                }
                 Command::Log(cmd) => match cmd {
                     LogCommand::Set { filter } => {
                         if let Some(ref handle) = tracing_handle {
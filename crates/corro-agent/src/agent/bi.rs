@@ -1,4 +1,4 @@
-use crate::api::peer::serve_sync;
+use crate::api::peer::{serve_has_version, serve_sync};
 use corro_types::{
     agent::{Agent, Bookie},
     broadcast::{BiPayload, BiPayloadV1},
@@ -98,6 +98,20 @@ pub fn spawn_bipayload_handler(
                                                         }
                                                         break;
                                                     }
+                                                    BiPayloadV1::HasVersion {
+                                                        actor_id,
+                                                        version,
+                                                    } => {
+                                                        if let Err(e) = serve_has_version(
+                                                            &agent, &bookie, actor_id, version,
+                                                            cluster_id, tx,
+                                                        )
+                                                        .await
+                                                        {
+                                                            warn!("could not answer version check: {e}");
+                                                        }
+                                                        break;
+                                                    }
                                                 },
                                             }
                                         }
@@ -50,6 +50,8 @@ pub enum SyncRecvError {
     UnexpectedEndOfStream,
     #[error("expected sync clock message, received something else")]
     ExpectedClockMessage,
+    #[error("expected version check message, received something else")]
+    ExpectedVersionCheck,
     #[error("timed out waiting for sync message")]
     TimedOut(#[from] Elapsed),
     #[error("changes channel is closed")]
@@ -635,6 +635,7 @@ pub async fn handle_changes(
 
                 gauge!("corro.agent.changes.in_queue").set(buf_cost as f64);
                 gauge!("corro.agent.changesets.in_queue").set(queue.len() as f64);
+                agent.replication().set_apply_queue_len(queue.len());
                 gauge!("corro.agent.changes.processing.jobs").set(join_set.len() as f64);
 
                 if buf_cost < max_changes_chunk && !queue.is_empty() && join_set.len() < MAX_CONCURRENT {
@@ -829,6 +830,8 @@ pub async fn handle_sync(
         };
 
         if candidates.is_empty() {
+            // nobody to sync with, so there's nothing we could be missing
+            agent.replication().record_sync();
             return Ok(());
         }
 
@@ -876,6 +879,8 @@ pub async fn handle_sync(
         }
     };
 
+    agent.replication().record_sync();
+
     let elapsed = start.elapsed();
     if n > 0 {
         info!(
@@ -896,7 +901,7 @@ pub async fn handle_sync(
 #[cfg(test)]
 mod tests {
     use crate::agent::setup;
-    use crate::api::public::api_v1_db_schema;
+    use crate::api::public::{api_v1_db_schema, authz::ApiScope};
 
     use super::*;
     use axum::{http::StatusCode, Extension, Json};
@@ -945,8 +950,12 @@ mod tests {
 
         let (agent, agent_options) = setup(config, tripwire.clone()).await?;
 
-        let (status_code, _res) =
-            api_v1_db_schema(Extension(agent.clone()), Json(vec![TEST_SCHEMA.to_owned()])).await;
+        let (status_code, _res) = api_v1_db_schema(
+            Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            Json(vec![TEST_SCHEMA.to_owned()]),
+        )
+        .await;
         assert_eq!(status_code, StatusCode::OK);
 
         let other_actor = ActorId(uuid::Uuid::new_v4());
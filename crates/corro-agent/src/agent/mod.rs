@@ -17,8 +17,7 @@ pub mod util;
 #[cfg(test)]
 mod tests;
 
-use bytes::Bytes;
-use corro_types::api::QueryEventMeta;
+use crate::api::public::pubsub::SubEvent;
 use std::{collections::HashMap, sync::Arc, time::Duration};
 use tokio::sync::{broadcast::Sender, RwLock};
 use uuid::Uuid;
@@ -26,7 +25,7 @@ use uuid::Uuid;
 // Public exports
 pub use error::{SyncClientError, SyncRecvError};
 pub use run_root::start_with_config;
-pub use setup::{setup, AgentOptions};
+pub use setup::{setup, AgentOptions, ApiListener};
 pub use uni::spawn_unipayload_handler;
 pub use util::process_multiple_changes;
 
@@ -36,7 +35,7 @@ pub const RANDOM_NODES_CHOICES: usize = 10;
 pub const CHECK_EMPTIES_TO_INSERT_AFTER: Duration = Duration::from_secs(120);
 pub const TO_CLEAR_COUNT: usize = 1000;
 
-pub type BcastCache = Arc<RwLock<HashMap<Uuid, Sender<(Bytes, QueryEventMeta)>>>>;
+pub type BcastCache = Arc<RwLock<HashMap<Uuid, Sender<SubEvent>>>>;
 
 #[derive(Clone)]
 pub struct CountedExecutor;
@@ -105,6 +105,13 @@ async fn run(agent: Agent, opts: AgentOptions, pconf: PerfConfig) -> eyre::Resul
         }
     }
 
+    // other actors' bookkeeping is loaded below, the API only needs a handle to it
+    let bookie = Bookie::new_with_registry(Default::default(), lock_registry);
+    {
+        let mut w = bookie.write::<&str, _>("init", None).await;
+        w.insert(agent.actor_id(), agent.booked().clone());
+    }
+
     // Setup client http API
     util::setup_http_api_handler(
         &agent,
@@ -112,12 +119,19 @@ async fn run(agent: Agent, opts: AgentOptions, pconf: PerfConfig) -> eyre::Resul
         subs_bcast_cache,
         updates_bcast_cache,
         &subs_manager,
+        &transport,
+        &bookie,
         api_listeners,
     )
     .await?;
 
     tokio::spawn(util::clear_buffered_meta_loop(agent.clone(), rx_clear_buf));
 
+    spawn_counted(
+        util::clear_expired_idempotency_keys_loop(agent.clone(), tripwire.clone())
+            .inspect(|_| info!("corrosion idempotency keys cleanup loop is done")),
+    );
+
     tokio::spawn(metrics::metrics_loop(agent.clone(), transport.clone()));
     tokio::spawn(handlers::handle_gossip_to_send(
         transport.clone(),
@@ -130,12 +144,6 @@ async fn run(agent: Agent, opts: AgentOptions, pconf: PerfConfig) -> eyre::Resul
 
     spawn_handle_db_maintenance(&agent);
 
-    let bookie = Bookie::new_with_registry(Default::default(), lock_registry);
-    {
-        let mut w = bookie.write::<&str, _>("init", None).await;
-        w.insert(agent.actor_id(), agent.booked().clone());
-    }
-
     let start = Instant::now();
     {
         let conn = agent.pool().read().await?;
@@ -3,20 +3,22 @@
 // External crates
 use antithesis_sdk::assert_always;
 use arc_swap::ArcSwap;
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use indexmap::IndexMap;
 use metrics::counter;
 use parking_lot::RwLock;
 use rusqlite::{Connection, OptionalExtension};
 use serde_json::json;
+use spawn::spawn_counted;
 use std::{
     net::SocketAddr,
     ops::{DerefMut, RangeInclusive},
+    os::unix::fs::{FileTypeExt, PermissionsExt},
     sync::Arc,
     time::Duration,
 };
 use tokio::{
-    net::TcpListener,
+    net::{TcpListener, UnixListener},
     sync::{
         mpsc::{channel as tokio_channel, Receiver as TokioReceiver},
         RwLock as TokioRwLock, Semaphore,
@@ -41,8 +43,10 @@ use corro_types::{
     agent::{
         migrate, Agent, AgentConfig, Booked, BookedVersions, LockRegistry, LockState, SplitPool,
     },
+    api::ApiAddr,
     base::CrsqlDbVersion,
     broadcast::{BroadcastInput, ChangeSource, ChangeV1, FocaInput},
+    cdc::ChangesFeed,
     channel::{bounded, CorroReceiver},
     config::Config,
     members::Members,
@@ -52,12 +56,18 @@ use corro_types::{
     updates::UpdatesManager,
 };
 
+/// Listener of the HTTP API
+pub enum ApiListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
 /// Runtime state for the Corrosion agent
 pub struct AgentOptions {
     pub lock_registry: LockRegistry,
     pub gossip_server_endpoint: quinn::Endpoint,
     pub transport: Transport,
-    pub api_listeners: Vec<TcpListener>,
+    pub api_listeners: Vec<ApiListener>,
     pub rx_bcast: CorroReceiver<BroadcastInput>,
     pub rx_apply: CorroReceiver<(ActorId, CrsqlDbVersion)>,
     pub rx_clear_buf: CorroReceiver<(ActorId, RangeInclusive<CrsqlDbVersion>)>,
@@ -117,6 +127,16 @@ pub async fn setup(conf: Config, tripwire: Tripwire) -> eyre::Result<(Agent, Age
     let subs_manager = SubsManager::default();
 
     let updates_manager = UpdatesManager::default();
+
+    let changes_feed = if conf.api.changes.enabled {
+        let (changes_feed, writer) =
+            ChangesFeed::open(&conf.api.changes.path(&conf.db), conf.api.changes.max_len)?;
+        spawn_counted(writer.run(tripwire.clone()));
+        changes_feed
+    } else {
+        ChangesFeed::default()
+    };
+
     // Setup subscription handlers
     let subs_bcast_cache = setup_spawn_subscriptions(
         &subs_manager,
@@ -158,9 +178,20 @@ pub async fn setup(conf: Config, tripwire: Tripwire) -> eyre::Result<(Agent, Age
 
     let mut api_listeners = Vec::with_capacity(conf.api.bind_addr.len());
     for addr in conf.api.bind_addr.iter() {
-        api_listeners.push(TcpListener::bind(addr).await?);
+        api_listeners.push(match addr {
+            ApiAddr::Tcp(addr) => ApiListener::Tcp(TcpListener::bind(addr).await?),
+            ApiAddr::Unix(path) => {
+                ApiListener::Unix(bind_unix_socket(path, conf.api.unix_socket_mode)?)
+            }
+        });
     }
-    let api_addr = api_listeners.first().unwrap().local_addr()?;
+    let api_addr = api_listeners
+        .iter()
+        .find_map(|listener| match listener {
+            ApiListener::Tcp(listener) => Some(listener.local_addr()),
+            ApiListener::Unix(_) => None,
+        })
+        .transpose()?;
 
     let (tx_bcast, rx_bcast) = bounded(conf.perf.bcast_channel_len, "bcast");
     let (tx_changes, rx_changes) = bounded(conf.perf.changes_channel_len, "changes");
@@ -282,12 +313,47 @@ pub async fn setup(conf: Config, tripwire: Tripwire) -> eyre::Result<(Agent, Age
         cluster_id,
         subs_manager,
         updates_manager,
+        changes_feed,
         tripwire,
     });
 
     Ok((agent, opts))
 }
 
+/// Binds a unix socket for the API, replacing any socket left over by a
+/// previous run
+fn bind_unix_socket(path: &Utf8Path, mode: Option<u32>) -> eyre::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => eyre::bail!("{path} already exists and is not a unix socket"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let Some(mode) = mode else {
+        return Ok(UnixListener::bind(path)?);
+    };
+
+    // bind under a temporary name and move the socket in place once its
+    // permissions are set, so it's never reachable with the umask's
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        path.file_name().unwrap_or("api.sock"),
+        std::process::id()
+    ));
+    _ = std::fs::remove_file(&tmp_path);
+
+    let listener = UnixListener::bind(&tmp_path)?;
+    std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(mode))?;
+    std::fs::rename(&tmp_path, path)?;
+
+    Ok(listener)
+}
+
 /// Initialise subscription state and tasks
 ///
 /// 1. Get subscriptions state directory from config
@@ -5,7 +5,7 @@ use std::{
     time::{Duration, Instant},
 };
 
-use axum::Extension;
+use axum::{http::HeaderMap, Extension};
 use futures::{future, stream::FuturesUnordered, StreamExt, TryStreamExt};
 use hyper::StatusCode;
 use rand::{
@@ -27,7 +27,10 @@ use crate::{
     agent::process_multiple_changes,
     api::{
         peer::parallel_sync,
-        public::{api_v1_db_schema, api_v1_transactions, TimeoutParams},
+        public::{
+            api_v1_db_schema, api_v1_transactions, authz::ApiScope, ReplicationParams,
+            TimeoutParams,
+        },
     },
     transport::Transport,
 };
@@ -35,7 +38,10 @@ use corro_tests::*;
 use corro_types::change::Change;
 use corro_types::{
     actor::ActorId,
-    api::{ExecResponse, ExecResult, Statement},
+    api::{
+        ConsistencyToken, ExecResponse, ExecResult, Precondition, QueryEvent, Statement,
+        TransactionRequest,
+    },
     base::{CrsqlDbVersion, CrsqlSeq},
     broadcast::{ChangeSource, ChangeV1, Changeset},
     sync::generate_sync,
@@ -77,7 +83,10 @@ async fn insert_rows_and_gossip() -> eyre::Result<()> {
         client.request(
             hyper::Request::builder()
                 .method(hyper::Method::POST)
-                .uri(format!("http://{}/v1/transactions", ta1.agent.api_addr()))
+                .uri(format!(
+                    "http://{}/v1/transactions",
+                    ta1.agent.api_addr().unwrap()
+                ))
                 .header(hyper::header::CONTENT_TYPE, "application/json")
                 .body(serde_json::to_vec(&req_body)?.into())?,
         ),
@@ -136,7 +145,10 @@ async fn insert_rows_and_gossip() -> eyre::Result<()> {
         .request(
             hyper::Request::builder()
                 .method(hyper::Method::POST)
-                .uri(format!("http://{}/v1/transactions", ta1.agent.api_addr()))
+                .uri(format!(
+                    "http://{}/v1/transactions",
+                    ta1.agent.api_addr().unwrap()
+                ))
                 .header(hyper::header::CONTENT_TYPE, "application/json")
                 .body(serde_json::to_vec(&req_body)?.into())?,
         )
@@ -219,7 +231,10 @@ async fn insert_rows_and_gossip() -> eyre::Result<()> {
         client.request(
             hyper::Request::builder()
                 .method(hyper::Method::POST)
-                .uri(format!("http://{}/v1/transactions", ta1.agent.api_addr()))
+                .uri(format!(
+                    "http://{}/v1/transactions",
+                    ta1.agent.api_addr().unwrap()
+                ))
                 .header(hyper::header::CONTENT_TYPE, "application/json")
                 .body(serde_json::to_vec(&req_body)?.into())?,
         ),
@@ -259,6 +274,288 @@ async fn insert_rows_and_gossip() -> eyre::Result<()> {
     Ok(())
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn wait_for_replication() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(
+        |conf| {
+            conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+
+    timeout(Duration::from_secs(10), async {
+        while !ta1
+            .agent
+            .members()
+            .read()
+            .states
+            .contains_key(&ta2.agent.actor_id())
+        {
+            sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await?;
+
+    let client = hyper::Client::builder().build_http::<hyper::Body>();
+
+    let transact = |id: i64, query: &'static str| {
+        let req_body: Vec<Statement> = vec![Statement::WithParams(
+            "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+            vec![id.into(), "hello".into()],
+        )];
+        let req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(format!(
+                "http://{}/v1/transactions?{query}",
+                ta1.agent.api_addr().unwrap()
+            ))
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&req_body).unwrap().into())
+            .unwrap();
+        let res = client.request(req);
+        async move {
+            let res = res.await?;
+            let status = res.status();
+            let body: ExecResponse =
+                serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+            Ok::<_, eyre::Report>((status, body))
+        }
+    };
+
+    let (status, body) = transact(1, "replicas=1&replication_timeout=10").await?;
+    assert_eq!(status, hyper::StatusCode::OK);
+    let replication = body.replication.expect("missing replication status");
+    assert!(replication.complete);
+    assert_eq!(
+        replication.confirmed_by,
+        vec![ta2.agent.actor_id().to_string()]
+    );
+
+    // not enough members to ever satisfy this
+    let (status, body) = transact(2, "replicas=2&replication_timeout=1").await?;
+    assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+    assert!(body.replication.is_none());
+
+    let (status, body) = transact(3, "").await?;
+    assert_eq!(status, hyper::StatusCode::OK);
+    assert!(body.replication.is_none());
+
+    let (status, _) = transact(4, "ring0_fraction=1.5").await?;
+    assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn read_your_writes() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(
+        |conf| {
+            conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+
+    let client = hyper::Client::builder().build_http::<hyper::Body>();
+
+    let req_body: Vec<Statement> = vec![Statement::WithParams(
+        "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+        vec![1i64.into(), "hello".into()],
+    )];
+    let res = client
+        .request(
+            hyper::Request::builder()
+                .method(hyper::Method::POST)
+                .uri(format!(
+                    "http://{}/v1/transactions",
+                    ta1.agent.api_addr().unwrap()
+                ))
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&req_body)?.into())?,
+        )
+        .await?;
+    let body: ExecResponse =
+        serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+    let token = body.consistency_token.expect("missing consistency token");
+    assert_eq!(token.actor_id, ta1.agent.actor_id().0);
+
+    let query = |token: ConsistencyToken, timeout: u64| {
+        client.request(
+            hyper::Request::builder()
+                .method(hyper::Method::POST)
+                .uri(format!(
+                    "http://{}/v1/queries?consistency_token={token}&consistency_timeout={timeout}",
+                    ta2.agent.api_addr().unwrap()
+                ))
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(
+                    serde_json::to_vec(&Statement::Simple("SELECT id FROM tests".into()))
+                        .unwrap()
+                        .into(),
+                )
+                .unwrap(),
+        )
+    };
+
+    let res = query(token, 10).await?;
+    assert_eq!(res.status(), hyper::StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    let rows = body
+        .split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(serde_json::from_slice::<QueryEvent>)
+        .filter(|evt| matches!(evt, Ok(QueryEvent::Row(..))))
+        .count();
+    assert_eq!(rows, 1);
+
+    // this version was never written
+    let res = query(
+        ConsistencyToken {
+            version: token.version + 100,
+            ..token
+        },
+        1,
+    )
+    .await?;
+    assert_eq!(res.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn row_version_of_replicated_row() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(
+        |conf| {
+            conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+
+    let client = hyper::Client::builder().build_http::<hyper::Body>();
+
+    let transact = |agent: &Agent, req: TransactionRequest| {
+        let addr = agent.api_addr().unwrap();
+        let client = client.clone();
+        async move {
+            let res = client
+                .request(
+                    hyper::Request::builder()
+                        .method(hyper::Method::POST)
+                        .uri(format!("http://{addr}/v1/transactions"))
+                        .header(hyper::header::CONTENT_TYPE, "application/json")
+                        .body(serde_json::to_vec(&req)?.into())?,
+                )
+                .await?;
+            let status = res.status();
+            let body: ExecResponse =
+                serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+            Ok::<_, eyre::Report>((status, body))
+        }
+    };
+
+    // the nodes' versions diverge, a local version wouldn't match the token
+    for id in 10i64..13 {
+        let (status, _) = transact(
+            &ta2.agent,
+            vec![Statement::WithParams(
+                "INSERT INTO tests2 (id,text) VALUES (?,?)".into(),
+                vec![id.into(), "local".into()],
+            )]
+            .into(),
+        )
+        .await?;
+        assert_eq!(status, hyper::StatusCode::OK);
+    }
+
+    let (status, body) = transact(
+        &ta1.agent,
+        vec![Statement::WithParams(
+            "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+            vec![1i64.into(), "hello".into()],
+        )]
+        .into(),
+    )
+    .await?;
+    assert_eq!(status, hyper::StatusCode::OK);
+    let token = body.consistency_token.expect("missing consistency token");
+
+    // wait for the row to reach the second node
+    let res = client
+        .request(
+            hyper::Request::builder()
+                .method(hyper::Method::POST)
+                .uri(format!(
+                    "http://{}/v1/queries?consistency_token={token}&consistency_timeout=10",
+                    ta2.agent.api_addr().unwrap()
+                ))
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(
+                    serde_json::to_vec(&Statement::Simple("SELECT id FROM tests".into()))?.into(),
+                )?,
+        )
+        .await?;
+    assert_eq!(res.status(), hyper::StatusCode::OK);
+
+    let update = |version: Option<ConsistencyToken>| TransactionRequest::Conditional {
+        statements: vec!["UPDATE tests SET text = 'bye' WHERE id = 1".into()],
+        preconditions: vec![Precondition::RowVersion {
+            table: "tests".into(),
+            pk: vec![1i64.into()],
+            version,
+        }],
+    };
+
+    // the writer's version, on a node it was replicated to
+    let (status, _) = transact(
+        &ta2.agent,
+        update(Some(ConsistencyToken {
+            version: token.version + 1,
+            ..token
+        })),
+    )
+    .await?;
+    assert_eq!(status, hyper::StatusCode::CONFLICT);
+
+    let (status, body) = transact(&ta2.agent, update(Some(token))).await?;
+    assert_eq!(status, hyper::StatusCode::OK);
+    let updated = body.consistency_token.expect("missing consistency token");
+    assert_eq!(updated.actor_id, ta2.agent.actor_id().0);
+
+    // the row was since written by the second node
+    let (status, _) = transact(&ta2.agent, update(Some(token))).await?;
+    assert_eq!(status, hyper::StatusCode::CONFLICT);
+    let (status, _) = transact(&ta2.agent, update(Some(updated))).await?;
+    assert_eq!(status, hyper::StatusCode::OK);
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
 async fn chill_test() -> eyre::Result<()> {
     configurable_stress_test(2, 1, 4).await
@@ -336,7 +633,7 @@ pub async fn configurable_stress_test(
 
     let addrs: Vec<(ActorId, SocketAddr)> = agents
         .iter()
-        .map(|ta| (ta.agent.actor_id(), ta.agent.api_addr()))
+        .map(|ta| (ta.agent.actor_id(), ta.agent.api_addr().unwrap()))
         .collect();
 
     let iter = (0..input_count).flat_map(|n| {
@@ -626,7 +923,10 @@ async fn large_tx_sync() -> eyre::Result<()> {
             client.request(
                 hyper::Request::builder()
                     .method(hyper::Method::POST)
-                    .uri(format!("http://{}/v1/transactions", ta1.agent.api_addr()))
+                    .uri(format!(
+                        "http://{}/v1/transactions",
+                        ta1.agent.api_addr().unwrap()
+                    ))
                     .header(hyper::header::CONTENT_TYPE, "application/json")
                     .body(serde_json::to_vec(&req_body)?.into())?,
             ),
@@ -789,6 +1089,7 @@ async fn test_clear_empty_versions() -> eyre::Result<()> {
     // setup the schema, for both nodes
     let (status_code, _body) = api_v1_db_schema(
         Extension(ta1.agent.clone()),
+        Extension(ApiScope::unrestricted()),
         axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
     )
     .await;
@@ -797,6 +1098,7 @@ async fn test_clear_empty_versions() -> eyre::Result<()> {
 
     let (status_code, _body) = api_v1_db_schema(
         Extension(ta2.agent.clone()),
+        Extension(ApiScope::unrestricted()),
         axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
     )
     .await;
@@ -885,6 +1187,7 @@ async fn process_failed_changes() -> eyre::Result<()> {
     // setup the schema, for both nodes
     let (status_code, _body) = api_v1_db_schema(
         Extension(ta1.agent.clone()),
+        Extension(ApiScope::unrestricted()),
         axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
     )
     .await;
@@ -893,6 +1196,7 @@ async fn process_failed_changes() -> eyre::Result<()> {
     let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
     let (status_code, _body) = api_v1_db_schema(
         Extension(ta2.agent.clone()),
+        Extension(ApiScope::unrestricted()),
         axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
     )
     .await;
@@ -901,11 +1205,19 @@ async fn process_failed_changes() -> eyre::Result<()> {
     for i in 1..=5_i64 {
         let (status_code, _) = api_v1_transactions(
             Extension(ta2.agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            None,
+            None,
+            HeaderMap::new(),
             axum::extract::Query(TimeoutParams { timeout: None }),
-            axum::Json(vec![Statement::WithParams(
-                "INSERT OR REPLACE INTO tests (id,text) VALUES (?,?)".into(),
-                vec![i.into(), "service-text".into()],
-            )]),
+            axum::extract::Query(ReplicationParams::default()),
+            axum::Json(
+                vec![Statement::WithParams(
+                    "INSERT OR REPLACE INTO tests (id,text) VALUES (?,?)".into(),
+                    vec![i.into(), "service-text".into()],
+                )]
+                .into(),
+            ),
         )
         .await;
         assert_eq!(status_code, StatusCode::OK);
@@ -1010,6 +1322,7 @@ async fn test_process_multiple_changes() -> eyre::Result<()> {
     // setup the schema, for both nodes
     let (status_code, _body) = api_v1_db_schema(
         Extension(ta1.agent.clone()),
+        Extension(ApiScope::unrestricted()),
         axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
     )
     .await;
@@ -1018,6 +1331,7 @@ async fn test_process_multiple_changes() -> eyre::Result<()> {
 
     let (status_code, _body) = api_v1_db_schema(
         Extension(ta2.agent.clone()),
+        Extension(ApiScope::unrestricted()),
         axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
     )
     .await;
@@ -1328,18 +1642,26 @@ async fn insert_rows(agent: Agent, start: i64, n: i64) {
     for i in start..=n {
         let (status_code, _) = api_v1_transactions(
             Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            None,
+            None,
+            HeaderMap::new(),
             axum::extract::Query(TimeoutParams { timeout: None }),
-            axum::Json(vec![Statement::WithParams(
-                "INSERT OR REPLACE INTO tests3 (id,text,text2, num, num2) VALUES (?,?,?,?,?)"
-                    .into(),
-                vec![
-                    i.into(),
-                    "service-name".into(),
-                    "second text".into(),
-                    (i + 20).into(),
-                    (i + 100).into(),
-                ],
-            )]),
+            axum::extract::Query(ReplicationParams::default()),
+            axum::Json(
+                vec![Statement::WithParams(
+                    "INSERT OR REPLACE INTO tests3 (id,text,text2, num, num2) VALUES (?,?,?,?,?)"
+                        .into(),
+                    vec![
+                        i.into(),
+                        "service-name".into(),
+                        "second text".into(),
+                        (i + 20).into(),
+                        (i + 100).into(),
+                    ],
+                )]
+                .into(),
+            ),
         )
         .await;
         assert_eq!(status_code, StatusCode::OK);
@@ -1408,7 +1730,7 @@ async fn many_small_changes() -> eyre::Result<()> {
                         .collect::<Vec<_>>()
                 };
 
-                let api_addr = ta.agent.api_addr();
+                let api_addr = ta.agent.api_addr().unwrap();
                 let actor_id = ta.agent.actor_id();
 
                 let _: () = FuturesUnordered::from_iter(durs.into_iter().map(|dur| {
@@ -6,11 +6,18 @@
 //! be pulled out of this file in future.
 
 use crate::{
-    agent::{handlers, CountedExecutor, TO_CLEAR_COUNT},
+    agent::{handlers, ApiListener, CountedExecutor, TO_CLEAR_COUNT},
     api::public::{
-        api_v1_db_schema, api_v1_queries, api_v1_table_stats, api_v1_transactions,
-        pubsub::{api_v1_sub_by_id, api_v1_subs},
+        api_v1_db_schema, api_v1_queries, api_v1_queries_explain, api_v1_table_stats,
+        api_v1_transactions,
+        authz::ApiScope,
+        health::api_v1_health,
+        history::api_v1_row_history,
+        pubsub::{api_v1_sub_by_id, api_v1_sub_cancel, api_v1_subs},
+        rate_limit::{forget_idle_clients, limit_requests, limit_rows, RateLimits},
+        tls::{reload_on_change, tls_incoming, ApiTls},
         update::SharedUpdateBroadcastCache,
+        ws::api_v1_ws,
     },
     transport::Transport,
 };
@@ -23,19 +30,20 @@ use corro_types::{
     base::{CrsqlDbVersion, CrsqlSeq},
     broadcast::{ChangeSource, ChangeV1, Changeset, ChangesetParts, FocaCmd, FocaInput},
     channel::CorroReceiver,
-    config::AuthzConfig,
+    config::{ApiPermission, AuthzConfig},
     pubsub::SubsManager,
     updates::{match_changes, match_changes_from_db_version},
 };
 
 use super::BcastCache;
+use crate::api::public::changes::api_v1_changes;
 use crate::api::public::update::api_v1_updates;
 use antithesis_sdk::{assert_always, assert_unreachable};
 use axum::{
     error_handling::HandleErrorLayer,
-    extract::DefaultBodyLimit,
+    extract::{DefaultBodyLimit, State},
     headers::{authorization::Bearer, Authorization},
-    routing::{get, post},
+    routing::{delete, get, post},
     BoxError, Extension, Router, TypedHeader,
 };
 use corro_types::broadcast::Timestamp;
@@ -57,7 +65,7 @@ use std::{
     sync::{atomic::AtomicI64, Arc},
     time::{Duration, Instant},
 };
-use tokio::{net::TcpListener, task::block_in_place};
+use tokio::task::block_in_place;
 use tower::{limit::ConcurrencyLimitLayer, load_shed::LoadShedLayer};
 use tower_http::trace::TraceLayer;
 use tracing::{debug, error, info, trace, warn};
@@ -168,116 +176,253 @@ pub async fn load_member_states(agent: &Agent) -> Vec<(SocketAddr, Member<Actor>
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn setup_http_api_handler(
     agent: &Agent,
     tripwire: &Tripwire,
     subs_bcast_cache: BcastCache,
     updates_bcast_cache: SharedUpdateBroadcastCache,
     subs_manager: &SubsManager,
-    api_listeners: Vec<TcpListener>,
+    transport: &Transport,
+    bookie: &Bookie,
+    api_listeners: Vec<ApiListener>,
 ) -> eyre::Result<()> {
+    let rate_limits = RateLimits::new(&agent.config().api.rate_limit);
+    if rate_limits.is_enabled() {
+        spawn_counted(forget_idle_clients(rate_limits.clone(), tripwire.clone()));
+    }
+
     let api = Router::new()
         // transactions
         .route(
             "/v1/transactions",
-            post(api_v1_transactions).route_layer(
-                tower::ServiceBuilder::new()
-                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
-                        Ok::<_, Infallible>((
-                            StatusCode::SERVICE_UNAVAILABLE,
-                            "max concurrency limit reached".to_string(),
-                        ))
-                    }))
-                    .layer(LoadShedLayer::new())
-                    .layer(ConcurrencyLimitLayer::new(128)),
-            ),
+            post(api_v1_transactions)
+                .route_layer(
+                    tower::ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                            Ok::<_, Infallible>((
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "max concurrency limit reached".to_string(),
+                            ))
+                        }))
+                        .layer(LoadShedLayer::new())
+                        .layer(ConcurrencyLimitLayer::new(128)),
+                )
+                .route_layer(axum::middleware::from_fn_with_state(
+                    rate_limits.clone(),
+                    limit_rows,
+                ))
+                .route_layer(axum::middleware::from_fn_with_state(
+                    ApiPermission::Transact,
+                    require_permission,
+                )),
         )
         // queries
         .route(
             "/v1/queries",
-            post(api_v1_queries).route_layer(
-                tower::ServiceBuilder::new()
-                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
-                        Ok::<_, Infallible>((
-                            StatusCode::SERVICE_UNAVAILABLE,
-                            "max concurrency limit reached".to_string(),
-                        ))
-                    }))
-                    .layer(LoadShedLayer::new())
-                    .layer(ConcurrencyLimitLayer::new(128)),
-            ),
+            post(api_v1_queries)
+                .route_layer(
+                    tower::ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                            Ok::<_, Infallible>((
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "max concurrency limit reached".to_string(),
+                            ))
+                        }))
+                        .layer(LoadShedLayer::new())
+                        .layer(ConcurrencyLimitLayer::new(128)),
+                )
+                .route_layer(axum::middleware::from_fn_with_state(
+                    ApiPermission::Query,
+                    require_permission,
+                )),
+        )
+        .route(
+            "/v1/queries/explain",
+            post(api_v1_queries_explain)
+                .route_layer(
+                    tower::ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                            Ok::<_, Infallible>((
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "max concurrency limit reached".to_string(),
+                            ))
+                        }))
+                        .layer(LoadShedLayer::new())
+                        .layer(ConcurrencyLimitLayer::new(128)),
+                )
+                .route_layer(axum::middleware::from_fn_with_state(
+                    ApiPermission::Query,
+                    require_permission,
+                )),
         )
         .route(
             "/v1/subscriptions",
-            post(api_v1_subs).route_layer(
-                tower::ServiceBuilder::new()
-                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
-                        Ok::<_, Infallible>((
-                            StatusCode::SERVICE_UNAVAILABLE,
-                            "max concurrency limit reached".to_string(),
-                        ))
-                    }))
-                    .layer(LoadShedLayer::new())
-                    .layer(ConcurrencyLimitLayer::new(128)),
-            ),
+            post(api_v1_subs)
+                .route_layer(
+                    tower::ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                            Ok::<_, Infallible>((
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "max concurrency limit reached".to_string(),
+                            ))
+                        }))
+                        .layer(LoadShedLayer::new())
+                        .layer(ConcurrencyLimitLayer::new(128)),
+                )
+                .route_layer(axum::middleware::from_fn_with_state(
+                    ApiPermission::Subscribe,
+                    require_permission,
+                )),
         )
         .route(
             "/v1/updates/:table",
-            post(api_v1_updates).route_layer(
-                tower::ServiceBuilder::new()
-                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
-                        Ok::<_, Infallible>((
-                            StatusCode::SERVICE_UNAVAILABLE,
-                            "max concurrency limit reached".to_string(),
-                        ))
-                    }))
-                    .layer(LoadShedLayer::new())
-                    .layer(ConcurrencyLimitLayer::new(128)),
-            ),
+            post(api_v1_updates)
+                .route_layer(
+                    tower::ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                            Ok::<_, Infallible>((
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "max concurrency limit reached".to_string(),
+                            ))
+                        }))
+                        .layer(LoadShedLayer::new())
+                        .layer(ConcurrencyLimitLayer::new(128)),
+                )
+                .route_layer(axum::middleware::from_fn_with_state(
+                    ApiPermission::Subscribe,
+                    require_permission,
+                )),
+        )
+        .route(
+            "/v1/changes",
+            get(api_v1_changes)
+                .route_layer(
+                    tower::ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                            Ok::<_, Infallible>((
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "max concurrency limit reached".to_string(),
+                            ))
+                        }))
+                        .layer(LoadShedLayer::new())
+                        .layer(ConcurrencyLimitLayer::new(128)),
+                )
+                .route_layer(axum::middleware::from_fn_with_state(
+                    ApiPermission::Subscribe,
+                    require_permission,
+                )),
         )
         .route(
             "/v1/subscriptions/:id",
-            get(api_v1_sub_by_id).route_layer(
-                tower::ServiceBuilder::new()
-                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
-                        Ok::<_, Infallible>((
-                            StatusCode::SERVICE_UNAVAILABLE,
-                            "max concurrency limit reached".to_string(),
-                        ))
-                    }))
-                    .layer(LoadShedLayer::new())
-                    .layer(ConcurrencyLimitLayer::new(128)),
-            ),
+            get(api_v1_sub_by_id)
+                .route_layer(
+                    tower::ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                            Ok::<_, Infallible>((
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "max concurrency limit reached".to_string(),
+                            ))
+                        }))
+                        .layer(LoadShedLayer::new())
+                        .layer(ConcurrencyLimitLayer::new(128)),
+                )
+                .route_layer(axum::middleware::from_fn_with_state(
+                    ApiPermission::Subscribe,
+                    require_permission,
+                )),
+        )
+        .route(
+            "/v1/subscriptions/:id",
+            // subscriptions are shared by every client with the same query
+            delete(api_v1_sub_cancel).route_layer(axum::middleware::from_fn_with_state(
+                ApiPermission::Migrate,
+                require_permission,
+            )),
+        )
+        .route(
+            "/v1/ws",
+            get(api_v1_ws)
+                .route_layer(
+                    tower::ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                            Ok::<_, Infallible>((
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "max concurrency limit reached".to_string(),
+                            ))
+                        }))
+                        .layer(LoadShedLayer::new())
+                        .layer(ConcurrencyLimitLayer::new(128)),
+                )
+                .route_layer(axum::middleware::from_fn_with_state(
+                    ApiPermission::Subscribe,
+                    require_permission,
+                )),
         )
         .route(
             "/v1/migrations",
-            post(api_v1_db_schema).route_layer(
-                tower::ServiceBuilder::new()
-                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
-                        Ok::<_, Infallible>((
-                            StatusCode::SERVICE_UNAVAILABLE,
-                            "max concurrency limit reached".to_string(),
-                        ))
-                    }))
-                    .layer(LoadShedLayer::new())
-                    .layer(ConcurrencyLimitLayer::new(4)),
-            ),
+            post(api_v1_db_schema)
+                .route_layer(
+                    tower::ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                            Ok::<_, Infallible>((
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "max concurrency limit reached".to_string(),
+                            ))
+                        }))
+                        .layer(LoadShedLayer::new())
+                        .layer(ConcurrencyLimitLayer::new(4)),
+                )
+                .route_layer(axum::middleware::from_fn_with_state(
+                    ApiPermission::Migrate,
+                    require_permission,
+                )),
+        )
+        .route(
+            "/v1/history/:table",
+            post(api_v1_row_history)
+                .route_layer(
+                    tower::ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                            Ok::<_, Infallible>((
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "max concurrency limit reached".to_string(),
+                            ))
+                        }))
+                        .layer(LoadShedLayer::new())
+                        .layer(ConcurrencyLimitLayer::new(128)),
+                )
+                .route_layer(axum::middleware::from_fn_with_state(
+                    ApiPermission::Query,
+                    require_permission,
+                )),
         )
         .route(
             "/v1/table_stats",
-            post(api_v1_table_stats).route_layer(
-                tower::ServiceBuilder::new()
-                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
-                        Ok::<_, Infallible>((
-                            StatusCode::SERVICE_UNAVAILABLE,
-                            "max concurrency limit reached".to_string(),
-                        ))
-                    }))
-                    .layer(LoadShedLayer::new())
-                    .layer(ConcurrencyLimitLayer::new(4)),
-            ),
+            post(api_v1_table_stats)
+                .route_layer(
+                    tower::ServiceBuilder::new()
+                        .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                            Ok::<_, Infallible>((
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "max concurrency limit reached".to_string(),
+                            ))
+                        }))
+                        .layer(LoadShedLayer::new())
+                        .layer(ConcurrencyLimitLayer::new(4)),
+                )
+                .route_layer(axum::middleware::from_fn_with_state(
+                    ApiPermission::Query,
+                    require_permission,
+                )),
         )
+        .layer(axum::middleware::from_fn_with_state(
+            rate_limits,
+            limit_requests,
+        ))
         .layer(axum::middleware::from_fn(require_authz))
+        // health checks come from load balancers, which don't have tokens
+        .route("/v1/health", get(api_v1_health))
         .layer(
             tower::ServiceBuilder::new()
                 .layer(Extension(Arc::new(AtomicI64::new(0))))
@@ -285,13 +430,79 @@ pub async fn setup_http_api_handler(
                 .layer(Extension(subs_bcast_cache))
                 .layer(Extension(updates_bcast_cache))
                 .layer(Extension(subs_manager.clone()))
+                .layer(Extension(transport.clone()))
+                .layer(Extension(bookie.clone()))
                 .layer(Extension(tripwire.clone())),
         )
         .layer(DefaultBodyLimit::disable())
         .layer(TraceLayer::new_for_http());
 
+    let api_tls = match agent.config().api.tls.clone() {
+        Some(tls_config) => {
+            let api_tls = ApiTls::new(tls_config)?;
+            spawn_counted(reload_on_change(api_tls.clone(), tripwire.clone()));
+            Some(api_tls)
+        }
+        None => None,
+    };
+
     for api_listener in api_listeners {
+        let api_listener = match api_listener {
+            ApiListener::Tcp(listener) => listener,
+            ApiListener::Unix(listener) => {
+                let api_path = listener
+                    .local_addr()?
+                    .as_pathname()
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_default();
+                info!("Starting API listener on unix/{api_path}");
+                spawn_counted(
+                    axum::Server::builder(hyper::server::accept::poll_fn(move |cx| {
+                        listener
+                            .poll_accept(cx)
+                            .map(|res| Some(res.map(|(stream, _)| stream)))
+                    }))
+                    .executor(CountedExecutor)
+                    .serve(
+                        api.clone()
+                            .layer(Extension(UnixSocketConn))
+                            .into_make_service(),
+                    )
+                    .with_graceful_shutdown(
+                        tripwire
+                            .clone()
+                            .inspect(move |_| info!("corrosion api unix tripped {api_path}")),
+                    )
+                    .inspect(|_| info!("corrosion api is done")),
+                );
+                continue;
+            }
+        };
         let api_addr = api_listener.local_addr()?;
+
+        if let Some(ref api_tls) = api_tls {
+            info!("Starting API listener on tcp/{api_addr} (TLS)");
+            spawn_counted(
+                axum::Server::builder(tls_incoming(
+                    api_listener,
+                    api_tls.clone(),
+                    tripwire.clone(),
+                ))
+                .executor(CountedExecutor)
+                .serve(
+                    api.clone()
+                        .into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(
+                    tripwire
+                        .clone()
+                        .inspect(move |_| info!("corrosion api https tripped {api_addr}")),
+                )
+                .inspect(|_| info!("corrosion api is done")),
+            );
+            continue;
+        }
+
         info!("Starting API listener on tcp/{api_addr}");
         let mut incoming = AddrIncoming::from_listener(api_listener)?;
 
@@ -315,24 +526,48 @@ pub async fn setup_http_api_handler(
     Ok(())
 }
 
+/// Marks requests made over a unix socket, access to those is controlled by
+/// the socket's file permissions instead of tokens
+#[derive(Clone, Copy)]
+struct UnixSocketConn;
+
 async fn require_authz<B>(
     Extension(agent): Extension<Agent>,
+    unix_conn: Option<Extension<UnixSocketConn>>,
     maybe_authz_header: Option<TypedHeader<Authorization<Bearer>>>,
-    request: axum::http::Request<B>,
+    mut request: axum::http::Request<B>,
     next: axum::middleware::Next<B>,
 ) -> Result<axum::response::Response, axum::http::StatusCode> {
-    let passed = if let Some(ref authz) = agent.config().api.authorization {
-        match authz {
-            AuthzConfig::BearerToken(token) => maybe_authz_header
-                .map(|h| h.token() == token)
-                .unwrap_or(false),
-        }
-    } else {
-        true
+    let scope = match agent.config().api.authorization {
+        _ if unix_conn.is_some() => Some(ApiScope::unrestricted()),
+        Some(AuthzConfig::BearerToken(ref token)) => maybe_authz_header
+            .filter(|h| h.token() == token)
+            .map(|_| ApiScope::unrestricted()),
+        Some(AuthzConfig::Tokens(ref tokens)) => maybe_authz_header.and_then(|h| {
+            tokens
+                .iter()
+                .find(|t| h.token() == t.token)
+                .map(ApiScope::from_token)
+        }),
+        None => Some(ApiScope::unrestricted()),
     };
 
-    if !passed {
-        return Err(axum::http::StatusCode::UNAUTHORIZED);
+    let scope = scope.ok_or(axum::http::StatusCode::UNAUTHORIZED)?;
+
+    request.extensions_mut().insert(scope);
+
+    Ok(next.run(request).await)
+}
+
+async fn require_permission<B>(
+    State(permission): State<ApiPermission>,
+    Extension(scope): Extension<ApiScope>,
+    request: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> Result<axum::response::Response, axum::http::StatusCode> {
+    if !scope.allows(permission) {
+        debug!(token = ?scope.name(), "token is missing the {permission:?} permission");
+        return Err(axum::http::StatusCode::FORBIDDEN);
     }
 
     Ok(next.run(request).await)
@@ -421,6 +656,41 @@ pub async fn apply_fully_buffered_changes_loop(
     info!("fully_buffered_changes_loop ended");
 }
 
+/// Periodically deletes transactions' idempotency keys older than their TTL
+pub async fn clear_expired_idempotency_keys_loop(agent: Agent, mut tripwire: Tripwire) {
+    let ttl = agent.config().api.idempotency_key_ttl;
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+
+    loop {
+        tokio::select! {
+            biased;
+
+            _ = &mut tripwire => {
+                break;
+            }
+            _ = interval.tick() => {}
+        };
+
+        let res = async {
+            let conn = agent.pool().write_low().await?;
+            let deleted = block_in_place(|| {
+                conn.prepare_cached(
+                    "DELETE FROM __corro_idempotency_keys WHERE created_at <= unixepoch() - ?",
+                )?
+                .execute([ttl])
+            })?;
+            Ok::<_, eyre::Report>(deleted)
+        }
+        .await;
+
+        match res {
+            Ok(0) => {}
+            Ok(deleted) => debug!("deleted {deleted} expired idempotency keys"),
+            Err(e) => error!("could not delete expired idempotency keys: {e}"),
+        }
+    }
+}
+
 /// Compact the database by finding cleared versions
 pub async fn clear_buffered_meta_loop(
     agent: Agent,
@@ -682,6 +952,15 @@ pub async fn process_fully_buffered_changes(
                 error!(%version, "could not match changes for updates from db version: {e}");
             }
         });
+
+        block_in_place(|| {
+            if let Err(e) = agent
+                .changes_feed()
+                .record_from_db(&conn, actor_id, version)
+            {
+                error!(%version, "could not record buffered changes in the changes feed: {e}");
+            }
+        });
     }
 
     Ok(rows_impacted)
@@ -1010,7 +1289,7 @@ pub async fn process_multiple_changes(
         let elapsed = sub_start.elapsed();
         let details = json!({"elapsed": elapsed.as_secs_f32()});
         assert_always!(
-            elapsed < Duration::from_secs(1 * 60),
+            elapsed < Duration::from_secs(60),
             "process_multiple_changes took too long",
             &details
         );
@@ -1023,10 +1302,16 @@ pub async fn process_multiple_changes(
 
     let mut change_chunk_size = 0;
 
-    for (_actor_id, changeset, db_version, _src) in changesets {
+    for (actor_id, changeset, db_version, _src) in changesets {
         change_chunk_size += changeset.changes().len();
         match_changes(agent.subs_manager(), changeset.changes(), db_version);
         match_changes(agent.updates_manager(), changeset.changes(), db_version);
+        block_in_place(|| {
+            agent.changes_feed().record(ChangeV1 {
+                actor_id,
+                changeset,
+            })
+        });
     }
 
     histogram!("corro.agent.changes.processing.time.seconds", "source" => "remote")
@@ -19,6 +19,7 @@ use corro_types::sync::{
     generate_sync, SyncMessage, SyncMessageEncodeError, SyncMessageV1, SyncNeedV1, SyncRejectionV1,
     SyncRequestV1, SyncStateV1, SyncTraceContextV1,
 };
+use corro_types::tls;
 use futures::stream::FuturesUnordered;
 use futures::{Future, Stream, TryFutureExt, TryStreamExt};
 use itertools::Itertools;
@@ -40,6 +41,7 @@ use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 use crate::agent::SyncRecvError;
+use crate::api::public::has_applied;
 use crate::transport::{Transport, TransportError};
 
 use corro_types::{actor::ActorId, agent::Bookie};
@@ -155,65 +157,16 @@ async fn build_quinn_server_config(config: &GossipConfig) -> eyre::Result<quinn:
             .as_ref()
             .ok_or_else(|| eyre::eyre!("either plaintext or a tls config is required"))?;
 
-        let key = tokio::fs::read(&tls.key_file).await?;
-        let key = if tls.key_file.extension().map_or(false, |x| x == "der") {
-            rustls::PrivateKey(key)
-        } else {
-            let pkcs8 = rustls_pemfile::pkcs8_private_keys(&mut &*key)?;
-            match pkcs8.into_iter().next() {
-                Some(x) => rustls::PrivateKey(x),
-                None => {
-                    let rsa = rustls_pemfile::rsa_private_keys(&mut &*key)?;
-                    match rsa.into_iter().next() {
-                        Some(x) => rustls::PrivateKey(x),
-                        None => {
-                            eyre::bail!("no private keys found");
-                        }
-                    }
-                }
-            }
-        };
-
-        let certs = tokio::fs::read(&tls.cert_file).await?;
-        let certs = if tls.cert_file.extension().map_or(false, |x| x == "der") {
-            vec![rustls::Certificate(certs)]
-        } else {
-            rustls_pemfile::certs(&mut &*certs)?
-                .into_iter()
-                .map(rustls::Certificate)
-                .collect()
-        };
+        let key = tls::load_private_key(&tls.key_file)?;
+        let certs = tls::load_certs(&tls.cert_file)?;
 
         let server_crypto = rustls::ServerConfig::builder().with_safe_defaults();
 
         let server_crypto = if tls.client.is_some() {
-            let ca_file = match &tls.ca_file {
-                None => {
-                    eyre::bail!(
-                        "ca_file required in tls config for server client cert auth verification"
-                    );
-                }
-                Some(ca_file) => ca_file,
-            };
-
-            let ca_certs = tokio::fs::read(&ca_file).await?;
-            let ca_certs = if ca_file.extension().map_or(false, |x| x == "der") {
-                vec![rustls::Certificate(ca_certs)]
-            } else {
-                rustls_pemfile::certs(&mut &*ca_certs)?
-                    .into_iter()
-                    .map(rustls::Certificate)
-                    .collect()
-            };
-
-            let mut root_store = rustls::RootCertStore::empty();
-
-            for cert in ca_certs {
-                root_store.add(&cert)?;
-            }
+            let ca_file = tls.ca_file.as_ref().ok_or(tls::Error::CaFileRequired)?;
 
             server_crypto.with_client_cert_verifier(Arc::new(
-                rustls::server::AllowAnyAuthenticatedClient::new(root_store),
+                rustls::server::AllowAnyAuthenticatedClient::new(tls::load_root_store(ca_file)?),
             ))
         } else {
             server_crypto.with_no_client_auth()
@@ -238,26 +191,8 @@ pub async fn gossip_server_endpoint(config: &GossipConfig) -> eyre::Result<quinn
 fn client_cert_auth(
     config: &TlsClientConfig,
 ) -> eyre::Result<(Vec<rustls::Certificate>, rustls::PrivateKey)> {
-    let mut cert_file = std::io::BufReader::new(
-        std::fs::OpenOptions::new()
-            .read(true)
-            .open(&config.cert_file)?,
-    );
-    let certs = rustls_pemfile::certs(&mut cert_file)?
-        .into_iter()
-        .map(rustls::Certificate)
-        .collect();
-
-    let mut key_file = std::io::BufReader::new(
-        std::fs::OpenOptions::new()
-            .read(true)
-            .open(&config.key_file)?,
-    );
-    let key = rustls_pemfile::pkcs8_private_keys(&mut key_file)?
-        .into_iter()
-        .map(rustls::PrivateKey)
-        .next()
-        .ok_or_else(|| eyre::eyre!("could not find client tls key"))?;
+    let certs = tls::load_certs(&config.cert_file)?;
+    let key = tls::load_private_key(&config.key_file)?;
 
     Ok((certs, key))
 }
@@ -274,23 +209,8 @@ async fn build_quinn_client_config(config: &GossipConfig) -> eyre::Result<quinn:
         let client_crypto = rustls::ClientConfig::builder().with_safe_defaults();
 
         let client_crypto = if let Some(ca_file) = &tls.ca_file {
-            let ca_certs = tokio::fs::read(&ca_file).await?;
-            let ca_certs = if ca_file.extension().map_or(false, |x| x == "der") {
-                vec![rustls::Certificate(ca_certs)]
-            } else {
-                rustls_pemfile::certs(&mut &*ca_certs)?
-                    .into_iter()
-                    .map(rustls::Certificate)
-                    .collect()
-            };
-
-            let mut root_store = rustls::RootCertStore::empty();
-
-            for cert in ca_certs {
-                root_store.add(&cert)?;
-            }
-
-            let client_crypto = client_crypto.with_root_certificates(root_store);
+            let client_crypto =
+                client_crypto.with_root_certificates(tls::load_root_store(ca_file)?);
 
             if let Some(client_config) = &tls.client {
                 let (certs, key) = client_cert_auth(client_config)?;
@@ -999,6 +919,97 @@ pub async fn read_sync_msg<R: Stream<Item = std::io::Result<BytesMut>> + Unpin>(
     }
 }
 
+/// Asks a peer whether it applied a version of an actor's changes, much
+/// cheaper than syncing with it
+pub async fn fetch_has_version(
+    agent: &Agent,
+    transport: &Transport,
+    addr: SocketAddr,
+    actor_id: ActorId,
+    version: CrsqlDbVersion,
+) -> Result<bool, SyncError> {
+    let mut codec = LengthDelimitedCodec::builder()
+        .max_frame_length(100 * 1_024 * 1_024)
+        .new_codec();
+    let mut send_buf = BytesMut::new();
+    let mut encode_buf = BytesMut::new();
+
+    let (mut tx, rx) = transport.open_bi(addr).await?;
+    let mut read = FramedRead::new(
+        rx,
+        LengthDelimitedCodec::builder()
+            .max_frame_length(100 * 1_024 * 1_024)
+            .new_codec(),
+    );
+
+    encode_write_bipayload_msg(
+        &mut codec,
+        &mut encode_buf,
+        &mut send_buf,
+        BiPayload::V1 {
+            data: BiPayloadV1::HasVersion { actor_id, version },
+            cluster_id: agent.cluster_id(),
+        },
+        &mut tx,
+    )
+    .await?;
+
+    tx.flush().await.map_err(SyncSendError::from)?;
+
+    let has_version = match timeout(Duration::from_secs(2), read_sync_msg(&mut read))
+        .await
+        .map_err(SyncRecvError::from)??
+    {
+        Some(SyncMessage::V1(SyncMessageV1::HasVersion(has_version))) => has_version,
+        Some(SyncMessage::V1(SyncMessageV1::Rejection(rejection))) => return Err(rejection.into()),
+        Some(_) => return Err(SyncRecvError::ExpectedVersionCheck.into()),
+        None => return Err(SyncRecvError::UnexpectedEndOfStream.into()),
+    };
+
+    if let Err(e) = tx.finish().await {
+        debug!("could not finish version check stream: {e}");
+    }
+
+    Ok(has_version)
+}
+
+/// Answers a `BiPayloadV1::HasVersion` check from our bookkeeping
+pub async fn serve_has_version(
+    agent: &Agent,
+    bookie: &Bookie,
+    actor_id: ActorId,
+    version: CrsqlDbVersion,
+    cluster_id: ClusterId,
+    mut write: SendStream,
+) -> Result<(), SyncError> {
+    let mut codec = LengthDelimitedCodec::builder()
+        .max_frame_length(100 * 1_024 * 1_024)
+        .new_codec();
+    let mut send_buf = BytesMut::new();
+    let mut encode_buf = BytesMut::new();
+
+    let msg = if cluster_id != agent.cluster_id() {
+        SyncMessageV1::Rejection(SyncRejectionV1::DifferentCluster)
+    } else {
+        SyncMessageV1::HasVersion(has_applied(bookie, actor_id, version).await)
+    };
+
+    encode_write_sync_msg(
+        &mut codec,
+        &mut encode_buf,
+        &mut send_buf,
+        SyncMessage::V1(msg),
+        &mut write,
+    )
+    .await?;
+
+    if let Err(e) = write.finish().await {
+        debug!("could not finish version check stream: {e}");
+    }
+
+    Ok(())
+}
+
 #[tracing::instrument(skip_all, err)]
 pub async fn parallel_sync(
     agent: &Agent,
@@ -1369,6 +1380,10 @@ pub async fn parallel_sync(
                             warn!("received sync clock message unexpectedly, ignoring");
                             continue;
                         }
+                        SyncMessage::V1(SyncMessageV1::HasVersion(_)) => {
+                            warn!("received version check message unexpectedly, ignoring");
+                            continue;
+                        }
                         SyncMessage::V1(SyncMessageV1::Rejection(rejection)) => {
                             return Err(rejection.into())
                         }
@@ -1626,6 +1641,10 @@ pub async fn serve_sync(
                             warn!(actor_id = %their_actor_id, "received sync clock message more than once, ignoring");
                             continue;
                         }
+                        SyncMessage::V1(SyncMessageV1::HasVersion(_)) => {
+                            warn!(actor_id = %their_actor_id, "received version check message unexpectedly, ignoring");
+                            continue;
+                        }
                         SyncMessage::V1(SyncMessageV1::Rejection(rejection)) => {
                             return Err(rejection.into())
                         }
@@ -1651,7 +1670,7 @@ pub async fn serve_sync(
 #[cfg(test)]
 mod tests {
     use crate::api::public::api_v1_transactions;
-    use axum::{Extension, Json};
+    use axum::{http::HeaderMap, Extension, Json};
     use camino::Utf8PathBuf;
     use corro_tests::launch_test_agent;
     use corro_tests::TEST_SCHEMA;
@@ -1670,7 +1689,7 @@ mod tests {
 
     use crate::{
         agent::{process_multiple_changes, setup},
-        api::public::{api_v1_db_schema, TimeoutParams},
+        api::public::{api_v1_db_schema, authz::ApiScope, ReplicationParams, TimeoutParams},
     };
 
     use super::*;
@@ -1688,11 +1707,19 @@ mod tests {
         for i in versions_range.clone() {
             let (status_code, body) = api_v1_transactions(
                 Extension(ta1.agent.clone()),
+                Extension(ApiScope::unrestricted()),
+                None,
+                None,
+                HeaderMap::new(),
                 axum::extract::Query(TimeoutParams { timeout: None }),
-                axum::Json(vec![Statement::WithParams(
-                    "INSERT OR REPLACE INTO testsblob (id,text) VALUES (?,?)".into(),
-                    vec![format!("service-id-{i}").into(), "service-name".into()],
-                )]),
+                axum::extract::Query(ReplicationParams::default()),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "INSERT OR REPLACE INTO testsblob (id,text) VALUES (?,?)".into(),
+                        vec![format!("service-id-{i}").into(), "service-name".into()],
+                    )]
+                    .into(),
+                ),
             )
             .await;
             assert_eq!(status_code, StatusCode::OK);
@@ -1745,8 +1772,12 @@ mod tests {
         )
         .await?;
 
-        let (status_code, _res) =
-            api_v1_db_schema(Extension(agent.clone()), Json(vec![TEST_SCHEMA.to_owned()])).await;
+        let (status_code, _res) = api_v1_db_schema(
+            Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            Json(vec![TEST_SCHEMA.to_owned()]),
+        )
+        .await;
 
         assert_eq!(status_code, StatusCode::OK);
 
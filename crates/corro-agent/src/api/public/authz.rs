@@ -0,0 +1,186 @@
+//! Scoped API token permissions
+//!
+//! The `require_authz` middleware resolves the request's bearer token
+//! into an [`ApiScope`] which is then available to route layers (for
+//! route permissions) and handlers (for table allowlists).
+
+use std::{collections::HashSet, sync::Arc};
+
+use corro_types::{
+    config::{ApiPermission, ApiTokenConfig},
+    schema::Schema,
+};
+use rusqlite::{
+    hooks::{AuthAction, AuthContext, Authorization},
+    Connection, ErrorCode,
+};
+
+#[derive(Debug, Clone)]
+pub struct ApiScope {
+    name: Option<String>,
+    permissions: HashSet<ApiPermission>,
+    tables: Option<Arc<HashSet<String>>>,
+}
+
+impl ApiScope {
+    /// Scope used when no authorization is configured or for the
+    /// legacy single bearer token
+    pub fn unrestricted() -> Self {
+        Self {
+            name: None,
+            permissions: ApiPermission::ALL.into_iter().collect(),
+            tables: None,
+        }
+    }
+
+    pub fn from_token(token: &ApiTokenConfig) -> Self {
+        Self {
+            name: Some(token.name.clone()),
+            permissions: token.permissions.iter().copied().collect(),
+            tables: token
+                .tables
+                .as_ref()
+                .map(|tables| Arc::new(tables.iter().cloned().collect())),
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn allows(&self, permission: ApiPermission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    pub fn allows_table(&self, table: &str) -> bool {
+        self.tables
+            .as_ref()
+            .map(|tables| tables.contains(table))
+            .unwrap_or(true)
+    }
+
+    pub fn allows_all_tables(&self) -> bool {
+        self.tables.is_none()
+    }
+
+    /// Install a SQLite authorizer on the connection enforcing the
+    /// table allowlist for every statement prepared until the
+    /// returned guard is dropped. Does nothing if this scope can
+    /// access all tables.
+    pub fn authorize<'conn>(
+        &self,
+        conn: &'conn Connection,
+        schema: &Schema,
+    ) -> Option<AuthorizerGuard<'conn>> {
+        let allowed = self.tables.clone()?;
+        let schema_tables: HashSet<String> = schema.tables.keys().cloned().collect();
+
+        conn.authorizer(Some(move |ctx: AuthContext<'_>| {
+            let table_allowed = |table: &str| {
+                let base = table
+                    .strip_suffix("__crsql_clock")
+                    .or_else(|| table.strip_suffix("__crsql_pks"))
+                    .unwrap_or(table);
+                if schema_tables.contains(base) {
+                    allowed.contains(base)
+                } else {
+                    // cr-sqlite's internal tables are allowed so triggers
+                    // keep working, but not the ones exposing every table
+                    table != "crsql_changes" && !table.starts_with("__corro_")
+                }
+            };
+
+            let authorized = match ctx.action {
+                AuthAction::Read { table_name, .. }
+                | AuthAction::Insert { table_name }
+                | AuthAction::Update { table_name, .. }
+                | AuthAction::Delete { table_name } => table_allowed(table_name),
+                AuthAction::CreateIndex { .. }
+                | AuthAction::CreateTable { .. }
+                | AuthAction::CreateTrigger { .. }
+                | AuthAction::CreateView { .. }
+                | AuthAction::CreateVtable { .. }
+                | AuthAction::DropIndex { .. }
+                | AuthAction::DropTable { .. }
+                | AuthAction::DropTrigger { .. }
+                | AuthAction::DropView { .. }
+                | AuthAction::DropVtable { .. }
+                | AuthAction::AlterTable { .. }
+                | AuthAction::Attach { .. }
+                | AuthAction::Detach { .. } => false,
+                _ => true,
+            };
+
+            if authorized {
+                Authorization::Allow
+            } else {
+                Authorization::Deny
+            }
+        }));
+
+        Some(AuthorizerGuard(conn))
+    }
+}
+
+/// Removes the connection's authorizer when dropped
+pub struct AuthorizerGuard<'conn>(&'conn Connection);
+
+impl Drop for AuthorizerGuard<'_> {
+    fn drop(&mut self) {
+        self.0
+            .authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+    }
+}
+
+/// Whether the error was caused by the authorizer refusing a statement
+pub fn is_authorization_denied(e: &rusqlite::Error) -> bool {
+    e.sqlite_error_code() == Some(ErrorCode::AuthorizationForStatementDenied)
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::schema::parse_sql;
+
+    use super::*;
+
+    #[test]
+    fn test_table_allowlist() -> eyre::Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let sql = "CREATE TABLE allowed (id INTEGER NOT NULL PRIMARY KEY, text TEXT);
+            CREATE TABLE denied (id INTEGER NOT NULL PRIMARY KEY, text TEXT);";
+        conn.execute_batch(sql)?;
+        let schema = parse_sql(sql)?;
+
+        let scope = ApiScope::from_token(&ApiTokenConfig {
+            name: "test".into(),
+            token: "secret".into(),
+            permissions: vec![ApiPermission::Query],
+            tables: Some(vec!["allowed".into()]),
+        });
+
+        assert!(scope.allows(ApiPermission::Query));
+        assert!(!scope.allows(ApiPermission::Transact));
+
+        {
+            let _guard = scope.authorize(&conn, &schema);
+            assert!(conn.prepare("SELECT * FROM allowed").is_ok());
+            assert!(conn.prepare("INSERT INTO allowed (id) VALUES (1)").is_ok());
+
+            let e = conn.prepare("SELECT * FROM denied").unwrap_err();
+            assert!(is_authorization_denied(&e));
+
+            let e = conn
+                .prepare("SELECT * FROM allowed WHERE id IN (SELECT id FROM denied)")
+                .unwrap_err();
+            assert!(is_authorization_denied(&e));
+
+            let e = conn.prepare("DROP TABLE allowed").unwrap_err();
+            assert!(is_authorization_denied(&e));
+        }
+
+        // guard dropped, no more restrictions
+        assert!(conn.prepare("SELECT * FROM denied").is_ok());
+
+        Ok(())
+    }
+}
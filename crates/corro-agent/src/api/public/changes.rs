@@ -0,0 +1,335 @@
+//! Stream of every changeset applied by this node, from its changes log
+
+use std::{collections::HashSet, time::Duration};
+
+use axum::{extract::Query, http::StatusCode, response::IntoResponse, Extension};
+use bytes::{BufMut, BytesMut};
+use compact_str::ToCompactString;
+use corro_types::{
+    agent::Agent,
+    api::{AppliedChangeset, ChangesEvent},
+    cdc::{ChangesFeedError, ChangesReader},
+};
+use futures::future::poll_fn;
+use serde::Deserialize;
+use tokio::task::block_in_place;
+use tracing::{debug, info, warn};
+use tripwire::Tripwire;
+
+use crate::api::public::authz::ApiScope;
+
+const READ_BATCH_LEN: usize = 500;
+// how often an idle stream checks that its client is still there
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ChangesParams {
+    /// apply sequence of the last changeset already received
+    #[serde(default)]
+    pub from: Option<u64>,
+    /// comma-separated tables to stream changes for, all of them by default
+    #[serde(default)]
+    pub tables: Option<String>,
+}
+
+/// Which changes of a changeset a stream gets
+struct ChangesFilter {
+    tables: Option<HashSet<String>>,
+    scope: ApiScope,
+}
+
+impl ChangesFilter {
+    fn new(scope: ApiScope, tables: Option<&str>) -> Result<Self, String> {
+        let tables = tables.map(|tables| {
+            tables
+                .split(',')
+                .map(str::trim)
+                .filter(|table| !table.is_empty())
+                .map(ToOwned::to_owned)
+                .collect::<HashSet<_>>()
+        });
+
+        if let Some(denied) = tables
+            .iter()
+            .flatten()
+            .find(|table| !scope.allows_table(table))
+        {
+            return Err(format!("not allowed to access table '{denied}'"));
+        }
+
+        Ok(Self { tables, scope })
+    }
+
+    fn allows(&self, table: &str) -> bool {
+        self.scope.allows_table(table)
+            && self
+                .tables
+                .as_ref()
+                .map(|tables| tables.contains(table))
+                .unwrap_or(true)
+    }
+
+    /// The changeset with only the changes this stream gets, if any
+    fn apply(&self, mut changeset: AppliedChangeset) -> Option<AppliedChangeset> {
+        changeset
+            .changes
+            .retain(|change| self.allows(&change.table.0));
+        (!changeset.changes.is_empty()).then_some(changeset)
+    }
+}
+
+fn error_response(status: StatusCode, e: impl ToCompactString) -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(status)
+        .body(
+            serde_json::to_vec(&ChangesEvent::Error(e.to_compact_string()))
+                .expect("could not serialize changes stream error")
+                .into(),
+        )
+        .expect("could not build error response")
+}
+
+fn feed_error_response(e: ChangesFeedError) -> hyper::Response<hyper::Body> {
+    let status = match e {
+        ChangesFeedError::Disabled => StatusCode::NOT_FOUND,
+        ChangesFeedError::Unavailable { .. } => StatusCode::GONE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error_response(status, e)
+}
+
+pub async fn api_v1_changes(
+    Extension(agent): Extension<Agent>,
+    Extension(scope): Extension<ApiScope>,
+    Extension(tripwire): Extension<Tripwire>,
+    Query(params): Query<ChangesParams>,
+) -> impl IntoResponse {
+    let filter = match ChangesFilter::new(scope, params.tables.as_deref()) {
+        Ok(filter) => filter,
+        Err(e) => return error_response(StatusCode::FORBIDDEN, e),
+    };
+
+    let mut reader = match agent.changes_feed().reader() {
+        Ok(reader) => reader,
+        Err(e) => return feed_error_response(e),
+    };
+
+    let from = params.from.unwrap_or_else(|| reader.last_seq());
+
+    // fail early if the stream can't resume from there
+    let first = match block_in_place(|| reader.read(from, READ_BATCH_LEN)) {
+        Ok(first) => first,
+        Err(e) => return feed_error_response(e),
+    };
+
+    info!(from, tables = ?params.tables, "streaming applied changes");
+
+    let (tx, body) = hyper::Body::channel();
+    tokio::spawn(forward_changes_to_body_sender(
+        reader, from, first, filter, tx, tripwire,
+    ));
+
+    hyper::Response::builder()
+        .status(StatusCode::OK)
+        .body(body)
+        .expect("could not generate ok http response for changes request")
+}
+
+async fn forward_changes_to_body_sender(
+    mut reader: ChangesReader,
+    mut from: u64,
+    mut changesets: Vec<AppliedChangeset>,
+    filter: ChangesFilter,
+    mut tx: hyper::body::Sender,
+    mut tripwire: Tripwire,
+) {
+    let mut buf = BytesMut::new();
+    let mut idle_check = tokio::time::interval(IDLE_CHECK_INTERVAL);
+
+    loop {
+        let read_all = changesets.len() < READ_BATCH_LEN;
+
+        for changeset in changesets.drain(..) {
+            from = changeset.apply_seq;
+            if let Some(changeset) = filter.apply(changeset) {
+                if let Err(e) =
+                    serde_json::to_writer((&mut buf).writer(), &ChangesEvent::Changeset(changeset))
+                {
+                    warn!("could not serialize changeset: {e}");
+                    return;
+                }
+                buf.put_u8(b'\n');
+            }
+        }
+
+        if !buf.is_empty() {
+            if let Err(e) = tx.send_data(buf.split().freeze()).await {
+                debug!("could not forward changes to receiver: {e}");
+                return;
+            }
+        }
+
+        if read_all {
+            loop {
+                tokio::select! {
+                    changed = reader.changed() => {
+                        if !changed {
+                            info!("changes log writer is gone, ending changes stream");
+                            return;
+                        }
+                        break;
+                    },
+                    _ = idle_check.tick() => {
+                        if let Err(e) = poll_fn(|cx| tx.poll_ready(cx)).await {
+                            debug!(error = %e, "changes stream receiver is gone");
+                            return;
+                        }
+                    },
+                    _ = &mut tripwire => return,
+                }
+            }
+        }
+
+        changesets = match block_in_place(|| reader.read(from, READ_BATCH_LEN)) {
+            Ok(changesets) => changesets,
+            Err(e) => {
+                warn!("could not read changes log after apply_seq {from}: {e}");
+                let mut buf = serde_json::to_vec(&ChangesEvent::Error(e.to_compact_string()))
+                    .expect("could not serialize changes stream error");
+                buf.push(b'\n');
+                _ = tx.send_data(buf.into()).await;
+                return;
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderMap;
+    use corro_tests::launch_test_agent;
+    use corro_types::api::{SqliteValue, Statement};
+    use hyper::body::HttpBody;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::api::public::{api_v1_transactions, ReplicationParams, TimeoutParams};
+
+    async fn next_event(body: &mut axum::body::BoxBody, buf: &mut BytesMut) -> ChangesEvent {
+        loop {
+            if let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+                let line = buf.split_to(pos + 1);
+                return serde_json::from_slice(&line[..pos]).unwrap();
+            }
+            let chunk = timeout(Duration::from_secs(5), body.data())
+                .await
+                .expect("timed out waiting for changes")
+                .expect("changes stream ended")
+                .unwrap();
+            buf.extend_from_slice(&chunk);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_api_v1_changes() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta = launch_test_agent(
+            |conf| {
+                let mut conf = conf.build()?;
+                conf.api.changes.enabled = true;
+                Ok(conf)
+            },
+            tripwire.clone(),
+        )
+        .await?;
+
+        let insert = |text: &'static str| {
+            let agent = ta.agent.clone();
+            async move {
+                let (status, _) = api_v1_transactions(
+                    Extension(agent),
+                    Extension(ApiScope::unrestricted()),
+                    None,
+                    None,
+                    HeaderMap::new(),
+                    Query(TimeoutParams { timeout: None }),
+                    Query(ReplicationParams::default()),
+                    axum::Json(
+                        vec![Statement::WithParams(
+                            "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                            vec![(text.len() as i64).into(), text.into()],
+                        )]
+                        .into(),
+                    ),
+                )
+                .await;
+                assert_eq!(status, StatusCode::OK);
+            }
+        };
+
+        insert("a").await;
+
+        let changes = |from: Option<u64>, tables: Option<&str>| {
+            let agent = ta.agent.clone();
+            let tripwire = tripwire.clone();
+            let tables = tables.map(ToOwned::to_owned);
+            async move {
+                api_v1_changes(
+                    Extension(agent),
+                    Extension(ApiScope::unrestricted()),
+                    Extension(tripwire),
+                    Query(ChangesParams { from, tables }),
+                )
+                .await
+                .into_response()
+            }
+        };
+
+        // resume from before the first local change
+        let res = changes(Some(0), None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let mut body = res.into_body();
+        let mut buf = BytesMut::new();
+
+        let ChangesEvent::Changeset(changeset) = next_event(&mut body, &mut buf).await else {
+            panic!("expected a changeset");
+        };
+        assert_eq!(changeset.apply_seq, 1);
+        assert_eq!(changeset.actor_id, ta.agent.actor_id().0);
+        assert!(changeset
+            .changes
+            .iter()
+            .all(|change| change.table.0 == "tests" && change.pk == vec![SqliteValue::Integer(1)]));
+
+        // live changes follow
+        insert("bb").await;
+        let ChangesEvent::Changeset(changeset) = next_event(&mut body, &mut buf).await else {
+            panic!("expected a changeset");
+        };
+        assert_eq!(changeset.apply_seq, 2);
+        assert!(changeset
+            .changes
+            .iter()
+            .any(|change| change.value == SqliteValue::Text("bb".into())));
+
+        // filtered out entirely
+        let res = changes(Some(0), Some("tests2")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let mut body = res.into_body();
+        assert!(timeout(Duration::from_millis(500), body.data())
+            .await
+            .is_err());
+
+        // past the end of the log
+        let res = changes(Some(100), None).await;
+        assert_eq!(res.status(), StatusCode::GONE);
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        spawn::wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+use axum::{http::StatusCode, Extension, Json};
+use corro_types::{
+    agent::{Agent, Bookie},
+    api::HealthResponse,
+    config::HealthConfig,
+    sync::generate_sync,
+};
+
+/// Reports the replication state of this node, with a `503` when it is past
+/// any of the configured health thresholds.
+///
+/// Meant for load balancers and orchestrators, so it doesn't require authorization.
+pub async fn api_v1_health(
+    Extension(agent): Extension<Agent>,
+    Extension(bookie): Extension<Bookie>,
+) -> (StatusCode, Json<HealthResponse>) {
+    let (members, ring0) = {
+        let members = agent.members().read();
+        let cluster_id = agent.cluster_id();
+        members
+            .states
+            .iter()
+            .filter(|(id, state)| **id != agent.actor_id() && state.cluster_id == cluster_id)
+            .fold((1, 0), |(members, ring0), (_, state)| {
+                (members + 1, ring0 + state.is_ring0() as usize)
+            })
+    };
+
+    let needed = generate_sync(&bookie, agent.actor_id()).await.need_len();
+
+    let mut health = HealthResponse {
+        healthy: true,
+        members,
+        ring0,
+        needed,
+        apply_queue_len: agent.replication().apply_queue_len(),
+        last_sync_age: agent
+            .replication()
+            .last_sync_age()
+            .map(|age| age.as_secs_f64()),
+        failures: vec![],
+    };
+
+    health.failures = check_thresholds(&agent.config().api.health, &health);
+    health.healthy = health.failures.is_empty();
+
+    let status = if health.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(health))
+}
+
+fn check_thresholds(thresholds: &HealthConfig, health: &HealthResponse) -> Vec<String> {
+    let mut failures = vec![];
+
+    if let Some(min) = thresholds.min_members {
+        if health.members < min {
+            failures.push(format!(
+                "{} members, expected at least {min}",
+                health.members
+            ));
+        }
+    }
+
+    if let Some(max) = thresholds.max_needed {
+        if health.needed > max {
+            failures.push(format!(
+                "{} versions needed, expected at most {max}",
+                health.needed
+            ));
+        }
+    }
+
+    if let Some(max) = thresholds.max_apply_queue_len {
+        if health.apply_queue_len > max {
+            failures.push(format!(
+                "{} changesets queued for apply, expected at most {max}",
+                health.apply_queue_len
+            ));
+        }
+    }
+
+    if let Some(max) = thresholds.max_sync_age {
+        match health.last_sync_age {
+            Some(age) if age <= max as f64 => {}
+            Some(age) => failures.push(format!(
+                "last synced {age:.1}s ago, expected at most {max}s"
+            )),
+            None => failures.push("has not synced yet".into()),
+        }
+    }
+
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::{
+        actor::{Actor, ActorId},
+        broadcast::Timestamp,
+        config::Config,
+    };
+    use tripwire::Tripwire;
+
+    use super::*;
+    use crate::agent::setup;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_api_v1_health() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let bookie = Bookie::new(Default::default());
+
+        // no thresholds configured, always healthy
+        let (status, Json(health)) =
+            api_v1_health(Extension(agent.clone()), Extension(bookie.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(health.healthy);
+        assert_eq!(health.members, 1);
+        assert_eq!(health.needed, 0);
+        assert_eq!(health.last_sync_age, None);
+
+        let mut conf = agent.config().as_ref().clone();
+        conf.api.health = HealthConfig {
+            min_members: Some(2),
+            max_sync_age: Some(60),
+            ..Default::default()
+        };
+        agent.set_config(conf);
+
+        let (status, Json(health)) =
+            api_v1_health(Extension(agent.clone()), Extension(bookie.clone())).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!health.healthy);
+        assert_eq!(health.failures.len(), 2);
+
+        agent.members().write().add_member(&Actor::new(
+            ActorId(uuid::Uuid::new_v4()),
+            "127.0.0.1:1".parse()?,
+            Timestamp::from(agent.clock().new_timestamp()),
+            agent.cluster_id(),
+        ));
+        agent.replication().record_sync();
+
+        let (status, Json(health)) =
+            api_v1_health(Extension(agent.clone()), Extension(bookie.clone())).await;
+        assert_eq!(status, StatusCode::OK, "{:?}", health.failures);
+        assert_eq!(health.members, 2);
+        assert!(health.last_sync_age.is_some());
+
+        Ok(())
+    }
+}
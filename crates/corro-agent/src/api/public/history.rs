@@ -0,0 +1,250 @@
+use axum::{http::StatusCode, Extension, Json};
+use corro_types::{
+    actor::ActorId,
+    agent::Agent,
+    api::{ColumnHistory, ColumnName, RowHistory, SqliteValue, TableName},
+    broadcast::Timestamp,
+};
+use rusqlite::params_from_iter;
+use tokio::task::block_in_place;
+use tracing::error;
+
+use crate::api::public::authz::ApiScope;
+
+/// Column of the crsql clock recording a row's creation or deletion
+const SENTINEL_COLUMN: &str = "-1";
+
+/// Reports which actor last wrote each column of a row, and when, from the
+/// table's crsql clock
+pub async fn api_v1_row_history(
+    Extension(agent): Extension<Agent>,
+    Extension(scope): Extension<ApiScope>,
+    axum::extract::Path(table): axum::extract::Path<String>,
+    Json(pk): Json<Vec<SqliteValue>>,
+) -> Result<Json<RowHistory>, (StatusCode, String)> {
+    if !scope.allows_table(&table) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("not authorized to access table '{table}'"),
+        ));
+    }
+
+    let (pk_cols, cols) = {
+        let schema = agent.schema().read();
+        let Some(table) = schema.tables.get(&table) else {
+            return Err((StatusCode::NOT_FOUND, format!("unknown table '{table}'")));
+        };
+        (
+            table.pk.iter().cloned().collect::<Vec<_>>(),
+            table.columns.keys().cloned().collect::<Vec<_>>(),
+        )
+    };
+
+    if pk.len() != pk_cols.len() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "table '{table}' has {} primary key column(s), got {}",
+                pk_cols.len(),
+                pk.len()
+            ),
+        ));
+    }
+
+    let internal_error = |e: &dyn std::fmt::Display| {
+        error!("could not read history of a row of '{table}': {e}");
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    };
+
+    let conn = agent.pool().read().await.map_err(|e| internal_error(&e))?;
+
+    let mut columns = block_in_place(|| {
+        let pk_filter = pk_cols
+            .iter()
+            .map(|col| format!("pks.\"{col}\" IS ?"))
+            .collect::<Vec<_>>()
+            .join(" AND ");
+
+        // the clock's db_version is the writing actor's own version, the
+        // bookkeeping tells if this node has every change of that version
+        conn.prepare_cached(&format!(
+            "SELECT clock.col_name, site.site_id, clock.col_version, clock.db_version, clock.ts,
+                    clock.db_version <= IFNULL(versions.db_version, 0) AND NOT EXISTS (
+                        SELECT 1 FROM __corro_bookkeeping_gaps AS gaps
+                            WHERE gaps.actor_id = site.site_id
+                            AND clock.db_version BETWEEN gaps.start AND gaps.end
+                    )
+                FROM \"{table}__crsql_clock\" AS clock
+                INNER JOIN \"{table}__crsql_pks\" AS pks ON pks.__crsql_key = clock.key
+                INNER JOIN crsql_site_id AS site ON site.ordinal = clock.site_id
+                LEFT JOIN crsql_db_versions AS versions ON versions.site_id = site.site_id
+                WHERE {pk_filter}"
+        ))?
+        .query_map(params_from_iter(&pk), |row| {
+            Ok(ColumnHistory {
+                column: row.get(0)?,
+                actor_id: row.get::<_, ActorId>(1)?.0,
+                col_version: row.get(2)?,
+                db_version: row.get(3)?,
+                cl: 0,
+                ts: row.get::<_, Timestamp>(4)?.as_u64(),
+                booked: row.get(5)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()
+    })
+    .map_err(|e| internal_error(&e))?;
+
+    if columns.is_empty() {
+        return Err((StatusCode::NOT_FOUND, "row not found".into()));
+    }
+
+    // the sentinel's version is the row's causal length, it's written when
+    // the row is deleted or re-inserted. Rows without one were only inserted.
+    let cl = columns
+        .iter()
+        .find(|col| col.column.0 == SENTINEL_COLUMN)
+        .map(|col| col.col_version)
+        .unwrap_or(1);
+
+    let position = |column: &ColumnName| {
+        cols.iter()
+            .position(|col| col.as_str() == column.0.as_str())
+            .map(|i| i + 1)
+            .unwrap_or(0)
+    };
+    columns.sort_by_key(|col| position(&col.column));
+    for col in columns.iter_mut() {
+        col.cl = cl;
+    }
+
+    Ok(Json(RowHistory {
+        table: TableName(table.into()),
+        pk,
+        cl,
+        columns,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderMap;
+    use corro_tests::launch_test_agent;
+    use corro_types::api::Statement;
+    use tripwire::Tripwire;
+
+    use super::*;
+    use crate::api::public::{api_v1_transactions, ReplicationParams, TimeoutParams};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_api_v1_row_history() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let exec = |stmt: &'static str| {
+            let agent = ta.agent.clone();
+            async move {
+                let (status, _) = api_v1_transactions(
+                    Extension(agent),
+                    Extension(ApiScope::unrestricted()),
+                    None,
+                    None,
+                    HeaderMap::new(),
+                    axum::extract::Query(TimeoutParams { timeout: None }),
+                    axum::extract::Query(ReplicationParams::default()),
+                    Json(vec![Statement::Simple(stmt.into())].into()),
+                )
+                .await;
+                assert_eq!(status, StatusCode::OK);
+            }
+        };
+
+        let history = |table: &'static str, pk: Vec<SqliteValue>| {
+            let agent = ta.agent.clone();
+            async move {
+                api_v1_row_history(
+                    Extension(agent),
+                    Extension(ApiScope::unrestricted()),
+                    axum::extract::Path(table.into()),
+                    Json(pk),
+                )
+                .await
+            }
+        };
+
+        exec("INSERT INTO tests3 (id, text, num) VALUES (1, 'a', 1)").await;
+        exec("UPDATE tests3 SET text = 'b' WHERE id = 1").await;
+
+        let Json(row) = history("tests3", vec![SqliteValue::Integer(1)])
+            .await
+            .map_err(|(_, e)| eyre::eyre!(e))?;
+        assert_eq!(row.cl, 1);
+        assert_eq!(
+            row.columns
+                .iter()
+                .map(|col| (col.column.0.as_str(), col.col_version, col.db_version))
+                .collect::<Vec<_>>(),
+            vec![
+                ("text", 2, 2),
+                ("text2", 1, 1),
+                ("num", 1, 1),
+                ("num2", 1, 1)
+            ]
+        );
+        assert!(row
+            .columns
+            .iter()
+            .all(|col| col.actor_id == ta.agent.actor_id().0
+                && col.cl == 1
+                && col.ts > 0
+                && col.booked));
+
+        exec("DELETE FROM tests3 WHERE id = 1").await;
+        let Json(row) = history("tests3", vec![SqliteValue::Integer(1)])
+            .await
+            .map_err(|(_, e)| eyre::eyre!(e))?;
+        assert_eq!(row.cl, 2);
+        assert_eq!(
+            row.columns
+                .iter()
+                .map(|col| (col.column.0.as_str(), col.col_version, col.db_version))
+                .collect::<Vec<_>>(),
+            vec![("-1", 2, 3)]
+        );
+
+        // re-inserting doesn't reset the causal length
+        exec("INSERT INTO tests3 (id, text) VALUES (1, 'c')").await;
+        let Json(row) = history("tests3", vec![SqliteValue::Integer(1)])
+            .await
+            .map_err(|(_, e)| eyre::eyre!(e))?;
+        assert_eq!(row.cl, 3);
+        assert_eq!(row.columns[0].column.0, "-1");
+        assert_eq!(row.columns[0].col_version, 3);
+        assert_eq!(row.columns[0].db_version, 4);
+        assert!(row
+            .columns
+            .iter()
+            .all(|col| col.cl == 3 && col.db_version == 4));
+
+        let status = |res: Result<Json<RowHistory>, (StatusCode, String)>| res.unwrap_err().0;
+        assert_eq!(
+            status(history("tests3", vec![SqliteValue::Integer(2)]).await),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(history("tests3", vec![]).await),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(history("nope", vec![SqliteValue::Integer(1)]).await),
+            StatusCode::NOT_FOUND
+        );
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        spawn::wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
@@ -1,31 +1,45 @@
 use std::{
     collections::BTreeSet,
+    fmt,
     net::SocketAddr,
     ops::Deref,
+    str::FromStr,
     time::{Duration, Instant},
 };
 
 use antithesis_sdk::assert_sometimes;
-use axum::{extract::ConnectInfo, response::IntoResponse, Extension};
-use bytes::{BufMut, BytesMut};
+use axum::{extract::ConnectInfo, http::HeaderMap, response::IntoResponse, Extension};
+use bytes::BytesMut;
 use compact_str::ToCompactString;
 use corro_types::{
-    agent::{Agent, ChangeError},
+    actor::ActorId,
+    agent::{Agent, Bookie, ChangeError},
     api::{
-        ColumnName, ExecResponse, ExecResult, QueryEvent, Statement, TableStatRequest,
-        TableStatResponse,
+        ColumnName, ConsistencyToken, ExecResponse, ExecResult, Precondition, QueryEvent,
+        QueryPlan, QueryPlanNode, ReplicationStatus, Statement, TableStatRequest,
+        TableStatResponse, TransactionRequest,
     },
     base::CrsqlDbVersion,
     broadcast::Timestamp,
     change::{insert_local_changes, InsertChangesInfo, SqliteValue},
-    schema::{apply_schema, parse_sql},
+    pubsub::explain_subscription,
+    schema::{apply_schema, parse_sql, Schema, Table},
     sqlite::SqlitePoolError,
+    updates::unquote,
 };
+use fallible_iterator::FallibleIterator;
 use hyper::StatusCode;
 use metrics::{counter, histogram};
-use rusqlite::{params_from_iter, ToSql, Transaction};
-use serde::Deserialize;
+use rusqlite::{params, params_from_iter, OptionalExtension, ToSql, Transaction};
+use serde::{Deserialize, Serialize};
 use spawn::spawn_counted;
+use sqlite3_parser::{
+    ast::{
+        As, Cmd, Expr, Limit, Literal, Name, OneSelect, Operator, ResultColumn, Select,
+        SelectTable, SortedColumn, Stmt,
+    },
+    lexer::sql::Parser,
+};
 use sqlite_pool::{Committable, InterruptibleTransaction};
 
 use tokio::{
@@ -40,16 +54,597 @@ use tracing::{debug, error, info, trace, warn};
 
 use corro_types::broadcast::broadcast_changes;
 
+use crate::{
+    api::{
+        peer::fetch_has_version,
+        public::{
+            authz::{is_authorization_denied, ApiScope},
+            pubsub::expanded_statement,
+            query_format::QueryFormat,
+            rate_limit::RowsLimit,
+        },
+    },
+    transport::Transport,
+};
+
+pub mod authz;
+pub mod changes;
+pub mod health;
+pub mod history;
+
 pub mod pubsub;
 
+pub mod query_format;
+pub mod rate_limit;
+pub mod tls;
 pub mod update;
 
+pub mod ws;
+
+#[cfg(test)]
+mod test_util;
+
 #[derive(Clone, Copy, Debug, Default, Deserialize)]
 pub struct TimeoutParams {
     #[serde(default)]
     pub timeout: Option<u64>,
 }
 
+/// Opt-in to wait for a transaction to be applied by other members
+/// before responding
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct ReplicationParams {
+    /// Wait until this many members have applied the transaction
+    #[serde(default)]
+    pub replicas: Option<usize>,
+    /// Wait until this fraction (between 0 and 1) of ring0 members have
+    /// applied the transaction
+    #[serde(default)]
+    pub ring0_fraction: Option<f64>,
+    /// How long to wait for replication, in seconds
+    #[serde(default)]
+    pub replication_timeout: Option<u64>,
+}
+
+const DEFAULT_REPLICATION_TIMEOUT: Duration = Duration::from_secs(5);
+const REPLICATION_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+const MAX_REPLICATION_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+impl ReplicationParams {
+    fn requested(&self) -> bool {
+        self.replicas.is_some() || self.ring0_fraction.is_some()
+    }
+
+    fn validate(&self, agent: &Agent) -> Result<(), String> {
+        match (self.replicas, self.ring0_fraction) {
+            (Some(_), Some(_)) => Err("only one of replicas or ring0_fraction can be set".into()),
+            (_, Some(fraction)) if !(fraction > 0.0 && fraction <= 1.0) => {
+                Err("ring0_fraction must be greater than 0 and at most 1".into())
+            }
+            // reject requests that can't be satisfied instead of waiting them out
+            _ if self.requested() => self.targets(agent).map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Members of our cluster to check and how many of them need to confirm
+    fn targets(&self, agent: &Agent) -> Result<(Vec<(ActorId, SocketAddr)>, usize), String> {
+        let members = agent.members().read();
+        let cluster_id = agent.cluster_id();
+        let states = members
+            .states
+            .iter()
+            .filter(|(_, state)| state.cluster_id == cluster_id);
+
+        match self.ring0_fraction {
+            Some(fraction) => {
+                let ring0 = states
+                    .filter(|(_, state)| state.ring == Some(0))
+                    .map(|(actor_id, state)| (*actor_id, state.addr))
+                    .collect::<Vec<_>>();
+                if ring0.is_empty() {
+                    return Err("ring0_fraction was set but there are no ring0 members".into());
+                }
+                let needed = (ring0.len() as f64 * fraction).ceil() as usize;
+                Ok((ring0, needed))
+            }
+            None => {
+                let replicas = self.replicas.unwrap_or(0);
+                let members = states
+                    .map(|(actor_id, state)| (*actor_id, state.addr))
+                    .collect::<Vec<_>>();
+                if replicas > members.len() {
+                    return Err(format!(
+                        "replicas ({replicas}) is more than the number of known members ({})",
+                        members.len()
+                    ));
+                }
+                Ok((members, replicas))
+            }
+        }
+    }
+}
+
+/// Asks members whether they have our `version`, backing off between rounds,
+/// until enough of them confirmed it or the timeout is reached
+async fn wait_for_replication(
+    agent: &Agent,
+    transport: &Transport,
+    version: CrsqlDbVersion,
+    params: ReplicationParams,
+) -> ReplicationStatus {
+    let (mut pending, needed) = match params.targets(agent) {
+        Ok(targets) => targets,
+        Err(e) => {
+            // membership changed since the request was validated
+            warn!("could not wait for replication: {e}");
+            return ReplicationStatus {
+                complete: false,
+                confirmed_by: vec![],
+            };
+        }
+    };
+    let deadline = Instant::now()
+        + params
+            .replication_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_REPLICATION_TIMEOUT);
+
+    let mut confirmed_by = vec![];
+    let mut interval = REPLICATION_CHECK_INTERVAL;
+
+    while confirmed_by.len() < needed && !pending.is_empty() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+
+        let checks = futures::future::join_all(pending.iter().map(|(actor_id, addr)| async move {
+            let res = fetch_has_version(agent, transport, *addr, agent.actor_id(), version).await;
+            (*actor_id, res)
+        }));
+
+        let results = match tokio::time::timeout(remaining, checks).await {
+            Ok(results) => results,
+            Err(_) => break,
+        };
+
+        for (actor_id, res) in results {
+            match res {
+                Ok(true) => {
+                    pending.retain(|(id, _)| *id != actor_id);
+                    confirmed_by.push(actor_id.to_string());
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    debug!(%actor_id, "could not check replication: {e}");
+                }
+            }
+        }
+
+        if confirmed_by.len() < needed {
+            tokio::time::sleep(interval.min(remaining)).await;
+            interval = (interval * 2).min(MAX_REPLICATION_CHECK_INTERVAL);
+        }
+    }
+
+    ReplicationStatus {
+        complete: confirmed_by.len() >= needed,
+        confirmed_by,
+    }
+}
+
+/// Makes a read wait until the write identified by `consistency_token` has been
+/// applied locally
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct ConsistencyParams {
+    #[serde(default)]
+    pub consistency_token: Option<ConsistencyToken>,
+    /// How long to wait for the write, in seconds
+    #[serde(default)]
+    pub consistency_timeout: Option<u64>,
+}
+
+const DEFAULT_CONSISTENCY_TIMEOUT: Duration = Duration::from_secs(5);
+const CONSISTENCY_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, thiserror::Error)]
+#[error("timed out waiting for version {} of actor {} to be applied", .0.version, .0.actor_id)]
+pub struct ConsistencyTimeout(ConsistencyToken);
+
+pub(crate) async fn has_applied(
+    bookie: &Bookie,
+    actor_id: ActorId,
+    version: CrsqlDbVersion,
+) -> bool {
+    let booked = bookie
+        .read("has_applied", actor_id.as_simple())
+        .await
+        .get(&actor_id)
+        .cloned();
+
+    match booked {
+        Some(booked) => {
+            let booked = booked.read("has_applied", actor_id.as_simple()).await;
+            // a partial version is known but not applied yet
+            booked.contains_version(&version) && booked.get_partial(&version).is_none()
+        }
+        None => false,
+    }
+}
+
+/// Waits until the version in the consistency token, if any, has been applied
+pub async fn wait_for_consistency(
+    bookie: &Bookie,
+    params: ConsistencyParams,
+) -> Result<(), ConsistencyTimeout> {
+    let token = match params.consistency_token {
+        Some(token) => token,
+        None => return Ok(()),
+    };
+    let actor_id = ActorId(token.actor_id);
+    let version = CrsqlDbVersion(token.version);
+
+    let wait = async {
+        while !has_applied(bookie, actor_id, version).await {
+            tokio::time::sleep(CONSISTENCY_CHECK_INTERVAL).await;
+        }
+    };
+
+    let timeout = params
+        .consistency_timeout
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_CONSISTENCY_TIMEOUT);
+
+    tokio::time::timeout(timeout, wait)
+        .await
+        .map_err(|_| ConsistencyTimeout(token))
+}
+
+/// Opt-in to receive a query's rows in pages
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PaginationParams {
+    /// Maximum number of rows per page
+    #[serde(default)]
+    pub page_size: Option<usize>,
+    /// Cursor from the end of the previous page
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+const DEFAULT_PAGE_SIZE: usize = 1000;
+
+/// Where the next page of a query starts.
+///
+/// Pages are keyed on the primary key or the values of the last row
+/// returned, so the cursor stays valid when rows are written in the
+/// meantime. Rows equal to a key made of all the columns are counted in
+/// `dups` since some of them may already have been returned.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct QueryCursor {
+    #[serde(rename = "h")]
+    query_hash: u64,
+    #[serde(rename = "o")]
+    offset: u64,
+    #[serde(rename = "k")]
+    key: Vec<SqliteValue>,
+    #[serde(rename = "d")]
+    dups: u64,
+}
+
+impl fmt::Display for QueryCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_vec(self).map_err(|_| fmt::Error)?;
+        f.write_str(&hex::encode(json))
+    }
+}
+
+impl FromStr for QueryCursor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let json = hex::decode(s).map_err(|_| ())?;
+        serde_json::from_slice(&json).map_err(|_| ())
+    }
+}
+
+/// Stable across builds, so a cursor can be resumed by any node
+fn query_hash(stmt: &Statement) -> u64 {
+    seahash::hash(&serde_json::to_vec(stmt).expect("could not serialize statement"))
+}
+
+#[derive(Clone, Debug)]
+struct Page {
+    size: usize,
+    query_hash: u64,
+    cursor: Option<QueryCursor>,
+}
+
+/// What the rows of a paginated query are ordered by
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PageKey {
+    /// Primary key of the only table the query reads, appended to its
+    /// columns. Each page seeks the table's primary key.
+    PrimaryKey(usize),
+    /// All of the query's columns, from left to right. Each page runs and
+    /// sorts the whole query again before skipping to the cursor, so it
+    /// costs as much as the entire result.
+    Columns,
+}
+
+impl Page {
+    /// Rewrites `query` so its rows are ordered by the page's key and start
+    /// at the cursor. Rows equal to a key made of all the columns get an
+    /// extra flag column.
+    fn query(
+        &self,
+        query: &str,
+        schema: &Schema,
+        col_count: usize,
+    ) -> Result<(String, PageKey), String> {
+        let mut parser = Parser::new(query.as_bytes());
+        let mut select = match parser.next() {
+            Ok(Some(Cmd::Stmt(Stmt::Select(select)))) => select,
+            Ok(_) => return Err("only SELECT statements can be paginated".into()),
+            Err(e) => return Err(e.to_string()),
+        };
+        if !matches!(parser.next(), Ok(None)) {
+            return Err("only a single statement can be paginated".into());
+        }
+        if select.order_by.is_some() || select.limit.is_some() {
+            return Err("paginated statements can't have an ORDER BY or a LIMIT".into());
+        }
+
+        let limit = self.size as u64 + 1 + self.cursor.as_ref().map_or(0, |cursor| cursor.dups);
+
+        let key = match keyset_table(&select, schema) {
+            Some((qualifier, table)) => {
+                let pk_exprs = table
+                    .pk
+                    .iter()
+                    .map(|pk| Expr::Qualified(Name(qualifier.clone()), Name(format!("\"{pk}\""))))
+                    .collect::<Vec<_>>();
+                let key_len = pk_exprs.len();
+
+                if let OneSelect::Select {
+                    columns,
+                    where_clause,
+                    ..
+                } = &mut select.body.select
+                {
+                    columns.extend(
+                        pk_exprs
+                            .iter()
+                            .map(|expr| ResultColumn::Expr(expr.clone(), None)),
+                    );
+                    if self.cursor.is_some() {
+                        // primary keys can't be NULL, a row value comparison will do
+                        let after_cursor = Expr::Binary(
+                            Box::new(Expr::Parenthesized(pk_exprs.clone())),
+                            Operator::Greater,
+                            Box::new(Expr::Parenthesized(
+                                (0..key_len)
+                                    .map(|i| Expr::Variable(format!(":__corro_k{i}")))
+                                    .collect(),
+                            )),
+                        );
+                        *where_clause = Some(match where_clause.take() {
+                            Some(expr) => Expr::Binary(
+                                Box::new(Expr::parenthesized(expr)),
+                                Operator::And,
+                                Box::new(after_cursor),
+                            ),
+                            None => after_cursor,
+                        });
+                    }
+                }
+                select.order_by = Some(
+                    pk_exprs
+                        .into_iter()
+                        .map(|expr| SortedColumn {
+                            expr,
+                            order: None,
+                            nulls: None,
+                        })
+                        .collect(),
+                );
+                select.limit = Some(Limit {
+                    expr: Expr::Literal(Literal::Numeric(limit.to_string())),
+                    offset: None,
+                });
+
+                PageKey::PrimaryKey(key_len)
+            }
+            None => PageKey::Columns,
+        };
+
+        let key_len = match key {
+            PageKey::PrimaryKey(len) => len,
+            PageKey::Columns => col_count,
+        };
+        if self
+            .cursor
+            .as_ref()
+            .is_some_and(|cursor| cursor.key.len() != key_len)
+        {
+            return Err("cursor was not created for this query".into());
+        }
+
+        // re-serialized from the AST, trailing comments and semicolons are gone
+        let mut query = Cmd::Stmt(Stmt::Select(select)).to_string();
+        query.pop();
+
+        if key != PageKey::Columns {
+            return Ok((query, key));
+        }
+
+        let cols = (0..col_count)
+            .map(|i| format!("c{i}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut sql = format!("WITH __corro_page({cols}) AS ({query}) SELECT *");
+
+        match &self.cursor {
+            Some(_) => {
+                // NULL-safe row value comparison, NULLs sort first
+                let eq = |n: usize| {
+                    (0..n)
+                        .map(|i| format!("c{i} IS :__corro_k{i}"))
+                        .collect::<Vec<_>>()
+                };
+                let all_eq = eq(col_count).join(" AND ");
+                let gt = (0..col_count).map(|i| {
+                    let mut terms = eq(i);
+                    terms.push(format!(
+                        "(c{i} > :__corro_k{i} OR (:__corro_k{i} IS NULL AND c{i} IS NOT NULL))"
+                    ));
+                    format!("({})", terms.join(" AND "))
+                });
+                let cond = std::iter::once(format!("({all_eq})"))
+                    .chain(gt)
+                    .collect::<Vec<_>>()
+                    .join(" OR ");
+                sql.push_str(&format!(
+                    ", ({all_eq}) FROM __corro_page WHERE {cond} ORDER BY {cols} LIMIT {limit}"
+                ));
+            }
+            None => {
+                sql.push_str(&format!(" FROM __corro_page ORDER BY {cols} LIMIT {limit}"));
+            }
+        }
+
+        Ok((sql, key))
+    }
+
+    /// Binds the statement's own params and the cursor's key to a query
+    /// built by [`Page::query`].
+    fn bind(
+        &self,
+        prepped: &mut rusqlite::Statement<'_>,
+        stmt: &Statement,
+    ) -> rusqlite::Result<()> {
+        match stmt {
+            Statement::Simple(_)
+            | Statement::Verbose {
+                params: None,
+                named_params: None,
+                ..
+            } => {}
+            Statement::WithParams(_, params)
+            | Statement::Verbose {
+                params: Some(params),
+                ..
+            } => {
+                for (i, param) in params.iter().enumerate() {
+                    prepped.raw_bind_parameter(i + 1, param)?;
+                }
+            }
+            Statement::WithNamedParams(_, params)
+            | Statement::Verbose {
+                named_params: Some(params),
+                ..
+            } => {
+                for (name, param) in params {
+                    let idx = prepped
+                        .parameter_index(name)?
+                        .ok_or_else(|| rusqlite::Error::InvalidParameterName(name.clone()))?;
+                    prepped.raw_bind_parameter(idx, param)?;
+                }
+            }
+        }
+
+        if let Some(cursor) = &self.cursor {
+            for (i, value) in cursor.key.iter().enumerate() {
+                if let Some(idx) = prepped.parameter_index(&format!(":__corro_k{i}"))? {
+                    prepped.raw_bind_parameter(idx, value)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Name a query's table is referred by and its schema, when the query reads
+/// a single table and projects its columns as they are. Its rows can then be
+/// paged by the table's primary key.
+fn keyset_table<'a>(select: &Select, schema: &'a Schema) -> Option<(String, &'a Table)> {
+    if select.with.is_some() || select.body.compounds.is_some() {
+        return None;
+    }
+
+    let OneSelect::Select {
+        distinctness: None,
+        columns,
+        from: Some(from),
+        group_by: None,
+        window_clause: None,
+        ..
+    } = &select.body.select
+    else {
+        return None;
+    };
+
+    if from.joins.as_ref().is_some_and(|joins| !joins.is_empty()) {
+        return None;
+    }
+
+    // aggregates, window functions and other expressions could make rows
+    // depend on each other
+    let plain = columns.iter().all(|col| match col {
+        ResultColumn::Star | ResultColumn::TableStar(_) => true,
+        ResultColumn::Expr(expr, _) => matches!(
+            expr,
+            Expr::Id(_) | Expr::Qualified(..) | Expr::DoublyQualified(..) | Expr::Literal(_)
+        ),
+    });
+    if !plain {
+        return None;
+    }
+
+    let SelectTable::Table(name, alias, _) = from.select.as_deref()? else {
+        return None;
+    };
+    let table = schema.tables.get(unquote(&name.name.0))?;
+    let qualifier = match alias {
+        Some(As::As(alias) | As::Elided(alias)) => alias.0.clone(),
+        None => name.name.0.clone(),
+    };
+
+    Some((qualifier, table))
+}
+
+impl PaginationParams {
+    fn page(&self, stmt: &Statement) -> Result<Option<Page>, String> {
+        if self.page_size.is_none() && self.cursor.is_none() {
+            return Ok(None);
+        }
+        if self.page_size == Some(0) {
+            return Err("page_size must be greater than 0".into());
+        }
+
+        let query_hash = query_hash(stmt);
+        let cursor = match self.cursor.as_deref() {
+            Some(cursor) => {
+                let cursor = cursor
+                    .parse::<QueryCursor>()
+                    .map_err(|_| "invalid cursor".to_string())?;
+                if cursor.query_hash != query_hash {
+                    return Err("cursor was not created for this query".into());
+                }
+                Some(cursor)
+            }
+            None => None,
+        };
+
+        Ok(Some(Page {
+            size: self.page_size.unwrap_or(DEFAULT_PAGE_SIZE),
+            query_hash,
+            cursor,
+        }))
+    }
+}
+
 pub async fn make_broadcastable_changes<F, T>(
     agent: &Agent,
     timeout: Option<u64>,
@@ -173,14 +768,285 @@ where
     }
 }
 
+/// Runs a read-only statement, collecting all of its rows
+fn query_statement<T>(
+    tx: &InterruptibleTransaction<T>,
+    stmt: &Statement,
+) -> rusqlite::Result<Vec<Vec<SqliteValue>>>
+where
+    T: Deref<Target = rusqlite::Connection> + Committable,
+{
+    let mut prepped = tx.prepare(stmt.query())?;
+    if !prepped.readonly() {
+        return Err(rusqlite::Error::InvalidQuery);
+    }
+    let col_count = prepped.column_count();
+
+    let mut rows = match stmt {
+        Statement::Simple(_)
+        | Statement::Verbose {
+            params: None,
+            named_params: None,
+            ..
+        } => prepped.query(()),
+        Statement::WithParams(_, params)
+        | Statement::Verbose {
+            params: Some(params),
+            ..
+        } => prepped.query(params_from_iter(params)),
+        Statement::WithNamedParams(_, params)
+        | Statement::Verbose {
+            named_params: Some(params),
+            ..
+        } => prepped.query(
+            params
+                .iter()
+                .map(|(k, v)| (k.as_str(), v as &dyn ToSql))
+                .collect::<Vec<(&str, &dyn ToSql)>>()
+                .as_slice(),
+        ),
+    }?;
+
+    let mut values = vec![];
+    while let Some(row) = rows.next()? {
+        values.push(
+            (0..col_count)
+                .map(|i| row.get::<_, SqliteValue>(i))
+                .collect::<rusqlite::Result<Vec<_>>>()?,
+        );
+    }
+
+    Ok(values)
+}
+
+/// Latest write to a row, according to its table's crsql clock. The clock
+/// records each write with the version its actor gave it, not the version
+/// it was applied at on this node, so tokens match on every node.
+fn row_version<T>(
+    tx: &InterruptibleTransaction<T>,
+    table: &Table,
+    pk: &[SqliteValue],
+) -> rusqlite::Result<Option<ConsistencyToken>>
+where
+    T: Deref<Target = rusqlite::Connection> + Committable,
+{
+    let pk_filter = table
+        .pk
+        .iter()
+        .map(|col| format!("pks.\"{col}\" IS ?"))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+
+    tx.prepare_cached(&format!(
+        "SELECT site.site_id, clock.db_version
+            FROM \"{table}__crsql_clock\" AS clock
+            INNER JOIN \"{table}__crsql_pks\" AS pks ON pks.__crsql_key = clock.key
+            INNER JOIN crsql_site_id AS site ON site.ordinal = clock.site_id
+            WHERE {pk_filter}
+            ORDER BY clock.ts DESC, site.site_id DESC
+            LIMIT 1",
+        table = table.name
+    ))?
+    .query_row(params_from_iter(pk), |row| {
+        Ok(ConsistencyToken {
+            actor_id: row.get::<_, ActorId>(0)?.0,
+            version: row.get(1)?,
+        })
+    })
+    .optional()
+}
+
+/// Aborts the transaction with [`ChangeError::PreconditionFailed`] if the
+/// precondition doesn't hold
+fn check_precondition<T>(
+    tx: &InterruptibleTransaction<T>,
+    schema: &Schema,
+    index: usize,
+    precondition: &Precondition,
+) -> Result<(), ChangeError>
+where
+    T: Deref<Target = rusqlite::Connection> + Committable,
+{
+    let failed = |reason: String| ChangeError::PreconditionFailed { index, reason };
+    let sqlite_err = |source| ChangeError::Rusqlite {
+        source,
+        actor_id: None,
+        version: None,
+    };
+
+    match precondition {
+        Precondition::Query { statement, rows } => {
+            let actual = query_statement(tx, statement).map_err(|e| match e {
+                rusqlite::Error::InvalidQuery => failed("statement is not readonly".into()),
+                e => sqlite_err(e),
+            })?;
+            if actual != *rows {
+                return Err(failed(format!("expected rows {rows:?}, got {actual:?}")));
+            }
+        }
+        Precondition::RowVersion { table, pk, version } => {
+            let table = schema
+                .tables
+                .get(table)
+                .ok_or_else(|| failed(format!("unknown table '{table}'")))?;
+            if pk.len() != table.pk.len() {
+                return Err(failed(format!(
+                    "table '{}' has {} primary key column(s), got {}",
+                    table.name,
+                    table.pk.len(),
+                    pk.len()
+                )));
+            }
+
+            let actual = row_version(tx, table, pk).map_err(sqlite_err)?;
+            if actual != *version {
+                return Err(failed(format!(
+                    "expected row version {}, got {}",
+                    display_row_version(version),
+                    display_row_version(&actual)
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn display_row_version(version: &Option<ConsistencyToken>) -> String {
+    match version {
+        Some(token) => token.to_string(),
+        None => "null".into(),
+    }
+}
+
+/// Header identifying a transaction across client retries
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+enum TransactionOutcome {
+    Executed(Vec<ExecResult>),
+    /// Already executed with the same idempotency key
+    Replayed {
+        results: Vec<ExecResult>,
+        version: Option<CrsqlDbVersion>,
+    },
+}
+
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<&str>, String> {
+    match headers.get(IDEMPOTENCY_KEY_HEADER) {
+        None => Ok(None),
+        Some(value) => match value.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN => Ok(Some(key)),
+            _ => Err(format!(
+                "{IDEMPOTENCY_KEY_HEADER} header must be between 1 and {MAX_IDEMPOTENCY_KEY_LEN} visible ASCII characters"
+            )),
+        },
+    }
+}
+
+/// Hash of a transaction's statements and preconditions, to tell a retry
+/// apart from a different request reusing its idempotency key
+fn request_hash(statements: &[Statement], preconditions: &[Precondition]) -> i64 {
+    let body = serde_json::to_vec(&(statements, preconditions))
+        .expect("could not serialize transaction request");
+    seahash::hash(&body) as i64
+}
+
+/// Results and version of a transaction, and the hash of its request
+type IdempotentResults = (Vec<ExecResult>, Option<CrsqlDbVersion>, i64);
+
+/// Results of a previous transaction with the same key, unless it expired
+fn find_idempotent_results(
+    conn: &rusqlite::Connection,
+    scope: &ApiScope,
+    key: &str,
+    ttl: u64,
+) -> rusqlite::Result<Option<IdempotentResults>> {
+    conn.prepare_cached(
+        "SELECT results, db_version, request_hash FROM __corro_idempotency_keys
+            WHERE scope = ? AND key = ? AND created_at > unixepoch() - ?",
+    )?
+    .query_row(params![scope.name().unwrap_or_default(), key, ttl], |row| {
+        let results = serde_json::from_str(&row.get::<_, String>(0)?).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into())
+        })?;
+        Ok((results, row.get(1)?, row.get(2)?))
+    })
+    .optional()
+}
+
+fn record_idempotent_results(
+    conn: &rusqlite::Connection,
+    actor_id: ActorId,
+    scope: &ApiScope,
+    key: &str,
+    request_hash: i64,
+    results: &[ExecResult],
+) -> rusqlite::Result<()> {
+    // the version these changes will be committed as, if they changed anything
+    let db_version: Option<CrsqlDbVersion> = conn
+        .prepare_cached(
+            "SELECT db_version FROM crsql_changes
+                WHERE site_id = ? AND db_version = crsql_peek_next_db_version()
+                LIMIT 1",
+        )?
+        .query_row([actor_id], |row| row.get(0))
+        .optional()?;
+
+    let results = serde_json::to_string(results)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?;
+
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO __corro_idempotency_keys (scope, key, request_hash, db_version, results, created_at)
+            VALUES (?, ?, ?, ?, ?, unixepoch())",
+    )?
+    .execute(params![
+        scope.name().unwrap_or_default(),
+        key,
+        request_hash,
+        db_version,
+        results
+    ])?;
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 #[tracing::instrument(skip_all)]
 pub async fn api_v1_transactions(
     // axum::extract::RawQuery(raw_query): axum::extract::RawQuery,
     Extension(agent): Extension<Agent>,
+    Extension(scope): Extension<ApiScope>,
+    transport: Option<Extension<Transport>>,
+    rows_limit: Option<Extension<RowsLimit>>,
+    headers: HeaderMap,
     axum::extract::Query(params): axum::extract::Query<TimeoutParams>,
-    axum::extract::Json(statements): axum::extract::Json<Vec<Statement>>,
+    axum::extract::Query(replication): axum::extract::Query<ReplicationParams>,
+    axum::extract::Json(body): axum::extract::Json<TransactionRequest>,
 ) -> (StatusCode, axum::Json<ExecResponse>) {
     let actor_id = agent.actor_id().to_string();
+    let (statements, preconditions) = body.into_parts();
+    let idempotency_key = match replication
+        .validate(&agent)
+        .and_then(|_| idempotency_key(&headers))
+    {
+        Ok(key) => key,
+        Err(error) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                axum::Json(ExecResponse {
+                    results: vec![ExecResult::Error { error }],
+                    time: 0.0,
+                    version: None,
+                    actor_id: Some(actor_id),
+                    replication: None,
+                    consistency_token: None,
+                }),
+            );
+        }
+    };
+
     if statements.is_empty() {
         return (
             StatusCode::BAD_REQUEST,
@@ -191,12 +1057,41 @@ pub async fn api_v1_transactions(
                 time: 0.0,
                 version: None,
                 actor_id: Some(actor_id),
+                replication: None,
+                consistency_token: None,
             }),
         );
     }
 
     assert_sometimes!(true, "Corrosion receives transactions through HTTP API");
-    let res = make_broadcastable_changes(&agent, params.timeout, move |tx| {
+    let idempotency_key_ttl = agent.config().api.idempotency_key_ttl;
+    let request_hash = idempotency_key.map(|_| request_hash(&statements, &preconditions));
+    let res = make_broadcastable_changes(&agent, params.timeout, |tx| {
+        let sqlite_err = |source| ChangeError::Rusqlite {
+            source,
+            actor_id: None,
+            version: None,
+        };
+
+        // the write connection is held, a concurrent retry can't get past this
+        if let (Some(key), Some(request_hash)) = (idempotency_key, request_hash) {
+            if let Some((results, version, previous_hash)) =
+                find_idempotent_results(tx, &scope, key, idempotency_key_ttl).map_err(sqlite_err)?
+            {
+                if previous_hash != request_hash {
+                    return Err(ChangeError::IdempotencyKeyReused);
+                }
+                return Ok(TransactionOutcome::Replayed { results, version });
+            }
+        }
+
+        let schema = agent.schema().read();
+        let authz_guard = scope.authorize(tx, &schema);
+
+        for (index, precondition) in preconditions.iter().enumerate() {
+            check_precondition(tx, &schema, index, precondition)?;
+        }
+
         let mut total_rows_affected = 0;
 
         let results = statements
@@ -220,18 +1115,46 @@ pub async fn api_v1_transactions(
                     Err(e) => Err(e),
                 }
             })
-            .collect::<Result<Vec<ExecResult>, ChangeError>>();
+            .collect::<Result<Vec<ExecResult>, ChangeError>>()?;
 
-        results
+        drop(authz_guard);
+
+        // rolled back if the client wrote more rows than its budget allows
+        if let Some(Extension(rows_limit)) = &rows_limit {
+            rows_limit.charge(total_rows_affected)?;
+        }
+
+        if let (Some(key), Some(request_hash)) = (idempotency_key, request_hash) {
+            record_idempotent_results(tx, agent.actor_id(), &scope, key, request_hash, &results)
+                .map_err(sqlite_err)?;
+        }
+
+        Ok(TransactionOutcome::Executed(results))
     })
     .await;
 
     let (results, version, elapsed) = match res {
-        Ok(res) => res,
+        Ok((TransactionOutcome::Executed(results), version, elapsed)) => {
+            (results, version, elapsed)
+        }
+        Ok((TransactionOutcome::Replayed { results, version }, _, elapsed)) => {
+            debug!("replaying transaction results for idempotency key");
+            (results, version, elapsed)
+        }
         Err(e) => {
             error!("could not execute statement(s): {e}");
+            let status = match e {
+                ChangeError::Rusqlite { ref source, .. } if is_authorization_denied(source) => {
+                    StatusCode::FORBIDDEN
+                }
+                ChangeError::PreconditionFailed { .. } => StatusCode::CONFLICT,
+                ChangeError::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+                ChangeError::RowsRateLimited => StatusCode::TOO_MANY_REQUESTS,
+                ChangeError::TooManyRows { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
             return (
-                StatusCode::INTERNAL_SERVER_ERROR,
+                status,
                 axum::Json(ExecResponse {
                     results: vec![ExecResult::Error {
                         error: e.to_string(),
@@ -239,11 +1162,35 @@ pub async fn api_v1_transactions(
                     time: 0.0,
                     version: None,
                     actor_id: Some(actor_id),
+                    replication: None,
+                    consistency_token: None,
                 }),
             );
         }
     };
 
+    let replication = if replication.requested() {
+        Some(match (version, transport) {
+            (Some(version), Some(Extension(transport))) => {
+                wait_for_replication(&agent, &transport, version, replication).await
+            }
+            (Some(_), None) => {
+                warn!("no transport available to check for replication");
+                ReplicationStatus {
+                    complete: false,
+                    confirmed_by: vec![],
+                }
+            }
+            // nothing was written, nothing to replicate
+            (None, _) => ReplicationStatus {
+                complete: true,
+                confirmed_by: vec![],
+            },
+        })
+    } else {
+        None
+    };
+
     (
         StatusCode::OK,
         axum::Json(ExecResponse {
@@ -251,6 +1198,11 @@ pub async fn api_v1_transactions(
             time: elapsed.as_secs_f64(),
             version: version.map(Into::into),
             actor_id: Some(actor_id),
+            replication,
+            consistency_token: version.map(|version| ConsistencyToken {
+                actor_id: agent.actor_id().0,
+                version: version.into(),
+            }),
         }),
     )
 }
@@ -263,16 +1215,22 @@ pub enum QueryError {
     Rusqlite(#[from] rusqlite::Error),
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn build_query_rows_response(
     agent: &Agent,
-    client_addr: SocketAddr,
+    scope: &ApiScope,
+    client_addr: Option<SocketAddr>,
     data_tx: mpsc::Sender<QueryEvent>,
+    types_tx: oneshot::Sender<Vec<Option<String>>>,
     stmt: Statement,
     timeout: Option<u64>,
+    page: Option<Page>,
 ) -> Result<(), (StatusCode, ExecResult)> {
     let (res_tx, res_rx) = oneshot::channel();
 
     let pool = agent.pool().clone();
+    let agent = agent.clone();
+    let scope = scope.clone();
 
     tokio::spawn(async move {
         let conn = match pool.read().await {
@@ -288,15 +1246,23 @@ async fn build_query_rows_response(
             }
         };
 
-        trace!(%client_addr, "Preparing statement {}", stmt.query());
+        trace!(?client_addr, "Preparing statement {}", stmt.query());
 
-        let prepped_res = block_in_place(|| conn.prepare(stmt.query()));
+        let prepped_res = block_in_place(|| {
+            let _authz_guard = scope.authorize(&conn, &agent.schema().read());
+            conn.prepare(stmt.query())
+        });
 
-        let mut prepped = match prepped_res {
+        let prepped = match prepped_res {
             Ok(prepped) => prepped,
             Err(e) => {
+                let status = if is_authorization_denied(&e) {
+                    StatusCode::FORBIDDEN
+                } else {
+                    StatusCode::BAD_REQUEST
+                };
                 _ = res_tx.send(Err((
-                    StatusCode::BAD_REQUEST,
+                    status,
                     ExecResult::Error {
                         error: e.to_string(),
                     },
@@ -315,6 +1281,55 @@ async fn build_query_rows_response(
             return;
         }
 
+        let col_count = prepped.column_count();
+        let columns: Vec<ColumnName> = prepped
+            .columns()
+            .into_iter()
+            .map(|col| ColumnName(col.name().to_compact_string()))
+            .collect();
+        let decl_types = prepped
+            .columns()
+            .into_iter()
+            .map(|col| col.decl_type().map(String::from))
+            .collect();
+
+        // pages are read through a rewritten statement
+        let (mut prepped, page_key) = match &page {
+            Some(page) => {
+                let paged = page.query(stmt.query(), &agent.schema().read(), col_count);
+                let (query, page_key) = match paged {
+                    Ok(paged) => paged,
+                    Err(error) => {
+                        _ = res_tx
+                            .send(Err((StatusCode::BAD_REQUEST, ExecResult::Error { error })));
+                        return;
+                    }
+                };
+                let prepped_res = block_in_place(|| {
+                    let _authz_guard = scope.authorize(&conn, &agent.schema().read());
+                    conn.prepare(&query)
+                });
+                match prepped_res {
+                    Ok(prepped) => (prepped, Some(page_key)),
+                    Err(e) => {
+                        let status = if is_authorization_denied(&e) {
+                            StatusCode::FORBIDDEN
+                        } else {
+                            StatusCode::BAD_REQUEST
+                        };
+                        _ = res_tx.send(Err((
+                            status,
+                            ExecResult::Error {
+                                error: e.to_string(),
+                            },
+                        )));
+                        return;
+                    }
+                }
+            }
+            None => (prepped, None),
+        };
+
         let timeout = timeout.unwrap_or(4);
         let timeout: Option<Duration> = if timeout > 0 {
             Some(Duration::from_secs(timeout * 60))
@@ -341,42 +1356,48 @@ async fn build_query_rows_response(
 
         let _dropguard = token.drop_guard();
         block_in_place(|| {
-            let col_count = prepped.column_count();
             trace!("inside block in place, col count: {col_count}");
 
-            if let Err(e) = data_tx.blocking_send(QueryEvent::Columns(
-                prepped
-                    .columns()
-                    .into_iter()
-                    .map(|col| ColumnName(col.name().to_compact_string()))
-                    .collect(),
-            )) {
+            // sent ahead of the columns so the encoder has them for its schema
+            _ = types_tx.send(decl_types);
+
+            if let Err(e) = data_tx.blocking_send(QueryEvent::Columns(columns)) {
                 error!("could not send back columns: {e}");
                 return;
             }
 
             let start = Instant::now();
 
-            trace!(%client_addr, "Executing statement {}", stmt.query());
+            trace!(?client_addr, "Executing statement {}", stmt.query());
             let elapsed = start.elapsed();
 
-            let query = match &stmt {
-                Statement::Simple(_)
-                | Statement::Verbose {
-                    params: None,
-                    named_params: None,
-                    ..
-                } => prepped.query(()),
-                Statement::WithParams(_, params)
-                | Statement::Verbose {
-                    params: Some(params),
-                    ..
-                } => prepped.query(params_from_iter(params)),
-                Statement::WithNamedParams(_, params)
-                | Statement::Verbose {
-                    named_params: Some(params),
-                    ..
-                } => prepped.query(
+            let query = match (&page, &stmt) {
+                (Some(page), _) => page.bind(&mut prepped, &stmt).map(|_| prepped.raw_query()),
+                (
+                    None,
+                    Statement::Simple(_)
+                    | Statement::Verbose {
+                        params: None,
+                        named_params: None,
+                        ..
+                    },
+                ) => prepped.query(()),
+                (
+                    None,
+                    Statement::WithParams(_, params)
+                    | Statement::Verbose {
+                        params: Some(params),
+                        ..
+                    },
+                ) => prepped.query(params_from_iter(params)),
+                (
+                    None,
+                    Statement::WithNamedParams(_, params)
+                    | Statement::Verbose {
+                        named_params: Some(params),
+                        ..
+                    },
+                ) => prepped.query(
                     params
                         .iter()
                         .map(|(k, v)| (k.as_str(), v as &dyn ToSql))
@@ -398,10 +1419,10 @@ async fn build_query_rows_response(
                 }
             };
 
-            trace!(%client_addr, elapsed = %elapsed.as_secs(), "Statement finished executing {}", stmt.query());
+            trace!(?client_addr, elapsed = %elapsed.as_secs(), "Statement finished executing {}", stmt.query());
 
             if elapsed > Duration::from_secs(10) {
-                warn!(%client_addr, elapsed = %elapsed.as_secs(), "Slow read statement {}!", stmt.query());
+                warn!(?client_addr, elapsed = %elapsed.as_secs(), "Slow read statement {}!", stmt.query());
             }
 
             if let Err(_e) = res_tx.send(Ok(())) {
@@ -409,7 +1430,15 @@ async fn build_query_rows_response(
                 return;
             }
 
-            let mut rowid = 1;
+            let cursor = page.as_ref().and_then(|page| page.cursor.as_ref());
+            let mut rowid = cursor.map_or(0, |cursor| cursor.offset) + 1;
+            let mut dups_left = cursor.map_or(0, |cursor| cursor.dups);
+            // the last key returned and how many rows had it, across pages
+            let (mut last_key, mut last_key_count) = cursor
+                .map(|cursor| (cursor.key.clone(), cursor.dups))
+                .unwrap_or_default();
+            let mut emitted = 0;
+            let mut next_cursor = None;
 
             trace!("about to loop through rows!");
 
@@ -417,11 +1446,66 @@ async fn build_query_rows_response(
                 match rows.next() {
                     Ok(Some(row)) => {
                         trace!("got a row: {row:?}");
+                        if dups_left > 0 {
+                            match row.get::<_, bool>(col_count) {
+                                // equal to the cursor's key, already returned
+                                Ok(true) => {
+                                    dups_left -= 1;
+                                    continue;
+                                }
+                                Ok(false) => dups_left = 0,
+                                Err(e) => {
+                                    _ = data_tx
+                                        .blocking_send(QueryEvent::Error(e.to_compact_string()));
+                                    return;
+                                }
+                            }
+                        }
+                        if let Some(page) = page.as_ref() {
+                            if emitted == page.size {
+                                // there's at least one more row, so there's another page
+                                next_cursor = Some(
+                                    QueryCursor {
+                                        query_hash: page.query_hash,
+                                        offset: rowid - 1,
+                                        key: std::mem::take(&mut last_key),
+                                        dups: last_key_count,
+                                    }
+                                    .to_string(),
+                                );
+                                break;
+                            }
+                        }
                         match (0..col_count)
                             .map(|i| row.get::<_, SqliteValue>(i))
                             .collect::<rusqlite::Result<Vec<_>>>()
                         {
                             Ok(cells) => {
+                                match page_key {
+                                    Some(PageKey::PrimaryKey(key_len)) => {
+                                        match (col_count..col_count + key_len)
+                                            .map(|i| row.get::<_, SqliteValue>(i))
+                                            .collect::<rusqlite::Result<Vec<_>>>()
+                                        {
+                                            Ok(key) => last_key = key,
+                                            Err(e) => {
+                                                _ = data_tx.blocking_send(QueryEvent::Error(
+                                                    e.to_compact_string(),
+                                                ));
+                                                return;
+                                            }
+                                        }
+                                    }
+                                    Some(PageKey::Columns) => {
+                                        if cells == last_key {
+                                            last_key_count += 1;
+                                        } else {
+                                            last_key = cells.clone();
+                                            last_key_count = 1;
+                                        }
+                                    }
+                                    None => {}
+                                }
                                 if let Err(e) =
                                     data_tx.blocking_send(QueryEvent::Row(rowid.into(), cells))
                                 {
@@ -429,6 +1513,7 @@ async fn build_query_rows_response(
                                     return;
                                 }
                                 rowid += 1;
+                                emitted += 1;
                             }
                             Err(e) => {
                                 _ = data_tx.blocking_send(QueryEvent::Error(e.to_compact_string()));
@@ -450,6 +1535,7 @@ async fn build_query_rows_response(
             _ = data_tx.blocking_send(QueryEvent::EndOfQuery {
                 time: elapsed.as_secs_f64(),
                 change_id: None,
+                cursor: next_cursor,
             });
         });
     });
@@ -465,26 +1551,76 @@ async fn build_query_rows_response(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn api_v1_queries(
     Extension(agent): Extension<Agent>,
-    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    Extension(scope): Extension<ApiScope>,
+    Extension(bookie): Extension<Bookie>,
+    client_addr: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
     axum::extract::Query(params): axum::extract::Query<TimeoutParams>,
+    axum::extract::Query(consistency): axum::extract::Query<ConsistencyParams>,
+    axum::extract::Query(pagination): axum::extract::Query<PaginationParams>,
     axum::extract::Json(stmt): axum::extract::Json<Statement>,
 ) -> impl IntoResponse {
-    let (mut tx, body) = hyper::Body::channel();
-
     counter!("corro.api.queries.count").increment(1);
+
+    let page = match pagination.page(&stmt) {
+        Ok(page) => page,
+        Err(e) => {
+            return hyper::Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(
+                    serde_json::to_vec(&ExecResult::Error { error: e })
+                        .expect("could not serialize query error response")
+                        .into(),
+                )
+                .expect("could not build query response body");
+        }
+    };
+
+    let format = QueryFormat::from_headers(&headers);
+    if page.is_some() && format != QueryFormat::Ndjson {
+        return hyper::Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(
+                serde_json::to_vec(&ExecResult::Error {
+                    error: format!("{format:?} results can't be paginated, only NDJSON can"),
+                })
+                .expect("could not serialize query error response")
+                .into(),
+            )
+            .expect("could not build query response body");
+    }
+
+    if let Err(e) = wait_for_consistency(&bookie, consistency).await {
+        return hyper::Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(
+                serde_json::to_vec(&QueryEvent::Error(e.to_compact_string()))
+                    .expect("could not serialize query error response")
+                    .into(),
+            )
+            .expect("could not build query response body");
+    }
+
+    let (mut tx, body) = hyper::Body::channel();
     // TODO: timeout on data send instead of infinitely waiting for channel space.
     let (data_tx, mut data_rx) = channel(512);
+    let (types_tx, types_rx) = oneshot::channel::<Vec<Option<String>>>();
 
     let start = Instant::now();
     tokio::spawn(async move {
         let mut buf = BytesMut::new();
+        let mut encoder = format.encoder();
+        // not sent if the statement couldn't be prepared
+        if let Ok(decl_types) = types_rx.await {
+            encoder.set_column_types(&decl_types);
+        }
 
         while let Some(row_res) = data_rx.recv().await {
-            {
-                let mut writer = (&mut buf).writer();
-                if let Err(e) = serde_json::to_writer(&mut writer, &row_res) {
+            if let Err(e) = encoder.encode(&row_res, &mut buf) {
+                if format == QueryFormat::Ndjson {
                     _ = tx
                         .send_data(
                             serde_json::to_vec(&serde_json::json!(QueryEvent::Error(
@@ -494,11 +1630,18 @@ pub async fn api_v1_queries(
                             .into(),
                         )
                         .await;
-                    return;
+                } else {
+                    // no way to signal an error in these formats, so cut the
+                    // body short instead of letting it look complete
+                    warn!("aborting {format:?} query response: {e}");
+                    tx.abort();
                 }
+                return;
             }
 
-            buf.extend_from_slice(b"\n");
+            if buf.is_empty() {
+                continue;
+            }
 
             if let Err(e) = tx.send_data(buf.split().freeze()).await {
                 error!("could not send data through body's channel: {e}");
@@ -511,13 +1654,27 @@ pub async fn api_v1_queries(
     trace!("building query rows response...");
     assert_sometimes!(true, "Corrosion accepts queries");
 
-    match build_query_rows_response(&agent, client_addr, data_tx, stmt, params.timeout).await {
+    match build_query_rows_response(
+        &agent,
+        &scope,
+        client_addr.map(|ConnectInfo(addr)| addr),
+        data_tx,
+        types_tx,
+        stmt,
+        params.timeout,
+        page,
+    )
+    .await
+    {
         Ok(_) => {
             histogram!("corro.api.queries.processing.time.seconds", "result" => "success")
                 .record(start.elapsed());
+            let mut builder = hyper::Response::builder().status(StatusCode::OK);
+            if let Some(content_type) = format.content_type() {
+                builder = builder.header(hyper::header::CONTENT_TYPE, content_type);
+            }
             #[allow(clippy::needless_return)]
-            return hyper::Response::builder()
-                .status(StatusCode::OK)
+            return builder
                 .body(body)
                 .expect("could not build query response body");
         }
@@ -537,6 +1694,85 @@ pub async fn api_v1_queries(
     }
 }
 
+/// Nests the rows of an `EXPLAIN QUERY PLAN`, which point to their parent's
+/// id (0 for top-level rows)
+fn query_plan_tree(rows: &[(i64, i64, String)], parent: i64) -> Vec<QueryPlanNode> {
+    rows.iter()
+        .filter(|(id, row_parent, _)| *row_parent == parent && *id != parent)
+        .map(|(id, _, detail)| QueryPlanNode {
+            id: *id,
+            detail: detail.clone(),
+            children: query_plan_tree(rows, *id),
+        })
+        .collect()
+}
+
+pub async fn api_v1_queries_explain(
+    Extension(agent): Extension<Agent>,
+    Extension(scope): Extension<ApiScope>,
+    axum::extract::Json(stmt): axum::extract::Json<Statement>,
+) -> Result<axum::Json<QueryPlan>, (StatusCode, axum::Json<ExecResult>)> {
+    let error =
+        |status: StatusCode, error: String| (status, axum::Json(ExecResult::Error { error }));
+    let sqlite_error = |e: rusqlite::Error| {
+        let status = if is_authorization_denied(&e) {
+            StatusCode::FORBIDDEN
+        } else {
+            StatusCode::BAD_REQUEST
+        };
+        error(status, e.to_string())
+    };
+
+    let conn = agent
+        .pool()
+        .read()
+        .await
+        .map_err(|e| error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let (sql, rows) = block_in_place(|| {
+        let _authz_guard = scope.authorize(&conn, &agent.schema().read());
+
+        if !conn.prepare(stmt.query()).map_err(sqlite_error)?.readonly() {
+            return Err(error(
+                StatusCode::BAD_REQUEST,
+                "statement is not readonly".into(),
+            ));
+        }
+
+        let sql = expanded_statement(&conn, &stmt)
+            .map_err(sqlite_error)?
+            .ok_or_else(|| {
+                error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "could not expand sql statement".into(),
+                )
+            })?;
+
+        let rows = conn
+            .prepare(&format!("EXPLAIN QUERY PLAN {sql}"))
+            .and_then(|mut prepped| {
+                prepped
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(3)?)))?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .map_err(sqlite_error)?;
+
+        Ok((sql, rows))
+    })?;
+
+    let (subscription, subscription_error) =
+        match explain_subscription(&agent.schema().read(), &sql) {
+            Ok(plan) => (Some(plan), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+    Ok(axum::Json(QueryPlan {
+        plan: query_plan_tree(&rows, 0),
+        subscription,
+        subscription_error,
+    }))
+}
+
 pub(crate) async fn execute_schema(agent: &Agent, statements: Vec<String>) -> eyre::Result<()> {
     let new_sql: String = statements.join(";");
 
@@ -594,6 +1830,7 @@ pub(crate) async fn execute_schema(agent: &Agent, statements: Vec<String>) -> ey
 
 pub async fn api_v1_db_schema(
     Extension(agent): Extension<Agent>,
+    Extension(scope): Extension<ApiScope>,
     axum::extract::Json(statements): axum::extract::Json<Vec<String>>,
 ) -> (StatusCode, axum::Json<ExecResponse>) {
     let actor_id = agent.actor_id().to_string();
@@ -607,6 +1844,35 @@ pub async fn api_v1_db_schema(
                 time: 0.0,
                 version: None,
                 actor_id: Some(actor_id),
+                replication: None,
+                consistency_token: None,
+            }),
+        );
+    }
+
+    let denied = match parse_sql(&statements.join(";")) {
+        Ok(partial_schema) => partial_schema
+            .tables
+            .keys()
+            .find(|table| !scope.allows_table(table))
+            .map(|table| format!("not authorized to migrate table '{table}'")),
+        // can't tell which tables would be migrated
+        Err(e) if !scope.allows_all_tables() => Some(format!(
+            "not authorized to migrate tables that could not be determined: {e}"
+        )),
+        Err(_) => None,
+    };
+
+    if let Some(error) = denied {
+        return (
+            StatusCode::FORBIDDEN,
+            axum::Json(ExecResponse {
+                results: vec![ExecResult::Error { error }],
+                time: 0.0,
+                version: None,
+                actor_id: Some(actor_id),
+                replication: None,
+                consistency_token: None,
             }),
         );
     }
@@ -625,6 +1891,8 @@ pub async fn api_v1_db_schema(
                 time: 0.0,
                 version: None,
                 actor_id: Some(actor_id),
+                replication: None,
+                consistency_token: None,
             }),
         );
     }
@@ -636,6 +1904,8 @@ pub async fn api_v1_db_schema(
             time: start.elapsed().as_secs_f64(),
             version: None,
             actor_id: Some(actor_id),
+            replication: None,
+            consistency_token: None,
         }),
     )
 }
@@ -647,6 +1917,7 @@ pub async fn api_v1_db_schema(
 /// existence before querying
 pub async fn api_v1_table_stats(
     Extension(agent): Extension<Agent>,
+    Extension(scope): Extension<ApiScope>,
     axum::extract::Json(ts_req): axum::extract::Json<TableStatRequest>,
 ) -> (StatusCode, axum::Json<TableStatResponse>) {
     async fn count_table_lengths(
@@ -682,6 +1953,23 @@ pub async fn api_v1_table_stats(
         })
     }
 
+    let denied_tables: Vec<String> = ts_req
+        .tables
+        .iter()
+        .filter(|table| !scope.allows_table(table))
+        .cloned()
+        .collect();
+    if !denied_tables.is_empty() {
+        // denied tables are reported as invalid, same as missing ones
+        return (
+            StatusCode::FORBIDDEN,
+            axum::Json(TableStatResponse {
+                total_row_count: 0,
+                invalid_tables: denied_tables,
+            }),
+        );
+    }
+
     match count_table_lengths(&agent, ts_req).await {
         Ok((count, invalid_tables)) => (
             StatusCode::OK,
@@ -690,15 +1978,18 @@ pub async fn api_v1_table_stats(
                 invalid_tables,
             }),
         ),
-        Err(_) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            axum::Json(TableStatResponse {
-                total_row_count: 0,
-                // Since we don't know what error occured or if any
-                // tables were valid, we just return an empty list
-                invalid_tables: vec![],
-            }),
-        ),
+        Err(e) => {
+            error!("could not count table rows: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                axum::Json(TableStatResponse {
+                    total_row_count: 0,
+                    // Since we don't know what error occured or if any
+                    // tables were valid, we just return an empty list
+                    invalid_tables: vec![],
+                }),
+            )
+        }
     }
 }
 
@@ -709,7 +2000,7 @@ mod tests {
         api::RowId,
         base::CrsqlDbVersion,
         broadcast::{BroadcastInput, BroadcastV1, ChangeV1, Changeset},
-        config::Config,
+        config::{ApiPermission, ApiTokenConfig, Config},
         schema::SqliteType,
     };
     use futures::Stream;
@@ -724,26 +2015,237 @@ mod tests {
 
     struct UnsyncBodyStream(std::pin::Pin<Box<UnsyncBoxBody<Bytes, axum::Error>>>);
 
-    impl Stream for UnsyncBodyStream {
-        type Item = Result<Bytes, axum::Error>;
+    impl Stream for UnsyncBodyStream {
+        type Item = Result<Bytes, axum::Error>;
+
+        fn poll_next(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Self::Item>> {
+            self.0.as_mut().poll_data(cx)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, mut agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let rx_bcast = &mut agent_options.rx_bcast;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            None,
+            None,
+            HeaderMap::new(),
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::extract::Query(ReplicationParams::default()),
+            axum::Json(
+                vec![Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec!["service-id".into(), "service-name".into()],
+                )]
+                .into(),
+            ),
+        )
+        .await;
+
+        println!("{body:?}");
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert!(body.0.results.len() == 1);
+
+        let msg = rx_bcast
+            .recv()
+            .await
+            .expect("not msg received on bcast channel");
+
+        assert!(matches!(
+            msg,
+            BroadcastInput::AddBroadcast(BroadcastV1::Change(ChangeV1 {
+                changeset: Changeset::Full {
+                    version: CrsqlDbVersion(1),
+                    ..
+                },
+                ..
+            }))
+        ));
+
+        assert_eq!(
+            agent.booked().read::<&str, _>("test", None).await.last(),
+            Some(CrsqlDbVersion(1))
+        );
+
+        println!("second req...");
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            None,
+            None,
+            HeaderMap::new(),
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::extract::Query(ReplicationParams::default()),
+            axum::Json(
+                vec![Statement::WithParams(
+                    "update tests SET text = ? where id = ?".into(),
+                    vec!["service-name".into(), "service-id".into()],
+                )]
+                .into(),
+            ),
+        )
+        .await;
+
+        println!("{body:?}");
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert!(body.0.results.len() == 1);
+
+        // no actual changes!
+        assert!(matches!(rx_bcast.try_recv(), Err(TryRecvError::Empty)));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_preconditions() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let transact = |statement: &str, preconditions: Vec<Precondition>| {
+            api_v1_transactions(
+                Extension(agent.clone()),
+                Extension(ApiScope::unrestricted()),
+                None,
+                None,
+                HeaderMap::new(),
+                axum::extract::Query(TimeoutParams { timeout: None }),
+                axum::extract::Query(ReplicationParams::default()),
+                axum::Json(TransactionRequest::Conditional {
+                    statements: vec![statement.into()],
+                    preconditions,
+                }),
+            )
+        };
+
+        let text_is = |text: &str| Precondition::Query {
+            statement: "SELECT text FROM tests WHERE id = 1".into(),
+            rows: vec![vec![text.into()]],
+        };
+        let version_is = |id: i64, version: Option<ConsistencyToken>| Precondition::RowVersion {
+            table: "tests".into(),
+            pk: vec![id.into()],
+            version,
+        };
+
+        let (status_code, body) = transact(
+            "INSERT INTO tests (id, text) VALUES (1, 'one')",
+            vec![version_is(1, None)],
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        let inserted = body.0.consistency_token;
+        assert!(inserted.is_some());
+
+        let (status_code, _) = transact(
+            "UPDATE tests SET text = 'two' WHERE id = 1",
+            vec![text_is("one"), version_is(1, inserted)],
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        // the row changed since both preconditions were true
+        let (status_code, body) = transact(
+            "UPDATE tests SET text = 'three' WHERE id = 1",
+            vec![text_is("one")],
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::CONFLICT);
+        assert!(matches!(
+            &body.0.results[..],
+            [ExecResult::Error { error }] if error.starts_with("precondition 0 failed")
+        ));
+        assert!(body.0.version.is_none());
+
+        let (status_code, _) = transact(
+            "UPDATE tests SET text = 'three' WHERE id = 1",
+            vec![text_is("two"), version_is(1, inserted)],
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::CONFLICT);
+
+        let (status_code, _) = transact(
+            "INSERT INTO tests (id, text) VALUES (1, 'one')",
+            vec![version_is(1, None)],
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::CONFLICT);
+
+        let text: String = agent.pool().read().await?.query_row(
+            "SELECT text FROM tests WHERE id = 1",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(text, "two");
 
-        fn poll_next(
-            mut self: std::pin::Pin<&mut Self>,
-            cx: &mut std::task::Context<'_>,
-        ) -> std::task::Poll<Option<Self::Item>> {
-            self.0.as_mut().poll_data(cx)
-        }
+        Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn test_api_db_execute() -> eyre::Result<()> {
+    async fn test_api_db_idempotency_key() -> eyre::Result<()> {
         _ = tracing_subscriber::fmt::try_init();
 
         let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
 
         let dir = tempfile::tempdir()?;
 
-        let (agent, mut agent_options) = setup(
+        let (agent, _agent_options) = setup(
             Config::builder()
                 .db_path(dir.path().join("corrosion.db").display().to_string())
                 .gossip_addr("127.0.0.1:0".parse()?)
@@ -753,73 +2255,75 @@ mod tests {
         )
         .await?;
 
-        let rx_bcast = &mut agent_options.rx_bcast;
-
         let (status_code, _body) = api_v1_db_schema(
             Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
             axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
         )
         .await;
-
         assert_eq!(status_code, StatusCode::OK);
 
-        let (status_code, body) = api_v1_transactions(
-            Extension(agent.clone()),
-            axum::extract::Query(TimeoutParams { timeout: None }),
-            axum::Json(vec![Statement::WithParams(
-                "insert into tests (id, text) values (?,?)".into(),
-                vec!["service-id".into(), "service-name".into()],
-            )]),
-        )
-        .await;
-
-        println!("{body:?}");
+        let transact = |key: Option<&'static str>, stmt: &'static str| {
+            let mut headers = HeaderMap::new();
+            if let Some(key) = key {
+                headers.insert(IDEMPOTENCY_KEY_HEADER, key.parse().unwrap());
+            }
+            api_v1_transactions(
+                Extension(agent.clone()),
+                Extension(ApiScope::unrestricted()),
+                None,
+                None,
+                headers,
+                axum::extract::Query(TimeoutParams { timeout: None }),
+                axum::extract::Query(ReplicationParams::default()),
+                axum::Json(vec![Statement::Simple(stmt.into())].into()),
+            )
+        };
+        let insert_one = "INSERT INTO tests (id, text) VALUES (1, 'one')";
 
+        let (status_code, body) = transact(Some("first"), insert_one).await;
         assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(body.0.version, Some(1));
 
-        assert!(body.0.results.len() == 1);
-
-        let msg = rx_bcast
-            .recv()
-            .await
-            .expect("not msg received on bcast channel");
-
+        // replayed instead of failing on the primary key
+        let (status_code, body) = transact(Some("first"), insert_one).await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(body.0.version, Some(1));
         assert!(matches!(
-            msg,
-            BroadcastInput::AddBroadcast(BroadcastV1::Change(ChangeV1 {
-                changeset: Changeset::Full {
-                    version: CrsqlDbVersion(1),
-                    ..
-                },
+            &body.0.results[..],
+            [ExecResult::Execute {
+                rows_affected: 1,
                 ..
-            }))
+            }]
         ));
 
-        assert_eq!(
-            agent.booked().read::<&str, _>("test", None).await.last(),
-            Some(CrsqlDbVersion(1))
-        );
-
-        println!("second req...");
-
-        let (status_code, body) = api_v1_transactions(
-            Extension(agent.clone()),
-            axum::extract::Query(TimeoutParams { timeout: None }),
-            axum::Json(vec![Statement::WithParams(
-                "update tests SET text = ? where id = ?".into(),
-                vec!["service-name".into(), "service-id".into()],
-            )]),
+        // same key, different request
+        let (status_code, body) = transact(
+            Some("first"),
+            "INSERT INTO tests (id, text) VALUES (2, 'two')",
         )
         .await;
+        assert_eq!(status_code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body.0.version.is_none());
 
-        println!("{body:?}");
+        let (status_code, _) = transact(Some("second"), insert_one).await;
+        assert_eq!(status_code, StatusCode::INTERNAL_SERVER_ERROR);
 
-        assert_eq!(status_code, StatusCode::OK);
+        let (status_code, _) = transact(None, insert_one).await;
+        assert_eq!(status_code, StatusCode::INTERNAL_SERVER_ERROR);
 
-        assert!(body.0.results.len() == 1);
+        // expired keys are not replayed
+        agent.pool().write_priority().await?.execute(
+            "UPDATE __corro_idempotency_keys SET created_at = created_at - ?",
+            [agent.config().api.idempotency_key_ttl],
+        )?;
+        let (status_code, _) = transact(Some("first"), insert_one).await;
+        assert_eq!(status_code, StatusCode::INTERNAL_SERVER_ERROR);
 
-        // no actual changes!
-        assert!(matches!(rx_bcast.try_recv(), Err(TryRecvError::Empty)));
+        assert_eq!(
+            agent.booked().read::<&str, _>("test", None).await.last(),
+            Some(CrsqlDbVersion(1))
+        );
 
         Ok(())
     }
@@ -844,6 +2348,7 @@ mod tests {
 
         let (status_code, _body) = api_v1_db_schema(
             Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
             axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
         )
         .await;
@@ -852,17 +2357,25 @@ mod tests {
 
         let (status_code, body) = api_v1_transactions(
             Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            None,
+            None,
+            HeaderMap::new(),
             axum::extract::Query(TimeoutParams { timeout: None }),
-            axum::Json(vec![
-                Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id".into(), "service-name".into()],
-                ),
-                Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id-2".into(), "service-name-2".into()],
-                ),
-            ]),
+            axum::extract::Query(ReplicationParams::default()),
+            axum::Json(
+                vec![
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id".into(), "service-name".into()],
+                    ),
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-2".into(), "service-name-2".into()],
+                    ),
+                ]
+                .into(),
+            ),
         )
         .await;
 
@@ -876,8 +2389,13 @@ mod tests {
 
         let res = api_v1_queries(
             Extension(agent.clone()),
-            ConnectInfo("127.0.0.1:1234".parse().unwrap()),
+            Extension(ApiScope::unrestricted()),
+            Extension(Bookie::new(Default::default())),
+            Some(ConnectInfo("127.0.0.1:1234".parse().unwrap())),
+            HeaderMap::new(),
             axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::extract::Query(ConsistencyParams::default()),
+            axum::extract::Query(PaginationParams::default()),
             axum::Json(Statement::Simple("select * from tests".into())),
         )
         .await
@@ -937,6 +2455,279 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_query_pagination() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let insert = |id: &str| {
+            api_v1_transactions(
+                Extension(agent.clone()),
+                Extension(ApiScope::unrestricted()),
+                None,
+                None,
+                HeaderMap::new(),
+                axum::extract::Query(TimeoutParams { timeout: None }),
+                axum::extract::Query(ReplicationParams::default()),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec![id.into(), "text".into()],
+                    )]
+                    .into(),
+                ),
+            )
+        };
+
+        for id in ["a", "b", "c"] {
+            let (status_code, _body) = insert(id).await;
+            assert_eq!(status_code, StatusCode::OK);
+        }
+
+        let query_stmt = |stmt: &str, page_size: Option<usize>, cursor: Option<String>| {
+            api_v1_queries(
+                Extension(agent.clone()),
+                Extension(ApiScope::unrestricted()),
+                Extension(Bookie::new(Default::default())),
+                Some(ConnectInfo("127.0.0.1:1234".parse().unwrap())),
+                HeaderMap::new(),
+                axum::extract::Query(TimeoutParams { timeout: None }),
+                axum::extract::Query(ConsistencyParams::default()),
+                axum::extract::Query(PaginationParams { page_size, cursor }),
+                axum::Json(Statement::Simple(stmt.into())),
+            )
+        };
+        let query = |page_size: Option<usize>, cursor: Option<String>| {
+            query_stmt("select id from tests", page_size, cursor)
+        };
+
+        async fn events(res: axum::response::Response) -> eyre::Result<Vec<QueryEvent>> {
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+            Ok(body
+                .split(|b| *b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(serde_json::from_slice)
+                .collect::<Result<_, _>>()?)
+        }
+
+        fn next_cursor(events: &[QueryEvent]) -> Option<String> {
+            match events.last() {
+                Some(QueryEvent::EndOfQuery { cursor, .. }) => cursor.clone(),
+                evt => panic!("expected end of query, got: {evt:?}"),
+            }
+        }
+
+        let res = query(Some(2), None).await.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        let first = events(res).await?;
+        assert_eq!(first[1], QueryEvent::Row(RowId(1), vec!["a".into()]));
+        assert_eq!(first[2], QueryEvent::Row(RowId(2), vec!["b".into()]));
+        assert_eq!(first.len(), 4);
+        let cursor = next_cursor(&first).expect("expected a cursor for the next page");
+
+        let res = query(None, Some(cursor.clone())).await.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        let second = events(res).await?;
+        assert_eq!(second[1], QueryEvent::Row(RowId(3), vec!["c".into()]));
+        assert_eq!(second.len(), 3);
+        assert_eq!(next_cursor(&second), None);
+
+        let res = query(None, Some("not-a-cursor".into()))
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // only NDJSON can carry the cursor
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            hyper::header::ACCEPT,
+            query_format::CSV_CONTENT_TYPE.parse().unwrap(),
+        );
+        let res = api_v1_queries(
+            Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            Extension(Bookie::new(Default::default())),
+            Some(ConnectInfo("127.0.0.1:1234".parse().unwrap())),
+            headers,
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::extract::Query(ConsistencyParams::default()),
+            axum::extract::Query(PaginationParams {
+                page_size: Some(2),
+                cursor: None,
+            }),
+            axum::Json(Statement::Simple("select id from tests".into())),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // cursors resume after their last row, whatever was written since
+        for id in ["aa", "d"] {
+            let (status_code, _body) = insert(id).await;
+            assert_eq!(status_code, StatusCode::OK);
+        }
+
+        let res = query(Some(2), Some(cursor)).await.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        let third = events(res).await?;
+        assert_eq!(third[1], QueryEvent::Row(RowId(3), vec!["c".into()]));
+        assert_eq!(third[2], QueryEvent::Row(RowId(4), vec!["d".into()]));
+        assert_eq!(third.len(), 4);
+
+        // identical rows are neither skipped nor repeated across pages
+        let mut cursor = None;
+        let mut rows = 0;
+        loop {
+            let res = query_stmt("select text from tests", Some(2), cursor)
+                .await
+                .into_response();
+            assert_eq!(res.status(), StatusCode::OK);
+            let page = events(res).await?;
+            rows += page
+                .iter()
+                .filter(|evt| matches!(evt, QueryEvent::Row(..)))
+                .count();
+            cursor = next_cursor(&page);
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(rows, 5);
+
+        // the statement is parsed, not pasted into the paginated query
+        let res = query_stmt("select id from tests; -- all of them", Some(2), None)
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(events(res).await?.len(), 4);
+
+        // pages decide the order of rows
+        let res = query_stmt("select id from tests order by id desc", Some(2), None)
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // other queries are keyed on all of their columns
+        let mut cursor = None;
+        let mut values = vec![];
+        loop {
+            let res = query_stmt("select upper(id) from tests", Some(2), cursor)
+                .await
+                .into_response();
+            assert_eq!(res.status(), StatusCode::OK);
+            let page = events(res).await?;
+            values.extend(page.iter().filter_map(|evt| match evt {
+                QueryEvent::Row(_, cells) => Some(cells[0].clone()),
+                _ => None,
+            }));
+            cursor = next_cursor(&page);
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(
+            values,
+            vec![
+                SqliteValue::from("A"),
+                "AA".into(),
+                "B".into(),
+                "C".into(),
+                "D".into()
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_query_explain() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let explain = |stmt: Statement| {
+            api_v1_queries_explain(
+                Extension(agent.clone()),
+                Extension(ApiScope::unrestricted()),
+                axum::Json(stmt),
+            )
+        };
+
+        let plan = explain(Statement::WithParams(
+            "select text from tests where id = ?".into(),
+            vec![1i64.into()],
+        ))
+        .await
+        .map_err(|(status, res)| eyre::eyre!("{status}: {:?}", res.0))?
+        .0;
+
+        assert!(!plan.plan.is_empty());
+        assert!(plan.plan[0].detail.contains("tests"), "{:?}", plan.plan);
+
+        let subscription = plan.subscription.expect("expected a subscription plan");
+        assert_eq!(subscription.tables.len(), 1);
+        let table = &subscription.tables[0];
+        assert_eq!(table.table, "tests");
+        assert_eq!(table.pks, vec!["__corro_pk_tests_id".to_string()]);
+        assert_eq!(table.columns, vec!["id".to_string(), "text".to_string()]);
+        assert!(subscription.query.contains("__corro_pk_tests_id"));
+        assert!(table.query.contains("temp_tests"), "{}", table.query);
+
+        // can be queried, but not subscribed to
+        let plan = explain(Statement::Simple("select 1".into()))
+            .await
+            .map_err(|(status, res)| eyre::eyre!("{status}: {:?}", res.0))?
+            .0;
+        assert!(plan.subscription.is_none());
+        assert!(plan.subscription_error.is_some());
+
+        let res = explain(Statement::Simple("delete from tests".into())).await;
+        assert!(matches!(res, Err((StatusCode::BAD_REQUEST, _))));
+
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn test_api_db_schema() -> eyre::Result<()> {
         _ = tracing_subscriber::fmt::try_init();
@@ -956,6 +2747,7 @@ mod tests {
 
         let (status_code, _body) = api_v1_db_schema(
             Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
             axum::Json(vec![
                 "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
             ]),
@@ -987,6 +2779,7 @@ mod tests {
 
         let (status_code, _body) = api_v1_db_schema(
             Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
             axum::Json(vec![
                 "CREATE TABLE tests2 (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
                 "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
@@ -1060,6 +2853,7 @@ mod tests {
 
         let (status_code, _body) = api_v1_db_schema(
             Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
             axum::Json(vec![create_stmt.into()]),
         )
         .await;
@@ -1130,4 +2924,142 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_scoped_tables() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let scope = ApiScope::from_token(&ApiTokenConfig {
+            name: "test".into(),
+            token: "secret".into(),
+            permissions: ApiPermission::ALL.to_vec(),
+            tables: Some(vec!["tests".into()]),
+        });
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            Extension(scope.clone()),
+            axum::Json(vec![
+                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            Extension(scope.clone()),
+            axum::Json(vec![
+                "CREATE TABLE other (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::FORBIDDEN);
+
+        // the migrated tables can't be determined
+        let (status_code, body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            Extension(scope.clone()),
+            axum::Json(vec!["CREATE TABLE other (".into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::FORBIDDEN);
+        assert!(matches!(
+            &body.0.results[..],
+            [ExecResult::Error { error }] if error.starts_with("not authorized")
+        ));
+
+        let (status_code, body) = api_v1_table_stats(
+            Extension(agent.clone()),
+            Extension(scope.clone()),
+            axum::Json(TableStatRequest {
+                tables: vec!["tests".into()],
+            }),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(body.0.total_row_count, 0);
+
+        let (status_code, body) = api_v1_table_stats(
+            Extension(agent.clone()),
+            Extension(scope),
+            axum::Json(TableStatRequest {
+                tables: vec!["tests".into(), "other".into()],
+            }),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::FORBIDDEN);
+        assert_eq!(body.0.invalid_tables, vec!["other".to_string()]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_replication_targets_skip_other_clusters() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let ts = Timestamp::from(agent.clock().new_timestamp());
+        let other_cluster = corro_types::actor::ClusterId(agent.cluster_id().0 + 1);
+        {
+            let mut members = agent.members().write();
+            for (port, cluster_id) in [(9001, agent.cluster_id()), (9002, other_cluster)] {
+                let mut state = corro_types::members::MemberState::new(
+                    ([127, 0, 0, 1], port).into(),
+                    ts,
+                    cluster_id,
+                );
+                state.ring = Some(0);
+                members.states.insert(ActorId(uuid::Uuid::new_v4()), state);
+            }
+        }
+
+        let replicas = |n| ReplicationParams {
+            replicas: Some(n),
+            ..Default::default()
+        };
+
+        let (targets, needed) = replicas(1).targets(&agent).unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].1.port(), 9001);
+        assert_eq!(needed, 1);
+        assert!(replicas(2).validate(&agent).is_err());
+
+        let (targets, needed) = ReplicationParams {
+            ring0_fraction: Some(1.0),
+            ..Default::default()
+        }
+        .targets(&agent)
+        .unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(needed, 1);
+
+        Ok(())
+    }
 }
@@ -1,13 +1,26 @@
-use std::{collections::HashMap, io::Write, sync::Arc, time::Duration};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::Write,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
 
-use axum::{http::StatusCode, response::IntoResponse, Extension};
+use axum::{
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension,
+};
 use bytes::{BufMut, Bytes, BytesMut};
-use compact_str::{format_compact, ToCompactString};
+use compact_str::{format_compact, CompactString, ToCompactString};
 use corro_types::updates::Handle;
 use corro_types::{
-    agent::Agent,
-    api::{ChangeId, QueryEvent, QueryEventMeta, Statement},
-    pubsub::{MatcherCreated, MatcherError, MatcherHandle, NormalizeStatementError, SubsManager},
+    agent::{Agent, Bookie},
+    api::{ChangeId, QueryEvent, QueryEventMeta, RowId, SqliteValue, Statement},
+    pubsub::{
+        ChangeType, MatcherCreated, MatcherError, MatcherHandle, NormalizeStatementError,
+        SubsManager,
+    },
     sqlite::SqlitePoolError,
 };
 use futures::future::poll_fn;
@@ -26,28 +39,212 @@ use tracing::{debug, error, info, warn};
 use tripwire::Tripwire;
 use uuid::Uuid;
 
+use crate::api::public::{
+    authz::{is_authorization_denied, ApiScope},
+    wait_for_consistency, ConsistencyParams, ConsistencyTimeout,
+};
+
 #[derive(Clone, Copy, Debug, Default, Deserialize)]
 pub struct SubParams {
     #[serde(default)]
-    from: Option<ChangeId>,
+    pub from: Option<ChangeId>,
+    #[serde(default)]
+    pub skip_rows: bool,
+    /// Coalesce changes for this many milliseconds, only sending the net
+    /// change to each row
     #[serde(default)]
-    skip_rows: bool,
+    pub coalesce_ms: Option<u64>,
+    /// Send coalesced changes early once this many rows changed
+    #[serde(default)]
+    pub coalesce_max: Option<usize>,
+}
+
+const DEFAULT_COALESCE_WINDOW: Duration = Duration::from_millis(100);
+
+impl SubParams {
+    fn coalesce_window(&self) -> Option<Duration> {
+        match (self.coalesce_ms, self.coalesce_max) {
+            (Some(ms), _) => Some(Duration::from_millis(ms)),
+            (None, Some(_)) => Some(DEFAULT_COALESCE_WINDOW),
+            (None, None) => None,
+        }
+    }
+
+    /// Rejects windows longer than `api.max_coalesce_ms`
+    pub(crate) fn check_coalesce_window(&self, max_ms: u64) -> Result<(), MatcherUpsertError> {
+        match self.coalesce_ms {
+            Some(ms) if ms > max_ms => {
+                Err(MatcherUpsertError::CoalesceWindowTooLong { ms, max_ms })
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
+/// Wire format of a subscription's events
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventStreamFormat {
+    /// One JSON event per line
+    Ndjson,
+    /// Server-Sent Events, negotiated via `Accept: text/event-stream`
+    Sse,
+}
+
+impl EventStreamFormat {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let wants_sse = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|mime| mime.trim().starts_with(SSE_CONTENT_TYPE));
+        if wants_sse {
+            EventStreamFormat::Sse
+        } else {
+            EventStreamFormat::Ndjson
+        }
+    }
+
+    fn response_builder(self) -> hyper::http::response::Builder {
+        let builder = hyper::Response::builder().status(StatusCode::OK);
+        match self {
+            EventStreamFormat::Ndjson => builder,
+            EventStreamFormat::Sse => builder
+                .header(header::CONTENT_TYPE, SSE_CONTENT_TYPE)
+                .header(header::CACHE_CONTROL, "no-cache"),
+        }
+    }
+}
+
+const SSE_CONTENT_TYPE: &str = "text/event-stream";
+const LAST_EVENT_ID: &str = "last-event-id";
+
+/// Parses the SSE format and, for reconnecting EventSource clients, resumes
+/// from the `Last-Event-ID` (which is always a `ChangeId`) instead of `from`
+fn sub_stream_params(
+    headers: &HeaderMap,
+    mut params: SubParams,
+    max_coalesce_ms: u64,
+) -> Result<(EventStreamFormat, SubParams), MatcherUpsertError> {
+    params.check_coalesce_window(max_coalesce_ms)?;
+    let format = EventStreamFormat::from_headers(headers);
+    if format == EventStreamFormat::Sse {
+        if let Some(last_event_id) = headers.get(LAST_EVENT_ID) {
+            let change_id = last_event_id
+                .to_str()
+                .ok()
+                .and_then(|id| id.trim().parse().ok())
+                .ok_or(MatcherUpsertError::InvalidLastEventId)?;
+            params.from = Some(ChangeId(change_id));
+        }
+    }
+    Ok((format, params))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn api_v1_sub_by_id(
     Extension(agent): Extension<Agent>,
+    Extension(scope): Extension<ApiScope>,
     Extension(bcast_cache): Extension<SharedMatcherBroadcastCache>,
     Extension(tripwire): Extension<Tripwire>,
+    Extension(bookie): Extension<Bookie>,
     axum::extract::Path(id): axum::extract::Path<Uuid>,
+    headers: HeaderMap,
     axum::extract::Query(params): axum::extract::Query<SubParams>,
+    axum::extract::Query(consistency): axum::extract::Query<ConsistencyParams>,
+) -> impl IntoResponse {
+    let (format, params) =
+        match sub_stream_params(&headers, params, agent.config().api.max_coalesce_ms) {
+            Ok(res) => res,
+            Err(e) => return hyper::Response::<hyper::Body>::from(e),
+        };
+
+    if let Err(e) = wait_for_consistency(&bookie, consistency).await {
+        return hyper::Response::<hyper::Body>::from(MatcherUpsertError::from(e));
+    }
+
+    sub_by_id(
+        agent.subs_manager(),
+        &scope,
+        id,
+        params,
+        format,
+        &bcast_cache,
+        tripwire,
+    )
+    .await
+}
+
+/// `DELETE /v1/subscriptions/:id`, cancels the subscription for every
+/// stream attached to it, not only the caller's
+pub async fn api_v1_sub_cancel(
+    Extension(agent): Extension<Agent>,
+    Extension(scope): Extension<ApiScope>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
 ) -> impl IntoResponse {
-    sub_by_id(agent.subs_manager(), id, params, &bcast_cache, tripwire).await
+    let subs = agent.subs_manager();
+
+    let error = |status: StatusCode, error: CompactString| {
+        hyper::Response::builder()
+            .status(status)
+            .body(
+                serde_json::to_vec(&QueryEvent::Error(error))
+                    .expect("could not serialize queries stream error")
+                    .into(),
+            )
+            .expect("could not build error response")
+    };
+
+    let matcher = match subs.get(&id) {
+        Some(matcher) => matcher,
+        None => {
+            return error(
+                StatusCode::NOT_FOUND,
+                format_compact!("could not find subscription with id {id}"),
+            )
+        }
+    };
+
+    if let Some(table) = matcher
+        .cached_stmts()
+        .keys()
+        .find(|table| !scope.allows_table(table))
+    {
+        return error(
+            StatusCode::FORBIDDEN,
+            MatcherUpsertError::TableNotAllowed(table.clone()).to_compact_string(),
+        );
+    }
+
+    if cancel_sub(subs, id).await.is_none() {
+        return error(
+            StatusCode::NOT_FOUND,
+            format_compact!("could not find subscription with id {id}"),
+        );
+    }
+
+    hyper::Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(hyper::Body::empty())
+        .expect("could not build cancel response")
+}
+
+/// Cancels a subscription: every stream attached to it gets a final error
+/// event and ends, and the matcher deletes the subscription's database when
+/// it stops.
+pub async fn cancel_sub(subs: &SubsManager, id: Uuid) -> Option<MatcherHandle> {
+    let handle = subs.remove(&id)?;
+    info!(sub_id = %id, "Cancelling subscription");
+    handle.cleanup().await;
+    Some(handle)
 }
 
 async fn sub_by_id(
     subs: &SubsManager,
+    scope: &ApiScope,
     id: Uuid,
     params: SubParams,
+    format: EventStreamFormat,
     bcast_cache: &SharedMatcherBroadcastCache,
     tripwire: Tripwire,
 ) -> hyper::Response<hyper::Body> {
@@ -94,6 +291,14 @@ async fn sub_by_id(
         }
     };
 
+    if let Some(table) = matcher
+        .cached_stmts()
+        .keys()
+        .find(|table| !scope.allows_table(table))
+    {
+        return MatcherUpsertError::TableNotAllowed(table.clone()).into();
+    }
+
     let (evt_tx, evt_rx) = mpsc::channel(512);
 
     let query_hash = matcher.hash().to_owned();
@@ -101,10 +306,17 @@ async fn sub_by_id(
 
     let (tx, body) = hyper::Body::channel();
 
-    tokio::spawn(forward_bytes_to_body_sender(id, evt_rx, tx, tripwire));
+    tokio::spawn(forward_bytes_to_body_sender(
+        id,
+        evt_rx,
+        tx,
+        format,
+        params.coalesce_window().is_some(),
+        tripwire,
+    ));
 
-    hyper::Response::builder()
-        .status(StatusCode::OK)
+    format
+        .response_builder()
         .header("corro-query-id", id.to_string())
         .header("corro-query-hash", query_hash)
         .body(body)
@@ -128,14 +340,18 @@ fn make_query_event_bytes(
     Ok((buf.split().freeze(), query_evt.meta()))
 }
 
-const MAX_UNSUB_TIME: Duration = Duration::from_secs(120);
+/// An event broadcast to the streams of a subscription, serialized once for
+/// all of them and typed for those coalescing its changes
+pub type SubEvent = (Bytes, QueryEventMeta, Arc<QueryEvent>);
+
+pub(crate) const MAX_UNSUB_TIME: Duration = Duration::from_secs(120);
 // this should be a fraction of the MAX_UNSUB_TIME
 const RECEIVERS_CHECK_INTERVAL: Duration = Duration::from_secs(30);
 
 pub async fn process_sub_channel(
     subs: SubsManager,
     id: Uuid,
-    tx: broadcast::Sender<(Bytes, QueryEventMeta)>,
+    tx: broadcast::Sender<SubEvent>,
     mut evt_rx: mpsc::Receiver<QueryEvent>,
 ) {
     let mut buf = BytesMut::new();
@@ -187,12 +403,15 @@ pub async fn process_sub_channel(
             }
         };
 
+        let query_evt = Arc::new(query_evt);
         let is_still_active = match make_query_event_bytes(&mut buf, &query_evt) {
-            Ok(b) => tx.send(b).is_ok(),
+            Ok((event_buf, meta)) => tx.send((event_buf, meta, query_evt.clone())).is_ok(),
             Err(e) => {
+                let error = QueryEvent::Error(e.to_compact_string());
                 _ = tx.send((
                     error_to_query_event_bytes(&mut buf, e),
                     QueryEventMeta::Error,
+                    Arc::new(error),
                 ));
                 break;
             }
@@ -217,7 +436,8 @@ pub async fn process_sub_channel(
             h
         }
         None => {
-            warn!(sub_id = %id, "subscription handle was already gone. odd!");
+            // removed by `cancel_sub`, which also cleaned it up
+            info!(sub_id = %id, "subscription handle was already removed");
             return;
         }
     };
@@ -226,7 +446,10 @@ pub async fn process_sub_channel(
     handle.cleanup().await;
 }
 
-fn expanded_statement(conn: &Connection, stmt: &Statement) -> rusqlite::Result<Option<String>> {
+pub(crate) fn expanded_statement(
+    conn: &Connection,
+    stmt: &Statement,
+) -> rusqlite::Result<Option<String>> {
     Ok(match stmt {
         Statement::Simple(query)
         | Statement::Verbose {
@@ -265,8 +488,13 @@ fn expanded_statement(conn: &Connection, stmt: &Statement) -> rusqlite::Result<O
     })
 }
 
-async fn expand_sql(agent: &Agent, stmt: &Statement) -> Result<String, MatcherUpsertError> {
+async fn expand_sql(
+    agent: &Agent,
+    scope: &ApiScope,
+    stmt: &Statement,
+) -> Result<String, MatcherUpsertError> {
     let conn = agent.pool().read().await?;
+    let _authz_guard = scope.authorize(&conn, &agent.schema().read());
     expanded_statement(&conn, stmt)?.ok_or(MatcherUpsertError::CouldNotExpand)
 }
 
@@ -286,6 +514,16 @@ pub enum MatcherUpsertError {
     SubFromWithoutMatcher,
     #[error("found a subscription, but missing broadcaster")]
     MissingBroadcaster,
+    #[error("not authorized to access table '{0}'")]
+    TableNotAllowed(String),
+    #[error("Last-Event-ID header is not a valid change id")]
+    InvalidLastEventId,
+    #[error("invalid updates request: {0}")]
+    InvalidUpdatesRequest(#[from] serde_json::Error),
+    #[error(transparent)]
+    ConsistencyTimeout(#[from] ConsistencyTimeout),
+    #[error("coalesce_ms is {ms}, it can't be more than {max_ms}")]
+    CoalesceWindowTooLong { ms: u64, max_ms: u64 },
 }
 
 impl MatcherUpsertError {
@@ -294,10 +532,19 @@ impl MatcherUpsertError {
             MatcherUpsertError::Pool(_)
             | MatcherUpsertError::CouldNotExpand
             | MatcherUpsertError::MissingBroadcaster => StatusCode::INTERNAL_SERVER_ERROR,
+            MatcherUpsertError::Sqlite(e) if is_authorization_denied(e) => StatusCode::FORBIDDEN,
+            MatcherUpsertError::TableNotAllowed(_) => StatusCode::FORBIDDEN,
+            MatcherUpsertError::Matcher(MatcherError::UpdatesChangeUnavailable { .. }) => {
+                StatusCode::GONE
+            }
             MatcherUpsertError::Sqlite(_)
             | MatcherUpsertError::NormalizeStatement(_)
             | MatcherUpsertError::Matcher(_)
-            | MatcherUpsertError::SubFromWithoutMatcher => StatusCode::BAD_REQUEST,
+            | MatcherUpsertError::SubFromWithoutMatcher
+            | MatcherUpsertError::InvalidLastEventId
+            | MatcherUpsertError::InvalidUpdatesRequest(_)
+            | MatcherUpsertError::CoalesceWindowTooLong { .. } => StatusCode::BAD_REQUEST,
+            MatcherUpsertError::ConsistencyTimeout(_) => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 }
@@ -314,7 +561,7 @@ impl From<MatcherUpsertError> for hyper::Response<hyper::Body> {
             .expect("could not build error response")
     }
 }
-pub type MatcherBroadcastCache = HashMap<Uuid, broadcast::Sender<(Bytes, QueryEventMeta)>>;
+pub type MatcherBroadcastCache = HashMap<Uuid, broadcast::Sender<SubEvent>>;
 pub type SharedMatcherBroadcastCache = Arc<TokioRwLock<MatcherBroadcastCache>>;
 
 #[derive(Debug, thiserror::Error)]
@@ -420,7 +667,7 @@ async fn catch_up_sub_from(
 pub async fn catch_up_sub(
     matcher: MatcherHandle,
     params: SubParams,
-    mut sub_rx: broadcast::Receiver<(Bytes, QueryEventMeta)>,
+    mut sub_rx: broadcast::Receiver<SubEvent>,
     evt_tx: mpsc::Sender<(Bytes, QueryEventMeta)>,
 ) {
     debug!("catching up sub {} params: {:?}", matcher.id(), params);
@@ -438,7 +685,7 @@ pub async fn catch_up_sub(
         let cancel = cancel.clone();
         async move {
             loop {
-                let (buf, meta) = tokio::select! {
+                let (buf, meta, _) = tokio::select! {
                     _ = cancel.cancelled() => {
                         break;
                     },
@@ -616,7 +863,7 @@ pub async fn catch_up_sub(
         }
     };
 
-    forward_sub_to_sender(matcher, sub_rx, evt_tx, params.skip_rows).await
+    forward_sub_to_sender(matcher, sub_rx, evt_tx, params).await
 }
 
 pub async fn upsert_sub(
@@ -634,12 +881,7 @@ pub async fn upsert_sub(
 
         let (sub_tx, sub_rx) = broadcast::channel(10240);
 
-        tokio::spawn(forward_sub_to_sender(
-            handle.clone(),
-            sub_rx,
-            tx,
-            params.skip_rows,
-        ));
+        tokio::spawn(forward_sub_to_sender(handle.clone(), sub_rx, tx, params));
 
         bcast_write.insert(handle.id(), sub_tx.clone());
 
@@ -665,17 +907,18 @@ pub async fn upsert_sub(
     }
 }
 
-pub async fn api_v1_subs(
-    Extension(agent): Extension<Agent>,
-    Extension(bcast_cache): Extension<SharedMatcherBroadcastCache>,
-    Extension(tripwire): Extension<Tripwire>,
-    axum::extract::Query(params): axum::extract::Query<SubParams>,
-    axum::extract::Json(stmt): axum::extract::Json<Statement>,
-) -> impl IntoResponse {
-    let stmt = match expand_sql(&agent, &stmt).await {
-        Ok(stmt) => stmt,
-        Err(e) => return hyper::Response::<hyper::Body>::from(e),
-    };
+/// Get or create the subscription for a statement and start sending its
+/// events to `tx`, returning the subscription's id and query hash
+pub async fn subscribe(
+    agent: &Agent,
+    scope: &ApiScope,
+    bcast_cache: &SharedMatcherBroadcastCache,
+    tripwire: Tripwire,
+    params: SubParams,
+    stmt: &Statement,
+    tx: mpsc::Sender<(Bytes, QueryEventMeta)>,
+) -> Result<(Uuid, String), MatcherUpsertError> {
+    let stmt = expand_sql(agent, scope, stmt).await?;
 
     info!("Received subscription request for query: {stmt}");
 
@@ -683,46 +926,72 @@ pub async fn api_v1_subs(
 
     let subs = agent.subs_manager();
 
-    let upsert_res = subs.get_or_insert(
+    let (handle, maybe_created) = subs.get_or_insert(
         &stmt,
         &agent.config().db.subscriptions_path(),
         &agent.schema().read(),
         agent.pool(),
-        tripwire.clone(),
-    );
+        tripwire,
+    )?;
 
-    let (handle, maybe_created) = match upsert_res {
-        Ok(res) => res,
-        Err(e) => return hyper::Response::<hyper::Body>::from(MatcherUpsertError::from(e)),
-    };
+    let query_hash = handle.hash().to_owned();
+    let matcher_id = upsert_sub(handle, maybe_created, subs, &mut bcast_write, params, tx).await?;
 
-    let (tx, body) = hyper::Body::channel();
-    let (forward_tx, forward_rx) = mpsc::channel(10240);
+    Ok((matcher_id, query_hash))
+}
 
-    tokio::spawn(forward_bytes_to_body_sender(
-        handle.id(),
-        forward_rx,
-        tx,
-        tripwire,
-    ));
+#[allow(clippy::too_many_arguments)]
+pub async fn api_v1_subs(
+    Extension(agent): Extension<Agent>,
+    Extension(scope): Extension<ApiScope>,
+    Extension(bcast_cache): Extension<SharedMatcherBroadcastCache>,
+    Extension(tripwire): Extension<Tripwire>,
+    Extension(bookie): Extension<Bookie>,
+    headers: HeaderMap,
+    axum::extract::Query(params): axum::extract::Query<SubParams>,
+    axum::extract::Query(consistency): axum::extract::Query<ConsistencyParams>,
+    axum::extract::Json(stmt): axum::extract::Json<Statement>,
+) -> impl IntoResponse {
+    let (format, params) =
+        match sub_stream_params(&headers, params, agent.config().api.max_coalesce_ms) {
+            Ok(res) => res,
+            Err(e) => return hyper::Response::<hyper::Body>::from(e),
+        };
 
-    let query_hash = handle.hash().to_owned();
-    let matcher_id = match upsert_sub(
-        handle,
-        maybe_created,
-        subs,
-        &mut bcast_write,
+    if let Err(e) = wait_for_consistency(&bookie, consistency).await {
+        return hyper::Response::<hyper::Body>::from(MatcherUpsertError::from(e));
+    }
+
+    let (forward_tx, forward_rx) = mpsc::channel(10240);
+
+    let (matcher_id, query_hash) = match subscribe(
+        &agent,
+        &scope,
+        &bcast_cache,
+        tripwire.clone(),
         params,
+        &stmt,
         forward_tx,
     )
     .await
     {
-        Ok(id) => id,
+        Ok(res) => res,
         Err(e) => return hyper::Response::<hyper::Body>::from(e),
     };
 
-    hyper::Response::builder()
-        .status(StatusCode::OK)
+    let (tx, body) = hyper::Body::channel();
+
+    tokio::spawn(forward_bytes_to_body_sender(
+        matcher_id,
+        forward_rx,
+        tx,
+        format,
+        params.coalesce_window().is_some(),
+        tripwire,
+    ));
+
+    format
+        .response_builder()
         .header("corro-query-id", matcher_id.to_string())
         .header("corro-query-hash", query_hash)
         .body(body)
@@ -731,36 +1000,155 @@ pub async fn api_v1_subs(
 
 const MAX_EVENTS_BUFFER_SIZE: usize = 1024;
 
+/// Changes held back by a subscriber coalescing them, keyed by row
+#[derive(Debug, Default)]
+struct CoalescedChanges {
+    rows: HashMap<RowId, CoalescedChange>,
+}
+
+#[derive(Debug)]
+struct CoalescedChange {
+    // whether the row existed before depends on the first change
+    first_type: ChangeType,
+    last_type: ChangeType,
+    cells: Vec<SqliteValue>,
+    change_id: ChangeId,
+}
+
+impl CoalescedChanges {
+    fn push(
+        &mut self,
+        change_type: ChangeType,
+        rowid: RowId,
+        cells: Vec<SqliteValue>,
+        change_id: ChangeId,
+    ) {
+        match self.rows.entry(rowid) {
+            Entry::Occupied(mut entry) => {
+                let change = entry.get_mut();
+                change.last_type = change_type;
+                change.cells = cells;
+                change.change_id = change_id;
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(CoalescedChange {
+                    first_type: change_type,
+                    last_type: change_type,
+                    cells,
+                    change_id,
+                });
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Takes the net change to each row, in the order of their last change
+    fn drain(&mut self) -> Vec<QueryEvent> {
+        let mut changes = self
+            .rows
+            .drain()
+            .filter_map(|(rowid, change)| {
+                let change_type = match (change.first_type, change.last_type) {
+                    // inserted then deleted, as if nothing happened
+                    (ChangeType::Insert, ChangeType::Delete) => return None,
+                    (ChangeType::Insert, _) => ChangeType::Insert,
+                    (_, ChangeType::Delete) => ChangeType::Delete,
+                    _ => ChangeType::Update,
+                };
+                Some((rowid, change_type, change.cells, change.change_id))
+            })
+            .collect::<Vec<_>>();
+
+        changes.sort_by_key(|(_, _, _, change_id)| *change_id);
+
+        changes
+            .into_iter()
+            .map(|(rowid, change_type, cells, change_id)| {
+                QueryEvent::Change(change_type, rowid, cells, change_id)
+            })
+            .collect()
+    }
+}
+
+async fn send_coalesced_changes(
+    buf: &mut BytesMut,
+    coalesced: &mut CoalescedChanges,
+    tx: &mpsc::Sender<(Bytes, QueryEventMeta)>,
+) -> Result<(), CatchUpError> {
+    for event in coalesced.drain() {
+        tx.send(make_query_event_bytes(buf, &event)?).await?;
+    }
+    Ok(())
+}
+
 async fn forward_sub_to_sender(
     handle: MatcherHandle,
-    mut sub_rx: broadcast::Receiver<(Bytes, QueryEventMeta)>,
+    mut sub_rx: broadcast::Receiver<SubEvent>,
     tx: mpsc::Sender<(Bytes, QueryEventMeta)>,
-    skip_rows: bool,
+    params: SubParams,
 ) {
     info!(sub_id = %handle.id(), "forwarding subscription events to a sender");
 
+    let mut buf = BytesMut::new();
+
+    let coalesce_window = params.coalesce_window();
+    let mut coalesced = CoalescedChanges::default();
+    let mut flush_deadline: Option<Pin<Box<tokio::time::Sleep>>> = None;
+
     loop {
-        let (event_buf, meta) = tokio::select! {
+        let flush_check = async {
+            if let Some(sleep) = flush_deadline.as_mut() {
+                sleep.await
+            } else {
+                futures::future::pending().await
+            }
+        };
+
+        let (event_buf, meta, event) = tokio::select! {
             res = sub_rx.recv() => {
                 match res {
-                    Ok((event_buf, meta)) => (event_buf, meta),
+                    Ok(res) => res,
                     Err(RecvError::Lagged(skipped)) => {
                         warn!(sub_id = %handle.id(), "subscription skipped {} events, aborting", skipped);
                         return;
                     },
                     Err(RecvError::Closed) => {
                         info!(sub_id = %handle.id(), "events subcription ran out");
+                        if let Err(e) = send_coalesced_changes(&mut buf, &mut coalesced, &tx).await {
+                            warn!(sub_id = %handle.id(), "could not send coalesced subscription changes to channel: {e}");
+                        }
                         return;
                     },
                 }
             },
+            _ = flush_check => {
+                flush_deadline = None;
+                if let Err(e) = send_coalesced_changes(&mut buf, &mut coalesced, &tx).await {
+                    warn!(sub_id = %handle.id(), "could not send coalesced subscription changes to channel: {e}");
+                    return;
+                }
+                continue;
+            },
             _ = handle.cancelled() => {
                 info!(sub_id = %handle.id(), "subscription cancelled, aborting forwarding bytes to subscriber");
+                _ = tx
+                    .send(error_to_query_event_bytes_with_meta(
+                        &mut BytesMut::new(),
+                        "subscription was cancelled",
+                    ))
+                    .await;
                 return;
             },
         };
 
-        if skip_rows
+        if params.skip_rows
             && matches!(
                 meta,
                 QueryEventMeta::Columns | QueryEventMeta::Row(_) | QueryEventMeta::EndOfQuery(_)
@@ -768,6 +1156,37 @@ async fn forward_sub_to_sender(
         {
             continue;
         }
+
+        if let Some(window) = coalesce_window {
+            if let QueryEvent::Change(change_type, rowid, cells, change_id) = event.as_ref() {
+                coalesced.push(*change_type, *rowid, cells.clone(), *change_id);
+                if flush_deadline.is_none() {
+                    flush_deadline = Some(Box::pin(tokio::time::sleep(window)));
+                }
+
+                if params
+                    .coalesce_max
+                    .map_or(false, |max| coalesced.len() >= max)
+                {
+                    flush_deadline = None;
+                    if let Err(e) = send_coalesced_changes(&mut buf, &mut coalesced, &tx).await {
+                        warn!(sub_id = %handle.id(), "could not send coalesced subscription changes to channel: {e}");
+                        return;
+                    }
+                }
+                continue;
+            }
+        }
+
+        // anything else is sent after the changes preceding it
+        if !coalesced.is_empty() {
+            flush_deadline = None;
+            if let Err(e) = send_coalesced_changes(&mut buf, &mut coalesced, &tx).await {
+                warn!(sub_id = %handle.id(), "could not send coalesced subscription changes to channel: {e}");
+                return;
+            }
+        }
+
         if let Err(e) = tx.send((event_buf, meta)).await {
             warn!(sub_id = %handle.id(), "could not send subscription event to channel: {e}");
             return;
@@ -775,17 +1194,21 @@ async fn forward_sub_to_sender(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_sub_event(
     sub_id: Uuid,
     buf: &mut BytesMut,
     event_buf: Bytes,
     meta: QueryEventMeta,
+    format: EventStreamFormat,
+    coalesced: bool,
     tx: &mut hyper::body::Sender,
     last_change_id: &mut ChangeId,
 ) -> hyper::Result<()> {
     match meta {
         QueryEventMeta::EndOfQuery(Some(change_id)) | QueryEventMeta::Change(change_id) => {
-            if !last_change_id.is_zero() && change_id > *last_change_id + 1 {
+            // coalescing skips the change ids of superseded changes
+            if !coalesced && !last_change_id.is_zero() && change_id > *last_change_id + 1 {
                 warn!(%sub_id, "non-contiguous change id (> + 1) received: {change_id:?}, last seen: {last_change_id:?}");
             } else if !last_change_id.is_zero() && change_id == *last_change_id {
                 warn!(%sub_id, "duplicate change id received: {change_id:?}, last seen: {last_change_id:?}");
@@ -798,7 +1221,10 @@ async fn handle_sub_event(
             // do nothing
         }
     }
-    buf.extend_from_slice(&event_buf);
+    match format {
+        EventStreamFormat::Ndjson => buf.extend_from_slice(&event_buf),
+        EventStreamFormat::Sse => write_sse_event(buf, &event_buf, meta),
+    }
     let to_send = if buf.len() >= 64 * 1024 {
         buf.split().freeze()
     } else {
@@ -808,10 +1234,22 @@ async fn handle_sub_event(
     tx.send_data(to_send).await
 }
 
+/// Frames an NDJSON event as SSE, using its change id (if any) as the event id
+fn write_sse_event(buf: &mut BytesMut, event_buf: &[u8], meta: QueryEventMeta) {
+    if let QueryEventMeta::EndOfQuery(Some(change_id)) | QueryEventMeta::Change(change_id) = meta {
+        buf.extend_from_slice(format!("id: {change_id}\n").as_bytes());
+    }
+    buf.extend_from_slice(b"data: ");
+    buf.extend_from_slice(event_buf.strip_suffix(b"\n").unwrap_or(event_buf));
+    buf.extend_from_slice(b"\n\n");
+}
+
 async fn forward_bytes_to_body_sender(
     sub_id: Uuid,
     mut rx: mpsc::Receiver<(Bytes, QueryEventMeta)>,
     mut tx: hyper::body::Sender,
+    format: EventStreamFormat,
+    coalesced: bool,
     mut tripwire: Tripwire,
 ) {
     let mut buf = BytesMut::new();
@@ -827,7 +1265,7 @@ async fn forward_bytes_to_body_sender(
             res = rx.recv() => {
                 match res {
                     Some((event_buf, meta)) => {
-                        if let Err(e) = handle_sub_event(sub_id, &mut buf, event_buf, meta, &mut tx, &mut last_change_id).await {
+                        if let Err(e) = handle_sub_event(sub_id, &mut buf, event_buf, meta, format, coalesced, &mut tx, &mut last_change_id).await {
                             warn!(%sub_id, "could not forward subscription query event to receiver: {e}");
                             return;
                         }
@@ -862,6 +1300,8 @@ async fn forward_bytes_to_body_sender(
             &mut buf,
             event_buf,
             meta,
+            format,
+            coalesced,
             &mut tx,
             &mut last_change_id,
         )
@@ -882,12 +1322,12 @@ async fn forward_bytes_to_body_sender(
 #[cfg(test)]
 mod tests {
     use corro_types::actor::ActorId;
-    use corro_types::api::NotifyEvent;
+    use corro_types::api::{ColumnChange, NotifyEvent};
     use corro_types::api::{ColumnName, TableName};
     use corro_types::base::{CrsqlDbVersion, CrsqlSeq};
     use corro_types::broadcast::{ChangeSource, ChangeV1, Changeset};
     use corro_types::change::Change;
-    use corro_types::pubsub::pack_columns;
+    use corro_types::pubsub::{pack_columns, Matcher};
     use corro_types::{
         api::{ChangeId, RowId},
         config::Config,
@@ -904,8 +1344,9 @@ mod tests {
 
     use super::*;
     use crate::agent::process_multiple_changes;
-    use crate::api::public::update::{api_v1_updates, SharedUpdateBroadcastCache};
-    use crate::api::public::TimeoutParams;
+    use crate::api::public::test_util::{launch_agent_with_schema, transact};
+    use crate::api::public::update::{api_v1_updates, SharedUpdateBroadcastCache, UpdatesParams};
+    use crate::api::public::{ReplicationParams, TimeoutParams};
     use crate::{
         agent::setup,
         api::public::{api_v1_db_schema, api_v1_transactions},
@@ -934,6 +1375,7 @@ mod tests {
 
         let (status_code, _body) = api_v1_db_schema(
             Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
             axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
         )
         .await;
@@ -942,17 +1384,25 @@ mod tests {
 
         let (status_code, body) = api_v1_transactions(
             Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            None,
+            None,
+            HeaderMap::new(),
             axum::extract::Query(TimeoutParams { timeout: None }),
-            axum::Json(vec![
-                Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id".into(), "service-name".into()],
-                ),
-                Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id-2".into(), "service-name-2".into()],
-                ),
-            ]),
+            axum::extract::Query(ReplicationParams::default()),
+            axum::Json(
+                vec![
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id".into(), "service-name".into()],
+                    ),
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-2".into(), "service-name-2".into()],
+                    ),
+                ]
+                .into(),
+            ),
         )
         .await;
 
@@ -966,9 +1416,13 @@ mod tests {
         {
             let mut res = api_v1_subs(
                 Extension(agent.clone()),
+                Extension(ApiScope::unrestricted()),
                 Extension(bcast_cache.clone()),
                 Extension(tripwire.clone()),
+                Extension(Bookie::new(Default::default())),
+                HeaderMap::new(),
                 axum::extract::Query(SubParams::default()),
+                axum::extract::Query(ConsistencyParams::default()),
                 axum::Json(Statement::Simple("select * from tests".into())),
             )
             .await
@@ -988,9 +1442,12 @@ mod tests {
 
             let mut notify_res = api_v1_updates(
                 Extension(agent.clone()),
+                Extension(ApiScope::unrestricted()),
                 Extension(update_bcast_cache.clone()),
                 Extension(tripwire.clone()),
                 axum::extract::Path("tests".to_string()),
+                axum::extract::Query(UpdatesParams::default()),
+                Bytes::new(),
             )
             .await
             .into_response();
@@ -1004,11 +1461,19 @@ mod tests {
 
             let (status_code, _) = api_v1_transactions(
                 Extension(agent.clone()),
+                Extension(ApiScope::unrestricted()),
+                None,
+                None,
+                HeaderMap::new(),
                 axum::extract::Query(TimeoutParams { timeout: None }),
-                axum::Json(vec![Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id-3".into(), "service-name-3".into()],
-                )]),
+                axum::extract::Query(ReplicationParams::default()),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-3".into(), "service-name-3".into()],
+                    )]
+                    .into(),
+                ),
             )
             .await;
 
@@ -1061,11 +1526,19 @@ mod tests {
 
             let (status_code, _) = api_v1_transactions(
                 Extension(agent.clone()),
+                Extension(ApiScope::unrestricted()),
+                None,
+                None,
+                HeaderMap::new(),
                 axum::extract::Query(TimeoutParams { timeout: None }),
-                axum::Json(vec![Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id-4".into(), "service-name-4".into()],
-                )]),
+                axum::extract::Query(ReplicationParams::default()),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-4".into(), "service-name-4".into()],
+                    )]
+                    .into(),
+                ),
             )
             .await;
 
@@ -1083,22 +1556,26 @@ mod tests {
 
             assert_eq!(
                 notify_rows.recv::<NotifyEvent>().await.unwrap().unwrap(),
-                NotifyEvent::Notify(ChangeType::Update, vec!["service-id-3".into()],)
+                NotifyEvent::Notify(ChangeType::Update, vec!["service-id-3".into()])
             );
 
             assert_eq!(
                 notify_rows.recv::<NotifyEvent>().await.unwrap().unwrap(),
-                NotifyEvent::Notify(ChangeType::Update, vec!["service-id-4".into()],)
+                NotifyEvent::Notify(ChangeType::Update, vec!["service-id-4".into()])
             );
 
             let mut res = api_v1_subs(
                 Extension(agent.clone()),
+                Extension(ApiScope::unrestricted()),
                 Extension(bcast_cache.clone()),
                 Extension(tripwire.clone()),
+                Extension(Bookie::new(Default::default())),
+                HeaderMap::new(),
                 axum::extract::Query(SubParams {
                     from: Some(1.into()),
                     ..Default::default()
                 }),
+                axum::extract::Query(ConsistencyParams::default()),
                 axum::Json(Statement::Simple("select * from tests".into())),
             )
             .await
@@ -1131,9 +1608,12 @@ mod tests {
             // new subscriber for updates
             let mut notify_res2 = api_v1_updates(
                 Extension(agent.clone()),
+                Extension(ApiScope::unrestricted()),
                 Extension(update_bcast_cache.clone()),
                 Extension(tripwire.clone()),
                 axum::extract::Path("tests".to_string()),
+                axum::extract::Query(UpdatesParams::default()),
+                Bytes::new(),
             )
             .await
             .into_response();
@@ -1154,11 +1634,19 @@ mod tests {
 
             let (status_code, _) = api_v1_transactions(
                 Extension(agent.clone()),
+                Extension(ApiScope::unrestricted()),
+                None,
+                None,
+                HeaderMap::new(),
                 axum::extract::Query(TimeoutParams { timeout: None }),
-                axum::Json(vec![Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id-5".into(), "service-name-5".into()],
-                )]),
+                axum::extract::Query(ReplicationParams::default()),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-5".into(), "service-name-5".into()],
+                    )]
+                    .into(),
+                ),
             )
             .await;
 
@@ -1194,9 +1682,13 @@ mod tests {
 
             let mut res = api_v1_subs(
                 Extension(agent.clone()),
+                Extension(ApiScope::unrestricted()),
                 Extension(bcast_cache.clone()),
                 Extension(tripwire.clone()),
+                Extension(Bookie::new(Default::default())),
+                HeaderMap::new(),
                 axum::extract::Query(SubParams::default()),
+                axum::extract::Query(ConsistencyParams::default()),
                 axum::Json(Statement::Simple("select * from tests".into())),
             )
             .await
@@ -1260,11 +1752,19 @@ mod tests {
 
             let (status_code, _) = api_v1_transactions(
                 Extension(agent.clone()),
+                Extension(ApiScope::unrestricted()),
+                None,
+                None,
+                HeaderMap::new(),
                 axum::extract::Query(TimeoutParams { timeout: None }),
-                axum::Json(vec![Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id-6".into(), "service-name-6".into()],
-                )]),
+                axum::extract::Query(ReplicationParams::default()),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-6".into(), "service-name-6".into()],
+                    )]
+                    .into(),
+                ),
             )
             .await;
 
@@ -1272,11 +1772,19 @@ mod tests {
 
             let (status_code, _) = api_v1_transactions(
                 Extension(agent.clone()),
+                Extension(ApiScope::unrestricted()),
+                None,
+                None,
+                HeaderMap::new(),
                 axum::extract::Query(TimeoutParams { timeout: None }),
-                axum::Json(vec![Statement::WithParams(
-                    "delete from  tests where id = ?".into(),
-                    vec!["service-id-6".into()],
-                )]),
+                axum::extract::Query(ReplicationParams::default()),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "delete from  tests where id = ?".into(),
+                        vec!["service-id-6".into()],
+                    )]
+                    .into(),
+                ),
             )
             .await;
 
@@ -1310,12 +1818,16 @@ mod tests {
 
         let mut res = api_v1_subs(
             Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
             Extension(bcast_cache.clone()),
             Extension(tripwire.clone()),
+            Extension(Bookie::new(Default::default())),
+            HeaderMap::new(),
             axum::extract::Query(SubParams {
                 from: Some(1.into()),
                 ..Default::default()
             }),
+            axum::extract::Query(ConsistencyParams::default()),
             axum::Json(Statement::Simple("select * from tests".into())),
         )
         .await
@@ -1359,12 +1871,16 @@ mod tests {
 
         let mut res = api_v1_subs(
             Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
             Extension(bcast_cache.clone()),
             Extension(tripwire.clone()),
+            Extension(Bookie::new(Default::default())),
+            HeaderMap::new(),
             axum::extract::Query(SubParams {
                 skip_rows: true,
                 ..Default::default()
             }),
+            axum::extract::Query(ConsistencyParams::default()),
             axum::Json(Statement::Simple("select * from tests".into())),
         )
         .await
@@ -1386,11 +1902,19 @@ mod tests {
 
         let (status_code, _) = api_v1_transactions(
             Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            None,
+            None,
+            HeaderMap::new(),
             axum::extract::Query(TimeoutParams { timeout: None }),
-            axum::Json(vec![Statement::WithParams(
-                "insert into tests (id, text) values (?,?)".into(),
-                vec!["service-id-6".into(), "service-name-6".into()],
-            )]),
+            axum::extract::Query(ReplicationParams::default()),
+            axum::Json(
+                vec![Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec!["service-id-6".into(), "service-name-6".into()],
+                )]
+                .into(),
+            ),
         )
         .await;
 
@@ -1410,12 +1934,17 @@ mod tests {
 
         let mut res = api_v1_subs(
             Extension(agent.clone()),
+            Extension(ApiScope::unrestricted()),
             Extension(bcast_cache.clone()),
             Extension(tripwire.clone()),
+            Extension(Bookie::new(Default::default())),
+            HeaderMap::new(),
             axum::extract::Query(SubParams {
                 skip_rows: true,
                 from: Some(ChangeId(3)),
+                ..Default::default()
             }),
+            axum::extract::Query(ConsistencyParams::default()),
             axum::Json(Statement::Simple("select * from tests".into())),
         )
         .await
@@ -1469,6 +1998,7 @@ mod tests {
 
         let (status_code, _body) = api_v1_db_schema(
             Extension(ta1.agent.clone()),
+            Extension(ApiScope::unrestricted()),
             axum::Json(vec![schema.into()]),
         )
         .await;
@@ -1523,9 +2053,13 @@ mod tests {
         let update_bcast_cache: SharedUpdateBroadcastCache = Default::default();
         let mut res = api_v1_subs(
             Extension(ta1.agent.clone()),
+            Extension(ApiScope::unrestricted()),
             Extension(bcast_cache.clone()),
             Extension(tripwire.clone()),
+            Extension(ta1.bookie.clone()),
+            HeaderMap::new(),
             axum::extract::Query(SubParams::default()),
+            axum::extract::Query(ConsistencyParams::default()),
             axum::Json(Statement::Simple("select * from buftests".into())),
         )
         .await
@@ -1541,9 +2075,12 @@ mod tests {
         // only notifications
         let mut notify_res = api_v1_updates(
             Extension(ta1.agent.clone()),
+            Extension(ApiScope::unrestricted()),
             Extension(update_bcast_cache.clone()),
             Extension(tripwire.clone()),
             axum::extract::Path("buftests".to_string()),
+            axum::extract::Query(UpdatesParams::default()),
+            Bytes::new(),
         )
         .await
         .into_response();
@@ -1683,6 +2220,716 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_sse() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta1 = launch_agent_with_schema(tripwire.clone()).await?;
+
+        let insert = |id: i64| {
+            transact(
+                &ta1.agent,
+                vec![Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec![id.into(), format!("service-name-{id}").into()],
+                )],
+            )
+        };
+
+        assert_eq!(insert(1).await.0, StatusCode::OK);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, "text/event-stream".parse()?);
+
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+        let res = api_v1_subs(
+            Extension(ta1.agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            Extension(ta1.bookie.clone()),
+            headers.clone(),
+            axum::extract::Query(SubParams::default()),
+            axum::extract::Query(ConsistencyParams::default()),
+            axum::Json(Statement::Simple("select * from tests".into())),
+        )
+        .await
+        .into_response();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/event-stream"
+        );
+
+        let sub_id: Uuid = res
+            .headers()
+            .get("corro-query-id")
+            .unwrap()
+            .to_str()?
+            .parse()?;
+
+        let mut events = SseIter {
+            body: res.into_body(),
+            buf: BytesMut::new(),
+        };
+
+        assert_eq!(
+            events.recv().await?,
+            (None, r#"{"columns":["id","text"]}"#.into())
+        );
+        assert_eq!(
+            events.recv().await?,
+            (None, r#"{"row":[1,[1,"service-name-1"]]}"#.into())
+        );
+        let (id, data) = events.recv().await?;
+        assert_eq!(id, Some(0));
+        assert!(data.starts_with(r#"{"eoq":"#));
+
+        assert_eq!(insert(2).await.0, StatusCode::OK);
+        assert_eq!(
+            events.recv().await?,
+            (
+                Some(1),
+                r#"{"change":["insert",2,[2,"service-name-2"],1]}"#.into()
+            )
+        );
+
+        assert_eq!(insert(3).await.0, StatusCode::OK);
+
+        // resuming with `Last-Event-ID` skips what was already received
+        headers.insert(LAST_EVENT_ID, "1".parse()?);
+        let res = api_v1_sub_by_id(
+            Extension(ta1.agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            Extension(ta1.bookie.clone()),
+            axum::extract::Path(sub_id),
+            headers.clone(),
+            axum::extract::Query(SubParams::default()),
+            axum::extract::Query(ConsistencyParams::default()),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut events = SseIter {
+            body: res.into_body(),
+            buf: BytesMut::new(),
+        };
+        assert_eq!(
+            events.recv().await?,
+            (
+                Some(2),
+                r#"{"change":["insert",3,[3,"service-name-3"],2]}"#.into()
+            )
+        );
+
+        headers.insert(LAST_EVENT_ID, "nope".parse()?);
+        let res = api_v1_sub_by_id(
+            Extension(ta1.agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            Extension(ta1.bookie.clone()),
+            axum::extract::Path(sub_id),
+            headers,
+            axum::extract::Query(SubParams::default()),
+            axum::extract::Query(ConsistencyParams::default()),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // waits for the write in the consistency token, like a new subscription
+        let res = api_v1_sub_by_id(
+            Extension(ta1.agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            Extension(ta1.bookie.clone()),
+            axum::extract::Path(sub_id),
+            HeaderMap::new(),
+            axum::extract::Query(SubParams::default()),
+            axum::extract::Query(ConsistencyParams {
+                consistency_token: Some(corro_types::api::ConsistencyToken {
+                    actor_id: ta1.agent.actor_id().0,
+                    version: 1000,
+                }),
+                consistency_timeout: Some(1),
+            }),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_coalesced() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta1 = launch_agent_with_schema(tripwire.clone()).await?;
+
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        // windows longer than the configured maximum are rejected
+        let res = api_v1_subs(
+            Extension(ta1.agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            Extension(ta1.bookie.clone()),
+            HeaderMap::new(),
+            axum::extract::Query(SubParams {
+                coalesce_ms: Some(ta1.agent.config().api.max_coalesce_ms + 1),
+                ..Default::default()
+            }),
+            axum::extract::Query(ConsistencyParams::default()),
+            axum::Json(Statement::Simple("select * from tests".into())),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = api_v1_subs(
+            Extension(ta1.agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            Extension(ta1.bookie.clone()),
+            HeaderMap::new(),
+            axum::extract::Query(SubParams {
+                coalesce_ms: Some(5000),
+                ..Default::default()
+            }),
+            axum::extract::Query(ConsistencyParams::default()),
+            axum::Json(Statement::Simple("select * from tests".into())),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        assert!(matches!(
+            rows.recv::<QueryEvent>().await.unwrap()?,
+            QueryEvent::Columns(_)
+        ));
+        assert!(matches!(
+            rows.recv::<QueryEvent>().await.unwrap()?,
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        // spread out the writes so the subscription matches them separately
+        for stmts in [
+            vec![Statement::Simple(
+                "insert into tests (id, text) values ('service-id', 'one')".into(),
+            )],
+            vec![
+                Statement::Simple("update tests set text = 'two' where id = 'service-id'".into()),
+                Statement::Simple(
+                    "insert into tests (id, text) values ('service-id-2', 'one')".into(),
+                ),
+            ],
+            vec![Statement::Simple(
+                "delete from tests where id = 'service-id-2'".into(),
+            )],
+        ] {
+            let (status_code, _) = transact(&ta1.agent, stmts).await;
+            assert_eq!(status_code, StatusCode::OK);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        // the update folds into the insert, the other row came and went
+        assert_eq!(
+            timeout(Duration::from_secs(10), rows.recv::<QueryEvent>())
+                .await?
+                .unwrap()?,
+            QueryEvent::Change(
+                ChangeType::Insert,
+                RowId(1),
+                vec!["service-id".into(), "two".into()],
+                ChangeId(2),
+            )
+        );
+        assert!(timeout(Duration::from_secs(1), rows.recv::<QueryEvent>())
+            .await
+            .is_err());
+
+        for stmt in [
+            "update tests set text = 'three' where id = 'service-id'",
+            "delete from tests where id = 'service-id'",
+        ] {
+            let (status_code, _) = transact(&ta1.agent, vec![Statement::Simple(stmt.into())]).await;
+            assert_eq!(status_code, StatusCode::OK);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        assert!(matches!(
+            timeout(Duration::from_secs(10), rows.recv::<QueryEvent>())
+                .await?
+                .unwrap()?,
+            QueryEvent::Change(ChangeType::Delete, RowId(1), _, ChangeId(6))
+        ));
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_sub_cancel() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta1 = launch_agent_with_schema(tripwire.clone()).await?;
+
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+        let res = api_v1_subs(
+            Extension(ta1.agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            Extension(ta1.bookie.clone()),
+            HeaderMap::new(),
+            axum::extract::Query(SubParams::default()),
+            axum::extract::Query(ConsistencyParams::default()),
+            axum::Json(Statement::Simple("select * from tests".into())),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let sub_id: Uuid = res
+            .headers()
+            .get("corro-query-id")
+            .unwrap()
+            .to_str()?
+            .parse()?;
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        assert!(matches!(
+            rows.recv::<QueryEvent>().await.unwrap()?,
+            QueryEvent::Columns(_)
+        ));
+        assert!(matches!(
+            rows.recv::<QueryEvent>().await.unwrap()?,
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        let sub_path = Matcher::sub_path(&ta1.agent.config().db.subscriptions_path(), sub_id);
+        assert!(sub_path.exists());
+
+        let cancel = |id: Uuid| {
+            api_v1_sub_cancel(
+                Extension(ta1.agent.clone()),
+                Extension(ApiScope::unrestricted()),
+                axum::extract::Path(id),
+            )
+        };
+
+        assert_eq!(
+            cancel(sub_id).await.into_response().status(),
+            StatusCode::NO_CONTENT
+        );
+
+        assert_eq!(
+            rows.recv::<QueryEvent>().await.unwrap()?,
+            QueryEvent::Error("subscription was cancelled".into())
+        );
+        assert!(rows.recv::<QueryEvent>().await.is_none());
+
+        assert!(ta1.agent.subs_manager().get(&sub_id).is_none());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while sub_path.exists() {
+            assert!(
+                Instant::now() < deadline,
+                "subscription database was not removed"
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        assert_eq!(
+            cancel(sub_id).await.into_response().status(),
+            StatusCode::NOT_FOUND
+        );
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_api_v1_updates_filter() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta1 = launch_agent_with_schema(tripwire.clone()).await?;
+
+        let update_bcast_cache: SharedUpdateBroadcastCache = Default::default();
+        let updates = |body: &'static str| {
+            api_v1_updates(
+                Extension(ta1.agent.clone()),
+                Extension(ApiScope::unrestricted()),
+                Extension(update_bcast_cache.clone()),
+                Extension(tripwire.clone()),
+                axum::extract::Path("tests".to_string()),
+                axum::extract::Query(UpdatesParams::default()),
+                Bytes::from_static(body.as_bytes()),
+            )
+        };
+
+        // only primary key columns can be filtered on
+        let res = updates(r#"{"filter": "text = 'a'"}"#).await.into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = updates(r#"{"filter": "id > 'a'"}"#).await.into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = updates("not json").await.into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = updates(r#"{"filter": "id IN ('a', 'c')"}"#)
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut notify_rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        for id in ["a", "b", "c"] {
+            let (status_code, _) = transact(
+                &ta1.agent,
+                vec![Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec![id.into(), "text".into()],
+                )],
+            )
+            .await;
+            assert_eq!(status_code, StatusCode::OK);
+        }
+
+        let mut notified = vec![];
+        for _ in 0..2 {
+            match timeout(Duration::from_secs(5), notify_rows.recv::<NotifyEvent>())
+                .await?
+                .unwrap()?
+            {
+                NotifyEvent::Notify(ChangeType::Update, pk) => notified.extend(pk),
+                evt => panic!("unexpected event: {evt:?}"),
+            }
+        }
+        notified.sort_by_key(|pk| pk.as_text().map(ToOwned::to_owned));
+        assert_eq!(notified, vec!["a".into(), "c".into()]);
+
+        assert!(
+            timeout(Duration::from_secs(1), notify_rows.recv::<NotifyEvent>())
+                .await
+                .is_err(),
+            "row b should have been filtered out"
+        );
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_api_v1_updates_columns() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta1 = launch_agent_with_schema(tripwire.clone()).await?;
+
+        let update_bcast_cache: SharedUpdateBroadcastCache = Default::default();
+        let res = api_v1_updates(
+            Extension(ta1.agent.clone()),
+            Extension(ApiScope::unrestricted()),
+            Extension(update_bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            axum::extract::Path("tests".to_string()),
+            axum::extract::Query(UpdatesParams::default()),
+            Bytes::from_static(br#"{"columns": true}"#),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut notify_rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        let exec = |sql: &'static str| transact(&ta1.agent, vec![Statement::Simple(sql.into())]);
+
+        let (status_code, _) = exec("insert into tests (id, text) values ('a', 'one')").await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert_eq!(
+            timeout(Duration::from_secs(5), notify_rows.recv::<NotifyEvent>())
+                .await?
+                .unwrap()?,
+            NotifyEvent::NotifyColumns(
+                ChangeType::Update,
+                vec!["a".into()],
+                vec![ColumnChange {
+                    column: "text".into(),
+                    value: "one".into(),
+                }]
+            )
+        );
+
+        let (status_code, _) = exec("update tests set text = 'two' where id = 'a'").await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert_eq!(
+            timeout(Duration::from_secs(5), notify_rows.recv::<NotifyEvent>())
+                .await?
+                .unwrap()?,
+            NotifyEvent::NotifyColumns(
+                ChangeType::Update,
+                vec!["a".into()],
+                vec![ColumnChange {
+                    column: "text".into(),
+                    value: "two".into(),
+                }]
+            )
+        );
+
+        let (status_code, _) = exec("delete from tests where id = 'a'").await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert_eq!(
+            timeout(Duration::from_secs(5), notify_rows.recv::<NotifyEvent>())
+                .await?
+                .unwrap()?,
+            NotifyEvent::NotifyColumns(ChangeType::Delete, vec!["a".into()], vec![])
+        );
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_api_v1_updates_from() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta1 = launch_agent_with_schema(tripwire.clone()).await?;
+
+        let update_bcast_cache: SharedUpdateBroadcastCache = Default::default();
+        let updates = |from: Option<ChangeId>, change_ids: bool| {
+            api_v1_updates(
+                Extension(ta1.agent.clone()),
+                Extension(ApiScope::unrestricted()),
+                Extension(update_bcast_cache.clone()),
+                Extension(tripwire.clone()),
+                axum::extract::Path("tests".to_string()),
+                axum::extract::Query(UpdatesParams { from, change_ids }),
+                Bytes::new(),
+            )
+        };
+
+        let insert = |id: &'static str| {
+            transact(
+                &ta1.agent,
+                vec![Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec![id.into(), "text".into()],
+                )],
+            )
+        };
+
+        let res = updates(None, true).await.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut notify_rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        for (id, change_id) in [("a", 1), ("b", 2)] {
+            let (status_code, _) = insert(id).await;
+            assert_eq!(status_code, StatusCode::OK);
+
+            for evt in [
+                NotifyEvent::Notify(ChangeType::Update, vec![id.into()]),
+                NotifyEvent::ChangeId(ChangeId(change_id)),
+            ] {
+                assert_eq!(
+                    timeout(Duration::from_secs(5), notify_rows.recv::<NotifyEvent>())
+                        .await?
+                        .unwrap()?,
+                    evt
+                );
+            }
+        }
+
+        // disconnect, and miss a notification
+        drop(notify_rows);
+
+        let (status_code, _) = insert("c").await;
+        assert_eq!(status_code, StatusCode::OK);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let res = updates(Some(ChangeId(1)), false).await.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut notify_rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        let (status_code, _) = insert("d").await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        // resuming implies change ids
+        for (id, change_id) in [("b", 2), ("c", 3), ("d", 4)] {
+            for evt in [
+                NotifyEvent::Notify(ChangeType::Update, vec![id.into()]),
+                NotifyEvent::ChangeId(ChangeId(change_id)),
+            ] {
+                assert_eq!(
+                    timeout(Duration::from_secs(5), notify_rows.recv::<NotifyEvent>())
+                        .await?
+                        .unwrap()?,
+                    evt
+                );
+            }
+        }
+
+        assert!(
+            timeout(Duration::from_secs(1), notify_rows.recv::<NotifyEvent>())
+                .await
+                .is_err(),
+            "live notifications should not be sent again after being replayed"
+        );
+
+        // unknown changes can't be resumed from
+        let res = updates(Some(ChangeId(100)), false).await.into_response();
+        assert_eq!(res.status(), StatusCode::GONE);
+
+        // the stream goes away along with its last listener, and misses a
+        // change while it's gone
+        drop(notify_rows);
+        let updates_manager = ta1.agent.updates_manager();
+        let id = updates_manager.get("tests", None, false).unwrap().id();
+        updates_manager.remove(&id).unwrap().cleanup().await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let (status_code, _) = insert("e").await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        // its log is kept, and the missed change is caught up
+        let res = updates(Some(ChangeId(3)), false).await.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut notify_rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        for (id, change_id) in [("d", 4), ("e", 5)] {
+            for evt in [
+                NotifyEvent::Notify(ChangeType::Update, vec![id.into()]),
+                NotifyEvent::ChangeId(ChangeId(change_id)),
+            ] {
+                assert_eq!(
+                    timeout(Duration::from_secs(5), notify_rows.recv::<NotifyEvent>())
+                        .await?
+                        .unwrap()?,
+                    evt
+                );
+            }
+        }
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    struct SseIter {
+        body: axum::body::BoxBody,
+        buf: BytesMut,
+    }
+
+    impl SseIter {
+        /// Returns the next event's id and data
+        async fn recv(&mut self) -> eyre::Result<(Option<u64>, String)> {
+            loop {
+                if let Some(pos) = self.buf.windows(2).position(|w| w == b"\n\n") {
+                    let event = self.buf.split_to(pos + 2);
+                    let event = std::str::from_utf8(&event)?;
+                    let mut id = None;
+                    let mut data = String::new();
+                    for line in event.lines() {
+                        if let Some(v) = line.strip_prefix("id: ") {
+                            id = Some(v.parse()?);
+                        } else if let Some(v) = line.strip_prefix("data: ") {
+                            data.push_str(v);
+                        }
+                    }
+                    return Ok((id, data));
+                }
+
+                let b = tokio::time::timeout(Duration::from_secs(5), self.body.data())
+                    .await?
+                    .ok_or_else(|| eyre::eyre!("body ended"))??;
+                self.buf.extend_from_slice(&b);
+            }
+        }
+    }
+
     struct RowsIter {
         body: axum::body::BoxBody,
         codec: LinesCodec,
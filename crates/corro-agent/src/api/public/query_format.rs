@@ -0,0 +1,572 @@
+//! Encodings for `/v1/queries` results, negotiated with the `Accept` header
+
+use std::{io, sync::Arc};
+
+use arrow_array::{
+    builder::{BinaryBuilder, Float64Builder, Int64Builder, StringBuilder},
+    ArrayRef, RecordBatch,
+};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+use axum::http::{header, HeaderMap};
+use bytes::{BufMut, BytesMut};
+use compact_str::CompactString;
+use corro_types::{
+    api::{ColumnName, QueryEvent},
+    change::SqliteValue,
+};
+use serde::{Serialize, Serializer};
+
+pub const CSV_CONTENT_TYPE: &str = "text/csv";
+pub const ARROW_STREAM_CONTENT_TYPE: &str = "application/vnd.apache.arrow.stream";
+/// `application/json` is taken by clients expecting NDJSON events, so the
+/// array of objects gets its own media type
+pub const JSON_ARRAY_CONTENT_TYPE: &str = "application/vnd.corrosion.rows+json";
+
+/// Rows buffered per Arrow record batch
+const ARROW_BATCH_SIZE: usize = 1024;
+
+/// Wire format of a query's results
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryFormat {
+    /// One JSON `QueryEvent` per line
+    Ndjson,
+    /// A header line with the column names, then one line per row
+    Csv,
+    /// A single JSON array with one object per row
+    JsonArray,
+    /// An Arrow IPC stream
+    Arrow,
+}
+
+impl QueryFormat {
+    /// Picks the first supported media type from the `Accept` header,
+    /// defaulting to NDJSON
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .find_map(|mime| {
+                let mime = mime.split(';').next().unwrap_or_default().trim();
+                if mime.eq_ignore_ascii_case(CSV_CONTENT_TYPE) {
+                    Some(QueryFormat::Csv)
+                } else if mime.eq_ignore_ascii_case(ARROW_STREAM_CONTENT_TYPE) {
+                    Some(QueryFormat::Arrow)
+                } else if mime.eq_ignore_ascii_case(JSON_ARRAY_CONTENT_TYPE) {
+                    Some(QueryFormat::JsonArray)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(QueryFormat::Ndjson)
+    }
+
+    pub fn content_type(self) -> Option<&'static str> {
+        match self {
+            QueryFormat::Ndjson => None,
+            QueryFormat::Csv => Some(CSV_CONTENT_TYPE),
+            QueryFormat::JsonArray => Some(JSON_ARRAY_CONTENT_TYPE),
+            QueryFormat::Arrow => Some(ARROW_STREAM_CONTENT_TYPE),
+        }
+    }
+
+    pub fn encoder(self) -> QueryEncoder {
+        match self {
+            QueryFormat::Ndjson => QueryEncoder::Ndjson,
+            QueryFormat::Csv => QueryEncoder::Csv,
+            QueryFormat::JsonArray => QueryEncoder::JsonArray {
+                columns: vec![],
+                rows: 0,
+            },
+            QueryFormat::Arrow => QueryEncoder::Arrow(ArrowEncoder::default()),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueryEncodeError {
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Arrow(#[from] ArrowError),
+    #[error("column {column} has {found} values, which don't fit its {expected:?} type")]
+    MixedTypes {
+        column: CompactString,
+        expected: DataType,
+        found: &'static str,
+    },
+    #[error("query failed: {0}")]
+    Query(CompactString),
+}
+
+/// Turns `QueryEvent`s into the bytes of the negotiated format.
+///
+/// Only NDJSON can represent errors and pagination cursors, other formats
+/// return an error when the query fails so the response can be aborted.
+pub enum QueryEncoder {
+    Ndjson,
+    Csv,
+    JsonArray {
+        columns: Vec<ColumnName>,
+        rows: usize,
+    },
+    Arrow(ArrowEncoder),
+}
+
+impl QueryEncoder {
+    /// Declared types of the query's columns, as reported by SQLite. Only
+    /// Arrow is typed, other formats ignore them.
+    pub fn set_column_types(&mut self, decl_types: &[Option<String>]) {
+        if let QueryEncoder::Arrow(encoder) = self {
+            encoder.types = decl_types
+                .iter()
+                .map(|decl_type| declared_type(decl_type.as_deref()))
+                .collect();
+        }
+    }
+
+    pub fn encode(
+        &mut self,
+        event: &QueryEvent,
+        buf: &mut BytesMut,
+    ) -> Result<(), QueryEncodeError> {
+        match self {
+            QueryEncoder::Ndjson => {
+                serde_json::to_writer(buf.writer(), event)?;
+                buf.extend_from_slice(b"\n");
+            }
+            QueryEncoder::Csv => {
+                let mut writer = csv::Writer::from_writer(buf.writer());
+                match event {
+                    QueryEvent::Columns(columns) => {
+                        writer.write_record(columns.iter().map(|col| col.0.as_bytes()))?;
+                    }
+                    QueryEvent::Row(_, cells) => {
+                        writer.write_record(cells.iter().map(csv_field))?;
+                    }
+                    QueryEvent::Error(e) => return Err(QueryEncodeError::Query(e.clone())),
+                    QueryEvent::EndOfQuery { .. } | QueryEvent::Change(..) => {}
+                }
+                writer.flush()?;
+            }
+            QueryEncoder::JsonArray { columns, rows } => match event {
+                QueryEvent::Columns(cols) => {
+                    *columns = cols.clone();
+                    buf.extend_from_slice(b"[");
+                }
+                QueryEvent::Row(_, cells) => {
+                    if *rows > 0 {
+                        buf.extend_from_slice(b",");
+                    }
+                    serde_json::to_writer(buf.writer(), &RowObject { columns, cells })?;
+                    *rows += 1;
+                }
+                QueryEvent::EndOfQuery { .. } => buf.extend_from_slice(b"]"),
+                QueryEvent::Error(e) => return Err(QueryEncodeError::Query(e.clone())),
+                QueryEvent::Change(..) => {}
+            },
+            QueryEncoder::Arrow(encoder) => match event {
+                QueryEvent::Columns(columns) => encoder.columns = columns.clone(),
+                QueryEvent::Row(_, cells) => {
+                    encoder.rows.push(cells.clone());
+                    if encoder.rows.len() >= ARROW_BATCH_SIZE {
+                        encoder.write_batch()?;
+                    }
+                }
+                QueryEvent::EndOfQuery { .. } => {
+                    encoder.write_batch()?;
+                    encoder.writer()?.finish()?;
+                }
+                QueryEvent::Error(e) => return Err(QueryEncodeError::Query(e.clone())),
+                QueryEvent::Change(..) => {}
+            },
+        }
+
+        self.drain_into(buf);
+        Ok(())
+    }
+
+    /// Moves whatever the Arrow writer has produced into `buf`
+    fn drain_into(&mut self, buf: &mut BytesMut) {
+        match self {
+            QueryEncoder::Arrow(encoder) => {
+                if let Some(writer) = encoder.writer.as_mut() {
+                    buf.extend_from_slice(writer.get_ref());
+                    writer.get_mut().clear();
+                }
+            }
+            QueryEncoder::Ndjson | QueryEncoder::Csv | QueryEncoder::JsonArray { .. } => {}
+        }
+    }
+}
+
+fn csv_field(value: &SqliteValue) -> Vec<u8> {
+    match value {
+        SqliteValue::Null => vec![],
+        SqliteValue::Integer(i) => i.to_string().into_bytes(),
+        SqliteValue::Real(r) => r.0.to_string().into_bytes(),
+        SqliteValue::Text(s) => s.as_bytes().to_vec(),
+        SqliteValue::Blob(b) => hex::encode(b).into_bytes(),
+    }
+}
+
+/// Serializes a row as an object keyed by column name
+struct RowObject<'a> {
+    columns: &'a [ColumnName],
+    cells: &'a [SqliteValue],
+}
+
+impl Serialize for RowObject<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(
+            self.columns
+                .iter()
+                .map(|col| col.0.as_str())
+                .zip(self.cells.iter()),
+        )
+    }
+}
+
+/// Buffers rows into record batches.
+///
+/// The schema comes from the columns' declared types, see [`declared_type`].
+/// SQLite doesn't enforce them outside of `STRICT` tables, so values are
+/// coerced where it's lossless enough and anything else fails the stream.
+#[derive(Default)]
+pub struct ArrowEncoder {
+    columns: Vec<ColumnName>,
+    types: Vec<DataType>,
+    rows: Vec<Vec<SqliteValue>>,
+    schema: Option<SchemaRef>,
+    writer: Option<StreamWriter<Vec<u8>>>,
+}
+
+impl ArrowEncoder {
+    fn writer(&mut self) -> Result<&mut StreamWriter<Vec<u8>>, QueryEncodeError> {
+        if self.writer.is_none() {
+            let fields = self
+                .columns
+                .iter()
+                .enumerate()
+                .map(|(i, col)| {
+                    let data_type = self.types.get(i).cloned().unwrap_or(DataType::Utf8);
+                    Field::new(col.0.as_str(), data_type, true)
+                })
+                .collect::<Vec<_>>();
+            let schema = Arc::new(Schema::new(fields));
+            self.writer = Some(StreamWriter::try_new(vec![], &schema)?);
+            self.schema = Some(schema);
+        }
+        Ok(self.writer.as_mut().expect("writer was just set"))
+    }
+
+    fn write_batch(&mut self) -> Result<(), QueryEncodeError> {
+        self.writer()?;
+        let schema = self.schema.clone().expect("schema is set with the writer");
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+
+        let arrays = schema
+            .fields()
+            .iter()
+            .enumerate()
+            .map(|(i, field)| build_array(field, self.rows.iter().map(|row| &row[i])))
+            .collect::<Result<Vec<_>, _>>()?;
+        let batch = RecordBatch::try_new(schema, arrays)?;
+        self.rows.clear();
+
+        self.writer()?.write(&batch)?;
+        Ok(())
+    }
+}
+
+fn value_kind(value: &SqliteValue) -> &'static str {
+    match value {
+        SqliteValue::Null => "null",
+        SqliteValue::Integer(_) => "integer",
+        SqliteValue::Real(_) => "real",
+        SqliteValue::Text(_) => "text",
+        SqliteValue::Blob(_) => "blob",
+    }
+}
+
+/// Arrow type for a declared column type, following SQLite's affinity rules.
+///
+/// Expressions and untyped columns can hold anything, so they're `Utf8`,
+/// which every value converts to.
+fn declared_type(decl_type: Option<&str>) -> DataType {
+    let decl_type = match decl_type {
+        Some(decl_type) if !decl_type.trim().is_empty() => decl_type.to_ascii_uppercase(),
+        _ => return DataType::Utf8,
+    };
+    if decl_type.contains("INT") {
+        DataType::Int64
+    } else if ["CHAR", "CLOB", "TEXT"]
+        .iter()
+        .any(|name| decl_type.contains(name))
+    {
+        DataType::Utf8
+    } else if decl_type.contains("BLOB") {
+        DataType::Binary
+    } else {
+        // REAL and NUMERIC affinities
+        DataType::Float64
+    }
+}
+
+fn build_array<'a>(
+    field: &Field,
+    values: impl Iterator<Item = &'a SqliteValue>,
+) -> Result<ArrayRef, QueryEncodeError> {
+    let mismatch = |value: &SqliteValue| QueryEncodeError::MixedTypes {
+        column: field.name().into(),
+        expected: field.data_type().clone(),
+        found: value_kind(value),
+    };
+
+    let array: ArrayRef = match field.data_type() {
+        DataType::Int64 => {
+            let mut builder = Int64Builder::new();
+            for value in values {
+                match value {
+                    SqliteValue::Null => builder.append_null(),
+                    SqliteValue::Integer(i) => builder.append_value(*i),
+                    value => return Err(mismatch(value)),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        DataType::Float64 => {
+            let mut builder = Float64Builder::new();
+            for value in values {
+                match value {
+                    SqliteValue::Null => builder.append_null(),
+                    SqliteValue::Integer(i) => builder.append_value(*i as f64),
+                    SqliteValue::Real(r) => builder.append_value(r.0),
+                    value => return Err(mismatch(value)),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        DataType::Binary => {
+            let mut builder = BinaryBuilder::new();
+            for value in values {
+                match value {
+                    SqliteValue::Null => builder.append_null(),
+                    SqliteValue::Blob(b) => builder.append_value(b),
+                    SqliteValue::Text(s) => builder.append_value(s.as_bytes()),
+                    value => return Err(mismatch(value)),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        _ => {
+            let mut builder = StringBuilder::new();
+            for value in values {
+                match value {
+                    SqliteValue::Null => builder.append_null(),
+                    SqliteValue::Integer(i) => builder.append_value(i.to_string()),
+                    SqliteValue::Real(r) => builder.append_value(r.0.to_string()),
+                    SqliteValue::Text(s) => builder.append_value(s),
+                    // same as CSV
+                    SqliteValue::Blob(b) => builder.append_value(hex::encode(b)),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+    };
+    Ok(array)
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::{Array, Float64Array, Int64Array, StringArray};
+    use arrow_ipc::reader::StreamReader;
+    use corro_types::api::RowId;
+
+    use super::*;
+
+    fn encode(format: QueryFormat, events: &[QueryEvent]) -> Result<Vec<u8>, QueryEncodeError> {
+        let mut encoder = format.encoder();
+        let mut buf = BytesMut::new();
+        for event in events {
+            encoder.encode(event, &mut buf)?;
+        }
+        Ok(buf.to_vec())
+    }
+
+    fn events() -> Vec<QueryEvent> {
+        vec![
+            QueryEvent::Columns(vec!["id".into(), "text".into(), "score".into()]),
+            QueryEvent::Row(
+                RowId(1),
+                vec![1i64.into(), "hello, world".into(), SqliteValue::Null],
+            ),
+            QueryEvent::Row(RowId(2), vec![2i64.into(), "bye".into(), 1.5f64.into()]),
+            QueryEvent::EndOfQuery {
+                time: 0.0,
+                change_id: None,
+                cursor: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_format_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(QueryFormat::from_headers(&headers), QueryFormat::Ndjson);
+
+        headers.insert(header::ACCEPT, "application/json".parse().unwrap());
+        assert_eq!(QueryFormat::from_headers(&headers), QueryFormat::Ndjson);
+
+        headers.insert(
+            header::ACCEPT,
+            "text/csv; header=present, */*".parse().unwrap(),
+        );
+        assert_eq!(QueryFormat::from_headers(&headers), QueryFormat::Csv);
+
+        headers.insert(header::ACCEPT, ARROW_STREAM_CONTENT_TYPE.parse().unwrap());
+        assert_eq!(QueryFormat::from_headers(&headers), QueryFormat::Arrow);
+    }
+
+    #[test]
+    fn test_csv() -> Result<(), QueryEncodeError> {
+        let out = encode(QueryFormat::Csv, &events())?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "id,text,score\n1,\"hello, world\",\n2,bye,1.5\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_array() -> Result<(), QueryEncodeError> {
+        let out = encode(QueryFormat::JsonArray, &events())?;
+        let value: serde_json::Value = serde_json::from_slice(&out)?;
+        assert_eq!(
+            value,
+            serde_json::json!([
+                {"id": 1, "text": "hello, world", "score": null},
+                {"id": 2, "text": "bye", "score": 1.5},
+            ])
+        );
+        Ok(())
+    }
+
+    fn encode_arrow(
+        decl_types: &[Option<&str>],
+        events: &[QueryEvent],
+    ) -> Result<Vec<u8>, QueryEncodeError> {
+        let mut encoder = QueryFormat::Arrow.encoder();
+        encoder.set_column_types(
+            &decl_types
+                .iter()
+                .map(|decl_type| decl_type.map(String::from))
+                .collect::<Vec<_>>(),
+        );
+        let mut buf = BytesMut::new();
+        for event in events {
+            encoder.encode(event, &mut buf)?;
+        }
+        Ok(buf.to_vec())
+    }
+
+    #[test]
+    fn test_arrow() -> Result<(), QueryEncodeError> {
+        let out = encode_arrow(&[Some("INTEGER"), Some("TEXT"), Some("REAL")], &events())?;
+        let batches =
+            StreamReader::try_new(out.as_slice(), None)?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(batches.len(), 1);
+
+        let batch = &batches[0];
+        let schema = batch.schema();
+        assert_eq!(schema.field(0).data_type(), &DataType::Int64);
+        assert_eq!(schema.field(1).data_type(), &DataType::Utf8);
+        assert_eq!(schema.field(2).data_type(), &DataType::Float64);
+
+        let ids = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(ids.values(), &[1, 2]);
+        let texts = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(texts.value(0), "hello, world");
+        let scores = batch
+            .column(2)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert!(scores.is_null(0));
+        assert_eq!(scores.value(1), 1.5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_arrow_untyped_columns() -> Result<(), QueryEncodeError> {
+        // expressions have no declared type, whatever their values are
+        let events = vec![
+            QueryEvent::Columns(vec!["value".into()]),
+            QueryEvent::Row(RowId(1), vec![1i64.into()]),
+            QueryEvent::Row(RowId(2), vec!["two".into()]),
+            QueryEvent::Row(RowId(3), vec![SqliteValue::Blob([3u8][..].into())]),
+            QueryEvent::EndOfQuery {
+                time: 0.0,
+                change_id: None,
+                cursor: None,
+            },
+        ];
+        let out = encode_arrow(&[None], &events)?;
+        let batches =
+            StreamReader::try_new(out.as_slice(), None)?.collect::<Result<Vec<_>, _>>()?;
+        let values = batches[0]
+            .column(0)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(values.value(0), "1");
+        assert_eq!(values.value(1), "two");
+        assert_eq!(values.value(2), "03");
+
+        // values that don't fit a declared type still fail
+        assert!(matches!(
+            encode_arrow(&[Some("INTEGER")], &events),
+            Err(QueryEncodeError::MixedTypes { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_declared_type() {
+        assert_eq!(declared_type(Some("BIGINT")), DataType::Int64);
+        assert_eq!(declared_type(Some("varchar(255)")), DataType::Utf8);
+        assert_eq!(declared_type(Some("BLOB")), DataType::Binary);
+        assert_eq!(declared_type(Some("DOUBLE")), DataType::Float64);
+        assert_eq!(declared_type(Some("NUMERIC")), DataType::Float64);
+        assert_eq!(declared_type(Some("")), DataType::Utf8);
+        assert_eq!(declared_type(None), DataType::Utf8);
+    }
+
+    #[test]
+    fn test_errors_fail_non_ndjson_formats() {
+        let error = QueryEvent::Error("interrupted".into());
+        assert!(encode(QueryFormat::Ndjson, &[error.clone()]).is_ok());
+        for format in [QueryFormat::Csv, QueryFormat::JsonArray, QueryFormat::Arrow] {
+            assert!(matches!(
+                encode(format, &[error.clone()]),
+                Err(QueryEncodeError::Query(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! Request and row rate limits of the public API
+//!
+//! Limits apply to each named API token, or to each client IP for requests
+//! made without one. Clients of unix sockets without a named token share a
+//! single limit. Requests past a limit get a `429 Too Many Requests` with a
+//! `Retry-After` header.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU32,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use corro_types::{agent::ChangeError, config::RateLimitConfig};
+use governor::{clock::Clock, DefaultKeyedRateLimiter, InsufficientCapacity, Quota, RateLimiter};
+use metrics::counter;
+use parking_lot::Mutex;
+use tracing::debug;
+use tripwire::Tripwire;
+
+use crate::api::public::authz::ApiScope;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RateLimitKey {
+    Token(String),
+    Ip(IpAddr),
+    /// Requests over unix sockets, which have no client address
+    UnixSocket,
+}
+
+impl RateLimitKey {
+    fn new(scope: &ApiScope, client_addr: Option<SocketAddr>) -> Self {
+        match (scope.name(), client_addr) {
+            (Some(name), _) => RateLimitKey::Token(name.to_owned()),
+            (None, Some(addr)) => RateLimitKey::Ip(addr.ip()),
+            (None, None) => RateLimitKey::UnixSocket,
+        }
+    }
+}
+
+type KeyedLimiter = DefaultKeyedRateLimiter<RateLimitKey>;
+
+#[derive(Clone, Default)]
+pub struct RateLimits {
+    requests: Option<Arc<KeyedLimiter>>,
+    /// Row limiter and its burst
+    rows: Option<(Arc<KeyedLimiter>, NonZeroU32)>,
+}
+
+impl RateLimits {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let limiter = |rate: Option<NonZeroU32>, burst: Option<NonZeroU32>| {
+            rate.map(|rate| {
+                let burst = burst.unwrap_or(rate);
+                (
+                    Arc::new(RateLimiter::keyed(
+                        Quota::per_second(rate).allow_burst(burst),
+                    )),
+                    burst,
+                )
+            })
+        };
+
+        Self {
+            requests: limiter(config.requests_per_second, config.requests_burst)
+                .map(|(limiter, _)| limiter),
+            rows: limiter(config.rows_per_second, config.rows_burst),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.requests.is_some() || self.rows.is_some()
+    }
+
+    fn check_requests(&self, key: &RateLimitKey) -> Result<(), Response> {
+        match self.requests.as_deref() {
+            Some(limiter) => check(limiter, key, NonZeroU32::MIN, "requests"),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes the first row of a transaction, before it runs. The rest is
+    /// charged through the returned [`RowsLimit`] once they are known.
+    fn check_rows(&self, key: &RateLimitKey) -> Result<Option<RowsLimit>, Response> {
+        let Some((limiter, burst)) = &self.rows else {
+            return Ok(None);
+        };
+        check(limiter, key, NonZeroU32::MIN, "rows")?;
+
+        Ok(Some(RowsLimit {
+            limiter: limiter.clone(),
+            burst: *burst,
+            key: key.clone(),
+            retry_after: Default::default(),
+        }))
+    }
+
+    /// Forget keys that are back to their full burst capacity
+    fn retain_recent(&self) {
+        let rows = self.rows.as_ref().map(|(limiter, _)| limiter);
+        for limiter in [self.requests.as_ref(), rows].into_iter().flatten() {
+            limiter.retain_recent();
+            limiter.shrink_to_fit();
+        }
+    }
+}
+
+/// Row budget of the client making a transaction. The handler charges the
+/// rows its statements wrote before committing, and rolls back if they are
+/// over the budget.
+#[derive(Clone)]
+pub struct RowsLimit {
+    limiter: Arc<KeyedLimiter>,
+    burst: NonZeroU32,
+    key: RateLimitKey,
+    /// Set when the transaction was rolled back, for the `Retry-After` header
+    retry_after: Arc<Mutex<Option<Duration>>>,
+}
+
+impl RowsLimit {
+    pub fn charge(&self, rows: usize) -> Result<(), ChangeError> {
+        if rows > self.burst.get() as usize {
+            return Err(ChangeError::TooManyRows {
+                rows,
+                max: self.burst.get(),
+            });
+        }
+
+        // the first row was taken before the transaction ran
+        let Some(n) = NonZeroU32::new(rows.saturating_sub(1) as u32) else {
+            return Ok(());
+        };
+        match self.limiter.check_key_n(&self.key, n) {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(not_until)) => {
+                let wait = not_until.wait_time_from(self.limiter.clock().now());
+                *self.retry_after.lock() = Some(wait);
+                Err(ChangeError::RowsRateLimited)
+            }
+            // checked against the burst above
+            Err(InsufficientCapacity(max)) => Err(ChangeError::TooManyRows { rows, max }),
+        }
+    }
+}
+
+fn check(
+    limiter: &KeyedLimiter,
+    key: &RateLimitKey,
+    n: NonZeroU32,
+    kind: &'static str,
+) -> Result<(), Response> {
+    match limiter.check_key_n(key, n) {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(not_until)) => {
+            debug!(?key, "{kind} rate limit exceeded");
+            let wait = not_until.wait_time_from(limiter.clock().now());
+            let mut res = (
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("{kind} rate limit exceeded"),
+            )
+                .into_response();
+            rate_limited(&mut res, kind, wait);
+            Err(res)
+        }
+        Err(InsufficientCapacity(max)) => Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("{n} {kind} exceed the burst of {max} {kind} allowed by the rate limit"),
+        )
+            .into_response()),
+    }
+}
+
+fn rate_limited(res: &mut Response, kind: &'static str, wait: Duration) {
+    counter!("corro.api.rate_limited.count", "limit" => kind).increment(1);
+    res.headers_mut().insert(
+        header::RETRY_AFTER,
+        HeaderValue::from(retry_after_secs(wait)),
+    );
+}
+
+/// `Retry-After` only has a precision of seconds, round up so the retry
+/// doesn't come too early
+fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+pub async fn limit_requests<B>(
+    State(limits): State<RateLimits>,
+    Extension(scope): Extension<ApiScope>,
+    client_addr: Option<ConnectInfo<SocketAddr>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let key = RateLimitKey::new(&scope, client_addr.map(|ConnectInfo(addr)| addr));
+    if let Err(res) = limits.check_requests(&key) {
+        return res;
+    }
+
+    next.run(request).await
+}
+
+/// Rejects transactions of clients out of row budget before they run, the
+/// handler charges the rows they wrote through the [`RowsLimit`] extension
+pub async fn limit_rows<B>(
+    State(limits): State<RateLimits>,
+    Extension(scope): Extension<ApiScope>,
+    client_addr: Option<ConnectInfo<SocketAddr>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let key = RateLimitKey::new(&scope, client_addr.map(|ConnectInfo(addr)| addr));
+    let rows_limit = match limits.check_rows(&key) {
+        Ok(Some(rows_limit)) => rows_limit,
+        Ok(None) => return next.run(request).await,
+        Err(res) => return res,
+    };
+
+    request.extensions_mut().insert(rows_limit.clone());
+    let mut res = next.run(request).await;
+
+    let retry_after = rows_limit.retry_after.lock().take();
+    if let Some(wait) = retry_after {
+        debug!(key = ?rows_limit.key, "rows rate limit exceeded");
+        rate_limited(&mut res, "rows", wait);
+    }
+
+    res
+}
+
+/// Periodically drops the state of idle clients, so it doesn't grow forever
+pub async fn forget_idle_clients(limits: RateLimits, mut tripwire: Tripwire) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => limits.retain_recent(),
+            _ = &mut tripwire => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::config::{ApiPermission, ApiTokenConfig};
+
+    use super::*;
+
+    #[test]
+    fn test_rate_limits() {
+        let limits = RateLimits::new(&RateLimitConfig {
+            requests_per_second: NonZeroU32::new(1),
+            requests_burst: NonZeroU32::new(2),
+            rows_per_second: NonZeroU32::new(10),
+            rows_burst: None,
+        });
+
+        let token = ApiScope::from_token(&ApiTokenConfig {
+            name: "batch".into(),
+            token: "secret".into(),
+            permissions: vec![ApiPermission::Transact],
+            tables: None,
+        });
+        let token = RateLimitKey::new(&token, Some("127.0.0.1:1234".parse().unwrap()));
+        assert_eq!(token, RateLimitKey::Token("batch".into()));
+
+        let ip = RateLimitKey::new(
+            &ApiScope::unrestricted(),
+            Some("127.0.0.1:1234".parse().unwrap()),
+        );
+        assert_eq!(ip, RateLimitKey::Ip("127.0.0.1".parse().unwrap()));
+
+        let unix = RateLimitKey::new(&ApiScope::unrestricted(), None);
+        assert_eq!(unix, RateLimitKey::UnixSocket);
+
+        // a burst, then limited
+        assert!(limits.check_requests(&token).is_ok());
+        assert!(limits.check_requests(&token).is_ok());
+        let res = limits.check_requests(&token).unwrap_err();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "1");
+
+        // keys are limited independently
+        assert!(limits.check_requests(&ip).is_ok());
+
+        // rows are charged once the transaction ran, the first one up front
+        let rows_limit = limits.check_rows(&ip).unwrap().unwrap();
+        rows_limit.charge(9).unwrap();
+        assert!(rows_limit.retry_after.lock().is_none());
+
+        let rows_limit = limits.check_rows(&ip).unwrap().unwrap();
+        assert!(matches!(
+            rows_limit.charge(8),
+            Err(ChangeError::RowsRateLimited)
+        ));
+        assert!(rows_limit.retry_after.lock().is_some());
+
+        // out of budget, rejected before running
+        let res = limits.check_rows(&ip).err().unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().contains_key(header::RETRY_AFTER));
+
+        // transactions writing nothing still count a row
+        let rows_limit = limits.check_rows(&token).unwrap().unwrap();
+        rows_limit.charge(0).unwrap();
+
+        // never allowed, retrying won't help
+        assert!(matches!(
+            rows_limit.charge(11),
+            Err(ChangeError::TooManyRows { rows: 11, max: 10 })
+        ));
+
+        let limits = RateLimits::new(&RateLimitConfig::default());
+        assert!(!limits.is_enabled());
+        assert!(limits.check_rows(&ip).unwrap().is_none());
+    }
+}
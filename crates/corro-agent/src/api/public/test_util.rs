@@ -0,0 +1,48 @@
+//! Fixtures shared by the public API tests
+
+use axum::{http::HeaderMap, Extension};
+use corro_tests::{launch_test_agent, TestAgent};
+use corro_types::{
+    agent::Agent,
+    api::{ExecResponse, Statement},
+};
+use hyper::StatusCode;
+use tripwire::Tripwire;
+
+use crate::api::public::{
+    api_v1_db_schema, api_v1_transactions, authz::ApiScope, ReplicationParams, TimeoutParams,
+};
+
+/// Launches an agent with the test schema applied
+pub async fn launch_agent_with_schema(tripwire: Tripwire) -> eyre::Result<TestAgent> {
+    let ta = launch_test_agent(|conf| conf.build(), tripwire).await?;
+
+    let (status_code, _body) = api_v1_db_schema(
+        Extension(ta.agent.clone()),
+        Extension(ApiScope::unrestricted()),
+        axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+    )
+    .await;
+    assert_eq!(status_code, StatusCode::OK);
+
+    Ok(ta)
+}
+
+/// Runs `statements` as a transaction with an unrestricted scope, without
+/// rate limits or waiting for replication
+pub async fn transact(
+    agent: &Agent,
+    statements: Vec<Statement>,
+) -> (StatusCode, axum::Json<ExecResponse>) {
+    api_v1_transactions(
+        Extension(agent.clone()),
+        Extension(ApiScope::unrestricted()),
+        None,
+        None,
+        HeaderMap::new(),
+        axum::extract::Query(TimeoutParams { timeout: None }),
+        axum::extract::Query(ReplicationParams::default()),
+        axum::Json(statements.into()),
+    )
+    .await
+}
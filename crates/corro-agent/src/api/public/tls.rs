@@ -0,0 +1,327 @@
+//! TLS for the public HTTP API
+//!
+//! The certificate, key and CA files are checked periodically and reloaded
+//! when they change, so they can be rotated without restarting the agent.
+
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwap;
+use axum::extract::connect_info::Connected;
+use camino::Utf8Path;
+use corro_types::{config::ServerTlsConfig, tls};
+use hyper::server::accept::Accept;
+use parking_lot::Mutex;
+use spawn::spawn_counted;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::block_in_place,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, error, info, warn};
+use tripwire::Tripwire;
+
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+pub struct ApiTls(Arc<InnerApiTls>);
+
+struct InnerApiTls {
+    config: ServerTlsConfig,
+    current: ArcSwap<rustls::ServerConfig>,
+    /// modification times of the files `current` was loaded from
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl ApiTls {
+    pub fn new(config: ServerTlsConfig) -> Result<Self, tls::Error> {
+        let modified = modified_times(&config);
+        let current = tls::server_config(&config)?;
+
+        Ok(Self(Arc::new(InnerApiTls {
+            config,
+            current: ArcSwap::from_pointee(current),
+            modified: Mutex::new(modified),
+        })))
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.0.current.load_full())
+    }
+
+    /// Reloads the certificates if any of their files changed since they were
+    /// last loaded. Connections that are already established aren't affected.
+    pub fn reload_if_modified(&self) -> Result<bool, tls::Error> {
+        let modified = modified_times(&self.0.config);
+        let mut last_modified = self.0.modified.lock();
+        if *last_modified == modified {
+            return Ok(false);
+        }
+
+        // files that are being replaced may not match yet, keep the last
+        // good config and retry on the next check
+        self.0
+            .current
+            .store(Arc::new(tls::server_config(&self.0.config)?));
+        *last_modified = modified;
+
+        Ok(true)
+    }
+}
+
+fn modified_times(config: &ServerTlsConfig) -> Vec<Option<SystemTime>> {
+    let modified = |path: &Utf8Path| {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    };
+
+    [
+        Some(&config.cert_file),
+        Some(&config.key_file),
+        config.ca_file.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|path| modified(path))
+    .collect()
+}
+
+pub async fn reload_on_change(tls: ApiTls, mut tripwire: Tripwire) {
+    let mut interval = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => match block_in_place(|| tls.reload_if_modified()) {
+                Ok(true) => info!("Reloaded API TLS certificates"),
+                Ok(false) => {}
+                Err(e) => error!("could not reload API TLS certificates: {e}"),
+            },
+            _ = &mut tripwire => break,
+        }
+    }
+}
+
+/// TLS connection to the API, along with the client's address
+pub struct TlsConn {
+    stream: TlsStream<TcpStream>,
+    remote_addr: SocketAddr,
+}
+
+impl Connected<&TlsConn> for SocketAddr {
+    fn connect_info(target: &TlsConn) -> Self {
+        target.remote_addr
+    }
+}
+
+impl AsyncRead for TlsConn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Accepts connections on `listener`, completing their TLS handshakes in the
+/// background so that a slow client doesn't hold up the others
+pub fn tls_incoming(
+    listener: TcpListener,
+    tls: ApiTls,
+    mut tripwire: Tripwire,
+) -> impl Accept<Conn = TlsConn, Error = io::Error> {
+    let (tx, rx) = mpsc::channel(128);
+
+    spawn_counted(async move {
+        loop {
+            let (stream, remote_addr) = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("could not accept API connection: {e}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                },
+                _ = &mut tripwire => break,
+            };
+
+            if let Err(e) = stream.set_nodelay(true) {
+                debug!(%remote_addr, "could not set nodelay on API connection: {e}");
+            }
+
+            let acceptor = tls.acceptor();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        _ = tx
+                            .send(Ok(TlsConn {
+                                stream,
+                                remote_addr,
+                            }))
+                            .await;
+                    }
+                    Ok(Err(e)) => debug!(%remote_addr, "API TLS handshake failed: {e}"),
+                    Err(_) => debug!(%remote_addr, "API TLS handshake timed out"),
+                }
+            });
+        }
+    });
+
+    hyper::server::accept::from_stream(ReceiverStream::new(rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_tests::launch_test_agent;
+    use corro_types::tls::{generate_ca, generate_server_cert};
+    use hyper::{Body, Request, StatusCode};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    struct TestCerts {
+        ca_der: Vec<u8>,
+        cert_pem: String,
+        key_pem: String,
+    }
+
+    fn test_certs() -> eyre::Result<TestCerts> {
+        let ca = generate_ca()?;
+        let (cert, cert_pem) = generate_server_cert(
+            &ca.serialize_pem()?,
+            &ca.serialize_private_key_pem(),
+            "127.0.0.1".parse()?,
+        )?;
+
+        Ok(TestCerts {
+            ca_der: ca.serialize_der()?,
+            cert_pem,
+            key_pem: cert.serialize_private_key_pem(),
+        })
+    }
+
+    async fn get_health(addr: SocketAddr, ca_der: &[u8]) -> eyre::Result<StatusCode> {
+        let mut root_store = rustls::RootCertStore::empty();
+        root_store.add(&rustls::Certificate(ca_der.to_vec()))?;
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(
+                rustls::ServerName::IpAddress(addr.ip()),
+                TcpStream::connect(addr).await?,
+            )
+            .await?;
+
+        let (mut sender, conn) = hyper::client::conn::handshake(stream).await?;
+        tokio::spawn(conn);
+
+        let res = sender
+            .send_request(
+                Request::get("/v1/health")
+                    .header(hyper::header::HOST, addr.to_string())
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        Ok(res.status())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_api_tls_reload() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+        let cert_file = Utf8Path::from_path(dir.path()).unwrap().join("cert.pem");
+        let key_file = Utf8Path::from_path(dir.path()).unwrap().join("key.pem");
+
+        let certs = test_certs()?;
+        std::fs::write(&cert_file, &certs.cert_pem)?;
+        std::fs::write(&key_file, &certs.key_pem)?;
+
+        let ta = launch_test_agent(
+            |conf| {
+                let mut conf = conf.build()?;
+                conf.api.tls = Some(ServerTlsConfig {
+                    cert_file: cert_file.clone(),
+                    key_file: key_file.clone(),
+                    ca_file: None,
+                    verify_client: false,
+                });
+                Ok(conf)
+            },
+            tripwire.clone(),
+        )
+        .await?;
+        let addr = ta.agent.api_addr().unwrap();
+
+        assert_eq!(get_health(addr, &certs.ca_der).await?, StatusCode::OK);
+
+        // rotate to certificates signed by another CA
+        let rotated = test_certs()?;
+        std::fs::write(&cert_file, &rotated.cert_pem)?;
+        std::fs::write(&key_file, &rotated.key_pem)?;
+
+        let start = std::time::Instant::now();
+        while get_health(addr, &rotated.ca_der).await.is_err() {
+            assert!(
+                start.elapsed() < RELOAD_CHECK_INTERVAL * 3,
+                "certificates were not reloaded"
+            );
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+
+        assert!(get_health(addr, &certs.ca_der).await.is_err());
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        spawn::wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
@@ -1,65 +1,206 @@
-use std::{collections::HashMap, io::Write, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Write,
+    sync::Arc,
+    time::Duration,
+};
 
 use antithesis_sdk::assert_sometimes;
-use axum::{http::StatusCode, response::IntoResponse, Extension};
+use axum::{extract::Query, http::StatusCode, response::IntoResponse, Extension};
 use bytes::{BufMut, Bytes, BytesMut};
 use compact_str::ToCompactString;
 use corro_types::{
     agent::Agent,
-    api::NotifyEvent,
+    api::{ChangeId, NotifyEvent, UpdatesRequest},
     updates::{Handle, UpdateCreated, UpdateHandle, UpdatesManager},
 };
 use futures::future::poll_fn;
-use tokio::sync::{
-    broadcast::{self, error::RecvError},
-    mpsc, RwLock as TokioRwLock,
+use serde::Deserialize;
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, RwLock as TokioRwLock,
+    },
+    task::block_in_place,
 };
 use tracing::{debug, info, warn};
 use tripwire::Tripwire;
 use uuid::Uuid;
 
-use crate::api::public::pubsub::MatcherUpsertError;
+use crate::api::public::{
+    authz::ApiScope,
+    pubsub::{MatcherUpsertError, MAX_UNSUB_TIME},
+};
 
-pub type UpdateBroadcastCache = HashMap<Uuid, broadcast::Sender<Bytes>>;
+pub type UpdateBroadcastCache = HashMap<Uuid, broadcast::Sender<(Bytes, Option<ChangeId>)>>;
 pub type SharedUpdateBroadcastCache = Arc<TokioRwLock<UpdateBroadcastCache>>;
 
 // this should be a fraction of the MAX_UNSUB_TIME
 const RECEIVERS_CHECK_INTERVAL: Duration = Duration::from_secs(30);
 
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct UpdatesParams {
+    /// Replay the notifications logged after this change id. The log outlives
+    /// the stream, rows changed while it wasn't running are notified when
+    /// it's created again.
+    #[serde(default)]
+    pub from: Option<ChangeId>,
+    /// Follow each notification with its change id, implied by `from`
+    #[serde(default)]
+    pub change_ids: bool,
+}
+
+/// Notifications of an updates stream: the ones replayed from its log first,
+/// then the live ones that weren't replayed
+pub struct UpdatesReceiver {
+    pending: VecDeque<Bytes>,
+    last_id: ChangeId,
+    change_ids: bool,
+    rx: broadcast::Receiver<(Bytes, Option<ChangeId>)>,
+}
+
+impl UpdatesReceiver {
+    pub async fn recv(&mut self) -> Result<Bytes, RecvError> {
+        if let Some(event_buf) = self.pending.pop_front() {
+            return Ok(event_buf);
+        }
+
+        loop {
+            match self.rx.recv().await? {
+                (_, Some(change_id)) if change_id <= self.last_id => continue,
+                (event_buf, change_id) => return Ok(self.live(event_buf, change_id)),
+            }
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<Bytes> {
+        if let Some(event_buf) = self.pending.pop_front() {
+            return Some(event_buf);
+        }
+
+        loop {
+            match self.rx.try_recv().ok()? {
+                (_, Some(change_id)) if change_id <= self.last_id => continue,
+                (event_buf, change_id) => return Some(self.live(event_buf, change_id)),
+            }
+        }
+    }
+
+    /// Queues the change id event of a live notification, if asked for
+    fn live(&mut self, event_buf: Bytes, change_id: Option<ChangeId>) -> Bytes {
+        if let (true, Some(change_id)) = (self.change_ids, change_id) {
+            self.pending.push_back(change_id_event_bytes(change_id));
+        }
+        event_buf
+    }
+}
+
+fn change_id_event_bytes(change_id: ChangeId) -> Bytes {
+    make_query_event_bytes(&mut BytesMut::new(), &NotifyEvent::ChangeId(change_id))
+        .expect("could not serialize change id event")
+}
+
+/// Get or create the updates handle for a table, optionally filtered on its
+/// primary key and including changed columns, and subscribe to its
+/// notifications, replaying the ones sent after change `from`.
+///
+/// Notifications are followed by their change id when `change_ids` is set
+/// or resuming.
+#[allow(clippy::too_many_arguments)]
+pub async fn subscribe_updates(
+    agent: &Agent,
+    scope: &ApiScope,
+    bcast_cache: &SharedUpdateBroadcastCache,
+    tripwire: Tripwire,
+    table: &str,
+    req: &UpdatesRequest,
+    from: Option<ChangeId>,
+    change_ids: bool,
+) -> Result<(UpdateHandle, UpdatesReceiver), MatcherUpsertError> {
+    if !scope.allows_table(table) {
+        return Err(MatcherUpsertError::TableNotAllowed(table.to_owned()));
+    }
+
+    let mut bcast_write = bcast_cache.write().await;
+    let updates = agent.updates_manager();
+
+    let (handle, maybe_created) = updates.get_or_insert(
+        table,
+        req,
+        &agent.schema().read(),
+        agent.pool(),
+        &agent.config().db.updates_path(),
+        tripwire,
+    )?;
+
+    let (_, rx) = upsert_update(handle.clone(), maybe_created, updates, &mut bcast_write).await?;
+
+    // notifications are logged before they're broadcast, and we're already
+    // subscribed: any of them is either replayed, received live, or both
+    let mut pending = VecDeque::new();
+    let mut last_id = ChangeId(0);
+    if let Some(from) = from {
+        let mut buf = BytesMut::new();
+        last_id = from;
+        for (change_id, event) in block_in_place(|| handle.changes_since(from))? {
+            last_id = change_id;
+            pending.push_back(make_query_event_bytes(&mut buf, &event)?);
+            pending.push_back(change_id_event_bytes(change_id));
+        }
+    }
+
+    Ok((
+        handle,
+        UpdatesReceiver {
+            pending,
+            last_id,
+            change_ids: change_ids || from.is_some(),
+            rx,
+        },
+    ))
+}
+
 pub async fn api_v1_updates(
     Extension(agent): Extension<Agent>,
+    Extension(scope): Extension<ApiScope>,
     Extension(bcast_cache): Extension<SharedUpdateBroadcastCache>,
     Extension(tripwire): Extension<Tripwire>,
     axum::extract::Path(table): axum::extract::Path<String>,
+    Query(params): Query<UpdatesParams>,
+    body: Bytes,
 ) -> impl IntoResponse {
     info!("Received update request for table: {table}");
 
     assert_sometimes!(true, "Corrosion receives requests for table updates");
 
-    let mut bcast_write = bcast_cache.write().await;
-    let updates = agent.updates_manager();
+    let req = if body.is_empty() {
+        UpdatesRequest::default()
+    } else {
+        match serde_json::from_slice::<UpdatesRequest>(&body) {
+            Ok(req) => req,
+            Err(e) => return hyper::Response::<hyper::Body>::from(MatcherUpsertError::from(e)),
+        }
+    };
 
-    let upsert_res = updates.get_or_insert(
-        &table,
-        &agent.schema().read(),
-        agent.pool(),
+    let (handle, sub_rx) = match subscribe_updates(
+        &agent,
+        &scope,
+        &bcast_cache,
         tripwire.clone(),
-    );
-
-    let (handle, maybe_created) = match upsert_res {
+        &table,
+        &req,
+        params.from,
+        params.change_ids,
+    )
+    .await
+    {
         Ok(res) => res,
-        Err(e) => return hyper::Response::<hyper::Body>::from(MatcherUpsertError::from(e)),
+        Err(e) => return hyper::Response::<hyper::Body>::from(e),
     };
 
     let (tx, body) = hyper::Body::channel();
-    // let (forward_tx, forward_rx) = mpsc::channel(10240);
-
-    let (update_id, sub_rx) =
-        match upsert_update(handle.clone(), maybe_created, updates, &mut bcast_write).await {
-            Ok(id) => id,
-            Err(e) => return hyper::Response::<hyper::Body>::from(e),
-        };
 
+    let update_id = handle.id();
     tokio::spawn(forward_update_bytes_to_body_sender(
         handle, sub_rx, tx, tripwire,
     ));
@@ -76,7 +217,7 @@ pub async fn upsert_update(
     maybe_created: Option<UpdateCreated>,
     updates: &UpdatesManager,
     bcast_write: &mut UpdateBroadcastCache,
-) -> Result<(Uuid, broadcast::Receiver<Bytes>), MatcherUpsertError> {
+) -> Result<(Uuid, broadcast::Receiver<(Bytes, Option<ChangeId>)>), MatcherUpsertError> {
     let sub_rx = if let Some(created) = maybe_created {
         let (sub_tx, sub_rx) = broadcast::channel(10240);
         bcast_write.insert(handle.id(), sub_tx.clone());
@@ -105,43 +246,78 @@ pub async fn upsert_update(
 pub async fn process_update_channel(
     updates: UpdatesManager,
     id: Uuid,
-    tx: broadcast::Sender<Bytes>,
-    mut evt_rx: mpsc::Receiver<NotifyEvent>,
+    tx: broadcast::Sender<(Bytes, Option<ChangeId>)>,
+    mut evt_rx: mpsc::Receiver<(ChangeId, NotifyEvent)>,
 ) {
     let mut buf = BytesMut::new();
 
+    // keep logging notifications for a while after the last listener is
+    // gone, so that it can resume where it left off
+    let mut deadline = if tx.receiver_count() == 0 {
+        Some(Box::pin(tokio::time::sleep(MAX_UNSUB_TIME)))
+    } else {
+        None
+    };
+
     // interval check for receivers
     // useful for queries that don't change often so we can cleanup...
     let mut subs_check = tokio::time::interval(RECEIVERS_CHECK_INTERVAL);
 
     loop {
-        tokio::select! {
+        let deadline_check = async {
+            if let Some(sleep) = deadline.as_mut() {
+                sleep.await
+            } else {
+                futures::future::pending().await
+            }
+        };
+
+        let (change_id, query_evt) = tokio::select! {
             biased;
-            Some(query_evt) = evt_rx.recv() => {
-                match make_query_event_bytes(&mut buf, &query_evt) {
-                    Ok(b) => {
-                        if tx.send(b).is_err() {
-                            break;
-                        }
-                    },
-                    Err(e) => {
-                        match make_query_event_bytes(&mut buf, &NotifyEvent::Error(e.to_compact_string())) {
-                            Ok(b) => {
-                                let _ = tx.send(b);
-                            }
-                            Err(e) => {
-                                warn!(update_id = %id, "failed to send error in update channel: {e}");
-                            }
-                        }
-                        break;
-                    }
-                };
+            Some(logged) = evt_rx.recv() => logged,
+            _ = deadline_check => {
+                if tx.receiver_count() == 0 {
+                    info!(update_id = %id, "All listeners for updates are gone and didn't come back within {MAX_UNSUB_TIME:?}");
+                    break;
+                }
+
+                deadline = None;
+                continue;
             },
             _ = subs_check.tick() => {
                 if tx.receiver_count() == 0 {
-                    break;
+                    if deadline.is_none() {
+                        deadline = Some(Box::pin(tokio::time::sleep(MAX_UNSUB_TIME)));
+                    }
+                } else {
+                    deadline = None;
                 };
+                continue;
             },
+            else => {
+                break;
+            }
+        };
+
+        match make_query_event_bytes(&mut buf, &query_evt) {
+            Ok(b) => {
+                if tx.send((b, Some(change_id))).is_ok() {
+                    deadline = None;
+                } else if deadline.is_none() {
+                    deadline = Some(Box::pin(tokio::time::sleep(MAX_UNSUB_TIME)));
+                }
+            }
+            Err(e) => {
+                match make_query_event_bytes(&mut buf, &NotifyEvent::Error(e.to_compact_string())) {
+                    Ok(b) => {
+                        let _ = tx.send((b, None));
+                    }
+                    Err(e) => {
+                        warn!(update_id = %id, "failed to send error in update channel: {e}");
+                    }
+                }
+                break;
+            }
         };
     }
 
@@ -182,7 +358,7 @@ fn make_query_event_bytes(
 
 async fn forward_update_bytes_to_body_sender(
     update: UpdateHandle,
-    mut rx: broadcast::Receiver<Bytes>,
+    mut rx: UpdatesReceiver,
     mut tx: hyper::body::Sender,
     mut tripwire: Tripwire,
 ) {
@@ -240,7 +416,7 @@ async fn forward_update_bytes_to_body_sender(
         }
     }
 
-    while let Ok(event_buf) = rx.try_recv() {
+    while let Some(event_buf) = rx.try_recv() {
         buf.extend_from_slice(&event_buf);
         if let Err(e) = tx.send_data(buf.split().freeze()).await {
             warn!(update_id = %update.id(), "could not forward subscription query event to receiver: {e}");
@@ -0,0 +1,655 @@
+//! Multiplexed subscriptions and updates over a single websocket
+//!
+//! Clients send [`WsRequest`] control frames and receive [`WsEvent`]s,
+//! each tagged with the client-chosen id of the stream they belong to.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    response::IntoResponse,
+    Extension,
+};
+use bytes::Bytes;
+use compact_str::ToCompactString;
+use corro_types::{
+    agent::{Agent, Bookie},
+    api::{QueryEventMeta, Statement, WsEvent, WsRequest},
+    updates::{Handle, UpdateHandle},
+};
+use futures::{SinkExt, StreamExt};
+use serde::Serialize;
+use serde_json::value::RawValue;
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc},
+    task::JoinHandle,
+};
+use tracing::{debug, info, warn};
+use tripwire::Tripwire;
+
+use crate::api::public::{
+    authz::ApiScope,
+    pubsub::{subscribe, SharedMatcherBroadcastCache, SubParams},
+    update::{subscribe_updates, SharedUpdateBroadcastCache, UpdatesReceiver},
+    wait_for_consistency, ConsistencyParams,
+};
+
+/// Same shape as the `query` and `notify` variants of [`WsEvent`], but
+/// reusing the already serialized event instead of decoding it again
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RawWsEvent<'a> {
+    Query { id: &'a str, event: &'a RawValue },
+    Notify { id: &'a str, event: &'a RawValue },
+}
+
+pub async fn api_v1_ws(
+    Extension(agent): Extension<Agent>,
+    Extension(scope): Extension<ApiScope>,
+    Extension(subs_cache): Extension<SharedMatcherBroadcastCache>,
+    Extension(updates_cache): Extension<SharedUpdateBroadcastCache>,
+    Extension(tripwire): Extension<Tripwire>,
+    Extension(bookie): Extension<Bookie>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| {
+        handle_socket(
+            agent,
+            scope,
+            subs_cache,
+            updates_cache,
+            tripwire,
+            bookie,
+            socket,
+        )
+    })
+}
+
+async fn handle_socket(
+    agent: Agent,
+    scope: ApiScope,
+    subs_cache: SharedMatcherBroadcastCache,
+    updates_cache: SharedUpdateBroadcastCache,
+    mut tripwire: Tripwire,
+    bookie: Bookie,
+    socket: WebSocket,
+) {
+    let (mut sink, mut stream) = socket.split();
+    let (msg_tx, mut msg_rx) = mpsc::channel::<Message>(10240);
+
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = msg_rx.recv().await {
+            if let Err(e) = sink.send(msg).await {
+                debug!("could not send websocket message: {e}");
+                break;
+            }
+        }
+        _ = sink.close().await;
+    });
+
+    let mut streams: HashMap<String, JoinHandle<()>> = HashMap::new();
+
+    loop {
+        let msg = tokio::select! {
+            msg = stream.next() => match msg {
+                Some(Ok(msg)) => msg,
+                Some(Err(e)) => {
+                    warn!("websocket error: {e}");
+                    break;
+                }
+                None => break,
+            },
+            _ = &mut tripwire => break,
+        };
+
+        let req = match msg {
+            Message::Text(text) => serde_json::from_str::<WsRequest>(&text),
+            Message::Binary(bytes) => serde_json::from_slice::<WsRequest>(&bytes),
+            Message::Close(_) => break,
+            // pings are answered automatically
+            Message::Ping(_) | Message::Pong(_) => continue,
+        };
+
+        let req = match req {
+            Ok(req) => req,
+            Err(e) => {
+                if send_event(
+                    &msg_tx,
+                    &WsEvent::Error {
+                        id: None,
+                        error: format!("invalid request: {e}").into(),
+                    },
+                )
+                .await
+                .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        let res = match req {
+            WsRequest::Subscribe {
+                id,
+                query,
+                from,
+                skip_rows,
+                coalesce_ms,
+                coalesce_max,
+                consistency_token,
+                consistency_timeout,
+            } => {
+                if streams.get(&id).map_or(false, |h| !h.is_finished()) {
+                    send_event(&msg_tx, &id_in_use(id)).await
+                } else {
+                    let consistency = ConsistencyParams {
+                        consistency_token,
+                        consistency_timeout,
+                    };
+                    let params = SubParams {
+                        from,
+                        skip_rows,
+                        coalesce_ms,
+                        coalesce_max,
+                    };
+                    // waiting for consistency can take a while, keep handling
+                    // this socket's other requests meanwhile
+                    let handle = tokio::spawn(subscribe_and_forward(
+                        id.clone(),
+                        agent.clone(),
+                        scope.clone(),
+                        subs_cache.clone(),
+                        bookie.clone(),
+                        tripwire.clone(),
+                        params,
+                        consistency,
+                        query,
+                        msg_tx.clone(),
+                    ));
+                    streams.insert(id, handle);
+                    Ok(())
+                }
+            }
+            WsRequest::Updates {
+                id,
+                table,
+                from,
+                change_ids,
+                options,
+            } => {
+                if streams.get(&id).map_or(false, |h| !h.is_finished()) {
+                    send_event(&msg_tx, &id_in_use(id)).await
+                } else {
+                    match subscribe_updates(
+                        &agent,
+                        &scope,
+                        &updates_cache,
+                        tripwire.clone(),
+                        &table,
+                        &options,
+                        from,
+                        change_ids,
+                    )
+                    .await
+                    {
+                        Ok((handle, rx)) => {
+                            let res = send_event(
+                                &msg_tx,
+                                &WsEvent::Subscribed {
+                                    id: id.clone(),
+                                    query_id: handle.id(),
+                                    hash: None,
+                                },
+                            )
+                            .await;
+                            streams.insert(
+                                id.clone(),
+                                tokio::spawn(forward_update_events(id, handle, rx, msg_tx.clone())),
+                            );
+                            res
+                        }
+                        Err(e) => {
+                            send_event(
+                                &msg_tx,
+                                &WsEvent::Error {
+                                    id: Some(id),
+                                    error: e.to_compact_string(),
+                                },
+                            )
+                            .await
+                        }
+                    }
+                }
+            }
+            WsRequest::Unsubscribe { id } => match streams.remove(&id) {
+                Some(handle) => {
+                    handle.abort();
+                    send_event(&msg_tx, &WsEvent::Unsubscribed { id }).await
+                }
+                None => {
+                    send_event(
+                        &msg_tx,
+                        &WsEvent::Error {
+                            error: format!("unknown subscription id '{id}'").into(),
+                            id: Some(id),
+                        },
+                    )
+                    .await
+                }
+            },
+        };
+
+        if res.is_err() {
+            break;
+        }
+    }
+
+    info!(
+        "websocket closed, dropping {} subscription stream(s)",
+        streams.len()
+    );
+
+    for (_, handle) in streams {
+        handle.abort();
+    }
+
+    drop(msg_tx);
+    _ = writer.await;
+}
+
+fn id_in_use(id: String) -> WsEvent {
+    WsEvent::Error {
+        error: format!("subscription id '{id}' is already in use").into(),
+        id: Some(id),
+    }
+}
+
+async fn send_event(
+    tx: &mpsc::Sender<Message>,
+    event: &WsEvent,
+) -> Result<(), mpsc::error::SendError<Message>> {
+    let text = serde_json::to_string(event).expect("could not serialize websocket event");
+    tx.send(Message::Text(text)).await
+}
+
+fn raw_event_message(event: RawWsEvent) -> Option<Message> {
+    match serde_json::to_string(&event) {
+        Ok(text) => Some(Message::Text(text)),
+        Err(e) => {
+            warn!("could not serialize websocket event: {e}");
+            None
+        }
+    }
+}
+
+/// Subscribes to `query` once this node is consistent with the token, if
+/// any, and forwards its events
+#[allow(clippy::too_many_arguments)]
+async fn subscribe_and_forward(
+    id: String,
+    agent: Agent,
+    scope: ApiScope,
+    subs_cache: SharedMatcherBroadcastCache,
+    bookie: Bookie,
+    tripwire: Tripwire,
+    params: SubParams,
+    consistency: ConsistencyParams,
+    query: Statement,
+    tx: mpsc::Sender<Message>,
+) {
+    let (sub_tx, rx) = mpsc::channel(10240);
+    let res = async {
+        params.check_coalesce_window(agent.config().api.max_coalesce_ms)?;
+        wait_for_consistency(&bookie, consistency).await?;
+        subscribe(
+            &agent,
+            &scope,
+            &subs_cache,
+            tripwire,
+            params,
+            &query,
+            sub_tx,
+        )
+        .await
+    }
+    .await;
+
+    let event = match res {
+        Ok((query_id, hash)) => WsEvent::Subscribed {
+            id: id.clone(),
+            query_id,
+            hash: Some(hash),
+        },
+        Err(e) => {
+            _ = send_event(
+                &tx,
+                &WsEvent::Error {
+                    id: Some(id),
+                    error: e.to_compact_string(),
+                },
+            )
+            .await;
+            return;
+        }
+    };
+
+    if send_event(&tx, &event).await.is_ok() {
+        forward_sub_events(id, rx, tx).await;
+    }
+}
+
+async fn forward_sub_events(
+    id: String,
+    mut rx: mpsc::Receiver<(Bytes, QueryEventMeta)>,
+    tx: mpsc::Sender<Message>,
+) {
+    while let Some((event_buf, _meta)) = rx.recv().await {
+        let msg = match serde_json::from_slice::<&RawValue>(&event_buf) {
+            Ok(event) => raw_event_message(RawWsEvent::Query { id: &id, event }),
+            Err(e) => {
+                warn!("could not read subscription event: {e}");
+                None
+            }
+        };
+
+        if let Some(msg) = msg {
+            if tx.send(msg).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    debug!("subscription events for websocket stream '{id}' ran out");
+}
+
+async fn forward_update_events(
+    id: String,
+    update: UpdateHandle,
+    mut rx: UpdatesReceiver,
+    tx: mpsc::Sender<Message>,
+) {
+    loop {
+        let event_buf = tokio::select! {
+            res = rx.recv() => match res {
+                Ok(event_buf) => event_buf,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(update_id = %update.id(), "update skipped {} events, aborting", skipped);
+                    _ = send_event(&tx, &WsEvent::Error {
+                        error: format!("skipped {skipped} events, aborting").into(),
+                        id: Some(id),
+                    }).await;
+                    return;
+                },
+                Err(RecvError::Closed) => {
+                    info!(update_id = %update.id(), "events subcription ran out");
+                    return;
+                },
+            },
+            _ = update.cancelled() => {
+                info!(update_id = %update.id(), "update cancelled, aborting forwarding to websocket");
+                return;
+            },
+        };
+
+        let msg = match serde_json::from_slice::<&RawValue>(&event_buf) {
+            Ok(event) => raw_event_message(RawWsEvent::Notify { id: &id, event }),
+            Err(e) => {
+                warn!("could not read update event: {e}");
+                None
+            }
+        };
+
+        if let Some(msg) = msg {
+            if tx.send(msg).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{routing::get, Router};
+    use corro_types::{
+        api::{ColumnName, ConsistencyToken, SqliteValue, Statement, TypedQueryEvent},
+        pubsub::ChangeType,
+    };
+    use hyper::StatusCode;
+    use tokio_tungstenite::tungstenite;
+
+    use super::*;
+    use crate::api::public::test_util::{launch_agent_with_schema, transact};
+
+    type Ws = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    async fn send_request(ws: &mut Ws, req: WsRequest) -> eyre::Result<()> {
+        ws.send(tungstenite::Message::Text(serde_json::to_string(&req)?))
+            .await?;
+        Ok(())
+    }
+
+    async fn next_event(ws: &mut Ws) -> eyre::Result<WsEvent> {
+        loop {
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), ws.next())
+                .await?
+                .ok_or_else(|| eyre::eyre!("websocket closed"))??;
+            if let tungstenite::Message::Text(text) = msg {
+                return Ok(serde_json::from_str(&text)?);
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_api_v1_ws() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let ta = launch_agent_with_schema(tripwire.clone()).await?;
+        let agent = ta.agent.clone();
+
+        let (status_code, _body) = transact(
+            &agent,
+            vec![Statement::WithParams(
+                "insert into tests (id, text) values (?,?)".into(),
+                vec![1i64.into(), "one".into()],
+            )],
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let subs_cache: SharedMatcherBroadcastCache = Default::default();
+        let updates_cache: SharedUpdateBroadcastCache = Default::default();
+
+        let app = Router::new()
+            .route("/v1/ws", get(api_v1_ws))
+            .layer(Extension(agent.clone()))
+            .layer(Extension(ApiScope::unrestricted()))
+            .layer(Extension(subs_cache))
+            .layer(Extension(updates_cache))
+            .layer(Extension(tripwire.clone()))
+            .layer(Extension(ta.bookie.clone()));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr: SocketAddr = listener.local_addr()?;
+        tokio::spawn(axum::Server::from_tcp(listener)?.serve(app.into_make_service()));
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/v1/ws")).await?;
+
+        send_request(
+            &mut ws,
+            WsRequest::Subscribe {
+                id: "sub".into(),
+                query: Statement::Simple("select * from tests".into()),
+                from: None,
+                skip_rows: false,
+                coalesce_ms: None,
+                coalesce_max: None,
+                consistency_token: None,
+                consistency_timeout: None,
+            },
+        )
+        .await?;
+
+        assert!(matches!(
+            next_event(&mut ws).await?,
+            WsEvent::Subscribed { id, hash: Some(_), .. } if id == "sub"
+        ));
+        assert_eq!(
+            next_event(&mut ws).await?,
+            WsEvent::Query {
+                id: "sub".into(),
+                event: TypedQueryEvent::Columns(vec![
+                    ColumnName("id".into()),
+                    ColumnName("text".into())
+                ]),
+            }
+        );
+        assert!(matches!(
+            next_event(&mut ws).await?,
+            WsEvent::Query { id, event: TypedQueryEvent::Row(_, cells) }
+                if id == "sub" && cells == vec![SqliteValue::Integer(1), "one".into()]
+        ));
+        assert!(matches!(
+            next_event(&mut ws).await?,
+            WsEvent::Query { id, event: TypedQueryEvent::EndOfQuery { .. } } if id == "sub"
+        ));
+
+        // ids have to be unique per socket
+        send_request(
+            &mut ws,
+            WsRequest::Updates {
+                id: "sub".into(),
+                table: "tests".into(),
+                from: None,
+                change_ids: false,
+                options: Default::default(),
+            },
+        )
+        .await?;
+        assert!(matches!(
+            next_event(&mut ws).await?,
+            WsEvent::Error { id: Some(id), .. } if id == "sub"
+        ));
+
+        send_request(
+            &mut ws,
+            WsRequest::Updates {
+                id: "updates".into(),
+                table: "tests".into(),
+                from: None,
+                change_ids: false,
+                options: Default::default(),
+            },
+        )
+        .await?;
+        assert!(matches!(
+            next_event(&mut ws).await?,
+            WsEvent::Subscribed { id, hash: None, .. } if id == "updates"
+        ));
+
+        let (status_code, _body) = transact(
+            &agent,
+            vec![Statement::WithParams(
+                "insert into tests (id, text) values (?,?)".into(),
+                vec![2i64.into(), "two".into()],
+            )],
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let mut got_change = false;
+        let mut got_notify = false;
+        while !(got_change && got_notify) {
+            match next_event(&mut ws).await? {
+                WsEvent::Query {
+                    id,
+                    event: TypedQueryEvent::Change(ChangeType::Insert, _, cells, _),
+                } => {
+                    assert_eq!(id, "sub");
+                    assert_eq!(cells, vec![SqliteValue::Integer(2), "two".into()]);
+                    got_change = true;
+                }
+                WsEvent::Notify { id, .. } => {
+                    assert_eq!(id, "updates");
+                    got_notify = true;
+                }
+                evt => panic!("unexpected event: {evt:?}"),
+            }
+        }
+
+        send_request(&mut ws, WsRequest::Unsubscribe { id: "sub".into() }).await?;
+        assert_eq!(
+            next_event(&mut ws).await?,
+            WsEvent::Unsubscribed { id: "sub".into() }
+        );
+
+        send_request(&mut ws, WsRequest::Unsubscribe { id: "sub".into() }).await?;
+        assert!(matches!(
+            next_event(&mut ws).await?,
+            WsEvent::Error { id: Some(id), .. } if id == "sub"
+        ));
+
+        // the write in the consistency token is never applied here
+        send_request(
+            &mut ws,
+            WsRequest::Subscribe {
+                id: "consistent".into(),
+                query: Statement::Simple("select * from tests".into()),
+                from: None,
+                skip_rows: false,
+                coalesce_ms: None,
+                coalesce_max: None,
+                consistency_token: Some(ConsistencyToken {
+                    actor_id: agent.actor_id().0,
+                    version: 1000,
+                }),
+                consistency_timeout: Some(1),
+            },
+        )
+        .await?;
+        assert!(matches!(
+            next_event(&mut ws).await?,
+            WsEvent::Error { id: Some(id), error } if id == "consistent" && error.starts_with("timed out")
+        ));
+
+        // other requests are handled while a subscription waits
+        send_request(
+            &mut ws,
+            WsRequest::Subscribe {
+                id: "waiting".into(),
+                query: Statement::Simple("select * from tests".into()),
+                from: None,
+                skip_rows: false,
+                coalesce_ms: None,
+                coalesce_max: None,
+                consistency_token: Some(ConsistencyToken {
+                    actor_id: agent.actor_id().0,
+                    version: 1000,
+                }),
+                consistency_timeout: Some(60),
+            },
+        )
+        .await?;
+        send_request(
+            &mut ws,
+            WsRequest::Unsubscribe {
+                id: "waiting".into(),
+            },
+        )
+        .await?;
+        assert_eq!(
+            next_event(&mut ws).await?,
+            WsEvent::Unsubscribed {
+                id: "waiting".into()
+            }
+        );
+
+        Ok(())
+    }
+}
@@ -3,9 +3,11 @@ use std::{
     collections::HashMap,
     fmt::{self, Write},
     hash::Hash,
-    ops::{AddAssign, Deref},
+    net::SocketAddr,
+    ops::{AddAssign, Deref, RangeInclusive},
 };
 
+use camino::Utf8PathBuf;
 use compact_str::CompactString;
 use rusqlite::{
     types::{FromSql, FromSqlError, ToSqlOutput, Value, ValueRef},
@@ -16,6 +18,7 @@ use serde_json::value::RawValue;
 use smallvec::{SmallVec, ToSmallVec};
 use speedy::{Context, Readable, Reader, Writable, Writer};
 use sqlite::ChangeType;
+use uuid::Uuid;
 
 pub mod sqlite;
 
@@ -31,6 +34,9 @@ pub enum TypedQueryEvent<T> {
         time: f64,
         #[serde(skip_serializing_if = "Option::is_none")]
         change_id: Option<ChangeId>,
+        /// Resumes a paginated query after this page, unset on the last page
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cursor: Option<String>,
     },
     Change(ChangeType, RowId, T, ChangeId),
     Error(CompactString),
@@ -60,11 +66,172 @@ pub enum QueryEventMeta {
 
 pub type NotifyEvent = TypedNotifyEvent<Vec<SqliteValue>>;
 
+/// Optional body of `POST /v1/updates/:table`
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct UpdatesRequest {
+    /// `WHERE` clause over the table's primary key columns, only changes to
+    /// matching rows are notified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    /// Include the changed columns and their new values
+    #[serde(default)]
+    pub columns: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum TypedNotifyEvent<T> {
     Notify(ChangeType, T),
+    /// `Notify` with the changed columns, for streams that asked for them
+    NotifyColumns(ChangeType, T, Vec<ColumnChange>),
     Error(CompactString),
+    /// Position of the previous notification in the stream's log, streams
+    /// can be resumed after it. Only sent to streams that asked for it.
+    ChangeId(ChangeId),
+}
+
+/// New value of a changed column
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ColumnChange {
+    pub column: ColumnName,
+    pub value: SqliteValue,
+}
+
+/// Changeset applied by a node, as streamed by `GET /v1/changes`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AppliedChangeset {
+    /// Position in the order changesets were applied by the node, the feed
+    /// can be resumed after it
+    pub apply_seq: u64,
+    /// Actor which made the changes
+    pub actor_id: Uuid,
+    /// Version of the changes for their actor
+    pub version: u64,
+    /// cr-sqlite sequences of the changes, out of `0..=last_seq` for the
+    /// whole version
+    pub seqs: RangeInclusive<u64>,
+    pub last_seq: u64,
+    /// HLC timestamp of the changes, as an NTP64
+    pub ts: u64,
+    pub changes: Vec<AppliedChange>,
+}
+
+/// Change of a row's column. Rows created or deleted have a `-1` column,
+/// with an odd causal length (`cl`) if the row exists and an even one if it
+/// was deleted.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AppliedChange {
+    pub table: TableName,
+    pub pk: Vec<SqliteValue>,
+    pub column: ColumnName,
+    pub value: SqliteValue,
+    pub col_version: i64,
+    pub seq: u64,
+    pub cl: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangesEvent {
+    Changeset(AppliedChangeset),
+    Error(CompactString),
+}
+
+/// Last write to each column of a row, as returned by `/v1/history/:table`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RowHistory {
+    pub table: TableName,
+    pub pk: Vec<SqliteValue>,
+    /// Causal length of the row, odd if it exists and even if it was deleted
+    pub cl: i64,
+    /// A `-1` column records the row's last deletion or re-insertion, if it
+    /// was ever deleted
+    pub columns: Vec<ColumnHistory>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ColumnHistory {
+    pub column: ColumnName,
+    /// Actor which last wrote the column
+    pub actor_id: Uuid,
+    pub col_version: i64,
+    /// Version of the write for its actor, not this node's version
+    pub db_version: u64,
+    pub cl: i64,
+    /// HLC timestamp of the write, as an NTP64
+    pub ts: u64,
+    /// Whether this node's bookkeeping has every change of the actor's
+    /// version, false while some of them are still being synced
+    pub booked: bool,
+}
+
+/// Control frames sent by a client over the `/v1/ws` websocket. The `id` is
+/// chosen by the client and identifies the stream in every message the
+/// server sends back for it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WsRequest {
+    /// Subscribe to a query, same as `POST /v1/subscriptions`
+    Subscribe {
+        id: String,
+        query: Statement,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<ChangeId>,
+        #[serde(default)]
+        skip_rows: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        coalesce_ms: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        coalesce_max: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        consistency_token: Option<ConsistencyToken>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        consistency_timeout: Option<u64>,
+    },
+    /// Listen for updates to a table, same as `POST /v1/updates/:table`
+    Updates {
+        id: String,
+        table: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<ChangeId>,
+        #[serde(default)]
+        change_ids: bool,
+        #[serde(flatten)]
+        options: UpdatesRequest,
+    },
+    Unsubscribe {
+        id: String,
+    },
+}
+
+pub type WsEvent = TypedWsEvent<Vec<SqliteValue>>;
+
+/// Messages sent by the server over the `/v1/ws` websocket
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TypedWsEvent<T> {
+    Subscribed {
+        id: String,
+        query_id: Uuid,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hash: Option<String>,
+    },
+    Unsubscribed {
+        id: String,
+    },
+    Query {
+        id: String,
+        event: TypedQueryEvent<T>,
+    },
+    Notify {
+        id: String,
+        event: TypedNotifyEvent<T>,
+    },
+    Error {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        error: CompactString,
+    },
 }
 
 /// RowId newtype to differentiate from ChangeId
@@ -215,12 +382,189 @@ impl From<&str> for Statement {
     }
 }
 
+/// Body of a transaction request: either a bare list of statements, or
+/// statements guarded by preconditions
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TransactionRequest {
+    Statements(Vec<Statement>),
+    Conditional {
+        statements: Vec<Statement>,
+        #[serde(default)]
+        preconditions: Vec<Precondition>,
+    },
+}
+
+impl TransactionRequest {
+    pub fn into_parts(self) -> (Vec<Statement>, Vec<Precondition>) {
+        match self {
+            TransactionRequest::Statements(statements) => (statements, vec![]),
+            TransactionRequest::Conditional {
+                statements,
+                preconditions,
+            } => (statements, preconditions),
+        }
+    }
+}
+
+impl From<Vec<Statement>> for TransactionRequest {
+    fn from(statements: Vec<Statement>) -> Self {
+        TransactionRequest::Statements(statements)
+    }
+}
+
+/// Checked within the transaction, before any of its statements run. The
+/// whole transaction is aborted if one doesn't hold.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Precondition {
+    /// The statement has to return exactly these rows, in order
+    Query {
+        statement: Statement,
+        rows: Vec<Vec<SqliteValue>>,
+    },
+    /// The latest change to a row, according to the crsql clock, has to be
+    /// the write identified by `version`. A `null` version means the row must
+    /// never have been written.
+    RowVersion {
+        table: String,
+        pk: Vec<SqliteValue>,
+        version: Option<ConsistencyToken>,
+    },
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ExecResponse {
     pub results: Vec<ExecResult>,
     pub time: f64,
     pub version: Option<u64>,
     pub actor_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replication: Option<ReplicationStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consistency_token: Option<ConsistencyToken>,
+}
+
+/// Identifies a write by the actor which made it and its db version. Passing
+/// it to a read on another node makes it wait until that write is applied.
+///
+/// Serialized as `<actor_id>:<version>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct ConsistencyToken {
+    pub actor_id: Uuid,
+    pub version: u64,
+}
+
+impl fmt::Display for ConsistencyToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.actor_id, self.version)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid consistency token, expected <actor_id>:<version>")]
+pub struct InvalidConsistencyToken;
+
+impl std::str::FromStr for ConsistencyToken {
+    type Err = InvalidConsistencyToken;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (actor_id, version) = s.split_once(':').ok_or(InvalidConsistencyToken)?;
+        Ok(ConsistencyToken {
+            actor_id: actor_id.parse().map_err(|_| InvalidConsistencyToken)?,
+            version: version.parse().map_err(|_| InvalidConsistencyToken)?,
+        })
+    }
+}
+
+impl From<ConsistencyToken> for String {
+    fn from(token: ConsistencyToken) -> Self {
+        token.to_string()
+    }
+}
+
+impl TryFrom<String> for ConsistencyToken {
+    type Error = InvalidConsistencyToken;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Address of the HTTP API, either a TCP socket address or the path of a
+/// unix domain socket.
+///
+/// Serialized as `<ip>:<port>` or `unix:<path>`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum ApiAddr {
+    Tcp(SocketAddr),
+    Unix(Utf8PathBuf),
+}
+
+impl ApiAddr {
+    /// Authority to use in the URIs of requests made to this address
+    pub fn authority(&self) -> String {
+        match self {
+            ApiAddr::Tcp(addr) => addr.to_string(),
+            ApiAddr::Unix(_) => "localhost".into(),
+        }
+    }
+}
+
+impl fmt::Display for ApiAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiAddr::Tcp(addr) => addr.fmt(f),
+            ApiAddr::Unix(path) => write!(f, "unix:{path}"),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid API address, expected <ip>:<port> or unix:<path>")]
+pub struct InvalidApiAddr;
+
+impl std::str::FromStr for ApiAddr {
+    type Err = InvalidApiAddr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err(InvalidApiAddr),
+            Some(path) => Ok(ApiAddr::Unix(path.into())),
+            None => Ok(ApiAddr::Tcp(s.parse().map_err(|_| InvalidApiAddr)?)),
+        }
+    }
+}
+
+impl From<SocketAddr> for ApiAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ApiAddr::Tcp(addr)
+    }
+}
+
+impl From<ApiAddr> for String {
+    fn from(addr: ApiAddr) -> Self {
+        addr.to_string()
+    }
+}
+
+impl TryFrom<String> for ApiAddr {
+    type Error = InvalidApiAddr;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Members which confirmed having the transaction's version, only set when
+/// waiting for replication was requested
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplicationStatus {
+    /// Whether enough members confirmed before the timeout
+    pub complete: bool,
+    pub confirmed_by: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -241,6 +585,66 @@ pub struct TableStatResponse {
     pub invalid_tables: Vec<String>,
 }
 
+/// Replication state of a node, as reported by `/v1/health`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HealthResponse {
+    pub healthy: bool,
+    /// Gossip members in this node's cluster, including itself
+    pub members: usize,
+    /// Members in the lowest latency ring
+    pub ring0: usize,
+    /// Versions known to exist but not yet synced
+    pub needed: u64,
+    /// Changesets waiting to be applied
+    pub apply_queue_len: usize,
+    /// Seconds since the last successful sync, if there has been one
+    pub last_sync_age: Option<f64>,
+    /// Thresholds this node is past
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failures: Vec<String>,
+}
+
+/// `EXPLAIN QUERY PLAN` of a statement and, when it can be subscribed to,
+/// how subscriptions rewrite it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct QueryPlan {
+    pub plan: Vec<QueryPlanNode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subscription: Option<SubscriptionPlan>,
+    /// Why the statement can't be used for a subscription
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subscription_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct QueryPlanNode {
+    pub id: i64,
+    pub detail: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<QueryPlanNode>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SubscriptionPlan {
+    /// The statement with the primary key columns of every table prepended
+    pub query: String,
+    pub tables: Vec<SubscriptionTablePlan>,
+}
+
+/// How changes to a table are matched against a subscription
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SubscriptionTablePlan {
+    pub table: String,
+    /// Result columns holding the table's primary key
+    pub pks: Vec<String>,
+    /// Columns of the table the statement reads
+    pub columns: Vec<String>,
+    /// Query run against changed rows of the table
+    pub query: String,
+    /// Query selecting the affected rows from the subscription's results
+    pub temp_query: String,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct SqliteValueRef<'a>(pub ValueRef<'a>);
 
@@ -896,4 +1300,45 @@ mod tests {
         let stmts: Vec<Statement> = serde_json::from_str(json).unwrap();
         println!("stmts: {stmts:?}");
     }
+
+    #[test]
+    fn test_consistency_token_roundtrip() {
+        let token = ConsistencyToken {
+            actor_id: Uuid::new_v4(),
+            version: 42,
+        };
+
+        let json = serde_json::to_string(&token).unwrap();
+        assert_eq!(json, format!("\"{}:42\"", token.actor_id));
+        assert_eq!(
+            serde_json::from_str::<ConsistencyToken>(&json).unwrap(),
+            token
+        );
+
+        assert!("42".parse::<ConsistencyToken>().is_err());
+        assert!(format!("{}:", token.actor_id)
+            .parse::<ConsistencyToken>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_api_addr_roundtrip() {
+        for (s, addr) in [
+            (
+                "127.0.0.1:8080",
+                ApiAddr::Tcp("127.0.0.1:8080".parse().unwrap()),
+            ),
+            (
+                "unix:/var/run/corrosion/api.sock",
+                ApiAddr::Unix("/var/run/corrosion/api.sock".into()),
+            ),
+        ] {
+            assert_eq!(s.parse::<ApiAddr>().unwrap(), addr);
+            assert_eq!(addr.to_string(), s);
+            assert_eq!(serde_json::to_string(&addr).unwrap(), format!("\"{s}\""));
+        }
+
+        assert!("unix:".parse::<ApiAddr>().is_err());
+        assert!("localhost".parse::<ApiAddr>().is_err());
+    }
 }
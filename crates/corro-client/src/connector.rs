@@ -0,0 +1,137 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use camino::Utf8PathBuf;
+use corro_api_types::ApiAddr;
+use hyper::{
+    client::{
+        connect::{Connected, Connection},
+        HttpConnector,
+    },
+    service::Service,
+    Uri,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpStream, UnixStream},
+};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Connects to the API over TCP, or to its unix socket regardless of the
+/// request's URI
+#[derive(Clone)]
+pub struct ApiConnector {
+    http: HttpConnector,
+    unix_path: Option<Arc<Utf8PathBuf>>,
+    connect_timeout: Duration,
+}
+
+impl ApiConnector {
+    pub fn new(api_addr: &ApiAddr, connect_timeout: Duration) -> Self {
+        let mut http = HttpConnector::new();
+        http.set_connect_timeout(Some(connect_timeout));
+        Self {
+            http,
+            unix_path: match api_addr {
+                ApiAddr::Tcp(_) => None,
+                ApiAddr::Unix(path) => Some(Arc::new(path.clone())),
+            },
+            connect_timeout,
+        }
+    }
+}
+
+impl Service<Uri> for ApiConnector {
+    type Response = ApiStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<ApiStream, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.unix_path {
+            Some(_) => Poll::Ready(Ok(())),
+            None => self.http.poll_ready(cx).map_err(Into::into),
+        }
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        match self.unix_path.clone() {
+            Some(path) => {
+                let connect_timeout = self.connect_timeout;
+                Box::pin(async move {
+                    let stream = tokio::time::timeout(
+                        connect_timeout,
+                        UnixStream::connect(path.as_std_path()),
+                    )
+                    .await
+                    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+                    Ok(ApiStream::Unix(stream))
+                })
+            }
+            None => {
+                let connecting = self.http.call(uri);
+                Box::pin(async move { Ok(ApiStream::Tcp(connecting.await?)) })
+            }
+        }
+    }
+}
+
+pub enum ApiStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Connection for ApiStream {
+    fn connected(&self) -> Connected {
+        match self {
+            ApiStream::Tcp(stream) => stream.connected(),
+            ApiStream::Unix(_) => Connected::new(),
+        }
+    }
+}
+
+impl AsyncRead for ApiStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ApiStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            ApiStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ApiStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ApiStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            ApiStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ApiStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            ApiStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ApiStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            ApiStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
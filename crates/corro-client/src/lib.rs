@@ -1,13 +1,18 @@
+pub mod connector;
 pub mod sub;
 
-use corro_api_types::{ChangeId, ExecResponse, ExecResult, SqliteValue, Statement};
+use connector::ApiConnector;
+use corro_api_types::{
+    ApiAddr, ChangeId, ExecResponse, ExecResult, Precondition, RowHistory, SqliteValue, Statement,
+    UpdatesRequest,
+};
 use hickory_resolver::{
     error::{ResolveError, ResolveErrorKind},
     name_server::TokioConnectionProvider,
     AsyncResolver,
 };
 use http::uri::PathAndQuery;
-use hyper::{client::HttpConnector, http::HeaderName, Body, StatusCode};
+use hyper::{http::HeaderName, Body, StatusCode};
 use serde::de::DeserializeOwned;
 use std::{
     net::SocketAddr,
@@ -30,14 +35,16 @@ const DNS_RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
 
 #[derive(Clone)]
 pub struct CorrosionApiClient {
-    api_addr: SocketAddr,
-    api_client: hyper::Client<HttpConnector, Body>,
+    api_addr: ApiAddr,
+    api_client: hyper::Client<ApiConnector, Body>,
 }
 
 impl CorrosionApiClient {
-    pub fn new(api_addr: SocketAddr) -> Self {
-        let mut connector = HttpConnector::new();
-        connector.set_connect_timeout(Some(HTTP2_CONNECT_TIMEOUT));
+    /// Client of the API at a TCP address or, with [`ApiAddr::Unix`], at the
+    /// path of its unix socket
+    pub fn new(api_addr: impl Into<ApiAddr>) -> Self {
+        let api_addr = api_addr.into();
+        let connector = ApiConnector::new(&api_addr, HTTP2_CONNECT_TIMEOUT);
         Self {
             api_addr,
             api_client: hyper::Client::builder()
@@ -58,7 +65,11 @@ impl CorrosionApiClient {
             .unwrap_or_default();
         let req = hyper::Request::builder()
             .method(hyper::Method::POST)
-            .uri(format!("http://{}/v1/queries{}", self.api_addr, params))
+            .uri(format!(
+                "http://{}/v1/queries{}",
+                self.api_addr.authority(),
+                params
+            ))
             .header(hyper::header::CONTENT_TYPE, "application/json")
             .header(hyper::header::ACCEPT, "application/json")
             .body(Body::from(serde_json::to_vec(statement)?))?;
@@ -119,7 +130,7 @@ impl CorrosionApiClient {
         };
         let url = hyper::Uri::builder()
             .scheme("http")
-            .authority(self.api_addr.to_string())
+            .authority(self.api_addr.authority())
             .path_and_query(p_and_q)
             .build()?;
 
@@ -151,7 +162,7 @@ impl CorrosionApiClient {
             id,
             hash,
             self.api_client.clone(),
-            self.api_addr,
+            self.api_addr.clone(),
             res.into_body(),
             from,
         ))
@@ -183,7 +194,7 @@ impl CorrosionApiClient {
         };
         let url = hyper::Uri::builder()
             .scheme("http")
-            .authority(self.api_addr.to_string())
+            .authority(self.api_addr.authority())
             .path_and_query(p_and_q)
             .build()?;
 
@@ -208,7 +219,7 @@ impl CorrosionApiClient {
             id,
             hash,
             self.api_client.clone(),
-            self.api_addr,
+            self.api_addr.clone(),
             res.into_body(),
             from,
         ))
@@ -227,11 +238,31 @@ impl CorrosionApiClient {
         &self,
         table: &str,
     ) -> Result<UpdatesStream<T>, Error> {
-        let p_and_q: PathAndQuery = format!("/v1/updates/{}", table).try_into()?;
+        self.updates_with_typed(table, &UpdatesRequest::default(), None, false)
+            .await
+    }
+
+    /// Updates for `table`, restricted by a filter on its primary key columns
+    /// and optionally including changed columns, see [`UpdatesRequest`].
+    /// Notifications sent after change `from` are replayed first. With
+    /// `change_ids`, or when resuming `from` a change, each notification is
+    /// followed by its change id to resume from.
+    pub async fn updates_with_typed<T: DeserializeOwned + Unpin>(
+        &self,
+        table: &str,
+        req: &UpdatesRequest,
+        from: Option<ChangeId>,
+        change_ids: bool,
+    ) -> Result<UpdatesStream<T>, Error> {
+        let p_and_q: PathAndQuery = match from {
+            Some(change_id) => format!("/v1/updates/{}?from={}", table, change_id.0).try_into()?,
+            None if change_ids => format!("/v1/updates/{}?change_ids=true", table).try_into()?,
+            None => format!("/v1/updates/{}", table).try_into()?,
+        };
 
         let url = hyper::Uri::builder()
             .scheme("http")
-            .authority(self.api_addr.to_string())
+            .authority(self.api_addr.authority())
             .path_and_query(p_and_q)
             .build()?;
 
@@ -240,7 +271,7 @@ impl CorrosionApiClient {
             .uri(url)
             .header(hyper::header::CONTENT_TYPE, "application/json")
             .header(hyper::header::ACCEPT, "application/json")
-            .body(hyper::Body::empty())?;
+            .body(Body::from(serde_json::to_vec(req)?))?;
 
         let res = self.api_client.request(req).await?;
 
@@ -262,18 +293,52 @@ impl CorrosionApiClient {
         self.updates_typed(table).await
     }
 
+    pub async fn updates_with(
+        &self,
+        table: &str,
+        req: &UpdatesRequest,
+        from: Option<ChangeId>,
+        change_ids: bool,
+    ) -> Result<UpdatesStream<Vec<SqliteValue>>, Error> {
+        self.updates_with_typed(table, req, from, change_ids).await
+    }
+
     pub async fn execute(
         &self,
         statements: &[Statement],
         timeout: Option<u64>,
     ) -> Result<ExecResponse, Error> {
+        self.transact(serde_json::to_vec(statements)?, timeout)
+            .await
+    }
+
+    /// Executes the statements only if all preconditions hold, the response
+    /// is an [`Error::ResponseError`] otherwise
+    pub async fn execute_conditional(
+        &self,
+        statements: &[Statement],
+        preconditions: &[Precondition],
+        timeout: Option<u64>,
+    ) -> Result<ExecResponse, Error> {
+        self.transact(
+            serde_json::to_vec(&serde_json::json!({
+                "statements": statements,
+                "preconditions": preconditions,
+            }))?,
+            timeout,
+        )
+        .await
+    }
+
+    async fn transact(&self, body: Vec<u8>, timeout: Option<u64>) -> Result<ExecResponse, Error> {
         let uri = if let Some(timeout) = timeout {
             format!(
                 "http://{}/v1/transactions?timeout={}",
-                self.api_addr, timeout
+                self.api_addr.authority(),
+                timeout
             )
         } else {
-            format!("http://{}/v1/transactions", self.api_addr)
+            format!("http://{}/v1/transactions", self.api_addr.authority())
         };
         // println!("uri: {:?}", uri);
         let req = hyper::Request::builder()
@@ -281,7 +346,7 @@ impl CorrosionApiClient {
             .uri(uri)
             .header(hyper::header::CONTENT_TYPE, "application/json")
             .header(hyper::header::ACCEPT, "application/json")
-            .body(Body::from(serde_json::to_vec(statements)?))?;
+            .body(Body::from(body))?;
 
         let res = self.api_client.request(req).await?;
 
@@ -321,10 +386,40 @@ impl CorrosionApiClient {
         Ok(serde_json::from_slice(&bytes)?)
     }
 
+    /// Which actor last wrote each column of the row of `table` with the
+    /// primary key `pk`, and when
+    pub async fn row_history(&self, table: &str, pk: &[SqliteValue]) -> Result<RowHistory, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(format!(
+                "http://{}/v1/history/{table}",
+                self.api_addr.authority()
+            ))
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::from(serde_json::to_vec(pk)?))?;
+
+        let res = self.api_client.request(req).await?;
+
+        let status = res.status();
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+        if !status.is_success() {
+            return Err(match String::from_utf8(bytes.to_vec()) {
+                Ok(error) if !error.is_empty() => Error::ResponseError(error),
+                _ => Error::UnexpectedStatusCode(status),
+            });
+        }
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
     pub async fn schema(&self, statements: &[Statement]) -> Result<ExecResponse, Error> {
         let req = hyper::Request::builder()
             .method(hyper::Method::POST)
-            .uri(format!("http://{}/v1/migrations", self.api_addr))
+            .uri(format!(
+                "http://{}/v1/migrations",
+                self.api_addr.authority()
+            ))
             .header(hyper::header::CONTENT_TYPE, "application/json")
             .header(hyper::header::ACCEPT, "application/json")
             .body(Body::from(serde_json::to_vec(statements)?))?;
@@ -366,7 +461,7 @@ pub struct CorrosionClient {
 }
 
 impl CorrosionClient {
-    pub fn new<P: AsRef<Path>>(api_addr: SocketAddr, db_path: P) -> Self {
+    pub fn new<P: AsRef<Path>>(api_addr: impl Into<ApiAddr>, db_path: P) -> Self {
         Self {
             api_client: CorrosionApiClient::new(api_addr),
             pool: sqlite_pool::Config::new(db_path.as_ref())
@@ -376,7 +471,7 @@ impl CorrosionClient {
         }
     }
 
-    pub fn with_sqlite_pool(api_addr: SocketAddr, pool: sqlite_pool::RusqlitePool) -> Self {
+    pub fn with_sqlite_pool(api_addr: impl Into<ApiAddr>, pool: sqlite_pool::RusqlitePool) -> Self {
         Self {
             api_client: CorrosionApiClient::new(api_addr),
             pool,
@@ -689,8 +784,9 @@ pub enum Error {
 
 #[cfg(test)]
 mod tests {
-    use crate::{CorrosionPooledClient, Error};
-    use corro_api_types::SqliteValue;
+    use crate::{CorrosionApiClient, CorrosionPooledClient, Error};
+    use corro_api_types::{sqlite::ChangeType, ChangeId, SqliteValue, TypedNotifyEvent};
+    use futures::StreamExt;
     use hickory_resolver::AsyncResolver;
     use hyper::{header::HeaderValue, service::service_fn, Body, Request, Response};
     use std::{
@@ -698,7 +794,7 @@ mod tests {
         net::SocketAddr,
         sync::{
             atomic::{AtomicBool, Ordering},
-            Arc,
+            Arc, Mutex,
         },
         time::Duration,
     };
@@ -772,6 +868,82 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_updates_without_change_ids() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let uris = Arc::new(Mutex::new(Vec::new()));
+
+        tokio::spawn({
+            let uris = uris.clone();
+            async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let uris = uris.clone();
+                    tokio::spawn(async move {
+                        let service = service_fn(move |req: Request<Body>| {
+                            let uris = uris.clone();
+                            async move {
+                                let uri = req.uri().to_string();
+                                // same as the agent, change ids are opt-in
+                                let mut body = r#"{"notify":["update",[1]]}"#.to_owned() + "\n";
+                                if uri.contains("change_ids=true") || uri.contains("from=") {
+                                    body.push_str("{\"change_id\":1}\n");
+                                }
+                                uris.lock().unwrap().push(uri);
+
+                                let mut res = Response::new(Body::from(body));
+                                res.headers_mut().insert(
+                                    "corro-query-id",
+                                    HeaderValue::from_str(&Uuid::new_v4().to_string()).unwrap(),
+                                );
+                                Ok::<_, Infallible>(res)
+                            }
+                        });
+                        _ = hyper::server::conn::Http::new()
+                            .serve_connection(stream, service)
+                            .await;
+                    });
+                }
+            }
+        });
+
+        let client = CorrosionApiClient::new(addr);
+
+        let events = client
+            .updates("tests")
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert!(matches!(
+            &events[..],
+            [Ok(TypedNotifyEvent::Notify(ChangeType::Update, pk))] if pk == &vec![SqliteValue::Integer(1)]
+        ));
+
+        let events = client
+            .updates_with("tests", &Default::default(), None, true)
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert!(matches!(
+            &events[..],
+            [
+                Ok(TypedNotifyEvent::Notify(..)),
+                Ok(TypedNotifyEvent::ChangeId(ChangeId(1)))
+            ]
+        ));
+
+        assert_eq!(
+            *uris.lock().unwrap(),
+            vec![
+                format!("http://{addr}/v1/updates/tests"),
+                format!("http://{addr}/v1/updates/tests?change_ids=true"),
+            ]
+        );
+    }
+
     async fn gen_servers(num: usize) -> (Vec<Server>, Vec<String>) {
         let mut servers = Vec::new();
 
@@ -1,16 +1,15 @@
 use std::{
     error::Error,
     io,
-    net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
     time::Duration,
 };
 
 use bytes::{Buf, Bytes, BytesMut};
-use corro_api_types::{ChangeId, QueryEvent, TypedNotifyEvent, TypedQueryEvent};
+use corro_api_types::{ApiAddr, ChangeId, QueryEvent, TypedNotifyEvent, TypedQueryEvent};
 use futures::{ready, Future, Stream};
-use hyper::{client::HttpConnector, Body};
+use hyper::Body;
 use pin_project_lite::pin_project;
 use serde::de::DeserializeOwned;
 use tokio::time::{sleep, Sleep};
@@ -21,6 +20,8 @@ use tokio_util::{
 use tracing::error;
 use uuid::Uuid;
 
+use crate::connector::ApiConnector;
+
 pin_project! {
     pub struct IoBodyStream {
         #[pin]
@@ -57,8 +58,8 @@ type FramedBody = FramedRead<IoBodyStreamReader, LinesBytesCodec>;
 pub struct SubscriptionStream<T> {
     id: Uuid,
     hash: Option<String>,
-    client: hyper::Client<HttpConnector, Body>,
-    api_addr: SocketAddr,
+    client: hyper::Client<ApiConnector, Body>,
+    api_addr: ApiAddr,
     observed_eoq: bool,
     last_change_id: Option<ChangeId>,
     stream: Option<FramedBody>,
@@ -93,8 +94,8 @@ where
     pub fn new(
         id: Uuid,
         hash: Option<String>,
-        client: hyper::Client<HttpConnector, Body>,
-        api_addr: SocketAddr,
+        client: hyper::Client<ApiConnector, Body>,
+        api_addr: ApiAddr,
         body: hyper::Body,
         change_id: Option<ChangeId>,
     ) -> Self {
@@ -124,8 +125,8 @@ where
         self.hash.as_deref()
     }
 
-    pub fn api_addr(&self) -> SocketAddr {
-        self.api_addr
+    pub fn api_addr(&self) -> &ApiAddr {
+        &self.api_addr
     }
 
     fn poll_stream(
@@ -246,7 +247,7 @@ where
                     .method(hyper::Method::GET)
                     .uri(format!(
                         "http://{}/v1/subscriptions/{}?from={}",
-                        self.api_addr,
+                        self.api_addr.authority(),
                         self.id,
                         self.last_change_id.unwrap_or_default()
                     ))
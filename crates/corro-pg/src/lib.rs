@@ -48,7 +48,6 @@ use rusqlite::{
     ffi::SQLITE_CONSTRAINT_UNIQUE, functions::FunctionFlags, types::ValueRef,
     vtab::eponymous_only_module, Connection, Statement,
 };
-use rustls::ServerConfig;
 use socket2::{SockRef, TcpKeepalive};
 use spawn::spawn_counted;
 use sqlite3_parser::ast::{
@@ -475,71 +474,7 @@ async fn setup_tls(pg: PgConfig) -> eyre::Result<(Option<TlsAcceptor>, bool)> {
 
     let ssl_required = tls.verify_client;
 
-    let key = tokio::fs::read(&tls.key_file).await?;
-    let key = if tls.key_file.extension().map_or(false, |x| x == "der") {
-        rustls::PrivateKey(key)
-    } else {
-        let pkcs8 = rustls_pemfile::pkcs8_private_keys(&mut &*key)?;
-        match pkcs8.into_iter().next() {
-            Some(x) => rustls::PrivateKey(x),
-            None => {
-                let rsa = rustls_pemfile::rsa_private_keys(&mut &*key)?;
-                match rsa.into_iter().next() {
-                    Some(x) => rustls::PrivateKey(x),
-                    None => {
-                        eyre::bail!("no private keys found");
-                    }
-                }
-            }
-        }
-    };
-
-    let certs = tokio::fs::read(&tls.cert_file).await?;
-    let certs = if tls.cert_file.extension().map_or(false, |x| x == "der") {
-        vec![rustls::Certificate(certs)]
-    } else {
-        rustls_pemfile::certs(&mut &*certs)?
-            .into_iter()
-            .map(rustls::Certificate)
-            .collect()
-    };
-
-    let server_crypto = ServerConfig::builder().with_safe_defaults();
-
-    let server_crypto = if ssl_required {
-        let ca_file = match &tls.ca_file {
-            None => {
-                eyre::bail!(
-                    "ca_file required in tls config for server client cert auth verification"
-                );
-            }
-            Some(ca_file) => ca_file,
-        };
-
-        let ca_certs = tokio::fs::read(&ca_file).await?;
-        let ca_certs = if ca_file.extension().map_or(false, |x| x == "der") {
-            vec![rustls::Certificate(ca_certs)]
-        } else {
-            rustls_pemfile::certs(&mut &*ca_certs)?
-                .into_iter()
-                .map(rustls::Certificate)
-                .collect()
-        };
-
-        let mut root_store = rustls::RootCertStore::empty();
-
-        for cert in ca_certs {
-            root_store.add(&cert)?;
-        }
-
-        server_crypto.with_client_cert_verifier(Arc::new(
-            rustls::server::AllowAnyAuthenticatedClient::new(root_store),
-        ))
-    } else {
-        server_crypto.with_no_client_auth()
-    };
-
-    let config = server_crypto.with_single_cert(certs, key)?;
+    let config = corro_types::tls::server_config(&tls)?;
     Ok((Some(TlsAcceptor::from(Arc::new(config))), ssl_required))
 }
 
@@ -20,7 +20,7 @@ use camino::Utf8PathBuf;
 use compact_str::{CompactString, ToCompactString};
 use indexmap::IndexMap;
 use metrics::{gauge, histogram};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use rangemap::RangeInclusiveSet;
 use rusqlite::{named_params, Connection, OptionalExtension, Transaction};
 use serde::{Deserialize, Serialize};
@@ -45,6 +45,7 @@ use crate::{
     actor::{Actor, ActorId, ClusterId},
     base::{CrsqlDbVersion, CrsqlSeq},
     broadcast::{BroadcastInput, ChangeSource, ChangeV1, FocaInput, Timestamp},
+    cdc::ChangesFeed,
     channel::{bounded, CorroSender},
     config::Config,
     pubsub::SubsManager,
@@ -67,7 +68,8 @@ pub struct AgentConfig {
     pub config: ArcSwap<Config>,
     pub gossip_addr: SocketAddr,
     pub external_addr: Option<SocketAddr>,
-    pub api_addr: SocketAddr,
+    /// First TCP address the API listens on, if any
+    pub api_addr: Option<SocketAddr>,
     pub members: RwLock<Members>,
     pub clock: Arc<uhlc::HLC>,
 
@@ -88,6 +90,8 @@ pub struct AgentConfig {
 
     pub updates_manager: UpdatesManager,
 
+    pub changes_feed: ChangesFeed,
+
     pub tripwire: Tripwire,
 }
 
@@ -97,7 +101,7 @@ pub struct AgentInner {
     config: ArcSwap<Config>,
     gossip_addr: SocketAddr,
     external_addr: Option<SocketAddr>,
-    api_addr: SocketAddr,
+    api_addr: Option<SocketAddr>,
     members: RwLock<Members>,
     clock: Arc<uhlc::HLC>,
     booked: Booked,
@@ -112,6 +116,8 @@ pub struct AgentInner {
     limits: Limits,
     subs_manager: SubsManager,
     updates_manager: UpdatesManager,
+    changes_feed: ChangesFeed,
+    replication: ReplicationStats,
 }
 
 #[derive(Debug, Clone)]
@@ -119,6 +125,34 @@ pub struct Limits {
     pub sync: Arc<Semaphore>,
 }
 
+/// Replication progress that isn't otherwise kept around, reported by `/v1/health`
+#[derive(Debug, Default)]
+pub struct ReplicationStats {
+    last_sync: Mutex<Option<Instant>>,
+    apply_queue_len: AtomicUsize,
+}
+
+impl ReplicationStats {
+    /// Records a sync that completed, or that had no peers to sync with
+    pub fn record_sync(&self) {
+        *self.last_sync.lock() = Some(Instant::now());
+    }
+
+    /// Time since the last successful sync, `None` if there hasn't been one yet
+    pub fn last_sync_age(&self) -> Option<Duration> {
+        self.last_sync.lock().map(|at| at.elapsed())
+    }
+
+    pub fn set_apply_queue_len(&self, len: usize) {
+        self.apply_queue_len.store(len, Ordering::Relaxed);
+    }
+
+    /// Changesets received but not yet handed off to be applied
+    pub fn apply_queue_len(&self) -> usize {
+        self.apply_queue_len.load(Ordering::Relaxed)
+    }
+}
+
 impl Agent {
     pub fn new(config: AgentConfig) -> Self {
         Self(Arc::new(AgentInner {
@@ -144,6 +178,8 @@ impl Agent {
             },
             subs_manager: config.subs_manager,
             updates_manager: config.updates_manager,
+            changes_feed: config.changes_feed,
+            replication: ReplicationStats::default(),
         }))
     }
 
@@ -177,7 +213,9 @@ impl Agent {
         self.0.external_addr
     }
 
-    pub fn api_addr(&self) -> SocketAddr {
+    /// First TCP address the API listens on, if it doesn't only listen on
+    /// unix sockets
+    pub fn api_addr(&self) -> Option<SocketAddr> {
         self.0.api_addr
     }
 
@@ -249,6 +287,14 @@ impl Agent {
         &self.0.updates_manager
     }
 
+    pub fn changes_feed(&self) -> &ChangesFeed {
+        &self.0.changes_feed
+    }
+
+    pub fn replication(&self) -> &ReplicationStats {
+        &self.0.replication
+    }
+
     pub fn set_cluster_id(&self, cluster_id: ClusterId) {
         self.0.cluster_id.store(Arc::new(cluster_id));
     }
@@ -274,6 +320,7 @@ pub fn migrate(clock: Arc<uhlc::HLC>, conn: &mut Connection) -> rusqlite::Result
     let migrations: Vec<Box<dyn Migration>> = vec![
         Box::new(init_migration as fn(&Transaction) -> rusqlite::Result<()>),
         Box::new(crsqlite_v0_17_migration(clock)),
+        Box::new(idempotency_keys_migration as fn(&Transaction) -> rusqlite::Result<()>),
     ];
 
     crate::sqlite::migrate(conn, migrations)
@@ -416,6 +463,33 @@ fn crsqlite_v0_17_migration(
     }
 }
 
+fn idempotency_keys_migration(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+            -- transactions' idempotency keys, to replay responses on retries
+            CREATE TABLE __corro_idempotency_keys (
+                -- name of the API token used, empty if unnamed
+                scope TEXT NOT NULL,
+                key TEXT NOT NULL,
+                -- seahash of the request, retries have to match it
+                request_hash INTEGER NOT NULL,
+
+                -- local db_version of the transaction, if it changed anything
+                db_version INTEGER,
+                -- JSON-encoded statement results
+                results TEXT NOT NULL,
+
+                -- unix timestamp, in seconds
+                created_at INTEGER NOT NULL,
+
+                PRIMARY KEY (scope, key)
+            ) WITHOUT ROWID;
+
+            CREATE INDEX __corro_idempotency_keys_created_at ON __corro_idempotency_keys (created_at);
+        "#,
+    )
+}
+
 #[derive(Debug, Clone)]
 pub struct SplitPool(Arc<SplitPoolInner>);
 
@@ -460,6 +534,14 @@ pub enum ChangeError {
     },
     #[error("non-contiguous empties range delete")]
     NonContiguousDelete,
+    #[error("precondition {index} failed: {reason}")]
+    PreconditionFailed { index: usize, reason: String },
+    #[error("idempotency key was already used for a different request")]
+    IdempotencyKeyReused,
+    #[error("rows rate limit exceeded")]
+    RowsRateLimited,
+    #[error("{rows} rows exceed the burst of {max} rows allowed by the rate limit")]
+    TooManyRows { rows: usize, max: u32 },
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -67,6 +67,12 @@ pub enum BiPayloadV1 {
         #[speedy(default_on_eof)]
         trace_ctx: SyncTraceContextV1,
     },
+    /// Asks whether a version of an actor's changes was applied, without
+    /// going through a sync
+    HasVersion {
+        actor_id: ActorId,
+        version: CrsqlDbVersion,
+    },
 }
 
 #[derive(Debug)]
@@ -544,22 +550,23 @@ pub async fn broadcast_changes(
                     match_changes(agent.subs_manager(), &changes, db_version);
                     match_changes(agent.updates_manager(), &changes, db_version);
 
+                    let change = ChangeV1 {
+                        actor_id,
+                        changeset: Changeset::Full {
+                            version: db_version,
+                            changes,
+                            seqs,
+                            last_seq,
+                            ts,
+                        },
+                    };
+                    agent.changes_feed().record(change.clone());
+
                     let tx_bcast = agent.tx_bcast().clone();
                     assert_sometimes!(true, "Corrosion broadcasts changes");
                     tokio::spawn(async move {
                         if let Err(e) = tx_bcast
-                            .send(BroadcastInput::AddBroadcast(BroadcastV1::Change(
-                                ChangeV1 {
-                                    actor_id,
-                                    changeset: Changeset::Full {
-                                        version: db_version,
-                                        changes,
-                                        seqs,
-                                        last_seq,
-                                        ts,
-                                    },
-                                },
-                            )))
+                            .send(BroadcastInput::AddBroadcast(BroadcastV1::Change(change)))
                             .await
                         {
                             error!("could not send change message for broadcast: {e}");
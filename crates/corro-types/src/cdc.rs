@@ -0,0 +1,450 @@
+//! Log of the changesets applied by this node, from local transactions,
+//! broadcasts and syncs alike, which `/v1/changes` streams.
+//!
+//! Changesets are numbered with an apply sequence in the order they're
+//! logged, so readers can resume after the last one they've seen as long as
+//! it's still in the log.
+
+use std::{sync::Arc, time::Duration};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use corro_api_types::{AppliedChange, AppliedChangeset, SqliteValueRef};
+use corro_base_types::CrsqlDbVersion;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use tokio::{sync::watch, task::block_in_place};
+use tracing::{debug, error};
+use tripwire::Tripwire;
+
+use crate::{
+    actor::ActorId,
+    base::CrsqlSeq,
+    broadcast::{ChangeV1, Changeset, Timestamp},
+    change::row_to_change,
+    channel::{bounded, CorroReceiver, CorroSender},
+    pubsub::{unpack_columns, UnpackError},
+};
+
+const CHANGES_CHANNEL_LEN: usize = 10240;
+const MAX_APPEND_BATCH: usize = 100;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChangesFeedError {
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Unpack(#[from] UnpackError),
+    #[error("the changes feed is not enabled")]
+    Disabled,
+    #[error(
+        "cannot resume after apply_seq {from}, the log has changesets after {min} up to {max}"
+    )]
+    Unavailable { from: u64, min: u64, max: u64 },
+}
+
+/// Records applied changesets, does nothing unless the feed is enabled
+#[derive(Clone, Default)]
+pub struct ChangesFeed(Option<Arc<ChangesFeedInner>>);
+
+struct ChangesFeedInner {
+    path: Utf8PathBuf,
+    tx: CorroSender<ChangeV1>,
+    last_seq: watch::Receiver<u64>,
+}
+
+impl ChangesFeed {
+    /// Opens the log at `path`, the returned writer must be run for
+    /// changesets to be logged
+    pub fn open(
+        path: &Utf8Path,
+        max_len: usize,
+    ) -> Result<(Self, ChangesLogWriter), ChangesFeedError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            r#"
+            PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = NORMAL;
+
+            CREATE TABLE IF NOT EXISTS changes (
+                apply_seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                changeset TEXT NOT NULL
+            );
+        "#,
+        )?;
+
+        let last_seq = last_seq(&conn)?;
+        let (last_seq_tx, last_seq_rx) = watch::channel(last_seq);
+        let (tx, rx) = bounded(CHANGES_CHANNEL_LEN, "changes_feed");
+
+        Ok((
+            Self(Some(Arc::new(ChangesFeedInner {
+                path: path.to_owned(),
+                tx,
+                last_seq: last_seq_rx,
+            }))),
+            ChangesLogWriter {
+                conn,
+                rx,
+                last_seq_tx,
+                last_seq,
+                max_len: max_len.max(1) as u64,
+            },
+        ))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Logs a changeset applied by this node. Waits for the writer when it's
+    /// behind, so changesets are logged in the order they're recorded: call
+    /// it from a blocking context, right after applying them.
+    pub fn record(&self, change: ChangeV1) {
+        let Some(inner) = self.0.as_ref() else {
+            return;
+        };
+
+        if !matches!(change.changeset, Changeset::Full { .. }) {
+            return;
+        }
+
+        if inner.tx.blocking_send(change).is_err() {
+            debug!("changes feed writer is gone, not logging changeset");
+        }
+    }
+
+    /// Logs the changes of a version which were applied from the buffered
+    /// changes table, reading them back from `crsql_changes`
+    pub fn record_from_db(
+        &self,
+        conn: &Connection,
+        actor_id: ActorId,
+        version: CrsqlDbVersion,
+    ) -> rusqlite::Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        let Some((last_seq, ts)) = conn
+            .prepare_cached(
+                "SELECT MAX(seq), MAX(ts) FROM crsql_changes WHERE site_id = ? AND db_version = ?",
+            )?
+            .query_row((actor_id, version), |row| {
+                Ok(Option::zip(
+                    row.get::<_, Option<CrsqlSeq>>(0)?,
+                    row.get::<_, Option<Timestamp>>(1)?,
+                ))
+            })?
+        else {
+            return Ok(());
+        };
+
+        let changes = conn
+            .prepare_cached(
+                r#"
+                SELECT "table", pk, cid, val, col_version, db_version, seq, site_id, cl
+                    FROM crsql_changes
+                    WHERE site_id = ?
+                      AND db_version = ?
+                    ORDER BY seq ASC
+            "#,
+            )?
+            .query_map((actor_id, version), row_to_change)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        self.record(ChangeV1 {
+            actor_id,
+            changeset: Changeset::Full {
+                version,
+                changes,
+                seqs: CrsqlSeq(0)..=last_seq,
+                last_seq,
+                ts,
+            },
+        });
+
+        Ok(())
+    }
+
+    /// Reader of the log, for a single stream
+    pub fn reader(&self) -> Result<ChangesReader, ChangesFeedError> {
+        let inner = self.0.as_ref().ok_or(ChangesFeedError::Disabled)?;
+
+        let conn = Connection::open_with_flags(
+            &inner.path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+
+        Ok(ChangesReader {
+            conn,
+            last_seq: inner.last_seq.clone(),
+        })
+    }
+}
+
+fn last_seq(conn: &Connection) -> rusqlite::Result<u64> {
+    Ok(conn
+        .query_row(
+            "SELECT seq FROM sqlite_sequence WHERE name = 'changes'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or_default())
+}
+
+fn applied_changeset(
+    apply_seq: u64,
+    change: &ChangeV1,
+) -> Result<Option<AppliedChangeset>, UnpackError> {
+    let Changeset::Full {
+        version,
+        changes,
+        seqs,
+        last_seq,
+        ts,
+    } = &change.changeset
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some(AppliedChangeset {
+        apply_seq,
+        actor_id: change.actor_id.0,
+        version: version.0,
+        seqs: seqs.start().0..=seqs.end().0,
+        last_seq: last_seq.0,
+        ts: ts.to_ntp64().as_u64(),
+        changes: changes
+            .iter()
+            .map(|change| {
+                Ok(AppliedChange {
+                    table: change.table.clone(),
+                    pk: unpack_columns(&change.pk)?
+                        .iter()
+                        .map(SqliteValueRef::to_owned)
+                        .collect(),
+                    column: change.cid.clone(),
+                    value: change.val.clone(),
+                    col_version: change.col_version,
+                    seq: change.seq.0,
+                    cl: change.cl,
+                })
+            })
+            .collect::<Result<_, UnpackError>>()?,
+    }))
+}
+
+/// Appends recorded changesets to the log, dropping the oldest ones past its
+/// maximum length
+pub struct ChangesLogWriter {
+    conn: Connection,
+    rx: CorroReceiver<ChangeV1>,
+    last_seq_tx: watch::Sender<u64>,
+    last_seq: u64,
+    max_len: u64,
+}
+
+impl ChangesLogWriter {
+    pub async fn run(mut self, mut tripwire: Tripwire) {
+        loop {
+            let change = tokio::select! {
+                change = self.rx.recv() => match change {
+                    Some(change) => change,
+                    None => break,
+                },
+                _ = &mut tripwire => break,
+            };
+
+            let mut batch = vec![change];
+            while batch.len() < MAX_APPEND_BATCH {
+                match self.rx.try_recv() {
+                    Ok(change) => batch.push(change),
+                    Err(_) => break,
+                }
+            }
+
+            if let Err(e) = block_in_place(|| self.append(&batch)) {
+                error!("could not log {} applied changesets: {e}", batch.len());
+            }
+        }
+
+        debug!("changes log writer is done");
+    }
+
+    fn append(&mut self, batch: &[ChangeV1]) -> Result<(), ChangesFeedError> {
+        let tx = self.conn.transaction()?;
+        let mut last_seq = self.last_seq;
+
+        {
+            let mut prepped =
+                tx.prepare_cached("INSERT INTO changes (apply_seq, changeset) VALUES (?, ?)")?;
+            for change in batch {
+                let changeset = match applied_changeset(last_seq + 1, change) {
+                    Ok(Some(changeset)) => changeset,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        error!(actor_id = %change.actor_id, versions = ?change.versions(), "could not decode changeset for the changes log: {e}");
+                        continue;
+                    }
+                };
+                prepped.execute((changeset.apply_seq, serde_json::to_string(&changeset)?))?;
+                last_seq = changeset.apply_seq;
+            }
+
+            tx.prepare_cached("DELETE FROM changes WHERE apply_seq <= ?")?
+                .execute([last_seq.saturating_sub(self.max_len)])?;
+        }
+
+        tx.commit()?;
+
+        self.last_seq = last_seq;
+        self.last_seq_tx.send_replace(last_seq);
+
+        Ok(())
+    }
+}
+
+/// Reads changesets from the log, waiting for new ones to be logged
+pub struct ChangesReader {
+    conn: Connection,
+    last_seq: watch::Receiver<u64>,
+}
+
+impl ChangesReader {
+    /// Apply sequence of the last logged changeset
+    pub fn last_seq(&self) -> u64 {
+        *self.last_seq.borrow()
+    }
+
+    /// Up to `limit` changesets logged after `from`, an error if some of them
+    /// were already dropped from the log
+    pub fn read(
+        &mut self,
+        from: u64,
+        limit: usize,
+    ) -> Result<Vec<AppliedChangeset>, ChangesFeedError> {
+        let tx = self.conn.transaction()?;
+
+        let max = last_seq(&tx)?;
+        let min = tx
+            .query_row("SELECT MIN(apply_seq) - 1 FROM changes", [], |row| {
+                row.get::<_, Option<u64>>(0)
+            })?
+            .unwrap_or(max);
+
+        if from < min || from > max {
+            return Err(ChangesFeedError::Unavailable { from, min, max });
+        }
+
+        let changesets = tx
+            .prepare_cached(
+                "SELECT changeset FROM changes WHERE apply_seq > ? ORDER BY apply_seq ASC LIMIT ?",
+            )?
+            .query_map((from, limit), |row| row.get::<_, String>(0))?
+            .map(|changeset| Ok(serde_json::from_str(&changeset?)?))
+            .collect::<Result<Vec<_>, ChangesFeedError>>()?;
+
+        Ok(changesets)
+    }
+
+    /// Waits for changesets to be logged, false once the writer is gone
+    pub async fn changed(&mut self) -> bool {
+        self.last_seq.changed().await.is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_api_types::{ColumnName, SqliteValue, TableName};
+    use uhlc::NTP64;
+
+    use super::*;
+    use crate::{change::Change, pubsub::pack_columns};
+
+    fn changeset(actor_id: ActorId, version: u64, table: &str) -> ChangeV1 {
+        ChangeV1 {
+            actor_id,
+            changeset: Changeset::Full {
+                version: CrsqlDbVersion(version),
+                changes: vec![Change {
+                    table: TableName(table.into()),
+                    pk: pack_columns(&[SqliteValue::Integer(version as i64)]).unwrap(),
+                    cid: ColumnName("text".into()),
+                    val: SqliteValue::Text(format!("v{version}").into()),
+                    col_version: 1,
+                    db_version: CrsqlDbVersion(version),
+                    seq: CrsqlSeq(0),
+                    site_id: actor_id.to_bytes(),
+                    cl: 1,
+                }],
+                seqs: CrsqlSeq(0)..=CrsqlSeq(0),
+                last_seq: CrsqlSeq(0),
+                ts: Timestamp(NTP64(version)),
+            },
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_changes_log() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = Utf8PathBuf::from_path_buf(dir.path().join("changes.sqlite")).unwrap();
+        let actor_id = ActorId(uuid::Uuid::new_v4());
+
+        let (feed, mut writer) = ChangesFeed::open(&path, 3)?;
+        let mut reader = feed.reader()?;
+        assert_eq!(reader.read(0, 10)?, vec![]);
+
+        writer.append(
+            &(1..=4)
+                .map(|version| changeset(actor_id, version, "tests"))
+                .collect::<Vec<_>>(),
+        )?;
+        assert_eq!(reader.last_seq(), 4);
+
+        // the first one was dropped from the log
+        assert!(matches!(
+            reader.read(0, 10),
+            Err(ChangesFeedError::Unavailable {
+                from: 0,
+                min: 1,
+                max: 4
+            })
+        ));
+
+        let changesets = reader.read(1, 10)?;
+        assert_eq!(
+            changesets
+                .iter()
+                .map(|changeset| (changeset.apply_seq, changeset.version))
+                .collect::<Vec<_>>(),
+            vec![(2, 2), (3, 3), (4, 4)]
+        );
+        assert_eq!(changesets[0].actor_id, actor_id.0);
+        assert_eq!(changesets[0].ts, 2);
+        assert_eq!(changesets[0].changes[0].pk, vec![SqliteValue::Integer(2)]);
+        assert_eq!(
+            changesets[0].changes[0].value,
+            SqliteValue::Text("v2".into())
+        );
+
+        assert_eq!(reader.read(2, 1)?.len(), 1);
+        assert!(reader.read(5, 10).is_err());
+
+        // apply sequences keep going after reopening the log
+        drop((feed, writer, reader));
+        let (feed, mut writer) = ChangesFeed::open(&path, 3)?;
+        writer.append(&[changeset(actor_id, 5, "tests")])?;
+        assert_eq!(feed.reader()?.read(4, 10)?[0].apply_seq, 5);
+
+        Ok(())
+    }
+}
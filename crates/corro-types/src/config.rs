@@ -1,6 +1,10 @@
-use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::{
+    net::{Ipv6Addr, SocketAddr, SocketAddrV6},
+    num::NonZeroU32,
+};
 
 use camino::Utf8PathBuf;
+use corro_api_types::ApiAddr;
 use serde::{Deserialize, Serialize};
 use serde_with::{formats::PreferOne, serde_as, OneOrMany};
 
@@ -58,6 +62,14 @@ fn default_max_sync_backoff() -> u32 {
     DEFAULT_MAX_SYNC_BACKOFF
 }
 
+const fn default_idempotency_key_ttl() -> u64 {
+    24 * 60 * 60
+}
+
+const fn default_max_coalesce_ms() -> u64 {
+    10_000
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub db: DbConfig,
@@ -120,6 +132,8 @@ pub struct DbConfig {
     pub schema_paths: Vec<Utf8PathBuf>,
     #[serde(default)]
     pub subscriptions_path: Option<Utf8PathBuf>,
+    #[serde(default)]
+    pub updates_path: Option<Utf8PathBuf>,
 }
 
 impl DbConfig {
@@ -134,6 +148,15 @@ impl DbConfig {
                     .unwrap_or_else(|| "/subscriptions".into())
             })
     }
+
+    pub fn updates_path(&self) -> Utf8PathBuf {
+        self.updates_path.as_ref().cloned().unwrap_or_else(|| {
+            self.path
+                .parent()
+                .map(|parent| parent.join("updates"))
+                .unwrap_or_else(|| "/updates".into())
+        })
+    }
 }
 
 #[serde_as]
@@ -141,11 +164,105 @@ impl DbConfig {
 pub struct ApiConfig {
     #[serde(alias = "addr")]
     #[serde_as(deserialize_as = "OneOrMany<_, PreferOne>")]
-    pub bind_addr: Vec<SocketAddr>,
+    pub bind_addr: Vec<ApiAddr>,
     #[serde(alias = "authz", default)]
     pub authorization: Option<AuthzConfig>,
     #[serde(default)]
     pub pg: Option<PgConfig>,
+    /// How long idempotency keys sent with transactions are remembered, in seconds
+    #[serde(default = "default_idempotency_key_ttl")]
+    pub idempotency_key_ttl: u64,
+    /// Longest window subscriptions can coalesce changes over, in milliseconds
+    #[serde(default = "default_max_coalesce_ms")]
+    pub max_coalesce_ms: u64,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// Serve the API over TLS, on every TCP `bind_addr`
+    #[serde(default)]
+    pub tls: Option<ServerTlsConfig>,
+    /// Permissions of the unix sockets in `bind_addr`, e.g. `0o660`
+    #[serde(default)]
+    pub unix_socket_mode: Option<u32>,
+    #[serde(default)]
+    pub changes: ChangesConfig,
+}
+
+/// Thresholds past which `/v1/health` reports the node as unhealthy, unset
+/// thresholds are not checked
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct HealthConfig {
+    /// Fewest gossip members, including this node
+    #[serde(default)]
+    pub min_members: Option<usize>,
+    /// Most versions this node can be missing from other actors
+    #[serde(default)]
+    pub max_needed: Option<u64>,
+    /// Most changesets waiting to be applied
+    #[serde(default)]
+    pub max_apply_queue_len: Option<usize>,
+    /// Longest time since the last successful sync, in seconds
+    #[serde(default)]
+    pub max_sync_age: Option<u64>,
+}
+
+/// Rates allowed for each API token, or each client IP for requests made
+/// without one. Unset rates are not limited
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// Requests per second
+    #[serde(default)]
+    pub requests_per_second: Option<NonZeroU32>,
+    /// Requests allowed at once, defaults to `requests_per_second`
+    #[serde(default)]
+    pub requests_burst: Option<NonZeroU32>,
+    /// Rows per second written by `/v1/transactions`, counted once its
+    /// statements have run
+    #[serde(default)]
+    pub rows_per_second: Option<NonZeroU32>,
+    /// Rows allowed at once, defaults to `rows_per_second`. Transactions
+    /// writing more rows than this are always rolled back
+    #[serde(default)]
+    pub rows_burst: Option<NonZeroU32>,
+}
+
+/// Log of the changesets applied by this node, streamed by `/v1/changes`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangesConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Defaults to `changes.sqlite` next to the database
+    #[serde(default)]
+    pub path: Option<Utf8PathBuf>,
+    /// Changesets kept in the log, the feed can't be resumed from older ones
+    #[serde(default = "default_changes_max_len")]
+    pub max_len: usize,
+}
+
+impl Default for ChangesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: None,
+            max_len: default_changes_max_len(),
+        }
+    }
+}
+
+impl ChangesConfig {
+    pub fn path(&self, db: &DbConfig) -> Utf8PathBuf {
+        self.path.as_ref().cloned().unwrap_or_else(|| {
+            db.path
+                .parent()
+                .map(|parent| parent.join("changes.sqlite"))
+                .unwrap_or_else(|| "/changes.sqlite".into())
+        })
+    }
+}
+
+const fn default_changes_max_len() -> usize {
+    100_000
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -155,12 +272,18 @@ pub struct PgConfig {
     pub tls: Option<PgTlsConfig>,
 }
 
+pub type PgTlsConfig = ServerTlsConfig;
+
+/// TLS settings of a server, for the HTTP API or the PostgreSQL protocol
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct PgTlsConfig {
+pub struct ServerTlsConfig {
     pub cert_file: Utf8PathBuf,
     pub key_file: Utf8PathBuf,
+    /// CA (Certificate Authority) file, client certificates are verified
+    /// against it
     #[serde(default)]
     pub ca_file: Option<Utf8PathBuf>,
+    /// Require clients to present a certificate signed by `ca_file`
     #[serde(default)]
     pub verify_client: bool,
 }
@@ -170,6 +293,48 @@ pub struct PgTlsConfig {
 pub enum AuthzConfig {
     #[serde(alias = "bearer")]
     BearerToken(String),
+    /// Multiple named bearer tokens, each with its own set of permissions
+    Tokens(Vec<ApiTokenConfig>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiTokenConfig {
+    /// Name used to identify the token in logs
+    pub name: String,
+    pub token: String,
+    /// Routes this token is allowed to use, defaults to read-only
+    /// access (`query` and `subscribe`)
+    #[serde(default = "default_api_permissions")]
+    pub permissions: Vec<ApiPermission>,
+    /// Tables this token is allowed to touch, defaults to all of them
+    #[serde(default)]
+    pub tables: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApiPermission {
+    /// `/v1/queries`, `/v1/table_stats` and `/v1/history`
+    Query,
+    /// `/v1/subscriptions`, `/v1/updates`, `/v1/changes` and `/v1/ws`
+    Subscribe,
+    /// `/v1/transactions`
+    Transact,
+    /// `/v1/migrations` and `DELETE /v1/subscriptions/:id`
+    Migrate,
+}
+
+impl ApiPermission {
+    pub const ALL: [ApiPermission; 4] = [
+        ApiPermission::Query,
+        ApiPermission::Subscribe,
+        ApiPermission::Transact,
+        ApiPermission::Migrate,
+    ];
+}
+
+fn default_api_permissions() -> Vec<ApiPermission> {
+    vec![ApiPermission::Query, ApiPermission::Subscribe]
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -332,7 +497,7 @@ impl Config {
 pub struct ConfigBuilder {
     pub db_path: Option<Utf8PathBuf>,
     gossip_addr: Option<SocketAddr>,
-    api_addr: Vec<SocketAddr>,
+    api_addr: Vec<ApiAddr>,
     external_addr: Option<SocketAddr>,
     admin_path: Option<Utf8PathBuf>,
     prometheus_addr: Option<SocketAddr>,
@@ -357,7 +522,12 @@ impl ConfigBuilder {
     }
 
     pub fn api_addr(mut self, addr: SocketAddr) -> Self {
-        self.api_addr.push(addr);
+        self.api_addr.push(ApiAddr::Tcp(addr));
+        self
+    }
+
+    pub fn api_unix_path<S: Into<Utf8PathBuf>>(mut self, path: S) -> Self {
+        self.api_addr.push(ApiAddr::Unix(path.into()));
         self
     }
 
@@ -420,11 +590,19 @@ impl ConfigBuilder {
                 path: db_path,
                 schema_paths: self.schema_paths,
                 subscriptions_path: None,
+                updates_path: None,
             },
             api: ApiConfig {
                 bind_addr: self.api_addr,
                 authorization: None,
                 pg: None,
+                idempotency_key_ttl: default_idempotency_key_ttl(),
+                max_coalesce_ms: default_max_coalesce_ms(),
+                health: Default::default(),
+                rate_limit: Default::default(),
+                tls: None,
+                unix_socket_mode: None,
+                changes: Default::default(),
             },
             gossip: GossipConfig {
                 bind_addr: self
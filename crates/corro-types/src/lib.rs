@@ -3,6 +3,7 @@ pub mod actor;
 pub mod agent;
 pub mod api;
 pub mod broadcast;
+pub mod cdc;
 pub mod change;
 pub mod channel;
 pub mod config;
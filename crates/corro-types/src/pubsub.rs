@@ -10,7 +10,8 @@ use bytes::{Buf, BufMut};
 use camino::{Utf8Path, Utf8PathBuf};
 use compact_str::{format_compact, ToCompactString};
 use corro_api_types::{
-    ChangeId, ColumnName, ColumnType, RowId, SqliteValue, SqliteValueRef, TableName,
+    ChangeId, ColumnName, ColumnType, RowId, SqliteValue, SqliteValueRef, SubscriptionPlan,
+    SubscriptionTablePlan, TableName,
 };
 use enquote::unquote;
 use fallible_iterator::FallibleIterator;
@@ -25,8 +26,9 @@ use rusqlite::{
 use spawn::spawn_counted;
 use sqlite3_parser::{
     ast::{
-        As, Cmd, Expr, FromClause, JoinConstraint, JoinOperator, JoinType, JoinedSelectTable, Name,
-        OneSelect, Operator, QualifiedName, ResultColumn, Select, SelectTable, Stmt,
+        As, Cmd, Expr, FromClause, GroupBy, Id, JoinConstraint, JoinOperator, JoinType,
+        JoinedSelectTable, Literal, Name, OneSelect, Operator, QualifiedName, ResultColumn, Select,
+        SelectTable, Stmt,
     },
     lexer::sql::Parser,
 };
@@ -196,6 +198,7 @@ pub struct MatchableChange<'a> {
     pub table: &'a TableName,
     pub pk: &'a [u8],
     pub column: &'a ColumnName,
+    pub val: &'a SqliteValue,
     pub cl: i64,
 }
 
@@ -205,6 +208,7 @@ impl<'a> From<&'a Change> for MatchableChange<'a> {
             table: &value.table,
             pk: &value.pk,
             column: &value.cid,
+            val: &value.val,
             cl: value.cl,
         }
     }
@@ -274,6 +278,8 @@ pub type MatchCandidates = IndexMap<TableName, IndexMap<Vec<u8>, i64>>;
 
 #[async_trait]
 impl Handle for MatcherHandle {
+    type Candidates = MatchCandidates;
+
     fn id(&self) -> Uuid {
         self.inner.id
     }
@@ -307,14 +313,21 @@ impl Handle for MatcherHandle {
             return false;
         }
 
-        // don't consider changes that don't have both the table + col in the matcher query
-        if !self
-            .inner
-            .parsed
-            .table_columns
-            .get(change.table.as_str())
-            .map(|cols| change.column.is_crsql_sentinel() || cols.contains(change.column.as_str()))
-            .unwrap_or_default()
+        // don't consider changes that don't have both the table + col in the matcher query,
+        // aggregates, windows and subqueries also depend on which rows exist, whatever their
+        // changed columns
+        let parsed = &self.inner.parsed;
+        if !parsed.subquery_columns.contains_key(change.table.as_str())
+            && !parsed
+                .table_columns
+                .get(change.table.as_str())
+                .map(|cols| {
+                    change.column.is_crsql_sentinel()
+                        || parsed.group_by.is_some()
+                        || parsed.windowed
+                        || cols.contains(change.column.as_str())
+                })
+                .unwrap_or_default()
         {
             trace!("could not match against parsed query table and columns");
             return false;
@@ -495,6 +508,7 @@ impl MatcherHandle {
         tx.blocking_send(QueryEvent::EndOfQuery {
             time: elapsed.as_secs_f64(),
             change_id: Some(max_change_id),
+            cursor: None,
         })
         .map_err(|_| MatcherError::EventReceiverClosed)?;
 
@@ -510,6 +524,9 @@ pub struct Matcher {
     pub query: Stmt,
     pub cached_statements: HashMap<String, MatcherStmt>,
     pub pks: IndexMap<String, Vec<String>>,
+    pub grouped: Option<GroupedStmt>,
+    pub full_stmt: Option<MatcherStmt>,
+    pub linked: IndexMap<String, LinkedStmt>,
     pub parsed: ParsedSelect,
     pub evt_tx: mpsc::Sender<QueryEvent>,
     pub col_names: Vec<ColumnName>,
@@ -526,6 +543,8 @@ pub struct Matcher {
 pub struct MatcherStmt {
     new_query: String,
     temp_query: String,
+    // the table is the right-hand side of a LEFT JOIN
+    left_joined: bool,
 }
 
 impl MatcherStmt {
@@ -534,10 +553,41 @@ impl MatcherStmt {
     }
 }
 
+/// Statements maintaining the groups of an aggregate query, which replace
+/// primary keys as the identity of its rows
+#[derive(Debug, Clone)]
+pub struct GroupedStmt {
+    keys: Vec<String>,
+    // group keys of every aggregated row, by primary keys
+    members_query: String,
+    // recomputes the groups listed in `temp_groups`
+    stmt: MatcherStmt,
+}
+
+/// Statements keeping the keys a table only read by correlated subqueries is
+/// read with, to find the outer rows its changes affect
+#[derive(Debug, Clone)]
+pub struct LinkedStmt {
+    pks: Vec<String>,
+    // columns the outer rows are correlated with
+    keys: Vec<String>,
+    // primary and correlated keys of every row
+    keys_query: String,
+    // primary and correlated keys of the rows listed in `temp_{table}`
+    changed_keys_query: String,
+    // outer tables, with the primary keys of their rows sharing keys with
+    // the changed rows
+    candidates: Vec<(String, String)>,
+}
+
 const CHANGE_ID_COL: &str = "id";
 const CHANGE_TYPE_COL: &str = "type";
 
 pub const QUERY_TABLE_NAME: &str = "query";
+// rows aggregated by a grouped query, with the key of their group
+const MEMBERS_TABLE_NAME: &str = "members";
+// keys of the rows of tables read by correlated subqueries, by primary keys
+const LINKS_TABLE_PREFIX: &str = "links_";
 
 pub const SUB_DB_PATH: &str = "sub.sqlite";
 
@@ -579,163 +629,18 @@ impl Matcher {
             "#,
         )?;
 
-        let mut parser = Parser::new(sql.as_bytes());
-
-        let (mut stmt, parsed) = match parser.next()?.ok_or(MatcherError::StatementRequired)? {
-            Cmd::Stmt(stmt) => {
-                let parsed = match stmt {
-                    Stmt::Select(ref select) => extract_select_columns(select, schema)?,
-                    _ => return Err(MatcherError::UnsupportedStatement),
-                };
-
-                (stmt, parsed)
-            }
-            _ => return Err(MatcherError::StatementRequired),
-        };
-
-        if parsed.table_columns.is_empty() {
-            return Err(MatcherError::TableRequired);
-        }
-
-        let mut statements = HashMap::new();
-
-        let mut pks = IndexMap::default();
-
-        match &mut stmt {
-            Stmt::Select(select) => match &mut select.body.select {
-                OneSelect::Select { columns, .. } => {
-                    let mut new_cols = parsed
-                        .table_columns
-                        .iter()
-                        .filter_map(|(tbl_name, _cols)| {
-                            schema.tables.get(tbl_name).map(|table| {
-                                let tbl_name = parsed
-                                    .aliases
-                                    .iter()
-                                    .find_map(|(alias, actual)| {
-                                        (actual == tbl_name).then_some(alias)
-                                    })
-                                    .unwrap_or(tbl_name);
-                                table
-                                    .pk
-                                    .iter()
-                                    .map(|pk| {
-                                        let alias = format!("__corro_pk_{tbl_name}_{pk}");
-                                        let entry: &mut Vec<String> =
-                                            pks.entry(table.name.clone()).or_default();
-                                        entry.push(alias.clone());
-
-                                        ResultColumn::Expr(
-                                            Expr::Qualified(
-                                                Name(tbl_name.clone()),
-                                                Name(pk.clone()),
-                                            ),
-                                            Some(As::As(Name(alias))),
-                                        )
-                                    })
-                                    .collect::<Vec<_>>()
-                            })
-                        })
-                        .flatten()
-                        .collect::<Vec<_>>();
-
-                    new_cols.append(&mut parsed.columns.clone());
-                    *columns = new_cols;
-                }
-                _ => unreachable!(),
-            },
-            _ => unreachable!(),
-        }
-
-        for (idx, (tbl_name, _cols)) in parsed.table_columns.iter().enumerate() {
-            let expr = table_to_expr(
-                &parsed.aliases,
-                schema
-                    .tables
-                    .get(tbl_name)
-                    .expect("this should not happen, missing table in schema"),
-                tbl_name,
-            )?;
-
-            let mut stmt = stmt.clone();
-
-            if let Stmt::Select(select) = &mut stmt {
-                if let OneSelect::Select {
-                    where_clause, from, ..
-                } = &mut select.body.select
-                {
-                    *where_clause = if let Some(prev) = where_clause.take() {
-                        Some(Expr::Binary(
-                            Box::new(expr),
-                            Operator::And,
-                            Box::new(Expr::parenthesized(prev)),
-                        ))
-                    } else {
-                        Some(expr)
-                    };
-
-                    match from {
-                        Some(FromClause {
-                            joins: Some(joins), ..
-                        }) if idx > 0 => {
-                            // Replace LEFT JOIN with INNER join if the target is the joined table
-                            if let Some(JoinedSelectTable {
-                                operator:
-                                    JoinOperator::TypedJoin {
-                                        join_type:
-                                            join_type @ Some(JoinType::LeftOuter | JoinType::Left),
-                                        ..
-                                    },
-                                ..
-                            }) = joins.get_mut(idx - 1)
-                            {
-                                *join_type = Some(JoinType::Inner);
-                            };
-
-                            // Remove all custom INDEXED BY clauses for the table as the most efficient
-                            // way is to query it by the primary keys
-                            if let Some(JoinedSelectTable {
-                                table: SelectTable::Table(_, _, indexed @ Some(_)),
-                                ..
-                            }) = joins.get_mut(idx - 1)
-                            {
-                                *indexed = None
-                            };
-                        }
-                        _ => (),
-                    };
-                }
-            }
-
-            let mut new_query = Cmd::Stmt(stmt).to_string();
-            new_query.pop();
-
-            let mut all_cols = pks.values().flatten().cloned().collect::<Vec<String>>();
-            for i in 0..(parsed.columns.len()) {
-                all_cols.push(format!("col_{i}"));
-            }
-
-            let temp_query = format!(
-                "SELECT {} FROM query WHERE ({}) IN temp_{tbl_name}",
-                all_cols.join(","),
-                pks.get(tbl_name)
-                    .cloned()
-                    .ok_or(MatcherError::MissingPrimaryKeys)?
-                    .into_iter()
-                    .map(|pk| format!("coalesce({pk}, \"\")"))
-                    .collect::<Vec<_>>()
-                    .join(","),
-            );
-
-            info!(%sql_hash, sub_id = %id, "modified query for table '{tbl_name}': {new_query}");
-
-            statements.insert(
-                tbl_name.clone(),
-                MatcherStmt {
-                    new_query,
-                    temp_query,
-                },
-            );
+        let RewrittenQuery {
+            stmt,
+            parsed,
+            pks,
+            statements,
+            grouped,
+            full_stmt,
+            linked,
+        } = rewrite_query(schema, sql)?;
+
+        for (tbl_name, stmt) in statements.iter() {
+            info!(%sql_hash, sub_id = %id, "modified query for table '{tbl_name}': {}", stmt.new_query);
         }
 
         let cancel = CancellationToken::new();
@@ -749,7 +654,11 @@ impl Matcher {
 
         // metrics counters
         let mut counter_map = HashMap::new();
-        for table in parsed.table_columns.keys() {
+        for table in parsed
+            .table_columns
+            .keys()
+            .chain(parsed.subquery_columns.keys())
+        {
             counter_map.insert(table.clone(), HandleMetrics{
                 matched_count: counter!("corro.subs.changes.matched.count", "sql_hash" => sql_hash.clone(), "table" => table.to_string()),
             });
@@ -783,6 +692,9 @@ impl Matcher {
             query: stmt,
             cached_statements: statements,
             pks,
+            grouped,
+            full_stmt,
+            linked,
             parsed,
             evt_tx,
             col_names,
@@ -798,6 +710,29 @@ impl Matcher {
         Ok((matcher, handle))
     }
 
+    /// Columns identifying the rows of the query table
+    fn key_cols(&self) -> Vec<String> {
+        match &self.grouped {
+            Some(grouped) => grouped.keys.clone(),
+            None => self.pks.values().flatten().cloned().collect(),
+        }
+    }
+
+    /// Key columns of the query table as they are indexed and compared, group
+    /// keys being NULL-safe
+    fn coalesced_key_cols(&self) -> String {
+        match &self.grouped {
+            Some(grouped) => coalesced_group_keys(&grouped.keys),
+            None => self
+                .pks
+                .values()
+                .flatten()
+                .map(|pk| format!("coalesce({pk},\"\")"))
+                .collect::<Vec<_>>()
+                .join(","),
+        }
+    }
+
     pub fn cleanup(id: Uuid, sub_path: Utf8PathBuf) -> rusqlite::Result<()> {
         info!(sub_id = %id, "Attempting to cleanup...");
 
@@ -869,12 +804,8 @@ impl Matcher {
     ) -> Result<MatcherHandle, MatcherError> {
         let (mut matcher, handle) = Self::new(id, subs_path, schema, &state_conn, evt_tx, sql)?;
 
-        let pk_cols = matcher
-            .pks
-            .values()
-            .flatten()
-            .cloned()
-            .collect::<Vec<String>>();
+        let pk_cols = matcher.key_cols();
+        let pks_coalesced = matcher.coalesced_key_cols();
 
         let mut all_cols = pk_cols.clone();
 
@@ -917,21 +848,34 @@ impl Matcher {
             "#,
                 columns = all_cols.join(","),
                 id = id.as_simple(),
-                pks_coalesced = pk_cols
-                    .iter()
-                    .map(|pk| format!("coalesce({pk}, \"\")"))
-                    .collect::<Vec<_>>()
-                    .join(","),
                 actual_columns = query_cols.join(","),
             );
 
             tx.execute_batch(&create_temp_table)?;
             trace!("created sub tables");
 
+            let pks_table = match &matcher.grouped {
+                Some(grouped) => {
+                    tx.execute_batch(&format!(
+                        "CREATE TABLE {MEMBERS_TABLE_NAME} ({})",
+                        matcher
+                            .pks
+                            .values()
+                            .flatten()
+                            .chain(grouped.keys.iter())
+                            .cloned()
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    ))?;
+                    MEMBERS_TABLE_NAME
+                }
+                None => QUERY_TABLE_NAME,
+            };
+
             for (table, pks) in matcher.pks.iter() {
                 tx.execute(
                     &format!(
-                        "CREATE INDEX index_{id}_{table}_pk ON query ({pks})",
+                        "CREATE INDEX index_{id}_{table}_pk ON {pks_table} ({pks})",
                         id = id.as_simple(),
                         table = table,
                         pks = pks.to_vec().join(","),
@@ -939,9 +883,29 @@ impl Matcher {
                     [],
                 )?;
             }
+            for (table, linked) in matcher.linked.iter() {
+                tx.execute_batch(&format!(
+                    "CREATE TABLE {LINKS_TABLE_PREFIX}{table} ({pks},{keys});
+                    CREATE INDEX index_{id}_{LINKS_TABLE_PREFIX}{table}_pk ON {LINKS_TABLE_PREFIX}{table} ({pks});",
+                    id = id.as_simple(),
+                    pks = linked.pks.join(","),
+                    keys = (0..linked.keys.len())
+                        .map(|i| format!("__corro_lk_{i}"))
+                        .collect::<Vec<_>>()
+                        .join(","),
+                ))?;
+            }
             trace!("created query indexes");
 
-            for (table, columns) in matcher.parsed.table_columns.iter() {
+            let mut sub_columns = matcher.parsed.table_columns.clone();
+            for (table, columns) in matcher.parsed.subquery_columns.iter() {
+                sub_columns
+                    .entry(table.clone())
+                    .or_default()
+                    .extend(columns.iter().cloned());
+            }
+
+            for (table, columns) in sub_columns.iter() {
                 tx.execute(
                     r#"INSERT INTO columns ("table", cid) VALUES (?, '-1')"#,
                     [table.as_str()],
@@ -1227,6 +1191,8 @@ impl Matcher {
             query_cols.push(format!("col_{i}"));
         }
 
+        let key_cols = self.key_cols();
+
         let mut first_buffered_db_version = None;
 
         let mut candidates = MatchCandidates::new();
@@ -1238,12 +1204,7 @@ impl Matcher {
             let mut stmt_str = Cmd::Stmt(self.query.clone()).to_string();
             stmt_str.pop(); // remove trailing `;`
 
-            let mut all_cols = self
-                .pks
-                .values()
-                .flatten()
-                .cloned()
-                .collect::<Vec<String>>();
+            let mut all_cols = key_cols;
 
             for i in 0..(self.parsed.columns.len()) {
                 let col_name = format!("col_{i}");
@@ -1345,6 +1306,44 @@ impl Matcher {
                     }
                 }
 
+                if let Some(grouped) = &self.grouped {
+                    let mut select = state_tx.prepare(&grouped.members_query)?;
+                    let col_count = select.column_count();
+                    let mut insert = tx.prepare(&format!(
+                        "INSERT INTO {MEMBERS_TABLE_NAME} VALUES ({})",
+                        (0..col_count).map(|_| "?").collect::<Vec<_>>().join(",")
+                    ))?;
+
+                    let mut rows = select.raw_query();
+                    while let Some(row) = rows.next()? {
+                        for i in 0..col_count {
+                            insert
+                                .raw_bind_parameter(i + 1, SqliteValueRef::from(row.get_ref(i)?))?;
+                        }
+                        insert.raw_execute()?;
+                    }
+                    info!(sub_id = %self.id, "Stored the rows of each group for initial query");
+                }
+
+                for (table, linked) in self.linked.iter() {
+                    let mut select = state_tx.prepare(&linked.keys_query)?;
+                    let col_count = select.column_count();
+                    let mut insert = tx.prepare(&format!(
+                        "INSERT INTO {LINKS_TABLE_PREFIX}{table} VALUES ({})",
+                        (0..col_count).map(|_| "?").collect::<Vec<_>>().join(",")
+                    ))?;
+
+                    let mut rows = select.raw_query();
+                    while let Some(row) = rows.next()? {
+                        for i in 0..col_count {
+                            insert
+                                .raw_bind_parameter(i + 1, SqliteValueRef::from(row.get_ref(i)?))?;
+                        }
+                        insert.raw_execute()?;
+                    }
+                    info!(sub_id = %self.id, "Stored the keys of {table} for initial query");
+                }
+
                 tx.execute_batch("DROP TABLE IF EXISTS state_rows;")?;
 
                 let db_version: CrsqlDbVersion =
@@ -1377,6 +1376,7 @@ impl Matcher {
                     .send(QueryEvent::EndOfQuery {
                         time: elapsed.as_secs_f64(),
                         change_id: Some(ChangeId(0)),
+                        cursor: None,
                     })
                     .await
                 {
@@ -1431,162 +1431,541 @@ impl Matcher {
         self.cmd_loop(state_conn, tripwire).await
     }
 
-    fn handle_candidates(
+    /// Rows of the FROM table can gain or lose their NULL-extended row when
+    /// the ON clause of a LEFT JOIN starts or stops matching a changed row,
+    /// which the changed table's own query can't see. Rows joined to changed
+    /// LEFT JOIN tables, before and after the change, are recomputed whole
+    /// by adding them to the FROM table's candidates. Grouped queries do the
+    /// same for every joined table, groups are recomputed from the FROM rows.
+    fn expand_left_joined_candidates(
         &mut self,
-        state_conn: &mut Connection,
-        candidates: MatchCandidates,
-        last_db_version: CrsqlDbVersion,
+        state_conn: &Connection,
+        tables: &mut IndexSet<TableName>,
     ) -> Result<(), MatcherError> {
-        let mut tables = IndexSet::new();
+        let (from_table, from_pks) = match self.pks.get_index(0) {
+            Some((table, pks)) => (table.clone(), pks.clone()),
+            None => return Ok(()),
+        };
 
-        if candidates.is_empty() {
-            update_last_db_version(&self.conn, last_db_version)?;
+        let left_joined = tables
+            .iter()
+            .filter(|table| {
+                table.as_str() != from_table
+                    && self
+                        .cached_statements
+                        .get(table.as_str())
+                        .map(|stmt| stmt.left_joined || self.grouped.is_some())
+                        .unwrap_or(false)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if left_joined.is_empty() {
             return Ok(());
         }
 
-        trace!(
-            "got some candidates! {:?}",
-            candidates.keys().collect::<Vec<_>>()
-        );
+        let rows_table = if self.grouped.is_some() {
+            MEMBERS_TABLE_NAME
+        } else {
+            QUERY_TABLE_NAME
+        };
 
         let tx = self.conn.transaction()?;
-        for (table, pks) in candidates {
-            let pks = pks
+        tx.prepare_cached(&format!(
+            "CREATE TABLE IF NOT EXISTS temp_{from_table} ({})",
+            from_pks.join(",")
+        ))?
+        .execute(())?;
+
+        {
+            let coalesced_from_pks = from_pks
                 .iter()
-                .map(|(pk, _)| unpack_columns(pk))
-                .collect::<Result<Vec<Vec<SqliteValueRef>>, _>>()?;
+                .map(|pk| format!("coalesce({pk}, \"\")"))
+                .collect::<Vec<_>>()
+                .join(",");
+
+            let mut insert_prepped = tx.prepare_cached(&format!(
+                "INSERT INTO temp_{from_table} VALUES ({})",
+                (0..from_pks.len())
+                    .map(|_i| "coalesce(?, \"\")")
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ))?;
 
-            let tmp_table_name = format!("temp_{table}");
-            if tables.insert(table.clone()) {
-                // create a temporary table to mix and match the data
-                tx.prepare_cached(
-                    // TODO: cache the statement's string somewhere, it's always the same!
-                    // NOTE: this can't be an actual CREATE TEMP TABLE or it won't be visible
-                    //       from the state db
-                    &format!(
-                        "CREATE TABLE IF NOT EXISTS {tmp_table_name} ({})",
-                        self.pks
-                            .get(table.as_str())
-                            .ok_or_else(|| MatcherError::MissingPrimaryKeys)?
-                            .to_vec()
-                            .join(",")
-                    ),
-                )?
-                .execute(())?;
-            }
+            for table in left_joined {
+                let pks = self
+                    .pks
+                    .get(table.as_str())
+                    .ok_or(MatcherError::MissingPrimaryKeys)?;
 
-            for pks in pks {
+                // rows currently joined to the changed ones
                 tx.prepare_cached(&format!(
-                    "INSERT INTO {tmp_table_name} VALUES ({})",
-                    (0..pks.len())
-                        .map(|_i| "coalesce(?, \"\")")
+                    "INSERT INTO temp_{from_table} SELECT {coalesced_from_pks} FROM {rows_table} WHERE ({}) IN temp_{table}",
+                    pks.iter()
+                        .map(|pk| format!("coalesce({pk}, \"\")"))
                         .collect::<Vec<_>>()
                         .join(",")
                 ))?
-                .execute(params_from_iter(pks))?;
+                .execute(())?;
+
+                // rows joined to the changed ones from now on, the FROM
+                // table's primary keys are the first columns of the query
+                let stmt = match self.cached_statements.get(table.as_str()) {
+                    Some(stmt) => stmt,
+                    None => continue,
+                };
+                let mut prepped = state_conn.prepare_cached(&stmt.new_query)?;
+                let mut rows = prepped.raw_query();
+                while let Some(row) = rows.next()? {
+                    for i in 0..from_pks.len() {
+                        insert_prepped
+                            .raw_bind_parameter(i + 1, SqliteValueRef::from(row.get_ref(i)?))?;
+                    }
+                    insert_prepped.raw_execute()?;
+                }
             }
         }
+
         // commit so it is visible to the other db!
         tx.commit()?;
-        trace!("committed temp pk tables");
+        trace!("added candidates for rows of {from_table} joined to changed rows");
 
-        let mut query_cols = vec![];
+        tables.insert(TableName(from_table.into()));
 
-        let pk_cols = self
-            .pks
-            .values()
-            .flatten()
-            .cloned()
-            .collect::<Vec<String>>();
+        Ok(())
+    }
 
-        let mut all_cols = pk_cols.clone();
-        for i in 0..(self.parsed.columns.len()) {
-            let col_name = format!("col_{i}");
-            all_cols.push(col_name.clone());
-            query_cols.push(col_name);
+    /// Adds the outer rows correlated with the changed rows of tables read by
+    /// subqueries to their tables' candidates, and stores the changed rows'
+    /// keys for the next changes
+    fn add_linked_candidates(
+        &mut self,
+        state_conn: &Connection,
+        linked_tables: &IndexSet<TableName>,
+        tables: &mut IndexSet<TableName>,
+    ) -> Result<(), MatcherError> {
+        if linked_tables.is_empty() {
+            return Ok(());
         }
 
-        // start a new tx
         let tx = self.conn.transaction()?;
 
-        // ensure drop and recreate
-        tx.execute_batch(&format!(
-            "CREATE TEMP TABLE IF NOT EXISTS state_results ({})",
-            all_cols.join(",")
-        ))?;
+        for table in linked_tables.iter() {
+            let linked = match self.linked.get(table.as_str()) {
+                Some(linked) => linked,
+                None => continue,
+            };
 
-        let mut new_last_rowid = self.last_rowid;
+            for (outer_table, query) in linked.candidates.iter() {
+                let outer_pks = self
+                    .pks
+                    .get(outer_table)
+                    .ok_or(MatcherError::MissingPrimaryKeys)?;
+
+                if tables.insert(TableName(outer_table.as_str().into())) {
+                    tx.prepare_cached(&format!(
+                        "CREATE TABLE IF NOT EXISTS temp_{outer_table} ({})",
+                        outer_pks.join(",")
+                    ))?
+                    .execute(())?;
+                }
 
-        {
-            // read-only!
-            let state_tx = state_conn.transaction()?;
-            let mut tmp_insert_prepped = tx.prepare_cached(&format!(
-                "INSERT INTO state_results VALUES ({})",
-                (0..all_cols.len())
-                    .map(|_| "?")
-                    .collect::<Vec<_>>()
-                    .join(",")
-            ))?;
+                let mut insert_prepped = tx.prepare_cached(&format!(
+                    "INSERT INTO temp_{outer_table} VALUES ({})",
+                    (0..outer_pks.len())
+                        .map(|_i| "?")
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ))?;
 
-            for table in tables.iter() {
-                let start = Instant::now();
-                let stmt = match self.cached_statements.get(table.as_str()) {
-                    Some(stmt) => stmt,
-                    None => {
-                        warn!(sub_id = %self.id, "no statements pre-computed for table {table}");
-                        continue;
+                let mut prepped = state_conn.prepare_cached(query)?;
+                let mut rows = prepped.raw_query();
+                while let Some(row) = rows.next()? {
+                    for i in 0..outer_pks.len() {
+                        insert_prepped
+                            .raw_bind_parameter(i + 1, SqliteValueRef::from(row.get_ref(i)?))?;
                     }
-                };
-
-                trace!("SELECT SQL: {}", stmt.new_query);
+                    insert_prepped.raw_execute()?;
+                }
+            }
 
-                let mut prepped = state_tx.prepare_cached(&stmt.new_query)?;
+            // keys of the changed rows from now on
+            tx.prepare_cached(&format!(
+                "DELETE FROM {LINKS_TABLE_PREFIX}{table} WHERE ({}) IN temp_{table}",
+                linked
+                    .pks
+                    .iter()
+                    .map(|pk| format!("coalesce({pk}, \"\")"))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ))?
+            .execute(())?;
 
+            {
+                let mut prepped = state_conn.prepare_cached(&linked.changed_keys_query)?;
                 let col_count = prepped.column_count();
+                let mut insert_prepped = tx.prepare_cached(&format!(
+                    "INSERT INTO {LINKS_TABLE_PREFIX}{table} VALUES ({})",
+                    (0..col_count).map(|_| "?").collect::<Vec<_>>().join(",")
+                ))?;
 
                 let mut rows = prepped.raw_query();
-
-                // insert each row in the other DB...
                 while let Some(row) = rows.next()? {
                     for i in 0..col_count {
-                        tmp_insert_prepped
+                        insert_prepped
                             .raw_bind_parameter(i + 1, SqliteValueRef::from(row.get_ref(i)?))?;
                     }
-                    tmp_insert_prepped.raw_execute()?;
+                    insert_prepped.raw_execute()?;
                 }
+            }
+
+            // tables only read by subqueries have no rows to match
+            if !self.pks.contains_key(table.as_str()) {
+                tx.prepare_cached(&format!("DELETE FROM temp_{table}"))?
+                    .execute(())?;
+            }
+        }
+
+        // commit so it is visible to the other db!
+        tx.commit()?;
+        trace!("added candidates for outer rows correlated with changed rows");
+
+        Ok(())
+    }
+
+    /// Stores the rows of the FROM table's candidates under their current
+    /// group, and lists the groups they belonged to, before and after, in
+    /// `temp_groups`
+    fn refresh_groups(&mut self, state_conn: &Connection) -> Result<(), MatcherError> {
+        let Some(grouped) = &self.grouped else {
+            return Ok(());
+        };
+
+        let (from_table, from_pks) = match self.pks.get_index(0) {
+            Some((table, pks)) => (table, pks),
+            None => return Ok(()),
+        };
+
+        let stmt = self
+            .cached_statements
+            .get(from_table)
+            .ok_or(MatcherError::StatementRequired)?;
+
+        let coalesced_from_pks = from_pks
+            .iter()
+            .map(|pk| format!("coalesce({pk}, \"\")"))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let tx = self.conn.transaction()?;
+        tx.prepare_cached(&format!(
+            "CREATE TABLE IF NOT EXISTS temp_groups ({})",
+            grouped
+                .keys
+                .iter()
+                .map(|key| format!("{key}_null,{key}"))
+                .collect::<Vec<_>>()
+                .join(",")
+        ))?
+        .execute(())?;
+
+        let insert_groups = format!(
+            "INSERT INTO temp_groups SELECT DISTINCT {} FROM {MEMBERS_TABLE_NAME} WHERE ({coalesced_from_pks}) IN temp_{from_table}",
+            coalesced_group_keys(&grouped.keys)
+        );
+
+        // groups the rows were part of
+        tx.prepare_cached(&insert_groups)?.execute(())?;
+
+        tx.prepare_cached(&format!(
+            "DELETE FROM {MEMBERS_TABLE_NAME} WHERE ({coalesced_from_pks}) IN temp_{from_table}"
+        ))?
+        .execute(())?;
+
+        {
+            let mut prepped = state_conn.prepare_cached(&stmt.new_query)?;
+            let col_count = prepped.column_count();
+            let mut insert_prepped = tx.prepare_cached(&format!(
+                "INSERT INTO {MEMBERS_TABLE_NAME} VALUES ({})",
+                (0..col_count).map(|_| "?").collect::<Vec<_>>().join(",")
+            ))?;
+
+            let mut rows = prepped.raw_query();
+            while let Some(row) = rows.next()? {
+                for i in 0..col_count {
+                    insert_prepped
+                        .raw_bind_parameter(i + 1, SqliteValueRef::from(row.get_ref(i)?))?;
+                }
+                insert_prepped.raw_execute()?;
+            }
+        }
+
+        // groups the rows are part of now
+        tx.prepare_cached(&insert_groups)?.execute(())?;
+
+        // commit so it is visible to the other db!
+        tx.commit()?;
+        trace!("committed changed groups");
+
+        Ok(())
+    }
+
+    /// Stores the rows of every group again, as subqueries deciding which rows
+    /// are aggregated may have changed
+    fn rebuild_groups(&mut self, state_conn: &Connection) -> Result<(), MatcherError> {
+        let Some(grouped) = &self.grouped else {
+            return Ok(());
+        };
+
+        let tx = self.conn.transaction()?;
+        tx.execute_batch(&format!("DELETE FROM {MEMBERS_TABLE_NAME}"))?;
+
+        {
+            let mut prepped = state_conn.prepare_cached(&grouped.members_query)?;
+            let col_count = prepped.column_count();
+            let mut insert_prepped = tx.prepare_cached(&format!(
+                "INSERT INTO {MEMBERS_TABLE_NAME} VALUES ({})",
+                (0..col_count).map(|_| "?").collect::<Vec<_>>().join(",")
+            ))?;
+
+            let mut rows = prepped.raw_query();
+            while let Some(row) = rows.next()? {
+                for i in 0..col_count {
+                    insert_prepped
+                        .raw_bind_parameter(i + 1, SqliteValueRef::from(row.get_ref(i)?))?;
+                }
+                insert_prepped.raw_execute()?;
+            }
+        }
+
+        tx.commit()?;
+        trace!("rebuilt the rows of every group");
+
+        Ok(())
+    }
+
+    /// Whether the changed rows of these tables are in the window, or match
+    /// the query now and could enter it
+    fn window_affected(
+        &self,
+        state_conn: &Connection,
+        tables: &IndexSet<TableName>,
+    ) -> Result<bool, MatcherError> {
+        for table in tables {
+            let Some(stmt) = self.cached_statements.get(table.as_str()) else {
+                return Ok(true);
+            };
+
+            let exists = |conn: &Connection, query: &str| {
+                conn.prepare_cached(&format!("SELECT EXISTS ({query})"))?
+                    .query_row((), |row| row.get::<_, bool>(0))
+            };
+            if exists(&self.conn, &stmt.temp_query)? || exists(state_conn, &stmt.new_query)? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn handle_candidates(
+        &mut self,
+        state_conn: &mut Connection,
+        candidates: MatchCandidates,
+        last_db_version: CrsqlDbVersion,
+    ) -> Result<(), MatcherError> {
+        let mut tables = IndexSet::new();
+
+        if candidates.is_empty() {
+            update_last_db_version(&self.conn, last_db_version)?;
+            return Ok(());
+        }
+
+        trace!(
+            "got some candidates! {:?}",
+            candidates.keys().collect::<Vec<_>>()
+        );
+
+        let mut subquery_changed = false;
+        let mut linked_tables = IndexSet::new();
+
+        let tx = self.conn.transaction()?;
+        for (table, pks) in candidates {
+            subquery_changed |= self.parsed.unlinked_tables.contains(table.as_str());
+
+            let table_pks = match (
+                self.pks.get(table.as_str()),
+                self.linked.get(table.as_str()),
+            ) {
+                (Some(pks), _) => pks,
+                (None, Some(linked)) => &linked.pks,
+                // tables only read by uncorrelated subqueries have no rows to match
+                (None, None) => continue,
+            };
+            let created = if self.pks.contains_key(table.as_str()) {
+                tables.insert(table.clone())
+            } else {
+                linked_tables.insert(table.clone())
+            };
+
+            let pks = pks
+                .iter()
+                .map(|(pk, _)| unpack_columns(pk))
+                .collect::<Result<Vec<Vec<SqliteValueRef>>, _>>()?;
+
+            let tmp_table_name = format!("temp_{table}");
+            if created {
+                // create a temporary table to mix and match the data
+                tx.prepare_cached(
+                    // TODO: cache the statement's string somewhere, it's always the same!
+                    // NOTE: this can't be an actual CREATE TEMP TABLE or it won't be visible
+                    //       from the state db
+                    &format!(
+                        "CREATE TABLE IF NOT EXISTS {tmp_table_name} ({})",
+                        table_pks.join(",")
+                    ),
+                )?
+                .execute(())?;
+            }
+
+            for pks in pks {
+                tx.prepare_cached(&format!(
+                    "INSERT INTO {tmp_table_name} VALUES ({})",
+                    (0..pks.len())
+                        .map(|_i| "coalesce(?, \"\")")
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ))?
+                .execute(params_from_iter(pks))?;
+            }
+        }
+        // commit so it is visible to the other db!
+        tx.commit()?;
+        trace!("committed temp pk tables");
+
+        for table in self.linked.keys() {
+            if self.pks.contains_key(table) && tables.contains(table.as_str()) {
+                linked_tables.insert(TableName(table.as_str().into()));
+            }
+        }
+        self.add_linked_candidates(state_conn, &linked_tables, &mut tables)?;
+
+        // a window is recomputed whole, whichever rows changed, and so is any row when
+        // tables read by subqueries change
+        let full = self.full_stmt.is_some() && (self.parsed.windowed || subquery_changed);
+
+        // rows neither in the window nor matching the query can't move it
+        let unaffected = full
+            && !subquery_changed
+            && linked_tables.is_empty()
+            && self.grouped.is_none()
+            && !self.window_affected(state_conn, &tables)?;
+
+        if !full {
+            self.expand_left_joined_candidates(state_conn, &mut tables)?;
+            self.refresh_groups(state_conn)?;
+        } else if !self.parsed.windowed {
+            self.rebuild_groups(state_conn)?;
+        }
+
+        let mut query_cols = vec![];
+
+        let pk_cols = self.key_cols();
+        let coalesced_pks = self.coalesced_key_cols();
+
+        let mut all_cols = pk_cols.clone();
+        for i in 0..(self.parsed.columns.len()) {
+            let col_name = format!("col_{i}");
+            all_cols.push(col_name.clone());
+            query_cols.push(col_name);
+        }
+
+        // grouped queries recompute their changed groups as a whole
+        let passes = match (&self.full_stmt, &self.grouped) {
+            _ if unaffected => vec![],
+            (Some(full_stmt), _) if full => vec![("full", full_stmt)],
+            (_, Some(grouped)) => vec![("groups", &grouped.stmt)],
+            (_, None) => tables
+                .iter()
+                .filter_map(|table| match self.cached_statements.get(table.as_str()) {
+                    Some(stmt) => Some((table.as_str(), stmt)),
+                    None => {
+                        warn!(sub_id = %self.id, "no statements pre-computed for table {table}");
+                        None
+                    }
+                })
+                .collect::<Vec<_>>(),
+        };
+
+        // start a new tx
+        let tx = self.conn.transaction()?;
+
+        // ensure drop and recreate
+        tx.execute_batch(&format!(
+            "CREATE TEMP TABLE IF NOT EXISTS state_results ({})",
+            all_cols.join(",")
+        ))?;
+
+        let mut new_last_rowid = self.last_rowid;
+
+        {
+            // read-only!
+            let state_tx = state_conn.transaction()?;
+            let mut tmp_insert_prepped = tx.prepare_cached(&format!(
+                "INSERT INTO state_results VALUES ({})",
+                (0..all_cols.len())
+                    .map(|_| "?")
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ))?;
+
+            for (table, stmt) in passes {
+                let start = Instant::now();
+
+                trace!("SELECT SQL: {}", stmt.new_query);
+
+                let mut prepped = state_tx.prepare_cached(&stmt.new_query)?;
+
+                let col_count = prepped.column_count();
+
+                let mut rows = prepped.raw_query();
+
+                // insert each row in the other DB...
+                while let Some(row) = rows.next()? {
+                    for i in 0..col_count {
+                        tmp_insert_prepped
+                            .raw_bind_parameter(i + 1, SqliteValueRef::from(row.get_ref(i)?))?;
+                    }
+                    tmp_insert_prepped.raw_execute()?;
+                }
+
+                let sql = format!(
+                    "INSERT INTO query ({insert_cols})
+                        SELECT * FROM (
+                            SELECT * FROM state_results
+                            EXCEPT
+                            {query_query}
+                        ) WHERE 1
+                        ON CONFLICT({conflict_clause})
+                            DO UPDATE SET
+                                {excluded}
+                            WHERE {excluded_not_same}
+                        RETURNING __corro_rowid,{return_cols}",
+                    // insert into
+                    insert_cols = all_cols.join(","),
+                    query_query = stmt.temp_query,
+                    conflict_clause = coalesced_pks,
+                    excluded = (0..(self.parsed.columns.len()))
+                        .map(|i| format!("col_{i} = excluded.col_{i}"))
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    excluded_not_same = (0..(self.parsed.columns.len()))
+                        .map(|i| format!("col_{i} IS NOT excluded.col_{i}"))
+                        .collect::<Vec<_>>()
+                        .join(" OR "),
+                    return_cols = query_cols.join(",")
+                );
 
-                let coalesced_pks = pk_cols
-                    .iter()
-                    .map(|pk| format!("coalesce({pk},\"\")"))
-                    .collect::<Vec<_>>()
-                    .join(",");
-                let sql = format!(
-                    "INSERT INTO query ({insert_cols})
-                        SELECT * FROM (
-                            SELECT * FROM state_results
-                            EXCEPT
-                            {query_query}
-                        ) WHERE 1
-                        ON CONFLICT({conflict_clause})
-                            DO UPDATE SET
-                                {excluded}
-                            WHERE {excluded_not_same}
-                        RETURNING __corro_rowid,{return_cols}",
-                    // insert into
-                    insert_cols = all_cols.join(","),
-                    query_query = stmt.temp_query,
-                    conflict_clause = coalesced_pks,
-                    excluded = (0..(self.parsed.columns.len()))
-                        .map(|i| format!("col_{i} = excluded.col_{i}"))
-                        .collect::<Vec<_>>()
-                        .join(","),
-                    excluded_not_same = (0..(self.parsed.columns.len()))
-                        .map(|i| format!("col_{i} IS NOT excluded.col_{i}"))
-                        .collect::<Vec<_>>()
-                        .join(" OR "),
-                    return_cols = query_cols.join(",")
-                );
-
                 trace!("INSERT SQL: {sql}");
 
                 let insert_prepped = tx.prepare_cached(&sql)?;
@@ -1683,7 +2062,7 @@ impl Matcher {
                 tx.execute_batch("DELETE FROM state_results")?;
 
                 let elapsed = start.elapsed();
-                histogram!("corro.subs.changes.processing.table.duration.seconds", "sql_hash" => self.hash.clone(), "table" => table.0.to_string()).record(elapsed);
+                histogram!("corro.subs.changes.processing.table.duration.seconds", "sql_hash" => self.hash.clone(), "table" => table.to_string()).record(elapsed);
             }
 
             // clean up temporary tables immediately
@@ -1693,6 +2072,9 @@ impl Matcher {
                     .execute(())?;
                 trace!("cleaned up temp_{table}");
             }
+            if self.grouped.is_some() && !full {
+                tx.execute_batch("DELETE FROM temp_groups")?;
+            }
         }
 
         update_last_db_version(&tx, last_db_version)?;
@@ -1804,49 +2186,562 @@ fn interrupt_deadline_guard(conn: &Connection, dur: Duration) -> DropGuard {
     cancel.drop_guard()
 }
 
-#[derive(Debug, Default, Clone)]
-pub struct ParsedSelect {
-    table_columns: IndexMap<String, HashSet<String>>,
-    aliases: HashMap<String, String>,
-    pub columns: Vec<ResultColumn>,
-    children: Vec<ParsedSelect>,
+/// A subscription's statement, changed to also select the primary keys of
+/// its tables, and the statements matching changes to each table
+struct RewrittenQuery {
+    stmt: Stmt,
+    parsed: ParsedSelect,
+    pks: IndexMap<String, Vec<String>>,
+    statements: HashMap<String, MatcherStmt>,
+    grouped: Option<GroupedStmt>,
+    // recomputes the whole result set, for windows and changes read by subqueries
+    full_stmt: Option<MatcherStmt>,
+    linked: IndexMap<String, LinkedStmt>,
 }
 
-fn extract_select_columns(select: &Select, schema: &Schema) -> Result<ParsedSelect, MatcherError> {
-    let mut parsed = ParsedSelect::default();
+fn rewrite_query(schema: &Schema, sql: &str) -> Result<RewrittenQuery, MatcherError> {
+    let mut parser = Parser::new(sql.as_bytes());
 
-    if let OneSelect::Select {
-        ref from,
-        ref columns,
-        ref where_clause,
-        ..
-    } = select.body.select
-    {
-        let from_table = match from {
-            Some(from) => {
-                let from_table = match &from.select {
-                    Some(table) => match table.as_ref() {
-                        SelectTable::Table(name, alias, _) => {
-                            if schema.tables.contains_key(name.name.0.as_str()) {
-                                if let Some(As::As(alias) | As::Elided(alias)) = alias {
-                                    parsed.aliases.insert(alias.0.clone(), name.name.0.clone());
-                                } else if let Some(ref alias) = name.alias {
-                                    parsed.aliases.insert(alias.0.clone(), name.name.0.clone());
-                                }
-                                parsed.table_columns.entry(name.name.0.clone()).or_default();
-                                Some(&name.name)
-                            } else {
-                                return Err(MatcherError::TableNotFound(name.name.0.clone()));
-                            }
-                        }
-                        // TODO: add support for:
-                        // TableCall(QualifiedName, Option<Vec<Expr>>, Option<As>),
-                        // Select(Select, Option<As>),
-                        // Sub(FromClause, Option<As>),
-                        t => {
-                            warn!("ignoring {t:?}");
-                            None
-                        }
+    let (mut stmt, parsed) = match parser.next()?.ok_or(MatcherError::StatementRequired)? {
+        Cmd::Stmt(stmt) => {
+            let parsed = match stmt {
+                Stmt::Select(ref select) => extract_select_columns(select, schema)?,
+                _ => return Err(MatcherError::UnsupportedStatement),
+            };
+
+            (stmt, parsed)
+        }
+        _ => return Err(MatcherError::StatementRequired),
+    };
+
+    if parsed.table_columns.is_empty() {
+        return Err(MatcherError::TableRequired);
+    }
+
+    let mut statements = HashMap::new();
+
+    let mut pks = IndexMap::default();
+    let mut member_columns = None;
+
+    match &mut stmt {
+        Stmt::Select(select) => match &mut select.body.select {
+            OneSelect::Select {
+                columns, group_by, ..
+            } => {
+                let mut new_cols = parsed
+                    .table_columns
+                    .iter()
+                    .filter_map(|(tbl_name, _cols)| {
+                        schema.tables.get(tbl_name).map(|table| {
+                            let tbl_name = parsed
+                                .aliases
+                                .iter()
+                                .find_map(|(alias, actual)| (actual == tbl_name).then_some(alias))
+                                .unwrap_or(tbl_name);
+                            table
+                                .pk
+                                .iter()
+                                .map(|pk| {
+                                    let alias = format!("__corro_pk_{tbl_name}_{pk}");
+                                    let entry: &mut Vec<String> =
+                                        pks.entry(table.name.clone()).or_default();
+                                    entry.push(alias.clone());
+
+                                    ResultColumn::Expr(
+                                        Expr::Qualified(Name(tbl_name.clone()), Name(pk.clone())),
+                                        Some(As::As(Name(alias))),
+                                    )
+                                })
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .flatten()
+                    .collect::<Vec<_>>();
+
+                if let Some(keys) = &parsed.group_by {
+                    // the rows of an aggregate query are its groups, identified by their
+                    // keys, while the primary keys identify the rows of each group
+                    let mut cols = std::mem::replace(&mut new_cols, group_key_columns(keys));
+                    cols.extend(group_key_columns(keys));
+                    member_columns = Some(cols);
+
+                    if let Some(group_by) = group_by {
+                        group_by.exprs = keys.clone();
+                        group_by.having = parsed.having.clone();
+                    }
+                }
+
+                new_cols.append(&mut parsed.columns.clone());
+                *columns = new_cols;
+            }
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    }
+
+    // result columns are renamed and preceded by key columns
+    if let (Some(order_by), Stmt::Select(select)) = (&parsed.order_by, &mut stmt) {
+        for (col, expr) in select.order_by.iter_mut().flatten().zip(order_by) {
+            col.expr = expr.clone();
+        }
+    }
+
+    // statement the queries matching each table's changes are derived from
+    let mut table_stmt = stmt.clone();
+    if let (Some(member_columns), Stmt::Select(select)) = (member_columns, &mut table_stmt) {
+        select.order_by = None;
+        select.limit = None;
+        if let OneSelect::Select {
+            columns, group_by, ..
+        } = &mut select.body.select
+        {
+            *columns = member_columns;
+            *group_by = None;
+        }
+    }
+
+    let group_keys = parsed.group_by.as_ref().map(|keys| {
+        (0..keys.len())
+            .map(|i| format!("__corro_gk_{i}"))
+            .collect::<Vec<_>>()
+    });
+
+    for (idx, (tbl_name, _cols)) in parsed.table_columns.iter().enumerate() {
+        let expr = table_to_expr(
+            &parsed.aliases,
+            schema
+                .tables
+                .get(tbl_name)
+                .expect("this should not happen, missing table in schema"),
+            tbl_name,
+        )?;
+
+        let mut stmt = table_stmt.clone();
+        let mut left_joined = false;
+
+        and_where(&mut stmt, expr);
+
+        if let Stmt::Select(select) = &mut stmt {
+            if let OneSelect::Select { from, .. } = &mut select.body.select {
+                match from {
+                    Some(FromClause {
+                        joins: Some(joins), ..
+                    }) if idx > 0 => {
+                        // Replace LEFT JOIN with INNER join if the target is the joined table
+                        if let Some(JoinedSelectTable {
+                            operator:
+                                JoinOperator::TypedJoin {
+                                    join_type:
+                                        join_type @ Some(JoinType::LeftOuter | JoinType::Left),
+                                    ..
+                                },
+                            ..
+                        }) = joins.get_mut(idx - 1)
+                        {
+                            *join_type = Some(JoinType::Inner);
+                            left_joined = true;
+                        };
+
+                        // Remove all custom INDEXED BY clauses for the table as the most efficient
+                        // way is to query it by the primary keys
+                        if let Some(JoinedSelectTable {
+                            table: SelectTable::Table(_, _, indexed @ Some(_)),
+                            ..
+                        }) = joins.get_mut(idx - 1)
+                        {
+                            *indexed = None
+                        };
+                    }
+                    _ => (),
+                };
+            }
+        }
+
+        let mut new_query = Cmd::Stmt(stmt).to_string();
+        new_query.pop();
+
+        let mut all_cols = pks.values().flatten().cloned().collect::<Vec<String>>();
+        match &group_keys {
+            Some(group_keys) => all_cols.extend(group_keys.iter().cloned()),
+            None => {
+                for i in 0..(parsed.columns.len()) {
+                    all_cols.push(format!("col_{i}"));
+                }
+            }
+        }
+
+        let temp_query = format!(
+            "SELECT {} FROM {} WHERE ({}) IN temp_{tbl_name}",
+            all_cols.join(","),
+            if group_keys.is_some() {
+                MEMBERS_TABLE_NAME
+            } else {
+                QUERY_TABLE_NAME
+            },
+            pks.get(tbl_name)
+                .cloned()
+                .ok_or(MatcherError::MissingPrimaryKeys)?
+                .into_iter()
+                .map(|pk| format!("coalesce({pk}, \"\")"))
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+
+        statements.insert(
+            tbl_name.clone(),
+            MatcherStmt {
+                new_query,
+                temp_query,
+                left_joined,
+            },
+        );
+    }
+
+    let grouped = match (&parsed.group_by, group_keys) {
+        (Some(keys), Some(group_keys)) => {
+            let mut groups_stmt = stmt.clone();
+            and_where(
+                &mut groups_stmt,
+                Expr::in_table(
+                    Expr::Parenthesized(
+                        keys.iter()
+                            .cloned()
+                            .flat_map(|key| {
+                                [
+                                    Expr::IsNull(Box::new(Expr::Parenthesized(vec![key.clone()]))),
+                                    coalesce_expr(key),
+                                ]
+                            })
+                            .collect(),
+                    ),
+                    false,
+                    QualifiedName::fullname(Name("__corro_sub".into()), Name("temp_groups".into())),
+                    None,
+                ),
+            );
+
+            let mut new_query = Cmd::Stmt(groups_stmt).to_string();
+            new_query.pop();
+
+            let mut all_cols = group_keys.clone();
+            for i in 0..(parsed.columns.len()) {
+                all_cols.push(format!("col_{i}"));
+            }
+
+            let temp_query = format!(
+                "SELECT {} FROM query WHERE ({}) IN temp_groups",
+                all_cols.join(","),
+                coalesced_group_keys(&group_keys),
+            );
+
+            let mut members_query = Cmd::Stmt(table_stmt).to_string();
+            members_query.pop();
+
+            Some(GroupedStmt {
+                keys: group_keys,
+                members_query,
+                stmt: MatcherStmt {
+                    new_query,
+                    temp_query,
+                    left_joined: false,
+                },
+            })
+        }
+        _ => None,
+    };
+
+    let full_stmt = if parsed.windowed || !parsed.subquery_columns.is_empty() {
+        let mut new_query = Cmd::Stmt(stmt.clone()).to_string();
+        new_query.pop();
+
+        let mut all_cols = match &grouped {
+            Some(grouped) => grouped.keys.clone(),
+            None => pks.values().flatten().cloned().collect(),
+        };
+        for i in 0..(parsed.columns.len()) {
+            all_cols.push(format!("col_{i}"));
+        }
+
+        Some(MatcherStmt {
+            new_query,
+            temp_query: format!("SELECT {} FROM query", all_cols.join(",")),
+            left_joined: false,
+        })
+    } else {
+        None
+    };
+
+    let mut linked = IndexMap::new();
+    for (tbl_name, links) in parsed.subquery_links.iter() {
+        if parsed.unlinked_tables.contains(tbl_name) {
+            continue;
+        }
+        let table = schema
+            .tables
+            .get(tbl_name)
+            .ok_or_else(|| MatcherError::TableNotFound(tbl_name.clone()))?;
+
+        let keys = links
+            .iter()
+            .map(|link| link.column.clone())
+            .collect::<IndexSet<_>>();
+
+        let keys_query = format!(
+            "SELECT {},{} FROM {tbl_name}",
+            table.pk.iter().cloned().collect::<Vec<_>>().join(","),
+            keys.iter().cloned().collect::<Vec<_>>().join(","),
+        );
+        let changed = format!(
+            "({}) IN __corro_sub.temp_{tbl_name}",
+            table
+                .pk
+                .iter()
+                .map(|pk| format!("coalesce({pk}, \"\")"))
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+
+        let candidates = links
+            .iter()
+            .map(|link| {
+                let outer_pks = &schema
+                    .tables
+                    .get(&link.outer_table)
+                    .ok_or_else(|| MatcherError::TableNotFound(link.outer_table.clone()))?
+                    .pk;
+                let key = keys.get_index_of(&link.column).unwrap_or_default();
+                let outer_column = format!("{}.{}", link.outer_table, link.outer_column);
+                // an `IN (SELECT ...)` test gives NULL outer keys another result once
+                // NULL or no key is listed, and NULL keys change the result of any row
+                let nulls = if link.listed {
+                    format!(
+                        " OR {outer_column} IS NULL OR EXISTS (SELECT 1 FROM keys WHERE k IS NULL)"
+                    )
+                } else {
+                    String::new()
+                };
+                // keys of the changed rows, before and after
+                let query = format!(
+                    "WITH keys(k) AS (
+                        SELECT __corro_lk_{key} FROM __corro_sub.{LINKS_TABLE_PREFIX}{tbl_name} WHERE {changed}
+                        UNION SELECT {column} FROM {tbl_name} WHERE {changed}
+                    )
+                    SELECT {} FROM {} WHERE {outer_column} IN keys{nulls}",
+                    outer_pks
+                        .iter()
+                        .map(|pk| format!("coalesce({}.{pk}, \"\")", link.outer_table))
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    link.outer_table,
+                    column = link.column,
+                );
+                Ok((link.outer_table.clone(), query))
+            })
+            .collect::<Result<Vec<_>, MatcherError>>()?;
+
+        linked.insert(
+            tbl_name.clone(),
+            LinkedStmt {
+                pks: table.pk.iter().cloned().collect(),
+                keys: keys.into_iter().collect(),
+                changed_keys_query: format!("{keys_query} WHERE {changed}"),
+                keys_query,
+                candidates,
+            },
+        );
+    }
+
+    Ok(RewrittenQuery {
+        stmt,
+        parsed,
+        pks,
+        statements,
+        grouped,
+        full_stmt,
+        linked,
+    })
+}
+
+fn group_key_columns(keys: &[Expr]) -> Vec<ResultColumn> {
+    keys.iter()
+        .enumerate()
+        .map(|(i, key)| {
+            ResultColumn::Expr(key.clone(), Some(As::As(Name(format!("__corro_gk_{i}")))))
+        })
+        .collect()
+}
+
+/// Group keys as they are compared: a NULL group and an empty string group
+/// must not be mistaken for one another, so each key comes with its nullness
+fn coalesced_group_keys(keys: &[String]) -> String {
+    keys.iter()
+        .map(|key| format!("{key} IS NULL,coalesce({key},\"\")"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn coalesce_expr(expr: Expr) -> Expr {
+    Expr::FunctionCall {
+        name: Id("coalesce".into()),
+        distinctness: None,
+        args: Some(vec![expr, Expr::Literal(Literal::String("''".into()))]),
+        order_by: None,
+        filter_over: None,
+    }
+}
+
+fn and_where(stmt: &mut Stmt, expr: Expr) {
+    if let Stmt::Select(select) = stmt {
+        if let OneSelect::Select { where_clause, .. } = &mut select.body.select {
+            *where_clause = if let Some(prev) = where_clause.take() {
+                Some(Expr::Binary(
+                    Box::new(expr),
+                    Operator::And,
+                    Box::new(Expr::parenthesized(prev)),
+                ))
+            } else {
+                Some(expr)
+            };
+        }
+    }
+}
+
+/// Describes how a subscription to `sql` would match changes, without
+/// creating it
+pub fn explain_subscription(schema: &Schema, sql: &str) -> Result<SubscriptionPlan, MatcherError> {
+    let RewrittenQuery {
+        stmt,
+        parsed,
+        pks,
+        statements,
+        ..
+    } = rewrite_query(schema, sql)?;
+
+    let mut query = Cmd::Stmt(stmt).to_string();
+    query.pop();
+
+    let tables = parsed
+        .table_columns
+        .iter()
+        .map(|(table, cols)| {
+            let stmt = statements.get(table);
+            let mut columns = cols.iter().cloned().collect::<Vec<_>>();
+            columns.sort();
+            SubscriptionTablePlan {
+                table: table.clone(),
+                pks: pks.get(table).cloned().unwrap_or_default(),
+                columns,
+                query: stmt.map(|stmt| stmt.new_query.clone()).unwrap_or_default(),
+                temp_query: stmt.map(|stmt| stmt.temp_query.clone()).unwrap_or_default(),
+            }
+        })
+        .collect();
+
+    Ok(SubscriptionPlan { query, tables })
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ParsedSelect {
+    table_columns: IndexMap<String, HashSet<String>>,
+    aliases: HashMap<String, String>,
+    pub columns: Vec<ResultColumn>,
+    children: Vec<ParsedSelect>,
+    // group keys of an aggregate query, with result aliases resolved
+    group_by: Option<Vec<Expr>>,
+    having: Option<Expr>,
+    // ORDER BY terms, with result aliases resolved
+    order_by: Option<Vec<Expr>>,
+    // only a window of the rows is kept, through LIMIT
+    windowed: bool,
+    // tables read by subqueries and CTEs
+    subquery_columns: IndexMap<String, HashSet<String>>,
+    // reads of subquery tables correlated with the outer query's rows, a change
+    // to one of their rows only affects the rows sharing its key
+    subquery_links: IndexMap<String, Vec<SubqueryLink>>,
+    // subquery tables read without such a correlation, any of their changes
+    // can affect any row
+    unlinked_tables: HashSet<String>,
+    // how the select, as a subquery, reads the rows of its only table
+    link: Option<(String, SubqueryLink)>,
+    // names of the CTEs visible from the select
+    ctes: HashSet<String>,
+}
+
+/// A subquery only reading the rows of its table whose `column` equals the
+/// outer query's `outer_table.outer_column`
+#[derive(Debug, Clone)]
+struct SubqueryLink {
+    column: String,
+    outer_table: String,
+    outer_column: String,
+    // the subquery lists the values of `column` for an `IN (SELECT ...)` test
+    listed: bool,
+}
+
+fn extract_select_columns(select: &Select, schema: &Schema) -> Result<ParsedSelect, MatcherError> {
+    extract_nested_select_columns(select, schema, None)
+}
+
+/// Extracts the columns of a select, which is a subquery when the CTEs
+/// visible from its parent are passed
+fn extract_nested_select_columns(
+    select: &Select,
+    schema: &Schema,
+    parent_ctes: Option<&HashSet<String>>,
+) -> Result<ParsedSelect, MatcherError> {
+    let mut parsed = ParsedSelect::default();
+    let nested = parent_ctes.is_some();
+    parsed.ctes = parent_ctes.cloned().unwrap_or_default();
+
+    if let Some(with) = &select.with {
+        for cte in with.ctes.iter() {
+            parsed.ctes.insert(cte.tbl_name.0.clone());
+        }
+        for cte in with.ctes.iter() {
+            let child = extract_nested_select_columns(&cte.select, schema, Some(&parsed.ctes))?;
+            parsed.children.push(child);
+        }
+    }
+
+    if let OneSelect::Select {
+        ref from,
+        ref columns,
+        ref where_clause,
+        ref group_by,
+        ..
+    } = select.body.select
+    {
+        let from_table = match from {
+            Some(from) => {
+                let from_table = match &from.select {
+                    Some(table) => match table.as_ref() {
+                        SelectTable::Table(name, _, _) if parsed.ctes.contains(&name.name.0) => {
+                            if !nested {
+                                return Err(MatcherError::CteInOuterQuery(name.name.0.clone()));
+                            }
+                            None
+                        }
+                        SelectTable::Table(name, alias, _) => {
+                            if schema.tables.contains_key(name.name.0.as_str()) {
+                                if let Some(As::As(alias) | As::Elided(alias)) = alias {
+                                    parsed.aliases.insert(alias.0.clone(), name.name.0.clone());
+                                } else if let Some(ref alias) = name.alias {
+                                    parsed.aliases.insert(alias.0.clone(), name.name.0.clone());
+                                }
+                                parsed.table_columns.entry(name.name.0.clone()).or_default();
+                                Some(&name.name)
+                            } else {
+                                return Err(MatcherError::TableNotFound(name.name.0.clone()));
+                            }
+                        }
+                        // TODO: add support for:
+                        // TableCall(QualifiedName, Option<Vec<Expr>>, Option<As>),
+                        // Select(Select, Option<As>),
+                        // Sub(FromClause, Option<As>),
+                        t => {
+                            warn!("ignoring {t:?}");
+                            None
+                        }
                     },
                     _ => {
                         // according to the sqlite3-parser docs, this can't really happen
@@ -1858,6 +2753,17 @@ fn extract_select_columns(select: &Select, schema: &Schema) -> Result<ParsedSele
                     for join in joins.iter() {
                         // let mut tbl_name = None;
                         let tbl_name = match &join.table {
+                            SelectTable::Table(name, _, _)
+                                if parsed.ctes.contains(&name.name.0) =>
+                            {
+                                if !nested {
+                                    return Err(MatcherError::CteInOuterQuery(name.name.0.clone()));
+                                }
+                                if let Some(JoinConstraint::On(expr)) = &join.constraint {
+                                    extract_expr_columns(expr, schema, &mut parsed)?;
+                                }
+                                continue;
+                            }
                             SelectTable::Table(name, alias, _) => {
                                 if let Some(As::As(alias) | As::Elided(alias)) = alias {
                                     parsed.aliases.insert(alias.0.clone(), name.name.0.clone());
@@ -1902,38 +2808,281 @@ fn extract_select_columns(select: &Select, schema: &Schema) -> Result<ParsedSele
         };
 
         extract_columns(columns.as_slice(), from_table, schema, &mut parsed)?;
+
+        parsed.group_by = match group_by {
+            Some(GroupBy { exprs, .. }) => Some(
+                exprs
+                    .iter()
+                    .map(|expr| resolve_result_column_ref(expr, columns, schema, &parsed))
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            // aggregates without a GROUP BY make up a single group
+            None if columns
+                .iter()
+                .any(|col| matches!(col, ResultColumn::Expr(expr, _) if is_aggregate(expr))) =>
+            {
+                Some(vec![Expr::Literal(Literal::Numeric("1".into()))])
+            }
+            None => None,
+        };
+
+        if let Some(GroupBy {
+            having: Some(having),
+            ..
+        }) = group_by
+        {
+            let mut having = having.clone();
+            resolve_result_aliases(&mut having, columns, schema, &parsed);
+            parsed.having = Some(having);
+        }
+
+        if let Some(order_by) = &select.order_by {
+            parsed.order_by = Some(
+                order_by
+                    .iter()
+                    .map(|col| resolve_result_column_ref(&col.expr, columns, schema, &parsed))
+                    .collect::<Result<Vec<_>, _>>()?,
+            );
+        }
+        parsed.windowed = select.limit.is_some();
+
+        let mut exprs = parsed.group_by.clone().unwrap_or_default();
+        exprs.extend(parsed.having.clone());
+        exprs.extend(parsed.order_by.clone().unwrap_or_default());
+        for expr in exprs.iter() {
+            extract_expr_columns(expr, schema, &mut parsed)?;
+        }
+    }
+
+    for mut child in std::mem::take(&mut parsed.children) {
+        // only the subqueries of the outer query are correlated with its rows
+        let link = if nested { None } else { child.link.take() };
+
+        for (tbl_name, cols) in child.subquery_columns {
+            parsed.unlinked_tables.insert(tbl_name.clone());
+            parsed
+                .subquery_columns
+                .entry(tbl_name)
+                .or_default()
+                .extend(cols);
+        }
+
+        for (tbl_name, cols) in child.table_columns {
+            let linked_table = link.as_ref().map(|(table, _)| table);
+            if linked_table.is_some_and(|table| *table != tbl_name)
+                && parsed.table_columns.contains_key(&tbl_name)
+            {
+                // a linked subquery reads a single table, any other is the outer one's
+                let entry = parsed.table_columns.entry(tbl_name.clone()).or_default();
+                for col in cols.iter() {
+                    insert_col(entry, schema, &tbl_name, col);
+                }
+            } else if schema.tables.contains_key(&tbl_name) {
+                match &link {
+                    Some((table, link)) if *table == tbl_name => parsed
+                        .subquery_links
+                        .entry(tbl_name.clone())
+                        .or_default()
+                        .push(link.clone()),
+                    _ => {
+                        parsed.unlinked_tables.insert(tbl_name.clone());
+                    }
+                }
+                parsed
+                    .subquery_columns
+                    .entry(tbl_name)
+                    .or_default()
+                    .extend(cols);
+            } else if let Some(actual) = parsed.aliases.get(&tbl_name).cloned() {
+                // a correlated subquery reading columns of this select's tables
+                let entry = parsed.table_columns.entry(actual.clone()).or_default();
+                for col in cols.iter() {
+                    insert_col(entry, schema, &actual, col);
+                }
+            }
+        }
     }
 
     Ok(parsed)
 }
 
-fn insert_col(set: &mut HashSet<String>, schema: &Schema, tbl_name: &str, name: &str) {
-    let table = schema.tables.get(tbl_name);
-    if let Some(generated) =
-        table.and_then(|tbl| tbl.columns.get(name).and_then(|col| col.generated.as_ref()))
-    {
-        // recursively check for generated columns
-        for name in generated.from.iter() {
-            insert_col(set, schema, tbl_name, name);
+const AGGREGATE_FUNCTIONS: &[&str] = &[
+    "avg",
+    "count",
+    "group_concat",
+    "json_group_array",
+    "json_group_object",
+    "max",
+    "min",
+    "string_agg",
+    "sum",
+    "total",
+];
+
+fn is_aggregate(expr: &Expr) -> bool {
+    match expr {
+        Expr::FunctionCallStar { name, .. } => name.0.eq_ignore_ascii_case("count"),
+        Expr::FunctionCall { name, args, .. } => {
+            let arg_count = args.as_ref().map(Vec::len).unwrap_or_default();
+            // min and max are scalar functions when given multiple arguments
+            let scalar = arg_count > 1
+                && (name.0.eq_ignore_ascii_case("min") || name.0.eq_ignore_ascii_case("max"));
+            (!scalar
+                && AGGREGATE_FUNCTIONS
+                    .iter()
+                    .any(|agg| name.0.eq_ignore_ascii_case(agg)))
+                || args.iter().flatten().any(is_aggregate)
         }
-    } else {
-        set.insert(name.to_owned());
+        Expr::Binary(lhs, _, rhs) => is_aggregate(lhs) || is_aggregate(rhs),
+        Expr::Unary(_, expr) | Expr::Collate(expr, _) | Expr::Cast { expr, .. } => {
+            is_aggregate(expr)
+        }
+        Expr::Parenthesized(exprs) => exprs.iter().any(is_aggregate),
+        Expr::Case {
+            base,
+            when_then_pairs,
+            else_expr,
+        } => {
+            base.iter()
+                .chain(else_expr.iter())
+                .any(|expr| is_aggregate(expr))
+                || when_then_pairs.iter().any(|(when_expr, then_expr)| {
+                    is_aggregate(when_expr) || is_aggregate(then_expr)
+                })
+        }
+        _ => false,
     }
 }
 
-fn extract_expr_columns(
+/// Resolves a GROUP BY or ORDER BY term referring to a result column, by
+/// ordinal or alias, to the result column's expression
+fn resolve_result_column_ref(
     expr: &Expr,
+    columns: &[ResultColumn],
     schema: &Schema,
-    parsed: &mut ParsedSelect,
-) -> Result<(), MatcherError> {
-    match expr {
-        // simplest case
-        Expr::Qualified(tblname, colname) => {
-            let resolved_name = parsed.aliases.get(&tblname.0).unwrap_or(&tblname.0);
-            // println!("adding column: {resolved_name} => {colname:?}");
-            insert_col(
-                parsed
-                    .table_columns
+    parsed: &ParsedSelect,
+) -> Result<Expr, MatcherError> {
+    if let Expr::Literal(Literal::Numeric(n)) = expr {
+        if let Ok(n) = n.parse::<usize>() {
+            return match n.checked_sub(1).and_then(|i| columns.get(i)) {
+                Some(ResultColumn::Expr(expr, _)) => Ok(expr.clone()),
+                _ => Err(MatcherError::UnsupportedExpr { expr: expr.clone() }),
+            };
+        }
+    }
+
+    let mut expr = expr.clone();
+    resolve_result_aliases(&mut expr, columns, schema, parsed);
+    Ok(expr)
+}
+
+/// Replaces identifiers naming a result column's alias, rather than a column
+/// of a queried table, with the aliased expression. Result columns get
+/// renamed when the query is rewritten, so their aliases can't be used.
+fn resolve_result_aliases(
+    expr: &mut Expr,
+    columns: &[ResultColumn],
+    schema: &Schema,
+    parsed: &ParsedSelect,
+) {
+    let resolve = |expr: &mut Expr| resolve_result_aliases(expr, columns, schema, parsed);
+
+    match expr {
+        Expr::Id(Id(name)) => {
+            let name = unquote(name).ok().unwrap_or(name.clone());
+            let is_column = parsed.table_columns.keys().any(|tbl| {
+                schema
+                    .tables
+                    .get(tbl)
+                    .map(|tbl| tbl.columns.contains_key(&name))
+                    .unwrap_or(false)
+            });
+            if is_column {
+                return;
+            }
+            let aliased = columns.iter().find_map(|col| match col {
+                ResultColumn::Expr(aliased, Some(As::As(alias) | As::Elided(alias)))
+                    if unquote(&alias.0).ok().unwrap_or(alias.0.clone()) == name =>
+                {
+                    Some(aliased.clone())
+                }
+                _ => None,
+            });
+            if let Some(aliased) = aliased {
+                *expr = Expr::parenthesized(aliased);
+            }
+        }
+        Expr::Binary(lhs, _, rhs) => {
+            resolve(lhs);
+            resolve(rhs);
+        }
+        Expr::Between {
+            lhs, start, end, ..
+        } => {
+            resolve(lhs);
+            resolve(start);
+            resolve(end);
+        }
+        Expr::Like { lhs, rhs, .. } => {
+            resolve(lhs);
+            resolve(rhs);
+        }
+        Expr::Unary(_, expr)
+        | Expr::Collate(expr, _)
+        | Expr::Cast { expr, .. }
+        | Expr::IsNull(expr)
+        | Expr::NotNull(expr) => resolve(expr),
+        Expr::Parenthesized(exprs) => exprs.iter_mut().for_each(resolve),
+        Expr::FunctionCall {
+            args: Some(args), ..
+        } => args.iter_mut().for_each(resolve),
+        Expr::InList { lhs, rhs, .. } => {
+            resolve(lhs);
+            rhs.iter_mut().flatten().for_each(resolve);
+        }
+        Expr::Case {
+            base,
+            when_then_pairs,
+            else_expr,
+        } => {
+            base.iter_mut().for_each(|expr| resolve(expr));
+            for (when_expr, then_expr) in when_then_pairs.iter_mut() {
+                resolve(when_expr);
+                resolve(then_expr);
+            }
+            else_expr.iter_mut().for_each(|expr| resolve(expr));
+        }
+        _ => {}
+    }
+}
+
+fn insert_col(set: &mut HashSet<String>, schema: &Schema, tbl_name: &str, name: &str) {
+    let table = schema.tables.get(tbl_name);
+    if let Some(generated) =
+        table.and_then(|tbl| tbl.columns.get(name).and_then(|col| col.generated.as_ref()))
+    {
+        // recursively check for generated columns
+        for name in generated.from.iter() {
+            insert_col(set, schema, tbl_name, name);
+        }
+    } else {
+        set.insert(name.to_owned());
+    }
+}
+
+fn extract_expr_columns(
+    expr: &Expr,
+    schema: &Schema,
+    parsed: &mut ParsedSelect,
+) -> Result<(), MatcherError> {
+    match expr {
+        // simplest case
+        Expr::Qualified(tblname, colname) => {
+            let resolved_name = parsed.aliases.get(&tblname.0).unwrap_or(&tblname.0);
+            // println!("adding column: {resolved_name} => {colname:?}");
+            insert_col(
+                parsed
+                    .table_columns
                     .entry(resolved_name.clone())
                     .or_default(),
                 schema,
@@ -2021,7 +3170,13 @@ fn extract_expr_columns(
             }
         }
 
-        Expr::Between { lhs, .. } => extract_expr_columns(lhs, schema, parsed)?,
+        Expr::Between {
+            lhs, start, end, ..
+        } => {
+            extract_expr_columns(lhs, schema, parsed)?;
+            extract_expr_columns(start, schema, parsed)?;
+            extract_expr_columns(end, schema, parsed)?;
+        }
         Expr::Binary(lhs, _, rhs) => {
             extract_expr_columns(lhs, schema, parsed)?;
             extract_expr_columns(rhs, schema, parsed)?;
@@ -2034,9 +3189,9 @@ fn extract_expr_columns(
             if let Some(expr) = base {
                 extract_expr_columns(expr, schema, parsed)?;
             }
-            for (when_expr, _then_expr) in when_then_pairs.iter() {
-                // NOTE: should we also parse the then expr?
+            for (when_expr, then_expr) in when_then_pairs.iter() {
                 extract_expr_columns(when_expr, schema, parsed)?;
+                extract_expr_columns(then_expr, schema, parsed)?;
             }
             if let Some(expr) = else_expr {
                 extract_expr_columns(expr, schema, parsed)?;
@@ -2045,9 +3200,9 @@ fn extract_expr_columns(
         Expr::Cast { expr, .. } => extract_expr_columns(expr, schema, parsed)?,
         Expr::Collate(expr, _) => extract_expr_columns(expr, schema, parsed)?,
         Expr::Exists(select) => {
-            parsed
-                .children
-                .push(extract_select_columns(select, schema)?);
+            let mut child = extract_nested_select_columns(select, schema, Some(&parsed.ctes))?;
+            child.link = subquery_link(select, None, schema, parsed);
+            parsed.children.push(child);
         }
         Expr::FunctionCall { args, .. } => {
             if let Some(args) = args {
@@ -2066,7 +3221,27 @@ fn extract_expr_columns(
         }
         Expr::InSelect { lhs, rhs, .. } => {
             extract_expr_columns(lhs, schema, parsed)?;
-            parsed.children.push(extract_select_columns(rhs, schema)?);
+            let mut child = extract_nested_select_columns(rhs, schema, Some(&parsed.ctes))?;
+            child.link = subquery_link(rhs, Some(lhs), schema, parsed);
+            parsed.children.push(child);
+        }
+        Expr::InTable {
+            lhs,
+            rhs,
+            args: None,
+            ..
+        } if rhs.db_name.is_none() => {
+            extract_expr_columns(lhs, schema, parsed)?;
+            if let Some(table) = schema.tables.get(&rhs.name.0) {
+                parsed.unlinked_tables.insert(table.name.clone());
+                parsed
+                    .subquery_columns
+                    .entry(table.name.clone())
+                    .or_default()
+                    .extend(table.columns.keys().cloned());
+            } else if !parsed.ctes.contains(&rhs.name.0) {
+                return Err(MatcherError::TableNotFound(rhs.name.0.clone()));
+            }
         }
         expr @ Expr::InTable { .. } => {
             return Err(MatcherError::UnsupportedExpr { expr: expr.clone() })
@@ -2088,9 +3263,9 @@ fn extract_expr_columns(
             }
         }
         Expr::Subquery(select) => {
-            parsed
-                .children
-                .push(extract_select_columns(select, schema)?);
+            let mut child = extract_nested_select_columns(select, schema, Some(&parsed.ctes))?;
+            child.link = subquery_link(select, None, schema, parsed);
+            parsed.children.push(child);
         }
         Expr::Unary(_, expr) => {
             extract_expr_columns(expr, schema, parsed)?;
@@ -2108,6 +3283,128 @@ fn extract_expr_columns(
     Ok(())
 }
 
+/// Finds how a subquery reading a single table is correlated with the rows of
+/// `parent`: through an equality between their columns in its WHERE clause, or
+/// as the list of an `IN (SELECT ...)` test on one of the parent's columns
+fn subquery_link(
+    select: &Select,
+    lhs: Option<&Expr>,
+    schema: &Schema,
+    parent: &ParsedSelect,
+) -> Option<(String, SubqueryLink)> {
+    if select.with.is_some() || select.body.compounds.is_some() {
+        return None;
+    }
+    let OneSelect::Select {
+        from: Some(from),
+        columns,
+        where_clause,
+        group_by,
+        ..
+    } = &select.body.select
+    else {
+        return None;
+    };
+    if from.joins.is_some() {
+        return None;
+    }
+    let (table, alias) = match from.select.as_deref() {
+        Some(SelectTable::Table(name, alias, _)) if !parent.ctes.contains(&name.name.0) => {
+            let alias = match alias {
+                Some(As::As(alias) | As::Elided(alias)) => alias.0.clone(),
+                None => name.name.0.clone(),
+            };
+            (schema.tables.get(&name.name.0)?, alias)
+        }
+        _ => return None,
+    };
+
+    let inner_column = |expr: &Expr| {
+        let col = match expr {
+            Expr::Qualified(tbl_name, col) if tbl_name.0 == alias => &col.0,
+            Expr::Name(Name(col)) | Expr::Id(Id(col)) => col,
+            _ => return None,
+        };
+        let col = unquote(col).ok().unwrap_or(col.clone());
+        table.columns.contains_key(&col).then_some(col)
+    };
+    let outer_column = |expr: &Expr| match expr {
+        Expr::Qualified(tbl_name, col) if tbl_name.0 != alias => {
+            let outer = parent.aliases.get(&tbl_name.0).unwrap_or(&tbl_name.0);
+            schema
+                .tables
+                .get(outer)
+                .filter(|outer| {
+                    parent.table_columns.contains_key(&outer.name)
+                        && outer.columns.contains_key(&col.0)
+                })
+                .map(|outer| (outer.name.clone(), col.0.clone()))
+        }
+        // unqualified names are the subquery's own columns first
+        Expr::Name(Name(col)) | Expr::Id(Id(col)) if inner_column(expr).is_none() => {
+            let col = unquote(col).ok().unwrap_or(col.clone());
+            let mut found = parent.table_columns.keys().filter(|tbl_name| {
+                schema
+                    .tables
+                    .get(*tbl_name)
+                    .is_some_and(|tbl| tbl.columns.contains_key(&col))
+            });
+            match (found.next(), found.next()) {
+                (Some(outer), None) => Some((outer.clone(), col)),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+
+    let mut conjuncts = vec![];
+    let mut exprs = where_clause.iter().collect::<Vec<_>>();
+    while let Some(expr) = exprs.pop() {
+        match expr {
+            Expr::Binary(lhs, Operator::And, rhs) => {
+                exprs.push(lhs);
+                exprs.push(rhs);
+            }
+            Expr::Parenthesized(parens) if parens.len() == 1 => exprs.push(&parens[0]),
+            expr => conjuncts.push(expr),
+        }
+    }
+
+    // a correlation only reads the rows with the outer row's key, whatever
+    // the subquery does with them
+    let correlated = conjuncts.into_iter().find_map(|expr| match expr {
+        Expr::Binary(lhs, Operator::Equals, rhs) => inner_column(lhs)
+            .zip(outer_column(rhs))
+            .or_else(|| inner_column(rhs).zip(outer_column(lhs))),
+        _ => None,
+    });
+
+    // membership of a value in the list only depends on the rows holding it
+    let listed = || match (lhs, columns.as_slice()) {
+        (Some(lhs), [ResultColumn::Expr(expr, _)])
+            if select.limit.is_none() && group_by.is_none() =>
+        {
+            inner_column(expr).zip(outer_column(lhs))
+        }
+        _ => None,
+    };
+
+    let ((column, (outer_table, outer_column)), listed) = match correlated {
+        Some(correlated) => (correlated, false),
+        None => (listed()?, true),
+    };
+
+    Some((
+        table.name.clone(),
+        SubqueryLink {
+            column,
+            outer_table,
+            outer_column,
+            listed,
+        },
+    ))
+}
+
 fn extract_columns(
     columns: &[ResultColumn],
     from: Option<&Name>,
@@ -2143,9 +3440,8 @@ fn extract_columns(
                             tbl_name: tbl_name.0.clone(),
                         });
                     }
-                } else {
-                    unreachable!()
                 }
+                // otherwise selecting from a CTE, which has no columns of its own
             }
             ResultColumn::TableStar(tbl_name) => {
                 let name = parsed
@@ -2207,6 +3503,8 @@ pub enum MatcherError {
     StatementRequired,
     #[error("unsupported statement")]
     UnsupportedStatement,
+    #[error("CTE '{0}' can't be read from the outer query's FROM or JOIN, read it from a subquery or an IN list instead")]
+    CteInOuterQuery(String),
     #[error("at least 1 table is required in FROM / JOIN clause")]
     TableRequired,
     #[error(transparent)]
@@ -2215,10 +3513,6 @@ pub enum MatcherError {
     TableNotFound(String),
     #[error("no primary key for table: {0}")]
     NoPrimaryKey(String),
-    #[error("aggregate missing primary key {0}.{1}")]
-    AggPrimaryKeyMissing(String, String),
-    #[error("JOIN .. ON expression is not supported for join on table '{table}': {expr:?}")]
-    JoinOnExprUnsupported { table: String, expr: Expr },
     #[error("expression is not supported: {expr:?}")]
     UnsupportedExpr { expr: Expr },
     #[error("could not find table for {tbl_name}.* in corrosion's schema")]
@@ -2255,6 +3549,16 @@ pub enum MatcherError {
     NotRunning,
     #[error("subscription restore is missing SQL query")]
     MissingSql,
+    #[error("unsupported filter, expected a WHERE clause over primary key columns: {0}")]
+    UnsupportedFilter(String),
+    #[error("{column} is not a primary key column of {table}")]
+    NotPrimaryKey { table: String, column: String },
+    #[error("cannot resume updates from change {from}, it must be between {min} and {max}")]
+    UpdatesChangeUnavailable {
+        from: ChangeId,
+        min: ChangeId,
+        max: ChangeId,
+    },
 }
 
 impl MatcherError {
@@ -2461,7 +3765,7 @@ mod tests {
 
     use crate::{
         actor::ActorId,
-        agent::migrate,
+        agent::{migrate, WriteConn},
         change::row_to_change,
         schema::{apply_schema, parse_sql},
         sqlite::{setup_conn, CrConn},
@@ -2982,6 +4286,709 @@ mod tests {
         wait_for_all_pending_handles().await;
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_join_on_compound_expr() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let schema_sql = "
+            CREATE TABLE orgs (
+                tenant INTEGER NOT NULL,
+                id INTEGER NOT NULL,
+                name TEXT NOT NULL DEFAULT '',
+                min_since INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (tenant, id)
+            );
+
+            CREATE TABLE members (
+                tenant INTEGER NOT NULL,
+                org_id INTEGER NOT NULL,
+                login TEXT NOT NULL,
+                role TEXT NOT NULL DEFAULT '',
+                since INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (tenant, org_id, login)
+            );
+        ";
+
+        let sql = "SELECT o.name, m.login FROM orgs o
+            LEFT JOIN members m ON m.tenant = o.tenant
+                AND m.org_id = o.id
+                AND lower(m.role) = 'admin'
+                AND m.since BETWEEN o.min_since AND 1000";
+
+        let mut schema = parse_sql(schema_sql)?;
+        let subs = SubsManager::default();
+
+        let (_tmpdir, subscriptions_path, pool, mut conn) = setup_test_db(&mut schema).await?;
+
+        {
+            let tx = conn.transaction()?;
+            tx.execute_batch(
+                "
+                INSERT INTO orgs (tenant, id, name, min_since) VALUES (1, 1, 'acme', 10), (1, 2, 'globex', 10);
+                INSERT INTO members (tenant, org_id, login, role, since) VALUES (1, 1, 'alice', 'Admin', 50), (1, 2, 'bob', 'member', 50);
+                ",
+            )?;
+            tx.commit()?;
+        }
+
+        let (matcher, maybe_created) = subs.get_or_insert(
+            sql,
+            subscriptions_path.as_path(),
+            &schema,
+            &pool,
+            tripwire.clone(),
+        )?;
+        let mut rx = maybe_created.unwrap().evt_rx;
+
+        assert!(matches!(rx.recv().await.unwrap(), QueryEvent::Columns(_)));
+
+        let mut rows = recv_initial_rows(&mut rx).await;
+
+        let row = |name: &str, login: Option<&str>| {
+            vec![
+                SqliteValue::Text(name.into()),
+                login
+                    .map(|login| SqliteValue::Text(login.into()))
+                    .unwrap_or(SqliteValue::Null),
+            ]
+        };
+
+        assert_eq!(
+            sorted_rows(&rows),
+            vec![row("acme", Some("alice")), row("globex", None)]
+        );
+
+        let steps = [
+            // the ON clause stops matching from the joined side
+            (
+                "UPDATE members SET role = 'member' WHERE login = 'alice'",
+                vec![row("acme", None), row("globex", None)],
+            ),
+            // ... and matches again
+            (
+                "UPDATE members SET role = 'ADMIN' WHERE login = 'alice'",
+                vec![row("acme", Some("alice")), row("globex", None)],
+            ),
+            // the ON clause stops matching from the FROM side
+            (
+                "UPDATE orgs SET min_since = 60 WHERE id = 1",
+                vec![row("acme", None), row("globex", None)],
+            ),
+            // a new joined row replaces a NULL-extended one
+            (
+                "INSERT INTO members (tenant, org_id, login, role, since) VALUES (1, 2, 'carol', 'admin', 100)",
+                vec![row("acme", None), row("globex", Some("carol"))],
+            ),
+        ];
+
+        for (i, (stmt, expected)) in steps.into_iter().enumerate() {
+            {
+                let tx = conn.transaction()?;
+                assert_eq!(tx.execute(stmt, ())?, 1);
+                tx.commit()?;
+            }
+
+            filter_changes_from_db(&matcher, &conn, None, CrsqlDbVersion(i as u64 + 2))?;
+            recv_changes_until(&mut rx, &mut rows, &expected).await?;
+        }
+
+        matcher.cleanup().await;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_group_by_aggregates() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let schema_sql = "
+            CREATE TABLE regions (
+                name TEXT NOT NULL PRIMARY KEY,
+                zone TEXT NOT NULL DEFAULT ''
+            );
+
+            CREATE TABLE machines (
+                id INTEGER NOT NULL PRIMARY KEY,
+                region TEXT NOT NULL DEFAULT '',
+                cpus INTEGER NOT NULL DEFAULT 0
+            );
+        ";
+
+        let mut schema = parse_sql(schema_sql)?;
+        let subs = SubsManager::default();
+
+        let (_tmpdir, subscriptions_path, pool, mut conn) = setup_test_db(&mut schema).await?;
+
+        {
+            let tx = conn.transaction()?;
+            tx.execute_batch(
+                "
+                INSERT INTO regions (name, zone) VALUES ('ams', 'eu'), ('fra', 'eu'), ('iad', 'us');
+                INSERT INTO machines (id, region, cpus) VALUES (1, 'ams', 2), (2, 'fra', 4), (3, 'iad', 8);
+                ",
+            )?;
+            tx.commit()?;
+        }
+
+        let subscribe = |sql: &str| {
+            let (matcher, maybe_created) = subs.get_or_insert(
+                sql,
+                subscriptions_path.as_path(),
+                &schema,
+                &pool,
+                tripwire.clone(),
+            )?;
+            Ok::<_, MatcherError>((matcher, maybe_created.unwrap().evt_rx))
+        };
+
+        let (zones, mut zones_rx) = subscribe(
+            "SELECT r.zone AS z, count(*), sum(m.cpus), max(m.cpus)
+                FROM machines m INNER JOIN regions r ON r.name = m.region
+                GROUP BY z",
+        )?;
+        let (total, mut total_rx) = subscribe("SELECT count(*) FROM machines")?;
+
+        assert!(matches!(
+            zones_rx.recv().await.unwrap(),
+            QueryEvent::Columns(_)
+        ));
+        assert!(matches!(
+            total_rx.recv().await.unwrap(),
+            QueryEvent::Columns(_)
+        ));
+
+        let mut zones_rows = recv_initial_rows(&mut zones_rx).await;
+        let mut total_rows = recv_initial_rows(&mut total_rx).await;
+
+        let zone = |zone: &str, count: i64, sum: i64, max: i64| {
+            vec![
+                SqliteValue::Text(zone.into()),
+                SqliteValue::Integer(count),
+                SqliteValue::Integer(sum),
+                SqliteValue::Integer(max),
+            ]
+        };
+
+        assert_eq!(
+            sorted_rows(&zones_rows),
+            vec![zone("eu", 2, 6, 4), zone("us", 1, 8, 8)]
+        );
+        assert_eq!(
+            sorted_rows(&total_rows),
+            vec![vec![SqliteValue::Integer(3)]]
+        );
+
+        let us_rowid = zones_rows.iter().find_map(|(rowid, cells)| {
+            (cells[0] == SqliteValue::Text("us".into())).then_some(*rowid)
+        });
+
+        let steps = [
+            // a row joins a group
+            (
+                "INSERT INTO machines (id, region, cpus) VALUES (4, 'iad', 16)",
+                vec![zone("eu", 2, 6, 4), zone("us", 2, 24, 16)],
+                4,
+            ),
+            // a row moves between groups
+            (
+                "UPDATE machines SET region = 'ams' WHERE id = 3",
+                vec![zone("eu", 3, 14, 8), zone("us", 1, 16, 16)],
+                4,
+            ),
+            // a change to a joined table moves several rows
+            (
+                "UPDATE regions SET zone = 'us' WHERE name = 'ams'",
+                vec![zone("eu", 1, 4, 4), zone("us", 3, 26, 16)],
+                4,
+            ),
+            // the last row of a group goes away
+            (
+                "DELETE FROM machines WHERE id = 2",
+                vec![zone("us", 3, 26, 16)],
+                3,
+            ),
+        ];
+
+        for (i, (stmt, expected, count)) in steps.into_iter().enumerate() {
+            {
+                let tx = conn.transaction()?;
+                assert_eq!(tx.execute(stmt, ())?, 1);
+                tx.commit()?;
+            }
+
+            let db_version = CrsqlDbVersion(i as u64 + 2);
+            filter_changes_from_db(&zones, &conn, None, db_version)?;
+            filter_changes_from_db(&total, &conn, None, db_version)?;
+
+            recv_changes_until(&mut zones_rx, &mut zones_rows, &expected).await?;
+            recv_changes_until(
+                &mut total_rx,
+                &mut total_rows,
+                &[vec![SqliteValue::Integer(count)]],
+            )
+            .await?;
+        }
+
+        // groups are updated in place
+        assert!(zones_rows.contains_key(&us_rowid.unwrap()));
+
+        zones.cleanup().await;
+        total.cleanup().await;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_group_by_null_key() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let schema_sql = "
+            CREATE TABLE machines (
+                id INTEGER NOT NULL PRIMARY KEY,
+                region TEXT,
+                cpus INTEGER NOT NULL DEFAULT 0
+            );
+        ";
+
+        let mut schema = parse_sql(schema_sql)?;
+        let subs = SubsManager::default();
+
+        let (_tmpdir, subscriptions_path, pool, mut conn) = setup_test_db(&mut schema).await?;
+
+        {
+            let tx = conn.transaction()?;
+            tx.execute_batch(
+                "INSERT INTO machines (id, region, cpus) VALUES (1, NULL, 2), (2, '', 4), (3, 'ams', 8);",
+            )?;
+            tx.commit()?;
+        }
+
+        let (matcher, maybe_created) = subs.get_or_insert(
+            "SELECT region, count(*), sum(cpus) FROM machines GROUP BY region",
+            subscriptions_path.as_path(),
+            &schema,
+            &pool,
+            tripwire.clone(),
+        )?;
+        let mut rx = maybe_created.unwrap().evt_rx;
+
+        assert!(matches!(rx.recv().await.unwrap(), QueryEvent::Columns(_)));
+
+        let mut rows = recv_initial_rows(&mut rx).await;
+
+        let group = |region: Option<&str>, count: i64, sum: i64| {
+            vec![
+                region.map_or(SqliteValue::Null, |region| SqliteValue::Text(region.into())),
+                SqliteValue::Integer(count),
+                SqliteValue::Integer(sum),
+            ]
+        };
+
+        // a NULL group is not the empty string group
+        assert_eq!(
+            sorted_rows(&rows),
+            vec![
+                group(None, 1, 2),
+                group(Some(""), 1, 4),
+                group(Some("ams"), 1, 8)
+            ]
+        );
+
+        let steps = [
+            (
+                "UPDATE machines SET region = '' WHERE id = 3",
+                vec![group(None, 1, 2), group(Some(""), 2, 12)],
+            ),
+            (
+                "UPDATE machines SET region = NULL WHERE id = 2",
+                vec![group(None, 2, 6), group(Some(""), 1, 8)],
+            ),
+            (
+                "DELETE FROM machines WHERE id = 1",
+                vec![group(None, 1, 4), group(Some(""), 1, 8)],
+            ),
+        ];
+
+        for (i, (stmt, expected)) in steps.into_iter().enumerate() {
+            {
+                let tx = conn.transaction()?;
+                assert_eq!(tx.execute(stmt, ())?, 1);
+                tx.commit()?;
+            }
+
+            filter_changes_from_db(&matcher, &conn, None, CrsqlDbVersion(i as u64 + 2))?;
+            recv_changes_until(&mut rx, &mut rows, &expected).await?;
+        }
+
+        matcher.cleanup().await;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_top_n_window() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let schema_sql = "
+            CREATE TABLE events (
+                id INTEGER NOT NULL PRIMARY KEY,
+                ts INTEGER NOT NULL DEFAULT 0,
+                kind TEXT NOT NULL DEFAULT ''
+            );
+        ";
+
+        let sql = "SELECT id, ts AS t FROM events WHERE kind != 'debug' ORDER BY t DESC LIMIT 3";
+
+        let mut schema = parse_sql(schema_sql)?;
+        let subs = SubsManager::default();
+
+        let (_tmpdir, subscriptions_path, pool, mut conn) = setup_test_db(&mut schema).await?;
+
+        {
+            let tx = conn.transaction()?;
+            tx.execute_batch(
+                "INSERT INTO events (id, ts) VALUES (1, 10), (2, 20), (3, 30), (4, 40), (5, 50);",
+            )?;
+            tx.commit()?;
+        }
+
+        let (matcher, maybe_created) = subs.get_or_insert(
+            sql,
+            subscriptions_path.as_path(),
+            &schema,
+            &pool,
+            tripwire.clone(),
+        )?;
+        let mut rx = maybe_created.unwrap().evt_rx;
+
+        assert!(matches!(rx.recv().await.unwrap(), QueryEvent::Columns(_)));
+
+        let mut rows = recv_initial_rows(&mut rx).await;
+
+        let event = |id: i64, ts: i64| vec![SqliteValue::Integer(id), SqliteValue::Integer(ts)];
+
+        // initial rows come in order
+        let mut initial = rows.iter().collect::<Vec<_>>();
+        initial.sort_by_key(|(rowid, _)| **rowid);
+        assert_eq!(
+            initial
+                .into_iter()
+                .map(|(_, cells)| cells.clone())
+                .collect::<Vec<_>>(),
+            vec![event(5, 50), event(4, 40), event(3, 30)]
+        );
+
+        let steps = [
+            // a row enters the window, pushing the last one out
+            (
+                "INSERT INTO events (id, ts) VALUES (6, 60)",
+                vec![event(4, 40), event(5, 50), event(6, 60)],
+            ),
+            // a row leaves the window, letting the next one in
+            (
+                "DELETE FROM events WHERE id = 5",
+                vec![event(3, 30), event(4, 40), event(6, 60)],
+            ),
+            // a row from outside the window moves into it
+            (
+                "UPDATE events SET ts = 100 WHERE id = 1",
+                vec![event(1, 100), event(4, 40), event(6, 60)],
+            ),
+            // a row moves within the window
+            (
+                "UPDATE events SET ts = 45 WHERE id = 4",
+                vec![event(1, 100), event(4, 45), event(6, 60)],
+            ),
+            // a row not matching the query doesn't recompute the window
+            (
+                "INSERT INTO events (id, ts, kind) VALUES (7, 1000, 'debug')",
+                vec![event(1, 100), event(4, 45), event(6, 60)],
+            ),
+            // until it does
+            (
+                "UPDATE events SET kind = '' WHERE id = 7",
+                vec![event(1, 100), event(6, 60), event(7, 1000)],
+            ),
+        ];
+
+        for (i, (stmt, expected)) in steps.into_iter().enumerate() {
+            {
+                let tx = conn.transaction()?;
+                assert_eq!(tx.execute(stmt, ())?, 1);
+                tx.commit()?;
+            }
+
+            filter_changes_from_db(&matcher, &conn, None, CrsqlDbVersion(i as u64 + 2))?;
+            recv_changes_until(&mut rx, &mut rows, &expected).await?;
+        }
+
+        matcher.cleanup().await;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_subqueries_and_ctes() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let schema_sql = "
+            CREATE TABLE users (
+                id INTEGER NOT NULL PRIMARY KEY,
+                name TEXT NOT NULL DEFAULT ''
+            );
+
+            CREATE TABLE roles (
+                user_id INTEGER NOT NULL,
+                role TEXT NOT NULL,
+                PRIMARY KEY (user_id, role)
+            );
+
+            CREATE TABLE teams (
+                id INTEGER NOT NULL PRIMARY KEY,
+                name TEXT NOT NULL DEFAULT ''
+            );
+
+            CREATE TABLE memberships (
+                team_id INTEGER NOT NULL,
+                user_id INTEGER NOT NULL,
+                PRIMARY KEY (team_id, user_id)
+            );
+        ";
+
+        let mut schema = parse_sql(schema_sql)?;
+        let subs = SubsManager::default();
+
+        let (_tmpdir, subscriptions_path, pool, mut conn) = setup_test_db(&mut schema).await?;
+
+        {
+            let tx = conn.transaction()?;
+            tx.execute_batch(
+                "
+                INSERT INTO users (id, name) VALUES (1, 'alice'), (2, 'bob'), (3, 'carol');
+                INSERT INTO roles (user_id, role) VALUES (1, 'admin'), (2, 'member');
+                INSERT INTO teams (id, name) VALUES (1, 'core'), (2, 'ops');
+                INSERT INTO memberships (team_id, user_id) VALUES (1, 1), (2, 2);
+                ",
+            )?;
+            tx.commit()?;
+        }
+
+        let queries = [
+            "SELECT u.name FROM users u WHERE u.id IN (SELECT user_id FROM roles WHERE role = 'admin')",
+            "WITH admins AS (SELECT user_id FROM roles WHERE role = 'admin')
+                SELECT t.name FROM teams t
+                WHERE EXISTS (SELECT 1 FROM memberships m WHERE m.team_id = t.id AND m.user_id IN admins)",
+            "SELECT count(*) FROM users WHERE id IN (SELECT user_id FROM roles WHERE role = 'admin')",
+        ];
+
+        // correlated subqueries only re-evaluate the rows sharing keys with changed rows
+        let links = [
+            (vec!["roles"], vec![]),
+            (vec!["memberships"], vec!["roles"]),
+            (vec!["roles"], vec![]),
+        ];
+        // CTEs aren't inlined in the outer query, their rows couldn't be matched
+        for sql in [
+            "WITH admins AS (SELECT user_id FROM roles WHERE role = 'admin')
+                SELECT u.name FROM admins a JOIN users u ON u.id = a.user_id",
+            "WITH admins AS (SELECT user_id FROM roles WHERE role = 'admin')
+                SELECT u.name FROM users u JOIN admins a ON a.user_id = u.id",
+        ] {
+            assert!(matches!(
+                rewrite_query(&schema, sql),
+                Err(MatcherError::CteInOuterQuery(name)) if name == "admins"
+            ));
+        }
+
+        for (sql, (linked, unlinked)) in queries.iter().zip(links) {
+            let rewritten = rewrite_query(&schema, sql)?;
+            assert_eq!(rewritten.linked.keys().collect::<Vec<_>>(), linked);
+            assert_eq!(
+                rewritten.parsed.unlinked_tables.iter().collect::<Vec<_>>(),
+                unlinked
+            );
+        }
+
+        let mut subs_rows = vec![];
+        for sql in queries {
+            let (matcher, maybe_created) = subs.get_or_insert(
+                sql,
+                subscriptions_path.as_path(),
+                &schema,
+                &pool,
+                tripwire.clone(),
+            )?;
+            let mut rx = maybe_created.unwrap().evt_rx;
+            assert!(matches!(rx.recv().await.unwrap(), QueryEvent::Columns(_)));
+            let rows = recv_initial_rows(&mut rx).await;
+            subs_rows.push((matcher, rx, rows));
+        }
+
+        let text = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| vec![SqliteValue::Text((*name).into())])
+                .collect::<Vec<_>>()
+        };
+        let count = |n: i64| vec![vec![SqliteValue::Integer(n)]];
+
+        let initial = [text(&["alice"]), text(&["core"]), count(1)];
+        for ((_, _, rows), expected) in subs_rows.iter().zip(initial) {
+            assert_eq!(sorted_rows(rows), expected);
+        }
+
+        let steps = [
+            // a subquery starts matching
+            (
+                "INSERT INTO roles (user_id, role) VALUES (2, 'admin')",
+                [text(&["alice", "bob"]), text(&["core", "ops"]), count(2)],
+            ),
+            // a subquery stops matching
+            (
+                "DELETE FROM roles WHERE user_id = 1 AND role = 'admin'",
+                [text(&["bob"]), text(&["ops"]), count(1)],
+            ),
+            // the outer query's own tables still match incrementally
+            (
+                "UPDATE users SET name = 'robert' WHERE id = 2",
+                [text(&["robert"]), text(&["ops"]), count(1)],
+            ),
+            // a table only read by a correlated subquery
+            (
+                "INSERT INTO memberships (team_id, user_id) VALUES (1, 2)",
+                [text(&["robert"]), text(&["core", "ops"]), count(1)],
+            ),
+            // a listed key changes, the rows of both keys are re-evaluated
+            (
+                "UPDATE roles SET user_id = 3 WHERE user_id = 2 AND role = 'admin'",
+                [text(&["carol"]), text(&[]), count(1)],
+            ),
+            (
+                "INSERT INTO memberships (team_id, user_id) VALUES (1, 3)",
+                [text(&["carol"]), text(&["core"]), count(1)],
+            ),
+            // a correlated key changes
+            (
+                "UPDATE memberships SET team_id = 2 WHERE team_id = 1 AND user_id = 3",
+                [text(&["carol"]), text(&["ops"]), count(1)],
+            ),
+        ];
+
+        for (i, (stmt, expected)) in steps.into_iter().enumerate() {
+            {
+                let tx = conn.transaction()?;
+                assert_eq!(tx.execute(stmt, ())?, 1);
+                tx.commit()?;
+            }
+
+            for ((matcher, rx, rows), expected) in subs_rows.iter_mut().zip(expected) {
+                filter_changes_from_db(matcher, &conn, None, CrsqlDbVersion(i as u64 + 2))?;
+                recv_changes_until(rx, rows, &expected).await?;
+            }
+        }
+
+        for (matcher, _, _) in subs_rows {
+            matcher.cleanup().await;
+        }
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    /// Creates a database with `schema` applied in a temporary directory, along
+    /// with a path for subscriptions next to it
+    async fn setup_test_db(
+        schema: &mut Schema,
+    ) -> Result<
+        (tempfile::TempDir, Utf8PathBuf, SplitPool, WriteConn),
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        let tmpdir = tempfile::tempdir()?;
+        let db_path = tmpdir.path().join("test.db");
+        let subscriptions_path: Utf8PathBuf =
+            tmpdir.path().join("subs").display().to_string().into();
+
+        let pool = SplitPool::create(&db_path, Arc::new(Semaphore::new(1))).await?;
+        let mut conn = pool.write_priority().await?;
+        setup_conn(&conn)?;
+        migrate(Arc::new(uhlc::HLC::default()), &mut conn)?;
+        let tx = conn.transaction()?;
+        apply_schema(&tx, &Schema::default(), schema)?;
+        tx.commit()?;
+
+        Ok((tmpdir, subscriptions_path, pool, conn))
+    }
+
+    fn sorted_rows(rows: &HashMap<RowId, Vec<SqliteValue>>) -> Vec<Vec<SqliteValue>> {
+        let mut rows = rows.values().cloned().collect::<Vec<_>>();
+        rows.sort_by_key(|cells| format!("{cells:?}"));
+        rows
+    }
+
+    async fn recv_initial_rows(
+        rx: &mut mpsc::Receiver<QueryEvent>,
+    ) -> HashMap<RowId, Vec<SqliteValue>> {
+        let mut rows = HashMap::new();
+        loop {
+            match rx.recv().await.unwrap() {
+                QueryEvent::Row(rowid, cells) => {
+                    rows.insert(rowid, cells);
+                }
+                QueryEvent::EndOfQuery { .. } => return rows,
+                evt => panic!("unexpected event: {evt:?}"),
+            }
+        }
+    }
+
+    /// Applies changes to `rows` until they match `expected`, then checks no
+    /// other change follows
+    async fn recv_changes_until(
+        rx: &mut mpsc::Receiver<QueryEvent>,
+        rows: &mut HashMap<RowId, Vec<SqliteValue>>,
+        expected: &[Vec<SqliteValue>],
+    ) -> Result<(), tokio::time::error::Elapsed> {
+        while sorted_rows(rows) != expected {
+            match tokio::time::timeout(Duration::from_secs(5), rx.recv()).await? {
+                Some(QueryEvent::Change(ChangeType::Delete, rowid, _, _)) => {
+                    rows.remove(&rowid);
+                }
+                Some(QueryEvent::Change(_, rowid, cells, _)) => {
+                    rows.insert(rowid, cells);
+                }
+                evt => panic!("unexpected event: {evt:?}"),
+            }
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(rx.try_recv(), Err(mpsc::error::TryRecvError::Empty));
+
+        Ok(())
+    }
+
     fn filter_changes_from_db(
         matcher: &MatcherHandle,
         state_conn: &Connection,
@@ -27,6 +27,8 @@ pub enum SyncMessageV1 {
     Clock(Timestamp),
     Rejection(SyncRejectionV1),
     Request(SyncRequestV1),
+    // answers a `BiPayloadV1::HasVersion` check
+    HasVersion(bool),
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Readable, Writable)]
@@ -124,6 +126,24 @@ impl SyncStateV1 {
                 .unwrap_or(0)
     }
 
+    /// Whether this state has fully applied an actor's version
+    pub fn contains(&self, actor_id: &ActorId, version: CrsqlDbVersion) -> bool {
+        let within_head = self
+            .heads
+            .get(actor_id)
+            .map_or(false, |head| *head >= version);
+        let needed = self
+            .need
+            .get(actor_id)
+            .map_or(false, |ranges| ranges.iter().any(|r| r.contains(&version)));
+        let partial = self
+            .partial_need
+            .get(actor_id)
+            .map_or(false, |partials| partials.contains_key(&version));
+
+        within_head && !needed && !partial
+    }
+
     pub fn compute_available_needs(
         &self,
         other: &SyncStateV1,
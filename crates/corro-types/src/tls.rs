@@ -1,17 +1,89 @@
-use std::net::IpAddr;
+use std::{net::IpAddr, sync::Arc};
 
+use camino::Utf8Path;
 use rcgen::{
     BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, DnValue, IsCa,
     KeyIdMethod, KeyPair, KeyUsagePurpose, SanType, PKCS_ECDSA_P384_SHA384,
 };
 use time::OffsetDateTime;
 
+use crate::config::ServerTlsConfig;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
     Rcgen(#[from] rcgen::RcgenError),
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+    #[error("no private keys found")]
+    NoPrivateKey,
+    #[error("ca_file required in tls config for server client cert auth verification")]
+    CaFileRequired,
+}
+
+/// Reads certificates from a PEM file, or a single DER one if the file has a
+/// `.der` extension
+pub fn load_certs(path: &Utf8Path) -> Result<Vec<rustls::Certificate>, Error> {
+    let certs = std::fs::read(path)?;
+    Ok(if path.extension().map_or(false, |x| x == "der") {
+        vec![rustls::Certificate(certs)]
+    } else {
+        rustls_pemfile::certs(&mut &*certs)?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect()
+    })
+}
+
+/// Reads a PKCS8 or RSA private key from a PEM file, or a DER one if the file
+/// has a `.der` extension
+pub fn load_private_key(path: &Utf8Path) -> Result<rustls::PrivateKey, Error> {
+    let key = std::fs::read(path)?;
+    if path.extension().map_or(false, |x| x == "der") {
+        return Ok(rustls::PrivateKey(key));
+    }
+
+    let pkcs8 = rustls_pemfile::pkcs8_private_keys(&mut &*key)?;
+    match pkcs8.into_iter().next() {
+        Some(x) => Ok(rustls::PrivateKey(x)),
+        None => rustls_pemfile::rsa_private_keys(&mut &*key)?
+            .into_iter()
+            .next()
+            .map(rustls::PrivateKey)
+            .ok_or(Error::NoPrivateKey),
+    }
+}
+
+pub fn load_root_store(path: &Utf8Path) -> Result<rustls::RootCertStore, Error> {
+    let mut root_store = rustls::RootCertStore::empty();
+
+    for cert in load_certs(path)? {
+        root_store.add(&cert)?;
+    }
+
+    Ok(root_store)
+}
+
+/// Builds the rustls config of a server, verifying client certificates if
+/// required
+pub fn server_config(tls: &ServerTlsConfig) -> Result<rustls::ServerConfig, Error> {
+    let key = load_private_key(&tls.key_file)?;
+    let certs = load_certs(&tls.cert_file)?;
+
+    let config = rustls::ServerConfig::builder().with_safe_defaults();
+
+    let config = if tls.verify_client {
+        let ca_file = tls.ca_file.as_ref().ok_or(Error::CaFileRequired)?;
+        config.with_client_cert_verifier(Arc::new(
+            rustls::server::AllowAnyAuthenticatedClient::new(load_root_store(ca_file)?),
+        ))
+    } else {
+        config.with_no_client_auth()
+    };
+
+    Ok(config.with_single_cert(certs, key)?)
 }
 
 pub fn generate_ca() -> Result<Certificate, Error> {
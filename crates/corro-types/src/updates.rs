@@ -2,17 +2,25 @@ use crate::actor::ActorId;
 use crate::agent::SplitPool;
 use crate::change::Change;
 use crate::pubsub::{unpack_columns, MatchCandidates, MatchableChange, MatcherError};
-use crate::schema::Schema;
+use crate::schema::{Schema, Table};
+use crate::sqlite::CrConn;
 use antithesis_sdk::assert_sometimes;
 use async_trait::async_trait;
+use camino::{Utf8Path, Utf8PathBuf};
 use corro_api_types::sqlite::ChangeType;
-use corro_api_types::{ColumnName, NotifyEvent, SqliteValueRef, TableName};
+use corro_api_types::{
+    ChangeId, ColumnChange, ColumnName, NotifyEvent, SqliteValue, SqliteValueRef, TableName,
+    UpdatesRequest,
+};
 use corro_base_types::CrsqlDbVersion;
+use fallible_iterator::FallibleIterator;
 use indexmap::{map::Entry, IndexMap};
 use metrics::{counter, histogram, Counter};
 use parking_lot::RwLock;
-use rusqlite::Connection;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
 use spawn::spawn_counted;
+use sqlite3_parser::ast::{Cmd, Expr, Id, Literal, Name, OneSelect, Operator, Stmt, UnaryOperator};
+use sqlite3_parser::lexer::sql::Parser;
 use std::collections::BTreeMap;
 use std::fmt::{Debug, Formatter};
 use std::sync::Arc;
@@ -32,16 +40,29 @@ pub trait Manager<H> {
     fn get_handles(&self) -> BTreeMap<Uuid, H>;
 }
 
+/// Changes matched by a handle, sent to it in batches
+pub trait CandidateSet: Default + Send + 'static {
+    fn pks(&self) -> &MatchCandidates;
+}
+
+impl CandidateSet for MatchCandidates {
+    fn pks(&self) -> &MatchCandidates {
+        self
+    }
+}
+
 #[async_trait]
 pub trait Handle {
+    type Candidates: CandidateSet;
+
     fn id(&self) -> Uuid;
     fn cancelled(&self) -> WaitForCancellationFuture;
     fn filter_matchable_change(
         &self,
-        candidates: &mut MatchCandidates,
+        candidates: &mut Self::Candidates,
         change: MatchableChange,
     ) -> bool;
-    fn changes_tx(&self) -> mpsc::Sender<(MatchCandidates, CrsqlDbVersion)>;
+    fn changes_tx(&self) -> mpsc::Sender<(Self::Candidates, CrsqlDbVersion)>;
     async fn cleanup(&self);
     fn get_counter(&self, table: &str) -> &HandleMetrics;
 }
@@ -79,8 +100,24 @@ impl Manager<UpdateHandle> for UpdatesManager {
     }
 }
 
+/// Primary keys matched by an updates handle and, if it includes them, the
+/// new values of their changed columns
+#[derive(Debug, Default)]
+pub struct UpdateCandidates {
+    pub pks: MatchCandidates,
+    pub columns: IndexMap<Vec<u8>, IndexMap<ColumnName, SqliteValue>>,
+}
+
+impl CandidateSet for UpdateCandidates {
+    fn pks(&self) -> &MatchCandidates {
+        &self.pks
+    }
+}
+
 #[async_trait]
 impl Handle for UpdateHandle {
+    type Candidates = UpdateCandidates;
+
     fn id(&self) -> Uuid {
         self.inner.id
     }
@@ -91,7 +128,7 @@ impl Handle for UpdateHandle {
 
     fn filter_matchable_change(
         &self,
-        candidates: &mut MatchCandidates,
+        candidates: &mut UpdateCandidates,
         change: MatchableChange,
     ) -> bool {
         if change.table.to_string() != self.inner.name {
@@ -99,28 +136,50 @@ impl Handle for UpdateHandle {
         }
 
         trace!("filtering change {change:?}");
-        // don't double process the same pk
-        if candidates
+        // don't double process the same pk, but keep collecting its columns
+        let inserted = if candidates
+            .pks
             .get(change.table)
             .map(|pks| pks.contains_key(change.pk))
             .unwrap_or_default()
         {
             trace!("already contained key");
-            return false;
-        }
-
-        if let Some(v) = candidates.get_mut(change.table) {
-            v.insert(change.pk.into(), change.cl).is_none()
+            false
         } else {
-            candidates.insert(
-                change.table.clone(),
-                [(change.pk.to_vec(), change.cl)].into(),
-            );
+            if let Some(filter) = &self.inner.filter {
+                match unpack_columns(change.pk) {
+                    Ok(pk) if filter.matches(&pk) => {}
+                    Ok(_) => return false,
+                    Err(e) => {
+                        warn!(update_id = %self.inner.id, "could not unpack pk to filter change: {e}");
+                        return false;
+                    }
+                }
+            }
+
+            if let Some(v) = candidates.pks.get_mut(change.table) {
+                v.insert(change.pk.into(), change.cl);
+            } else {
+                candidates.pks.insert(
+                    change.table.clone(),
+                    [(change.pk.to_vec(), change.cl)].into(),
+                );
+            }
             true
+        };
+
+        if self.inner.columns && !change.column.is_crsql_sentinel() {
+            candidates
+                .columns
+                .entry(change.pk.to_vec())
+                .or_default()
+                .insert(change.column.clone(), change.val.clone());
         }
+
+        inserted
     }
 
-    fn changes_tx(&self) -> mpsc::Sender<(MatchCandidates, CrsqlDbVersion)> {
+    fn changes_tx(&self) -> mpsc::Sender<(UpdateCandidates, CrsqlDbVersion)> {
         self.inner.changes_tx.clone()
     }
 
@@ -142,12 +201,16 @@ const UPDATE_EVENT_CHANNEL_CAP: usize = 512;
 
 // tools to bootstrap a new notifier
 pub struct UpdateCreated {
-    pub evt_rx: mpsc::Receiver<NotifyEvent>,
+    pub evt_rx: mpsc::Receiver<(ChangeId, NotifyEvent)>,
 }
 
+/// Table name, filter key and whether columns are included, streams with the
+/// same key are shared
+type UpdateKey = (String, Option<String>, bool);
+
 #[derive(Debug, Default)]
 struct InnerUpdatesManager {
-    tables: BTreeMap<String, Uuid>,
+    tables: BTreeMap<UpdateKey, Uuid>,
     handles: BTreeMap<Uuid, UpdateHandle>,
 }
 
@@ -163,22 +226,43 @@ impl InnerUpdatesManager {
 }
 
 impl UpdatesManager {
-    pub fn get(&self, table: &str) -> Option<UpdateHandle> {
-        let id = self.0.read().tables.get(table).cloned();
+    pub fn get(
+        &self,
+        table: &str,
+        filter: Option<&PkFilter>,
+        columns: bool,
+    ) -> Option<UpdateHandle> {
+        let key = (table.to_owned(), filter.map(PkFilter::key), columns);
+        let id = self.0.read().tables.get(&key).cloned();
         if let Some(id) = id {
             return self.0.read().handles.get(&id).cloned();
         }
         None
     }
 
+    /// Get or create the handle for a table, optionally restricted to rows
+    /// matching a `WHERE` clause over its primary key columns
     pub fn get_or_insert(
         &self,
         tbl_name: &str,
+        req: &UpdatesRequest,
         schema: &Schema,
-        _pool: &SplitPool,
+        pool: &SplitPool,
+        updates_path: &Utf8Path,
         tripwire: Tripwire,
     ) -> Result<(UpdateHandle, Option<UpdateCreated>), MatcherError> {
-        if let Some(handle) = self.get(tbl_name) {
+        let filter = match req.filter.as_deref() {
+            Some(filter) => {
+                let table = schema
+                    .tables
+                    .get(tbl_name)
+                    .ok_or_else(|| MatcherError::TableNotFound(tbl_name.to_string()))?;
+                Some(PkFilter::parse(table, filter)?)
+            }
+            None => None,
+        };
+
+        if let Some(handle) = self.get(tbl_name, filter.as_ref(), req.columns) {
             return Ok((handle, None));
         }
 
@@ -186,7 +270,23 @@ impl UpdatesManager {
         let (evt_tx, evt_rx) = mpsc::channel(UPDATE_EVENT_CHANNEL_CAP);
 
         let id = Uuid::new_v4();
-        let handle_res = UpdateHandle::create(id, tbl_name, schema, evt_tx, tripwire);
+        let key = (
+            tbl_name.to_string(),
+            filter.as_ref().map(PkFilter::key),
+            req.columns,
+        );
+        let log_path = UpdatesLog::path(updates_path, &key);
+        let handle_res = UpdateHandle::create(
+            id,
+            tbl_name,
+            filter,
+            req.columns,
+            schema,
+            log_path,
+            pool.client_dedicated()?,
+            evt_tx,
+            tripwire,
+        );
 
         let handle = match handle_res {
             Ok(handle) => handle,
@@ -196,7 +296,7 @@ impl UpdatesManager {
         };
 
         inner.handles.insert(id, handle.clone());
-        inner.tables.insert(tbl_name.to_string(), id);
+        inner.tables.insert(key, id);
 
         Ok((handle, Some(UpdateCreated { evt_rx })))
     }
@@ -217,8 +317,12 @@ pub struct UpdateHandle {
 pub struct InnerUpdateHandle {
     id: Uuid,
     name: String,
+    filter: Option<PkFilter>,
+    /// whether notifications include changed columns
+    columns: bool,
+    log_path: Utf8PathBuf,
     cancel: CancellationToken,
-    changes_tx: mpsc::Sender<(MatchCandidates, CrsqlDbVersion)>,
+    changes_tx: mpsc::Sender<(UpdateCandidates, CrsqlDbVersion)>,
     counters: HandleMetrics,
 }
 
@@ -227,11 +331,16 @@ impl UpdateHandle {
         self.inner.id
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn create(
         id: Uuid,
         tbl_name: &str,
+        filter: Option<PkFilter>,
+        columns: bool,
         schema: &Schema,
-        evt_tx: mpsc::Sender<NotifyEvent>,
+        log_path: Utf8PathBuf,
+        state_conn: CrConn,
+        evt_tx: mpsc::Sender<(ChangeId, NotifyEvent)>,
         tripwire: Tripwire,
     ) -> Result<UpdateHandle, MatcherError> {
         // check for existing handles
@@ -244,12 +353,17 @@ impl UpdateHandle {
             None => return Err(MatcherError::TableNotFound(tbl_name.to_string())),
         };
 
+        let log = UpdatesLog::open(&log_path)?;
+
         let cancel = CancellationToken::new();
         let (changes_tx, changes_rx) = mpsc::channel(20480);
         let handle = UpdateHandle {
             inner: Arc::new(InnerUpdateHandle {
                 id,
                 name: tbl_name.to_owned(),
+                filter,
+                columns,
+                log_path,
                 cancel: cancel.clone(),
                 changes_tx,
                 counters: HandleMetrics {
@@ -257,47 +371,493 @@ impl UpdateHandle {
                 },
             }),
         };
-        spawn_counted(batch_candidates(id, cancel, evt_tx, changes_rx, tripwire));
+        spawn_counted(batch_candidates(
+            handle.clone(),
+            state_conn,
+            log,
+            cancel,
+            evt_tx,
+            changes_rx,
+            tripwire,
+        ));
         Ok(handle)
     }
 
+    /// Notifications sent after change `from`, read from the stream's log
+    pub fn changes_since(
+        &self,
+        from: ChangeId,
+    ) -> Result<Vec<(ChangeId, NotifyEvent)>, MatcherError> {
+        UpdatesLog::changes_since(&self.inner.log_path, from)
+    }
+
     pub async fn cleanup(self) {
         self.inner.cancel.cancel();
         info!(update_id = %self.inner.id, "Canceled update");
     }
 }
 
-fn handle_candidates(
-    evt_tx: mpsc::Sender<NotifyEvent>,
-    candidates: MatchCandidates,
+/// Notifications kept in an updates stream's log
+const UPDATES_LOG_LEN: u64 = 10_000;
+
+/// Bounded log of the notifications sent by an updates stream, which clients
+/// can resume from. Logs are named after the stream's table and options, so
+/// they're kept when a stream is created again, after a restart for example.
+/// Each append also records the versions of every actor the stream had seen,
+/// changes applied after them while the stream wasn't running are caught up
+/// when it's created again.
+struct UpdatesLog {
+    conn: Connection,
+    last_id: ChangeId,
+    /// whether the log was just created, it has no versions to catch up from
+    new: bool,
+}
+
+impl UpdatesLog {
+    fn path(updates_path: &Utf8Path, key: &UpdateKey) -> Utf8PathBuf {
+        let key = serde_json::json!(key).to_string();
+        updates_path.join(format!(
+            "{}.sqlite",
+            hex::encode(seahash::hash(key.as_bytes()).to_be_bytes())
+        ))
+    }
+
+    fn open(path: &Utf8Path) -> Result<Self, MatcherError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let conn = Connection::open(path)?;
+        // a previous stream for the same key may still be shutting down
+        conn.busy_timeout(Duration::from_secs(5))?;
+
+        let new = conn.query_row(
+            "SELECT COUNT(*) = 0 FROM sqlite_master WHERE name = 'changes'",
+            [],
+            |row| row.get(0),
+        )?;
+
+        conn.execute_batch(
+            r#"
+            PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = NORMAL;
+
+            CREATE TABLE IF NOT EXISTS changes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                event TEXT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS db_versions (
+                actor_id BLOB PRIMARY KEY NOT NULL,
+                db_version INTEGER NOT NULL
+            ) WITHOUT ROWID;
+        "#,
+        )?;
+
+        let last_id = Self::last_id(&conn)?;
+
+        Ok(Self { conn, last_id, new })
+    }
+
+    fn last_id(conn: &Connection) -> rusqlite::Result<ChangeId> {
+        Ok(conn
+            .query_row(
+                "SELECT seq FROM sqlite_sequence WHERE name = 'changes'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or_default())
+    }
+
+    fn next_id(&mut self) -> ChangeId {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    /// Reads notifications from a separate connection, the log is owned by
+    /// the stream's task
+    fn changes_since(
+        path: &Utf8Path,
+        from: ChangeId,
+    ) -> Result<Vec<(ChangeId, NotifyEvent)>, MatcherError> {
+        let mut conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        let tx = conn.transaction()?;
+
+        let max = Self::last_id(&tx)?;
+        let min = tx
+            .query_row("SELECT MIN(id) - 1 FROM changes", [], |row| {
+                row.get::<_, Option<ChangeId>>(0)
+            })?
+            .unwrap_or(max);
+
+        if from < min || from > max {
+            return Err(MatcherError::UpdatesChangeUnavailable { from, min, max });
+        }
+
+        let mut prepped =
+            tx.prepare("SELECT id, event FROM changes WHERE id > ? ORDER BY id ASC")?;
+        let events = prepped
+            .query_map([from], |row| {
+                let event = serde_json::from_str(row.get_ref(1)?.as_str()?).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        1,
+                        rusqlite::types::Type::Text,
+                        Box::new(e),
+                    )
+                })?;
+                Ok((row.get(0)?, event))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(events)
+    }
+
+    /// Versions of every actor the stream had seen when it last logged
+    /// notifications, or `None` if it never did
+    fn db_versions(&self) -> rusqlite::Result<Option<Vec<(ActorId, CrsqlDbVersion)>>> {
+        if self.new {
+            return Ok(None);
+        }
+
+        self.conn
+            .prepare_cached("SELECT actor_id, db_version FROM db_versions")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map(Some)
+    }
+
+    /// Logs notifications along with the versions they were matched up to,
+    /// and drops the ones that don't fit anymore
+    fn append(
+        &mut self,
+        events: &[(ChangeId, NotifyEvent)],
+        db_versions: &[(ActorId, CrsqlDbVersion)],
+    ) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+
+        {
+            let mut prepped = tx.prepare_cached("INSERT INTO changes (id, event) VALUES (?, ?)")?;
+            for (change_id, event) in events {
+                let json = serde_json::to_string(event)
+                    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+                prepped.execute(params![change_id, json])?;
+            }
+        }
+
+        {
+            let mut prepped = tx.prepare_cached(
+                "INSERT INTO db_versions (actor_id, db_version) VALUES (?, ?)
+                    ON CONFLICT (actor_id) DO UPDATE SET db_version = excluded.db_version",
+            )?;
+            for (actor_id, db_version) in db_versions {
+                prepped.execute(params![actor_id, db_version])?;
+            }
+        }
+
+        tx.execute(
+            "DELETE FROM changes WHERE id <= ?",
+            [self.last_id.0.saturating_sub(UPDATES_LOG_LEN)],
+        )?;
+
+        tx.commit()?;
+        self.new = false;
+
+        Ok(())
+    }
+}
+
+/// Versions of every actor up to which this node has all changes, a version
+/// that is still missing or partially applied stops its actor before it
+fn complete_db_versions(conn: &Connection) -> rusqlite::Result<Vec<(ActorId, CrsqlDbVersion)>> {
+    conn.prepare_cached(
+        r#"
+        SELECT versions.site_id, MIN(
+            versions.db_version,
+            IFNULL((SELECT MIN(gaps.start) - 1 FROM __corro_bookkeeping_gaps AS gaps
+                WHERE gaps.actor_id = versions.site_id), versions.db_version),
+            IFNULL((SELECT MIN(seqs.db_version) - 1 FROM __corro_seq_bookkeeping AS seqs
+                WHERE seqs.site_id = versions.site_id), versions.db_version)
+        )
+            FROM crsql_db_versions AS versions
+    "#,
+    )?
+    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+    .collect()
+}
+
+/// Changes of the handle's table applied after the logged versions, which the
+/// stream missed while it wasn't running
+fn catch_up_candidates(
+    handle: &UpdateHandle,
+    conn: &Connection,
+    logged: &[(ActorId, CrsqlDbVersion)],
+    db_versions: &[(ActorId, CrsqlDbVersion)],
+) -> rusqlite::Result<UpdateCandidates> {
+    let table = TableName(handle.inner.name.as_str().into());
+    let mut candidates = UpdateCandidates::default();
+
+    let mut prepped = conn.prepare_cached(
+        r#"
+        SELECT pk, cid, val, cl
+            FROM crsql_changes
+            WHERE "table" = ?
+              AND site_id = ?
+              AND db_version > ?
+    "#,
+    )?;
+
+    for (actor_id, db_version) in db_versions {
+        // actors the stream never saw are caught up from their first version
+        let from = logged
+            .iter()
+            .find(|(logged_id, _)| logged_id == actor_id)
+            .map(|(_, logged)| *logged)
+            .unwrap_or_default();
+        if from >= *db_version {
+            continue;
+        }
+
+        let mut rows = prepped.query(params![table, actor_id, from])?;
+        while let Some(row) = rows.next()? {
+            let pk: Vec<u8> = row.get(0)?;
+            let column: ColumnName = row.get(1)?;
+            let val: SqliteValue = row.get(2)?;
+            handle.filter_matchable_change(
+                &mut candidates,
+                MatchableChange {
+                    table: &table,
+                    pk: &pk,
+                    column: &column,
+                    val: &val,
+                    cl: row.get(3)?,
+                },
+            );
+        }
+    }
+
+    Ok(candidates)
+}
+
+/// Restricts an updates stream to rows whose primary key satisfies a simple
+/// `WHERE` clause: `=`, `IS` and `IN (...)` comparisons of primary key columns
+/// with literals, combined with `AND`
+#[derive(Clone, Debug, PartialEq)]
+pub struct PkFilter {
+    /// position of a column in the primary key, and the values it may have
+    columns: Vec<(usize, Vec<SqliteValue>)>,
+}
+
+impl PkFilter {
+    pub fn parse(table: &Table, filter: &str) -> Result<Self, MatcherError> {
+        let sql = format!("SELECT 1 WHERE {filter}");
+        let mut parser = Parser::new(sql.as_bytes());
+
+        let expr = match parser.next()? {
+            Some(Cmd::Stmt(Stmt::Select(select))) => match select.body.select {
+                OneSelect::Select { where_clause, .. } if select.body.compounds.is_none() => {
+                    where_clause
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        let expr = match expr {
+            Some(expr) if parser.next()?.is_none() => expr,
+            _ => return Err(MatcherError::UnsupportedFilter(filter.to_owned())),
+        };
+
+        let mut columns = vec![];
+        collect_pk_filter(table, &expr, &mut columns)?;
+        columns.sort_by_key(|(idx, _)| *idx);
+
+        Ok(Self { columns })
+    }
+
+    pub fn matches(&self, pk: &[SqliteValueRef]) -> bool {
+        self.columns.iter().all(|(idx, values)| {
+            pk.get(*idx)
+                .map(|value| values.iter().any(|v| v.as_ref() == *value))
+                .unwrap_or_default()
+        })
+    }
+
+    /// Canonical form of the filter, equivalent filters share a stream
+    fn key(&self) -> String {
+        serde_json::to_string(&self.columns).expect("could not serialize pk filter")
+    }
+}
+
+fn collect_pk_filter(
+    table: &Table,
+    expr: &Expr,
+    columns: &mut Vec<(usize, Vec<SqliteValue>)>,
 ) -> Result<(), MatcherError> {
-    if candidates.is_empty() {
-        return Ok(());
+    let unsupported = || MatcherError::UnsupportedExpr { expr: expr.clone() };
+    match expr {
+        Expr::Parenthesized(exprs) if exprs.len() == 1 => {
+            collect_pk_filter(table, &exprs[0], columns)
+        }
+        Expr::Binary(lhs, Operator::And, rhs) => {
+            collect_pk_filter(table, lhs, columns)?;
+            collect_pk_filter(table, rhs, columns)
+        }
+        Expr::Binary(lhs, Operator::Equals | Operator::Is, rhs) => {
+            let (col, value) = match (pk_column_index(table, lhs), pk_column_index(table, rhs)) {
+                (Some(col), None) => (col?, rhs),
+                (None, Some(col)) => (col?, lhs),
+                _ => return Err(unsupported()),
+            };
+            columns.push((col, vec![literal_value(value).ok_or_else(unsupported)?]));
+            Ok(())
+        }
+        Expr::InList {
+            lhs,
+            not: false,
+            rhs,
+        } => {
+            let col = pk_column_index(table, lhs).ok_or_else(unsupported)??;
+            let values = rhs
+                .iter()
+                .flatten()
+                .map(literal_value)
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(unsupported)?;
+            columns.push((col, values));
+            Ok(())
+        }
+        _ => Err(unsupported()),
     }
+}
+
+/// `None` if the expression isn't a column, an error if the column isn't part
+/// of the table's primary key
+fn pk_column_index(table: &Table, expr: &Expr) -> Option<Result<usize, MatcherError>> {
+    let name = match expr {
+        Expr::Id(Id(name)) | Expr::Name(Name(name)) => name,
+        Expr::Qualified(Name(tbl_name), Name(name))
+            if unquote(tbl_name).eq_ignore_ascii_case(&table.name) =>
+        {
+            name
+        }
+        _ => return None,
+    };
+    let name = unquote(name);
+
+    Some(
+        table
+            .pk
+            .iter()
+            .position(|pk| pk.eq_ignore_ascii_case(name))
+            .ok_or_else(|| MatcherError::NotPrimaryKey {
+                table: table.name.clone(),
+                column: name.to_owned(),
+            }),
+    )
+}
+
+/// Strips the quotes around an identifier, if any
+pub fn unquote(name: &str) -> &str {
+    name.strip_prefix('"')
+        .and_then(|n| n.strip_suffix('"'))
+        .or_else(|| name.strip_prefix('`').and_then(|n| n.strip_suffix('`')))
+        .or_else(|| name.strip_prefix('[').and_then(|n| n.strip_suffix(']')))
+        .unwrap_or(name)
+}
+
+fn literal_value(expr: &Expr) -> Option<SqliteValue> {
+    match expr {
+        Expr::Literal(Literal::Numeric(n)) => parse_numeric(n),
+        Expr::Unary(UnaryOperator::Negative, expr) => match expr.as_ref() {
+            Expr::Literal(Literal::Numeric(n)) => match parse_numeric(n)? {
+                SqliteValue::Integer(i) => Some(SqliteValue::Integer(-i)),
+                SqliteValue::Real(r) => Some(SqliteValue::from(-r.0)),
+                _ => None,
+            },
+            _ => None,
+        },
+        Expr::Literal(Literal::String(s)) => s
+            .strip_prefix('\'')
+            .and_then(|s| s.strip_suffix('\''))
+            .map(|s| SqliteValue::Text(s.replace("''", "'").into())),
+        Expr::Literal(Literal::Blob(b)) => hex::decode(b).ok().map(SqliteValue::from),
+        Expr::Literal(Literal::Null) => Some(SqliteValue::Null),
+        _ => None,
+    }
+}
+
+fn parse_numeric(n: &str) -> Option<SqliteValue> {
+    if let Some(hex) = n.strip_prefix("0x").or_else(|| n.strip_prefix("0X")) {
+        return i64::from_str_radix(hex, 16).ok().map(SqliteValue::Integer);
+    }
+    n.parse::<i64>()
+        .map(SqliteValue::Integer)
+        .or_else(|_| n.parse::<f64>().map(SqliteValue::from))
+        .ok()
+}
+
+fn handle_candidates(
+    evt_tx: mpsc::Sender<(ChangeId, NotifyEvent)>,
+    candidates: UpdateCandidates,
+    log: &mut UpdatesLog,
+    with_columns: bool,
+    db_versions: &[(ActorId, CrsqlDbVersion)],
+) -> Result<(), MatcherError> {
+    let UpdateCandidates {
+        pks: candidates,
+        mut columns,
+    } = candidates;
 
     trace!(
         "got some candidates for updates! {:?}",
         candidates.keys().collect::<Vec<_>>()
     );
 
+    let mut events = vec![];
+
     for (_, pks) in candidates {
-        let pks = pks
-            .iter()
-            .map(|(pk, cl)| unpack_columns(pk).map(|x| (x, *cl)))
-            .collect::<Result<Vec<(Vec<SqliteValueRef>, i64)>, _>>()?;
+        for (pk_bytes, cl) in pks.iter() {
+            let pk = unpack_columns(pk_bytes)?
+                .iter()
+                .map(|x| x.to_owned())
+                .collect::<Vec<_>>();
 
-        for (pk, cl) in pks {
             let mut change_type = ChangeType::Update;
             if cl % 2 == 0 {
                 change_type = ChangeType::Delete
             }
-            if let Err(e) = evt_tx.blocking_send(NotifyEvent::Notify(
-                change_type,
-                pk.iter().map(|x| x.to_owned()).collect::<Vec<_>>(),
-            )) {
-                debug!("could not send back row to matcher sub sender: {e}");
-                return Err(MatcherError::EventReceiverClosed);
-            }
+
+            let event = if with_columns {
+                let changes = if change_type == ChangeType::Delete {
+                    vec![]
+                } else {
+                    columns
+                        .swap_remove(pk_bytes)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|(column, value)| ColumnChange { column, value })
+                        .collect()
+                };
+                NotifyEvent::NotifyColumns(change_type, pk, changes)
+            } else {
+                NotifyEvent::Notify(change_type, pk)
+            };
+
+            events.push((log.next_id(), event));
+        }
+    }
+
+    // log before sending, so clients resuming concurrently don't miss any
+    log.append(&events, db_versions)?;
+
+    for event in events {
+        if let Err(e) = evt_tx.blocking_send(event) {
+            debug!("could not send back row to matcher sub sender: {e}");
+            return Err(MatcherError::EventReceiverClosed);
         }
     }
 
@@ -305,10 +865,12 @@ fn handle_candidates(
 }
 
 async fn batch_candidates(
-    id: Uuid,
+    handle: UpdateHandle,
+    state_conn: CrConn,
+    mut log: UpdatesLog,
     cancel: CancellationToken,
-    evt_tx: mpsc::Sender<NotifyEvent>,
-    mut changes_rx: mpsc::Receiver<(MatchCandidates, CrsqlDbVersion)>,
+    evt_tx: mpsc::Sender<(ChangeId, NotifyEvent)>,
+    mut changes_rx: mpsc::Receiver<(UpdateCandidates, CrsqlDbVersion)>,
     mut tripwire: Tripwire,
 ) {
     const PROCESS_CHANGES_THRESHOLD: usize = 1000;
@@ -324,9 +886,33 @@ async fn batch_candidates(
     // (TODO: maybe we could send the cl to the client? or read from db?)
     let mut cl_cache: IndexMap<(TableName, Vec<u8>), i64> = IndexMap::new();
 
+    let id = handle.id();
+
+    // notify changes applied while the stream wasn't running, before any
+    // live one is logged along with newer versions
+    if let Err(e) = block_in_place(|| {
+        let db_versions = complete_db_versions(&state_conn)?;
+        let candidates = match log.db_versions()? {
+            Some(logged) => catch_up_candidates(&handle, &state_conn, &logged, &db_versions)?,
+            None => UpdateCandidates::default(),
+        };
+        handle_candidates(
+            evt_tx.clone(),
+            candidates,
+            &mut log,
+            handle.inner.columns,
+            &db_versions,
+        )
+    }) {
+        if !matches!(e, MatcherError::EventReceiverClosed) {
+            error!(sub_id = %id, "could not catch up updates: {e}");
+        }
+        return;
+    }
+
     info!(sub_id = %id, "Starting loop to receive candidates from updates");
 
-    let mut buf = MatchCandidates::new();
+    let mut buf = UpdateCandidates::default();
     let mut buf_count = 0;
 
     // max duration of aggregating candidates
@@ -343,8 +929,9 @@ async fn batch_candidates(
             }
             Some((candidates, _)) = changes_rx.recv() => {
                 debug!(sub_id = %id, "updates got candidates: {candidates:?}");
+                let UpdateCandidates { pks: candidates, columns: mut changed } = candidates;
                 for (table, pk_map) in  candidates {
-                    let buffed = buf.entry(table.clone()).or_default();
+                    let buffed = buf.pks.entry(table.clone()).or_default();
 
                     for (pk, cl) in pk_map {
                         let e = cl_cache.entry((table.clone(), pk.clone()));
@@ -360,6 +947,14 @@ async fn batch_candidates(
                             }
                         }
 
+                        // columns changed before a delete don't apply anymore
+                        if buffed.get(&pk).map_or(false, |prev| *prev < cl) {
+                            buf.columns.swap_remove(&pk);
+                        }
+                        if let Some(changed) = changed.swap_remove(&pk) {
+                            buf.columns.entry(pk.clone()).or_default().extend(changed);
+                        }
+
                         buffed.insert(pk, cl);
                         buf_count += 1;
                     }
@@ -393,9 +988,17 @@ async fn batch_candidates(
         if process {
             let start = Instant::now();
 
-            if let Err(e) =
-                block_in_place(|| handle_candidates(evt_tx.clone(), std::mem::take(&mut buf)))
-            {
+            if let Err(e) = block_in_place(|| {
+                // every change buffered so far was applied before this
+                let db_versions = complete_db_versions(&state_conn)?;
+                handle_candidates(
+                    evt_tx.clone(),
+                    std::mem::take(&mut buf),
+                    &mut log,
+                    handle.inner.columns,
+                    &db_versions,
+                )
+            }) {
                 if !matches!(e, MatcherError::EventReceiverClosed) {
                     error!(sub_id = %id, "could not handle change: {e}");
                 }
@@ -439,7 +1042,7 @@ where
     assert_sometimes!(true, "Corrosion matches changes for updates");
     for (id, handle) in handles.iter() {
         trace!(sub_id = %id, %db_version, "attempting to match changes to a subscription");
-        let mut candidates = MatchCandidates::new();
+        let mut candidates = H::Candidates::default();
         let mut match_count = 0;
         for change in changes.iter().map(MatchableChange::from) {
             if handle.filter_matchable_change(&mut candidates, change) {
@@ -448,7 +1051,7 @@ where
         }
 
         // metrics...
-        for (table, pks) in candidates.iter() {
+        for (table, pks) in candidates.pks().iter() {
             handle
                 .get_counter(table)
                 .matched_count
@@ -497,13 +1100,13 @@ where
     let trait_type = manager.trait_type();
     let mut candidates = handles
         .iter()
-        .map(|(id, handle)| (id, (MatchCandidates::new(), handle)))
+        .map(|(id, handle)| (id, (H::Candidates::default(), handle)))
         .collect::<BTreeMap<_, _>>();
 
     {
         let mut prepped = conn.prepare_cached(
             r#"
-        SELECT "table", pk, cid, cl
+        SELECT "table", pk, cid, val, cl
             FROM crsql_changes
             WHERE db_version = ?
               AND site_id = ?
@@ -516,18 +1119,20 @@ where
                 row.get::<_, TableName>(0)?,
                 row.get::<_, Vec<u8>>(1)?,
                 row.get::<_, ColumnName>(2)?,
-                row.get::<_, i64>(3)?,
+                row.get::<_, SqliteValue>(3)?,
+                row.get::<_, i64>(4)?,
             ))
         })?;
 
         for change_res in rows {
-            let (table, pk, column, cl) = change_res?;
+            let (table, pk, column, val, cl) = change_res?;
 
             for (_id, (candidates, handle)) in candidates.iter_mut() {
                 let change = MatchableChange {
                     table: &table,
                     pk: &pk,
                     column: &column,
+                    val: &val,
                     cl,
                 };
                 handle.filter_matchable_change(candidates, change);
@@ -538,7 +1143,7 @@ where
     // metrics...
     for (id, (candidates, handle)) in candidates {
         let mut match_count = 0;
-        for (table, pks) in candidates.iter() {
+        for (table, pks) in candidates.pks().iter() {
             let count = pks.len();
             match_count += count;
             handle
@@ -572,3 +1177,111 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::parse_sql;
+
+    #[test]
+    fn test_updates_log() -> Result<(), MatcherError> {
+        let dir = tempfile::tempdir()?;
+        let path = Utf8PathBuf::from_path_buf(dir.path().join("log.sqlite")).unwrap();
+
+        let notify = |id: ChangeId| {
+            (
+                id,
+                NotifyEvent::Notify(ChangeType::Update, vec![SqliteValue::Integer(id.0 as i64)]),
+            )
+        };
+
+        let actor_id = ActorId(Uuid::new_v4());
+
+        let mut log = UpdatesLog::open(&path)?;
+        assert_eq!(log.db_versions()?, None);
+
+        let events: Vec<_> = (0..3).map(|_| notify(log.next_id())).collect();
+        log.append(&events, &[(actor_id, CrsqlDbVersion(2))])?;
+
+        assert_eq!(UpdatesLog::changes_since(&path, ChangeId(1))?, events[1..]);
+        assert!(UpdatesLog::changes_since(&path, ChangeId(3))?.is_empty());
+        assert!(UpdatesLog::changes_since(&path, ChangeId(4)).is_err());
+
+        // the log and the versions it was matched up to outlive the stream
+        drop(log);
+        let mut log = UpdatesLog::open(&path)?;
+        assert_eq!(
+            log.db_versions()?,
+            Some(vec![(actor_id, CrsqlDbVersion(2))])
+        );
+        assert_eq!(UpdatesLog::changes_since(&path, ChangeId(1))?, events[1..]);
+        assert_eq!(log.last_id, ChangeId(3));
+
+        // only the last UPDATES_LOG_LEN notifications are kept
+        let events: Vec<_> = (0..UPDATES_LOG_LEN + 10)
+            .map(|_| notify(log.next_id()))
+            .collect();
+        log.append(&events, &[(actor_id, CrsqlDbVersion(3))])?;
+
+        assert!(UpdatesLog::changes_since(&path, ChangeId(4)).is_err());
+        assert_eq!(
+            UpdatesLog::changes_since(&path, ChangeId(13))?.len() as u64,
+            UPDATES_LOG_LEN
+        );
+        assert_eq!(
+            log.db_versions()?,
+            Some(vec![(actor_id, CrsqlDbVersion(3))])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pk_filter() {
+        let schema = parse_sql(
+            "CREATE TABLE hosts (tenant TEXT NOT NULL, id INTEGER NOT NULL, name TEXT, PRIMARY KEY (tenant, id));",
+        )
+        .unwrap();
+        let table = schema.tables.get("hosts").unwrap();
+
+        let matches = |filter: &str, tenant: &str, id: i64| {
+            let pk = [SqliteValue::from(tenant), SqliteValue::Integer(id)];
+            PkFilter::parse(table, filter)
+                .unwrap()
+                .matches(&pk.iter().map(SqliteValue::as_ref).collect::<Vec<_>>())
+        };
+
+        assert!(matches("tenant = 'acme'", "acme", 1));
+        assert!(!matches("tenant = 'acme'", "other", 1));
+        assert!(matches("\"tenant\" = 'o''brien'", "o'brien", 1));
+        assert!(matches(
+            "hosts.id IN (1, -2) AND 'acme' = tenant",
+            "acme",
+            -2
+        ));
+        assert!(!matches("(id IN (1, -2)) AND tenant = 'acme'", "acme", 3));
+
+        // equivalent filters share a key
+        assert_eq!(
+            PkFilter::parse(table, "tenant = 'acme' AND id = 1")
+                .unwrap()
+                .key(),
+            PkFilter::parse(table, "id = 1 AND tenant = 'acme'")
+                .unwrap()
+                .key(),
+        );
+
+        assert!(matches!(
+            PkFilter::parse(table, "name = 'a'"),
+            Err(MatcherError::NotPrimaryKey { .. })
+        ));
+        assert!(matches!(
+            PkFilter::parse(table, "id = 1 OR id = 2"),
+            Err(MatcherError::UnsupportedExpr { .. })
+        ));
+        assert!(matches!(
+            PkFilter::parse(table, "id = 1; DELETE FROM hosts"),
+            Err(MatcherError::UnsupportedFilter(_))
+        ));
+    }
+}
@@ -1,14 +1,16 @@
 use consul_client::{AgentCheck, AgentService, Client};
 use corro_api_types::ColumnType;
 use corro_client::CorrosionClient;
-use corro_types::{api::Statement, config::ConsulConfig};
+use corro_types::{
+    api::{ApiAddr, Statement},
+    config::ConsulConfig,
+};
 use futures::future::select;
 use metrics::{counter, histogram};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     hash::{Hash, Hasher},
-    net::SocketAddr,
     path::Path,
     time::{Duration, Instant, SystemTime},
 };
@@ -22,7 +24,7 @@ const CONSUL_PULL_INTERVAL: Duration = Duration::from_secs(1);
 
 pub async fn run<P: AsRef<Path>>(
     config: &ConsulConfig,
-    api_addr: SocketAddr,
+    api_addr: ApiAddr,
     db_path: P,
 ) -> eyre::Result<()> {
     let mut sigterm = signal(SignalKind::terminate())?;
@@ -798,7 +800,7 @@ mod tests {
         )
         .await?;
 
-        let ta1_client = CorrosionClient::new(ta1.agent.api_addr(), ta1.agent.db_path());
+        let ta1_client = CorrosionClient::new(ta1.agent.api_addr().unwrap(), ta1.agent.db_path());
 
         setup(&ta1_client).await?;
 
@@ -916,7 +918,7 @@ mod tests {
 
         assert_eq!(svc_hashes.get("service-id"), Some(&hash_service(&svc)));
 
-        let ta2_client = CorrosionClient::new(ta2.agent.api_addr(), ta2.agent.db_path());
+        let ta2_client = CorrosionClient::new(ta2.agent.api_addr().unwrap(), ta2.agent.db_path());
 
         setup(&ta2_client).await?;
 
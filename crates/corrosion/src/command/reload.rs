@@ -1,9 +1,10 @@
-use std::{net::SocketAddr, path::Path};
+use std::path::Path;
 
+use corro_api_types::ApiAddr;
 use corro_client::CorrosionApiClient;
 use tracing::info;
 
-pub async fn run<P: AsRef<Path>>(api_addr: SocketAddr, schema_paths: &[P]) -> eyre::Result<()> {
+pub async fn run<P: AsRef<Path>>(api_addr: ApiAddr, schema_paths: &[P]) -> eyre::Result<()> {
     let client = CorrosionApiClient::new(api_addr);
 
     client.schema_from_paths(schema_paths).await?;
@@ -15,7 +16,13 @@ pub async fn run<P: AsRef<Path>>(api_addr: SocketAddr, schema_paths: &[P]) -> ey
 mod tests {
     use super::*;
 
+    use std::os::unix::fs::PermissionsExt;
+
+    use camino::Utf8PathBuf;
+    use corro_api_types::{QueryEvent, SqliteValue, Statement};
     use corro_tests::launch_test_agent;
+    use corro_types::config::AuthzConfig;
+    use futures::StreamExt;
     use spawn::wait_for_all_pending_handles;
     use tripwire::Tripwire;
 
@@ -25,7 +32,7 @@ mod tests {
         let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
         let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
 
-        let client = corro_client::CorrosionApiClient::new(ta.agent.api_addr());
+        let client = corro_client::CorrosionApiClient::new(ta.agent.api_addr().unwrap());
         client
             .schema_from_paths(&ta.agent.config().db.schema_paths)
             .await?;
@@ -46,10 +53,85 @@ mod tests {
 
         println!("conf: {conf:?}");
 
-        run(ta.agent.api_addr(), &conf.db.schema_paths).await?;
+        run(ta.agent.api_addr().unwrap().into(), &conf.db.schema_paths).await?;
+
+        assert!(ta.agent.schema().read().tables.contains_key("blah"));
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn reload_over_unix_socket() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+        let socket_path = Utf8PathBuf::from_path_buf(dir.path().join("api.sock")).unwrap();
+
+        let ta = launch_test_agent(
+            |conf| {
+                let mut conf = conf.api_unix_path(socket_path.clone()).build()?;
+                conf.api.authorization = Some(AuthzConfig::BearerToken("secret".into()));
+                conf.api.unix_socket_mode = Some(0o600);
+                Ok(conf)
+            },
+            tripwire.clone(),
+        )
+        .await?;
+
+        assert_eq!(
+            std::fs::metadata(&socket_path)?.permissions().mode() & 0o777,
+            0o600
+        );
+
+        let mut conf = ta.agent.config().as_ref().clone();
+        let new_path = ta.tmpdir.path().join("schema2");
+        tokio::fs::create_dir_all(&new_path).await?;
+        tokio::fs::write(
+            new_path.join("blah.sql"),
+            b"CREATE TABLE blah (id BIGINT NOT NULL PRIMARY KEY);",
+        )
+        .await?;
+        conf.db
+            .schema_paths
+            .push(new_path.display().to_string().into());
+
+        // a token is still required over TCP
+        assert!(
+            run(ta.agent.api_addr().unwrap().into(), &conf.db.schema_paths)
+                .await
+                .is_err()
+        );
+        assert!(!ta.agent.schema().read().tables.contains_key("blah"));
 
+        run(ApiAddr::Unix(socket_path.clone()), &conf.db.schema_paths).await?;
         assert!(ta.agent.schema().read().tables.contains_key("blah"));
 
+        // queries are served over the socket too, without a client address
+        let client = CorrosionApiClient::new(ApiAddr::Unix(socket_path));
+        client
+            .execute(
+                &[Statement::Simple("INSERT INTO blah (id) VALUES (1)".into())],
+                None,
+            )
+            .await?;
+        let mut query = client
+            .query(&Statement::Simple("SELECT id FROM blah".into()), None)
+            .await?;
+        let mut rows = vec![];
+        while let Some(event) = query.next().await {
+            match event? {
+                QueryEvent::Row(_, cells) => rows.push(cells),
+                QueryEvent::EndOfQuery { .. } => break,
+                _ => {}
+            }
+        }
+        assert_eq!(rows, vec![vec![SqliteValue::Integer(1)]]);
+
         tripwire_tx.send(()).await.ok();
         tripwire_worker.await;
         wait_for_all_pending_handles().await;
@@ -1,12 +1,12 @@
 use std::{
     collections::{HashMap, HashSet},
     env::current_dir,
-    net::SocketAddr,
     time::{Duration, Instant},
 };
 
 use camino::Utf8PathBuf;
 use clap::Args;
+use corro_api_types::ApiAddr;
 use corro_client::CorrosionApiClient;
 use corro_tpl::{Dynamic, TemplateCommand, TemplateState};
 use futures::{stream::FuturesUnordered, StreamExt};
@@ -27,7 +27,7 @@ pub struct TemplateFlags {
 }
 
 pub async fn run(
-    api_addr: SocketAddr,
+    api_addr: ApiAddr,
     template: &Vec<String>,
     flags: &TemplateFlags,
 ) -> eyre::Result<()> {
@@ -1,5 +1,5 @@
 use std::{
-    net::{IpAddr, SocketAddr},
+    net::IpAddr,
     path::PathBuf,
     time::{Duration, Instant},
 };
@@ -13,12 +13,13 @@ use command::{
     tpl::TemplateFlags,
 };
 use corro_admin::TracingHandle;
-use corro_api_types::SqliteParam;
+use corro_api_types::{ApiAddr, SqliteParam, SqliteValue};
 use corro_client::CorrosionApiClient;
 use corro_types::{
     actor::{ActorId, ClusterId},
     api::{ExecResult, QueryEvent, Statement},
     base::CrsqlDbVersion,
+    broadcast::Timestamp,
     config::{default_admin_path, Config, ConfigError, LogFormat, OtelConfig},
 };
 use futures::StreamExt;
@@ -452,6 +453,28 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
                 }
             }
         }
+        Command::History { table, pk } => {
+            let pk = pk
+                .iter()
+                .map(|value| match value.parse::<i64>() {
+                    Ok(i) => SqliteValue::Integer(i),
+                    Err(_) => SqliteValue::Text(value.as_str().into()),
+                })
+                .collect::<Vec<_>>();
+
+            let history = cli.api_client()?.row_history(table, &pk).await?;
+
+            println!("column|actor_id|col_version|db_version|cl|ts|booked");
+            for col in history.columns {
+                let ts = Timestamp::from(col.ts)
+                    .to_time()
+                    .format(&time::format_description::well_known::Rfc3339)?;
+                println!(
+                    "{}|{}|{}|{}|{}|{ts}|{}",
+                    col.column.0, col.actor_id, col.col_version, col.db_version, col.cl, col.booked
+                );
+            }
+        }
         Command::Reload => {
             command::reload::run(cli.api_addr()?, &cli.config()?.db.schema_paths).await?
         }
@@ -545,6 +568,16 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
             conn.send_command(corro_admin::Command::Subs(corro_admin::SubsCommand::List))
                 .await?;
         }
+        Command::Subs(SubsCommand::Cancel { hash, id }) => {
+            let mut conn = AdminConn::connect(cli.admin_path()).await?;
+            conn.send_command(corro_admin::Command::Subs(
+                corro_admin::SubsCommand::Cancel {
+                    hash: hash.clone(),
+                    id: *id,
+                },
+            ))
+            .await?;
+        }
         Command::Log(LogCommand::Set { filter }) => {
             let mut conn = AdminConn::connect(cli.admin_path()).await?;
             conn.send_command(corro_admin::Command::Log(corro_admin::LogCommand::Set {
@@ -590,7 +623,7 @@ struct Cli {
     config_path: Utf8PathBuf,
 
     #[arg(long, global = true)]
-    api_addr: Option<SocketAddr>,
+    api_addr: Option<ApiAddr>,
 
     #[arg(long, global = true)]
     db_path: Option<Utf8PathBuf>,
@@ -609,11 +642,11 @@ impl Cli {
             .cloned()
     }
 
-    fn api_addr(&self) -> Result<SocketAddr, ConfigError> {
-        Ok(if let Some(api_addr) = self.api_addr {
-            api_addr
+    fn api_addr(&self) -> Result<ApiAddr, ConfigError> {
+        Ok(if let Some(ref api_addr) = self.api_addr {
+            api_addr.clone()
         } else {
-            *self.config()?.api.bind_addr.first().unwrap()
+            self.config()?.api.bind_addr.first().unwrap().clone()
         })
     }
 
@@ -696,6 +729,15 @@ enum Command {
         timeout: Option<u64>,
     },
 
+    /// Show which actor last wrote each column of a row, and when
+    History {
+        table: String,
+        /// Primary key values of the row, in order. Values that parse as
+        /// integers are sent as integers, others as text.
+        #[arg(required = true)]
+        pk: Vec<String>,
+    },
+
     /// Reload the config
     Reload,
 
@@ -826,6 +868,13 @@ enum SubsCommand {
         #[arg(long)]
         id: Option<Uuid>,
     },
+    /// Cancel a subscription, ending its streams and deleting its database
+    Cancel {
+        #[arg(long)]
+        hash: Option<String>,
+        #[arg(long)]
+        id: Option<Uuid>,
+    },
 }
 
 #[derive(Subcommand)]
@@ -30,7 +30,7 @@ async fn test_query() {
 
     let mut cmd = CORROSION_BIN.command();
 
-    let api_addr = ta.agent.api_addr();
+    let api_addr = ta.agent.api_addr().unwrap();
 
     let expected = ta.agent.actor_id().as_simple().to_string().to_uppercase();
 